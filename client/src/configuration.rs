@@ -34,18 +34,37 @@ use cfxcore::{
     consensus_parameters::*,
     light_protocol::LightNodeConfiguration,
     machine::Machine,
+    pos::{
+        consensus::{
+            BackpressureConfig, ConsensusQueueConfig,
+            EpochRetrievalFailoverConfig, VoteRebroadcastConfig,
+        },
+        protocol::{
+            blacklist::PeerBlacklistConfig,
+            liveness::PeerLivenessConfig,
+            message::{codec::CodecKind, msgid as pos_msgid},
+            message_size::MessageSizeLimits,
+            rate_limit::SendRateLimit,
+            request_manager::{
+                circuit_breaker::CircuitBreakerConfig,
+                retry_budget::RetryBudgetConfig, rpc_timeouts::RpcTimeouts,
+            },
+            send_jitter::SendJitterConfig,
+        },
+    },
     spec::CommonParams,
     sync::{ProtocolConfiguration, StateSyncConfiguration, SyncGraphConfig},
     sync_parameters::*,
     transaction_pool::TxPoolConfig,
     NodeType,
 };
+use diem_channel::message_queues::QueueStyle;
 use diem_types::term_state::{
     pos_state_config::PosStateConfig, IN_QUEUE_LOCKED_VIEWS,
     OUT_QUEUE_LOCKED_VIEWS, ROUND_PER_TERM, TERM_ELECTED_SIZE, TERM_MAX_SIZE,
 };
 use metrics::MetricsConfiguration;
-use network::DiscoveryConfiguration;
+use network::{service::ProtocolVersion, DiscoveryConfiguration};
 use txgen::TransactionGeneratorConfig;
 
 use crate::rpc::{
@@ -335,6 +354,57 @@ build_config! {
         (nonce_limit_transition_view, (u64), u64::MAX)
         (dev_pos_private_key_encryption_password, (Option<String>), None)
         (pos_started_as_voter, (bool), true)
+        (pos_message_compression_threshold, (usize), 8 * 1024)
+        (pos_request_max_retries, (usize), 0)
+        (pos_request_retry_base_delay_ms, (u64), 1000)
+        (pos_request_retry_max_delay_ms, (u64), 10000)
+        (pos_request_retry_backoff_multiplier, (f64), 2.0)
+        (pos_max_concurrent_rpcs, (usize), 4096)
+        (pos_max_pending_requests, (usize), 16 * 1024)
+        (pos_request_weight_by_voting_power, (bool), false)
+        (pos_rpc_circuit_breaker_max_failures, (u32), 5)
+        (pos_rpc_circuit_breaker_cooldown_ms, (u64), 30_000)
+        (pos_rpc_timeouts_ms, (Option<String>), None)
+        (pos_sync_retry_budget, (u32), 16)
+        (pos_sync_retry_budget_refill_ms, (u64), 1_000)
+        (pos_max_concurrent_epoch_retrievals, (usize), 8)
+        (pos_epoch_retrieval_timeout_ms, (u64), 5_000)
+        (pos_epoch_retrieval_max_attempts, (u32), 3)
+        (pos_epoch_sync_debounce_ms, (u64), 1_000)
+        (pos_consensus_msg_sequencing, (bool), true)
+        (pos_seen_msgs_cache_size, (usize), 4096)
+        (pos_consensus_queue_style, (String), "lifo".to_string())
+        (pos_consensus_msg_codec, (String), "bcs".to_string())
+        (pos_consensus_queue_size_per_key, (usize), 1)
+        (pos_consensus_queue_high_water_mark, (Option<usize>), None)
+        (pos_consensus_queue_low_water_mark, (Option<usize>), None)
+        (pos_max_message_size, (usize), 1024 * 1024)
+        (pos_max_proposal_size, (usize), 8 * 1024 * 1024)
+        (pos_max_block_retrieval_response_size, (usize), 64 * 1024 * 1024)
+        (pos_max_epoch_change_proof_size, (usize), 16 * 1024 * 1024)
+        (pos_max_consensus_msg_size, (usize), 64 * 1024 * 1024)
+        (pos_max_mempool_sync_msg_size, (usize), 16 * 1024 * 1024)
+        (pos_block_retrieval_max_response_bytes, (u64), 16 * 1024 * 1024)
+        (pos_block_stream_max_in_flight, (u32), 64)
+        (pos_block_retrieval_min_chunk_size, (u64), 4)
+        (pos_block_retrieval_max_chunk_size, (u64), 60)
+        (pos_block_retrieval_target_latency_ms, (u64), 200)
+        (pos_send_rate_limit_per_peer, (Option<f64>), None)
+        (pos_send_burst_per_peer, (f64), 100.0)
+        (pos_peer_send_queue_size, (usize), 1024)
+        (pos_epoch_change_chunk_size, (usize), 256 * 1024)
+        (pos_epoch_change_chunk_compression, (bool), true)
+        (pos_max_critical_send_jitter_ms, (u64), 0)
+        (pos_max_send_jitter_ms, (u64), 0)
+        (pos_broadcast_to_validators_only, (bool), false)
+        (pos_liveness_ping_interval_ms, (u64), 30_000)
+        (pos_liveness_max_missed_pongs, (u32), 3)
+        (pos_peer_blacklist_ttl_ms, (u64), 600_000)
+        (pos_peer_blacklist_max_violations, (u32), 3)
+        (pos_min_peer_protocol_version, (u8), 1)
+        (pos_handshake_timeout_ms, (u64), 10_000)
+        (pos_vote_rebroadcast_interval_ms, (u64), 500)
+        (pos_vote_rebroadcast_max_attempts, (u32), 0)
 
         // Light node section
         (ln_epoch_request_batch_size, (Option<usize>), None)
@@ -846,6 +916,199 @@ impl Configuration {
                 .expect("set to genesis if none"),
             check_status_genesis: self.raw_conf.check_status_genesis,
             pos_started_as_voter: self.raw_conf.pos_started_as_voter,
+            pos_message_compression_threshold: self
+                .raw_conf
+                .pos_message_compression_threshold,
+            pos_request_max_retries: self.raw_conf.pos_request_max_retries,
+            pos_request_retry_base_delay: Duration::from_millis(
+                self.raw_conf.pos_request_retry_base_delay_ms,
+            ),
+            pos_request_retry_max_delay: Duration::from_millis(
+                self.raw_conf.pos_request_retry_max_delay_ms,
+            ),
+            pos_request_retry_backoff_multiplier: self
+                .raw_conf
+                .pos_request_retry_backoff_multiplier,
+            pos_max_concurrent_rpcs: self.raw_conf.pos_max_concurrent_rpcs,
+            pos_max_pending_requests: self.raw_conf.pos_max_pending_requests,
+            pos_request_weight_by_voting_power: self
+                .raw_conf
+                .pos_request_weight_by_voting_power,
+            pos_rpc_circuit_breaker: CircuitBreakerConfig {
+                max_failures: self
+                    .raw_conf
+                    .pos_rpc_circuit_breaker_max_failures,
+                cooldown: Duration::from_millis(
+                    self.raw_conf.pos_rpc_circuit_breaker_cooldown_ms,
+                ),
+            },
+            pos_rpc_timeouts: match &self.raw_conf.pos_rpc_timeouts_ms {
+                Some(overrides) => RpcTimeouts::new(Duration::from_millis(
+                    self.raw_conf.blocks_request_timeout_ms,
+                ))
+                .with_overrides(overrides)
+                .expect("Invalid pos_rpc_timeouts_ms parameter!"),
+                None => RpcTimeouts::new(Duration::from_millis(
+                    self.raw_conf.blocks_request_timeout_ms,
+                )),
+            },
+            pos_sync_retry_budget: RetryBudgetConfig {
+                capacity: self.raw_conf.pos_sync_retry_budget,
+                refill_interval: Duration::from_millis(
+                    self.raw_conf.pos_sync_retry_budget_refill_ms,
+                ),
+            },
+            pos_max_concurrent_epoch_retrievals: self
+                .raw_conf
+                .pos_max_concurrent_epoch_retrievals,
+            pos_epoch_retrieval_failover: EpochRetrievalFailoverConfig {
+                timeout: Duration::from_millis(
+                    self.raw_conf.pos_epoch_retrieval_timeout_ms,
+                ),
+                max_attempts: self.raw_conf.pos_epoch_retrieval_max_attempts,
+            },
+            pos_epoch_sync_debounce: Duration::from_millis(
+                self.raw_conf.pos_epoch_sync_debounce_ms,
+            ),
+            pos_consensus_msg_sequencing: self
+                .raw_conf
+                .pos_consensus_msg_sequencing,
+            pos_seen_msgs_cache_size: self.raw_conf.pos_seen_msgs_cache_size,
+            pos_consensus_queue_config: ConsensusQueueConfig {
+                queue_style: match self
+                    .raw_conf
+                    .pos_consensus_queue_style
+                    .as_str()
+                {
+                    "lifo" => QueueStyle::LIFO,
+                    "fifo" => QueueStyle::FIFO,
+                    "klast" => QueueStyle::KLAST,
+                    _ => panic!("Invalid pos_consensus_queue_style parameter!"),
+                },
+                max_queue_size_per_key: self
+                    .raw_conf
+                    .pos_consensus_queue_size_per_key,
+                backpressure: self
+                    .raw_conf
+                    .pos_consensus_queue_high_water_mark
+                    .map(|high_water_mark| BackpressureConfig {
+                        high_water_mark,
+                        low_water_mark: self
+                            .raw_conf
+                            .pos_consensus_queue_low_water_mark
+                            .unwrap_or(high_water_mark / 2),
+                    }),
+            },
+            pos_message_size_limits: MessageSizeLimits::new(
+                self.raw_conf.pos_max_message_size,
+            )
+            .with_limit(
+                pos_msgid::PROPOSAL,
+                self.raw_conf.pos_max_proposal_size,
+            )
+            .with_limit(
+                pos_msgid::BLOCK_RETRIEVAL_RESPONSE,
+                self.raw_conf.pos_max_block_retrieval_response_size,
+            )
+            .with_limit(
+                pos_msgid::BLOCK_RETRIEVAL_PAGE_RESPONSE,
+                self.raw_conf.pos_max_block_retrieval_response_size,
+            )
+            .with_limit(
+                pos_msgid::EPOCH_CHANGE,
+                self.raw_conf.pos_max_epoch_change_proof_size,
+            )
+            .with_limit(
+                pos_msgid::CONSENSUS_MSG,
+                self.raw_conf.pos_max_consensus_msg_size,
+            )
+            .with_limit(
+                pos_msgid::CONSENSUS_MSG_JSON,
+                self.raw_conf.pos_max_consensus_msg_size,
+            )
+            // It may carry a proposal.
+            .with_limit(
+                pos_msgid::WITH_SYNC_INFO,
+                self.raw_conf.pos_max_proposal_size,
+            )
+            .with_limit(
+                pos_msgid::MEMPOOL_SYNC_MSG,
+                self.raw_conf.pos_max_mempool_sync_msg_size,
+            ),
+            pos_block_retrieval_max_response_bytes: self
+                .raw_conf
+                .pos_block_retrieval_max_response_bytes,
+            pos_block_stream_max_in_flight: self
+                .raw_conf
+                .pos_block_stream_max_in_flight,
+            pos_block_retrieval_min_chunk_size: self
+                .raw_conf
+                .pos_block_retrieval_min_chunk_size,
+            pos_block_retrieval_max_chunk_size: self
+                .raw_conf
+                .pos_block_retrieval_max_chunk_size,
+            pos_block_retrieval_target_latency: Duration::from_millis(
+                self.raw_conf.pos_block_retrieval_target_latency_ms,
+            ),
+            pos_consensus_msg_codec: self
+                .raw_conf
+                .pos_consensus_msg_codec
+                .parse::<CodecKind>()
+                .expect("Invalid pos_consensus_msg_codec parameter!"),
+            pos_chain_id: self
+                .chain_id_params()
+                .read()
+                .get_chain_id(/* epoch_number = */ 0)
+                .in_native_space() as u64,
+            pos_send_rate_limit: self
+                .raw_conf
+                .pos_send_rate_limit_per_peer
+                .map(|messages_per_sec| SendRateLimit {
+                    messages_per_sec,
+                    burst: self.raw_conf.pos_send_burst_per_peer,
+                }),
+            pos_peer_send_queue_size: self.raw_conf.pos_peer_send_queue_size,
+            pos_epoch_change_chunk_size: self
+                .raw_conf
+                .pos_epoch_change_chunk_size,
+            pos_epoch_change_chunk_compression: self
+                .raw_conf
+                .pos_epoch_change_chunk_compression,
+            pos_send_jitter: SendJitterConfig {
+                max_critical_jitter: Duration::from_millis(
+                    self.raw_conf.pos_max_critical_send_jitter_ms,
+                ),
+                max_jitter: Duration::from_millis(
+                    self.raw_conf.pos_max_send_jitter_ms,
+                ),
+            },
+            pos_broadcast_to_validators_only: self
+                .raw_conf
+                .pos_broadcast_to_validators_only,
+            pos_peer_liveness: PeerLivenessConfig {
+                ping_interval: Duration::from_millis(
+                    self.raw_conf.pos_liveness_ping_interval_ms,
+                ),
+                max_missed_pongs: self.raw_conf.pos_liveness_max_missed_pongs,
+            },
+            pos_peer_blacklist: PeerBlacklistConfig {
+                ttl: Duration::from_millis(
+                    self.raw_conf.pos_peer_blacklist_ttl_ms,
+                ),
+                max_violations: self.raw_conf.pos_peer_blacklist_max_violations,
+            },
+            pos_min_peer_protocol_version: ProtocolVersion(
+                self.raw_conf.pos_min_peer_protocol_version,
+            ),
+            pos_handshake_timeout: Duration::from_millis(
+                self.raw_conf.pos_handshake_timeout_ms,
+            ),
+            pos_vote_rebroadcast: VoteRebroadcastConfig {
+                initial_interval: Duration::from_millis(
+                    self.raw_conf.pos_vote_rebroadcast_interval_ms,
+                ),
+                max_attempts: self.raw_conf.pos_vote_rebroadcast_max_attempts,
+            },
         }
     }
 
@@ -138,7 +138,9 @@ impl PosHandler {
         if let Some(network) = &network {
             // initialize hotstuff protocol handler
             let (consensus_network_task, consensus_network_receiver) =
-                ConsensusNetworkTask::new();
+                ConsensusNetworkTask::new_with_queue_config(
+                    pos.conf.protocol_conf.pos_consensus_queue_config,
+                );
             let (mempool_network_task, mempool_network_receiver) =
                 MempoolNetworkTask::new();
             let own_node_hash = keccak(
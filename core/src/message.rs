@@ -10,7 +10,9 @@ pub use cfx_bytes::Bytes;
 pub use priority_send_queue::SendQueuePriority;
 use rlp::{Decodable, Rlp};
 
-use crate::sync::msg_sender::metric_message;
+use crate::{
+    pos::protocol::message::codec::CodecKind, sync::msg_sender::metric_message,
+};
 use network::{
     node_table::NodeId, parse_msg_id_leb128_2_bytes_at_most,
     service::ProtocolVersion, ProtocolId,
@@ -76,6 +78,14 @@ pub trait Message:
 
     fn encode(&self) -> Vec<u8>;
 
+    /// Encode the message for a peer that negotiated `codec` for the PoS
+    /// consensus messages. Only `ConsensusMsg` has other encodings.
+    fn encode_with_codec(&self, _codec: CodecKind) -> Vec<u8> { self.encode() }
+
+    /// The epoch and the round of the message for the logs, if it has them.
+    /// Only the PoS consensus messages have them.
+    fn epoch_round(&self) -> (Option<u64>, Option<u64>) { (None, None) }
+
     fn throttle_token_cost(&self) -> (u64, u64) { (1, 0) }
 
     fn send(
@@ -87,6 +87,37 @@ impl<M: Debug> Debug for ElementStatus<M> {
     }
 }
 
+/// The error returned by `Sender::try_push`. The message is handed back so
+/// the sender can retry later.
+pub enum TryPushError<M> {
+    /// The queue of the key is full. `depth` is the number of messages
+    /// queued for the key.
+    Full { message: M, depth: usize },
+    /// The receiver has been dropped.
+    Closed(M),
+}
+
+impl<M> TryPushError<M> {
+    /// Returns the message that failed to be pushed.
+    pub fn into_inner(self) -> M {
+        match self {
+            TryPushError::Full { message, .. } => message,
+            TryPushError::Closed(message) => message,
+        }
+    }
+}
+
+impl<M> Debug for TryPushError<M> {
+    fn fmt(
+        &self, f: &mut Formatter,
+    ) -> std::result::Result<(), std::fmt::Error> {
+        match self {
+            TryPushError::Full { depth, .. } => write!(f, "Full({})", depth),
+            TryPushError::Closed(_) => write!(f, "Closed"),
+        }
+    }
+}
+
 impl<K: Eq + Hash + Clone, M> Sender<K, M> {
     /// This adds the message into the internal queue data structure. This is a
     /// synchronous call.
@@ -121,6 +152,80 @@ impl<K: Eq + Hash + Clone, M> Sender<K, M> {
         }
         Ok(())
     }
+
+    /// Same as `push`, but when the queue of the key is full and the queue
+    /// style drops the newest message, i.e. FIFO, the new message is rejected
+    /// and returned in the error instead of being dropped. With LIFO and
+    /// KLAST the oldest message of the key is dropped as in `push`, since
+    /// the new one supersedes it.
+    pub fn try_push(
+        &self, key: K, message: M,
+    ) -> std::result::Result<(), TryPushError<M>> {
+        let mut shared_state = self.shared_state.lock();
+        Self::try_push_locked(&mut shared_state, key, message)?;
+        if let Some(w) = shared_state.waker.take() {
+            w.wake();
+        }
+        Ok(())
+    }
+
+    /// Same as `try_push` for each of the messages in order, but within one
+    /// acquisition of the lock. Stops at the first message that cannot be
+    /// pushed and returns it in the error, together with the number of the
+    /// messages pushed before it. The remaining messages are dropped.
+    pub fn try_push_all(
+        &self, messages: impl IntoIterator<Item = (K, M)>,
+    ) -> std::result::Result<usize, (usize, TryPushError<M>)> {
+        let mut shared_state = self.shared_state.lock();
+        let mut pushed = 0;
+        let mut result = Ok(());
+        for (key, message) in messages {
+            if let Err(e) =
+                Self::try_push_locked(&mut shared_state, key, message)
+            {
+                result = Err(e);
+                break;
+            }
+            pushed += 1;
+        }
+        if pushed > 0 {
+            if let Some(w) = shared_state.waker.take() {
+                w.wake();
+            }
+        }
+        result.map(|()| pushed).map_err(|e| (pushed, e))
+    }
+
+    fn try_push_locked(
+        shared_state: &mut SharedState<K, M>, key: K, message: M,
+    ) -> std::result::Result<(), TryPushError<M>> {
+        if shared_state.receiver_dropped {
+            return Err(TryPushError::Closed(message));
+        }
+        debug_assert!(shared_state.num_senders > 0);
+
+        let internal_queue = &mut shared_state.internal_queue;
+        if internal_queue.drops_newest()
+            && internal_queue.is_key_queue_full(&key)
+        {
+            let depth = internal_queue.key_queue_len(&key);
+            return Err(TryPushError::Full { message, depth });
+        }
+        if let Some((dropped_val, Some(dropped_status_ch))) =
+            internal_queue.push(key, (message, None))
+        {
+            // Ignore errors.
+            let _err =
+                dropped_status_ch.send(ElementStatus::Dropped(dropped_val));
+        }
+        Ok(())
+    }
+
+    /// Returns the number of messages in the channel.
+    pub fn len(&self) -> usize { self.shared_state.lock().internal_queue.len() }
+
+    /// Returns whether the channel has no message.
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
 }
 
 impl<K: Eq + Hash + Clone, M> Clone for Sender<K, M> {
@@ -163,6 +268,12 @@ impl<K: Eq + Hash + Clone, M> Receiver<K, M> {
         let mut shared_state = self.shared_state.lock();
         shared_state.internal_queue.clear();
     }
+
+    /// Returns the number of messages in the channel.
+    pub fn len(&self) -> usize { self.shared_state.lock().internal_queue.len() }
+
+    /// Returns whether the channel has no message.
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
 }
 
 impl<K: Eq + Hash + Clone, M> Drop for Receiver<K, M> {
@@ -6,7 +6,7 @@
 // See http://www.gnu.org/licenses/
 
 use crate::{
-    diem_channel::{self, ElementStatus},
+    diem_channel::{self, ElementStatus, TryPushError},
     message_queues::QueueStyle,
 };
 use diem_types::account_address::AccountAddress;
@@ -162,3 +162,83 @@ fn test_feedback_on_drop() {
     };
     block_on(task);
 }
+
+#[test]
+fn test_try_push() {
+    let (sender, mut receiver) = diem_channel::new(QueueStyle::FIFO, 2, None);
+    sender.try_push(0, 'a').unwrap();
+    sender.try_push(0, 'b').unwrap();
+    // The queue of key 0 is full, so 'c' is rejected instead of dropped.
+    match sender.try_push(0, 'c') {
+        Err(TryPushError::Full { message, depth }) => {
+            assert_eq!(message, 'c');
+            assert_eq!(depth, 2);
+        }
+        res => panic!("unexpected result {:?}", res),
+    }
+    // Other keys are not affected.
+    sender.try_push(1, 'd').unwrap();
+    let task = async move {
+        assert_eq!(receiver.select_next_some().await, 'a');
+        assert_eq!(receiver.select_next_some().await, 'd');
+        assert_eq!(receiver.select_next_some().await, 'b');
+        assert_eq!(receiver.select_next_some().now_or_never(), None);
+        drop(receiver);
+        match sender.try_push(0, 'e') {
+            Err(TryPushError::Closed(message)) => assert_eq!(message, 'e'),
+            res => panic!("unexpected result {:?}", res),
+        }
+    };
+    block_on(task);
+}
+
+#[test]
+fn test_try_push_drops_oldest() {
+    for (queue_style, expected) in vec![
+        (QueueStyle::LIFO, vec!['c', 'b']),
+        (QueueStyle::KLAST, vec!['b', 'c']),
+    ] {
+        let (sender, mut receiver) = diem_channel::new(queue_style, 2, None);
+        let (a_status_tx, a_status_rx) = oneshot::channel();
+        sender
+            .push_with_feedback(0, 'a', Some(a_status_tx))
+            .unwrap();
+        sender.try_push(0, 'b').unwrap();
+        // The queue of key 0 is full, so 'a' is dropped for 'c' as with
+        // `push`.
+        sender.try_push(0, 'c').unwrap();
+        assert_eq!(sender.len(), 2);
+        let task = async move {
+            assert_eq!(ElementStatus::Dropped('a'), a_status_rx.await.unwrap());
+            for message in expected {
+                assert_eq!(receiver.select_next_some().await, message);
+            }
+            assert_eq!(receiver.select_next_some().now_or_never(), None);
+        };
+        block_on(task);
+    }
+}
+
+#[test]
+fn test_try_push_all() {
+    let (sender, mut receiver) = diem_channel::new(QueueStyle::FIFO, 2, None);
+    assert_eq!(sender.try_push_all(vec![(0, 'a'), (1, 'b')]).unwrap(), 2);
+    // 'd' does not fit in the queue of key 0, so it and the messages after
+    // it are not pushed.
+    match sender.try_push_all(vec![(0, 'c'), (0, 'd'), (1, 'e')]) {
+        Err((pushed, TryPushError::Full { message, depth })) => {
+            assert_eq!(pushed, 1);
+            assert_eq!(message, 'd');
+            assert_eq!(depth, 2);
+        }
+        res => panic!("unexpected result {:?}", res),
+    }
+    assert_eq!(sender.len(), 3);
+    let task = async move {
+        assert_eq!(receiver.select_next_some().await, 'a');
+        assert_eq!(receiver.select_next_some().await, 'b');
+        assert_eq!(receiver.select_next_some().await, 'c');
+        assert_eq!(receiver.select_next_some().now_or_never(), None);
+    };
+    block_on(task);
+}
@@ -56,6 +56,8 @@ pub(crate) struct PerKeyQueue<K: Eq + Hash + Clone, T> {
     max_queue_size: NonZeroUsize,
     /// Number of messages dequeued since last GC
     num_popped_since_gc: u32,
+    /// Number of messages in all the queues
+    num_messages: usize,
     /// Optional counters for recording # enqueued, # dequeued, and # dropped
     /// messages
     counters: Option<&'static IntCounterVec>,
@@ -85,6 +87,7 @@ impl<K: Eq + Hash + Clone, T> PerKeyQueue<K, T> {
             per_key_queue: HashMap::new(),
             round_robin_queue: VecDeque::new(),
             num_popped_since_gc: 0,
+            num_messages: 0,
             counters,
         }
     }
@@ -147,6 +150,7 @@ impl<K: Eq + Hash + Clone, T> PerKeyQueue<K, T> {
             }
         } else {
             key_message_queue.push_back(message);
+            self.num_messages += 1;
             None
         }
     }
@@ -167,6 +171,7 @@ impl<K: Eq + Hash + Clone, T> PerKeyQueue<K, T> {
         }
 
         if message.is_some() {
+            self.num_messages -= 1;
             if let Some(c) = self.counters.as_ref() {
                 c.with_label_values(&["dequeued"]).inc();
             }
@@ -200,6 +205,26 @@ impl<K: Eq + Hash + Clone, T> PerKeyQueue<K, T> {
         message
     }
 
+    /// Returns the number of messages queued for `key`.
+    pub(crate) fn key_queue_len(&self, key: &K) -> usize {
+        self.per_key_queue.get(key).map_or(0, |q| q.len())
+    }
+
+    /// Returns the number of messages queued for all the keys.
+    pub(crate) fn len(&self) -> usize { self.num_messages }
+
+    /// Returns whether a new message for `key` would cause a message to be
+    /// dropped.
+    pub(crate) fn is_key_queue_full(&self, key: &K) -> bool {
+        self.key_queue_len(key) >= self.max_queue_size.get()
+    }
+
+    /// Returns whether a new message for a full key queue is dropped rather
+    /// than the oldest one, i.e. with FIFO.
+    pub(crate) fn drops_newest(&self) -> bool {
+        matches!(self.queue_style, QueueStyle::FIFO)
+    }
+
     /// Garbage collect any empty per-key-queues.
     fn remove_empty_queues(&mut self) {
         self.per_key_queue.retain(|_key, queue| !queue.is_empty());
@@ -210,5 +235,6 @@ impl<K: Eq + Hash + Clone, T> PerKeyQueue<K, T> {
     pub(crate) fn clear(&mut self) {
         self.per_key_queue.clear();
         self.round_robin_queue.clear();
+        self.num_messages = 0;
     }
 }
@@ -441,3 +441,33 @@ fn test_message_queue_clear() {
     );
     assert_eq!(q.pop().unwrap().msg, "msg3".to_string());
 }
+
+#[test]
+fn test_message_queue_len() {
+    let mut q = PerKeyQueue::new(QueueStyle::LIFO, NonZeroUsize!(2), None);
+    let validator1 = AccountAddress::new([0u8; AccountAddress::LENGTH]);
+    let validator2 = AccountAddress::new([1u8; AccountAddress::LENGTH]);
+
+    for i in 0..3 {
+        q.push(
+            validator1,
+            VoteMsg {
+                msg: format!("msg{}", i),
+            },
+        );
+    }
+    // The dropped message is not counted.
+    assert_eq!(q.len(), 2);
+    q.push(
+        validator2,
+        VoteMsg {
+            msg: "msg".to_string(),
+        },
+    );
+    assert_eq!(q.len(), 3);
+
+    q.pop();
+    assert_eq!(q.len(), 2);
+    q.clear();
+    assert_eq!(q.len(), 0);
+}
@@ -41,6 +41,12 @@ pub struct ConsensusConfig {
     pub chain_id: ChainId,
 
     pub hardcoded_epoch_committee: BTreeMap<u64, ValidatorVerifier>,
+
+    // The number of the peers to wait for before starting consensus, so the
+    // first proposals are not sent to nobody. 0 disables the wait.
+    pub startup_min_peers: usize,
+    // The longest time to wait for `startup_min_peers` (in milliseconds)
+    pub startup_peers_timeout_ms: u64,
 }
 
 impl Default for ConsensusConfig {
@@ -60,6 +66,8 @@ impl Default for ConsensusConfig {
             mempool_poll_count: 1,
             chain_id: Default::default(),
             hardcoded_epoch_committee: Default::default(),
+            startup_min_peers: 0,
+            startup_peers_timeout_ms: 30_000,
         }
     }
 }
@@ -5,19 +5,22 @@
 // Conflux is free software and distributed under GNU General Public License.
 // See http://www.gnu.org/licenses/
 
-use crate::pos::consensus::{
-    block_storage::{
-        block_tree::BlockTree,
-        tracing::{observe_block, BlockStage},
-        BlockReader,
+use crate::pos::{
+    consensus::{
+        block_storage::{
+            block_tree::BlockTree,
+            tracing::{observe_block, BlockStage},
+            BlockReader,
+        },
+        counters,
+        logging::{LogEvent, LogSchema},
+        persistent_liveness_storage::{
+            PersistentLivenessStorage, RecoveryData, RootInfo, RootMetadata,
+        },
+        state_replication::StateComputer,
+        util::time_service::TimeService,
     },
-    counters,
-    logging::{LogEvent, LogSchema},
-    persistent_liveness_storage::{
-        PersistentLivenessStorage, RecoveryData, RootInfo, RootMetadata,
-    },
-    state_replication::StateComputer,
-    util::time_service::TimeService,
+    protocol::block_stream::BlockStreamSource,
 };
 use anyhow::{bail, ensure, format_err, Context};
 use consensus_types::{
@@ -516,3 +519,14 @@ impl BlockStore {
         self.execute_and_insert_block(block, false, false)
     }
 }
+
+// The block streams opened by the peers are pushed from the blocks in memory,
+// and then from the committed blocks in the ledger.
+impl BlockStreamSource for BlockStore {
+    fn get_block(&self, block_id: &HashValue) -> Option<Block> {
+        match BlockReader::get_block(self, *block_id) {
+            Some(block) => Some(block.block().clone()),
+            None => self.storage.get_ledger_block(block_id).ok().flatten(),
+        }
+    }
+}
@@ -14,6 +14,7 @@ use std::sync::Arc;
 
 mod block_store;
 mod block_tree;
+pub mod retrieval_chunk;
 pub mod tracing;
 
 pub use block_store::{sync_manager::BlockRetriever, BlockStore};
@@ -0,0 +1,166 @@
+// Copyright 2021 Conflux Foundation. All rights reserved.
+// Conflux is free software and distributed under GNU General Public License.
+// See http://www.gnu.org/licenses/
+
+//! Adaptive sizing of the block retrieval requests.
+//!
+//! The number of blocks asked in one `BlockRetrievalRequest` starts small
+//! and is adjusted in the AIMD way after each response: it grows by a fixed
+//! step while the responses arrive faster than the target transfer time, and
+//! is halved when a response is slower or the request fails.
+
+use std::time::Duration;
+
+use crate::sync::ProtocolConfiguration;
+
+/// The number of blocks added to the chunk size after a fast response.
+const ADDITIVE_INCREASE: u64 = 4;
+
+#[derive(Clone, Debug)]
+pub struct ChunkSizeConfig {
+    /// The chunk size is never below this, and starts from it.
+    pub min_chunk_size: u64,
+    /// The chunk size is never above this. 0 disables the adaptive sizing,
+    /// so all the remaining blocks are asked in one request.
+    pub max_chunk_size: u64,
+    /// The transfer time of one response the chunk size converges to.
+    pub target_latency: Duration,
+}
+
+impl From<&ProtocolConfiguration> for ChunkSizeConfig {
+    fn from(conf: &ProtocolConfiguration) -> Self {
+        Self {
+            min_chunk_size: conf.pos_block_retrieval_min_chunk_size,
+            max_chunk_size: conf.pos_block_retrieval_max_chunk_size,
+            target_latency: conf.pos_block_retrieval_target_latency,
+        }
+    }
+}
+
+pub struct ChunkSizeController {
+    config: ChunkSizeConfig,
+    chunk_size: u64,
+}
+
+impl ChunkSizeController {
+    pub fn new(mut config: ChunkSizeConfig) -> Self {
+        // At least one block is asked in each request.
+        config.min_chunk_size = config.min_chunk_size.max(1);
+        if config.max_chunk_size != 0 {
+            config.max_chunk_size =
+                config.max_chunk_size.max(config.min_chunk_size);
+        }
+        let chunk_size = config.min_chunk_size;
+        Self { config, chunk_size }
+    }
+
+    /// The number of blocks to ask in the next request, `None` if the
+    /// adaptive sizing is disabled.
+    pub fn chunk_size(&self) -> Option<u64> {
+        if self.config.max_chunk_size == 0 {
+            None
+        } else {
+            Some(self.chunk_size)
+        }
+    }
+
+    /// Record a response with `received` of the `requested` blocks that is
+    /// received after `latency`.
+    ///
+    /// A response with fewer blocks than requested is cut by the byte limit
+    /// of the response or the start of the chain, so asking more blocks
+    /// would not make it larger, and the size is not grown after it.
+    pub fn on_response(
+        &mut self, requested: u64, received: u64, latency: Duration,
+    ) {
+        if self.chunk_size().is_none() {
+            return;
+        }
+        if latency > self.config.target_latency {
+            self.decrease();
+        } else if received >= requested && requested >= self.chunk_size {
+            self.chunk_size = (self.chunk_size + ADDITIVE_INCREASE)
+                .min(self.config.max_chunk_size);
+        }
+    }
+
+    /// Record a request that fails or times out.
+    pub fn on_failure(&mut self) { self.decrease(); }
+
+    fn decrease(&mut self) {
+        self.chunk_size = (self.chunk_size / 2).max(self.config.min_chunk_size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChunkSizeConfig, ChunkSizeController, ADDITIVE_INCREASE};
+    use std::time::Duration;
+
+    fn controller() -> ChunkSizeController {
+        ChunkSizeController::new(ChunkSizeConfig {
+            min_chunk_size: 2,
+            max_chunk_size: 32,
+            target_latency: Duration::from_millis(200),
+        })
+    }
+
+    /// Feed a response of the full chunk received after `latency_ms`.
+    fn feed(controller: &mut ChunkSizeController, latency_ms: u64) -> u64 {
+        let size = controller.chunk_size().unwrap();
+        controller.on_response(size, size, Duration::from_millis(latency_ms));
+        controller.chunk_size().unwrap()
+    }
+
+    #[test]
+    fn test_chunk_size_grows_and_shrinks() {
+        let mut controller = controller();
+        assert_eq!(controller.chunk_size(), Some(2));
+        // Fast responses grow it additively up to the maximum.
+        assert_eq!(feed(&mut controller, 50), 2 + ADDITIVE_INCREASE);
+        for _ in 0..20 {
+            feed(&mut controller, 50);
+        }
+        assert_eq!(controller.chunk_size(), Some(32));
+        // Slow responses halve it down to the minimum.
+        assert_eq!(feed(&mut controller, 500), 16);
+        assert_eq!(feed(&mut controller, 500), 8);
+        for _ in 0..10 {
+            feed(&mut controller, 500);
+        }
+        assert_eq!(controller.chunk_size(), Some(2));
+        feed(&mut controller, 50);
+        controller.on_failure();
+        assert_eq!(controller.chunk_size(), Some(3));
+    }
+
+    #[test]
+    fn test_chunk_size_converges() {
+        // The link takes 20ms for each block, so the target of 200ms is
+        // reached with 10 blocks.
+        let mut controller = controller();
+        let mut sizes = Vec::new();
+        for _ in 0..50 {
+            let size = controller.chunk_size().unwrap();
+            sizes.push(feed(&mut controller, size * 20));
+        }
+        assert!(sizes[10..].iter().all(|size| (5..=14).contains(size)));
+    }
+
+    #[test]
+    fn test_truncated_response_no_growth() {
+        let mut controller = controller();
+        controller.on_response(2, 1, Duration::from_millis(10));
+        assert_eq!(controller.chunk_size(), Some(2));
+    }
+
+    #[test]
+    fn test_disabled() {
+        let controller = ChunkSizeController::new(ChunkSizeConfig {
+            min_chunk_size: 0,
+            max_chunk_size: 0,
+            target_latency: Duration::from_millis(200),
+        });
+        assert_eq!(controller.chunk_size(), None);
+    }
+}
@@ -5,17 +5,27 @@
 // Conflux is free software and distributed under GNU General Public License.
 // See http://www.gnu.org/licenses/
 
-use crate::pos::consensus::{
-    block_storage::{BlockReader, BlockStore},
-    logging::{LogEvent, LogSchema},
-    network::{ConsensusMsg, ConsensusNetworkSender},
-    persistent_liveness_storage::{PersistentLivenessStorage, RecoveryData},
-    state_replication::StateComputer,
+use crate::pos::{
+    consensus::{
+        block_storage::{
+            retrieval_chunk::{ChunkSizeConfig, ChunkSizeController},
+            BlockReader, BlockStore,
+        },
+        logging::{LogEvent, LogSchema},
+        network::{ConsensusMsg, ConsensusNetworkSender},
+        persistent_liveness_storage::{
+            PersistentLivenessStorage, RecoveryData,
+        },
+        state_replication::StateComputer,
+    },
+    protocol::request_manager::retry_budget::RetryBudget,
 };
 use anyhow::{bail, format_err};
 use consensus_types::{
     block::Block,
-    block_retrieval::{BlockRetrievalRequest, BlockRetrievalStatus},
+    block_retrieval::{
+        BlockRetrievalRequest, BlockRetrievalResponse, BlockRetrievalStatus,
+    },
     common::Author,
     quorum_cert::QuorumCert,
 };
@@ -26,7 +36,11 @@ use diem_types::{
     ledger_info::LedgerInfoWithSignatures,
 };
 use rand::{prelude::*, Rng};
-use std::{clone::Clone, sync::Arc, time::Duration};
+use std::{
+    clone::Clone,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 pub const BLOCK_FETCH_BATCH_MAX_SIZE: u64 = 60;
 
@@ -118,6 +132,10 @@ impl BlockStore {
         &self, qc: QuorumCert, retriever: &mut BlockRetriever,
     ) -> anyhow::Result<()> {
         debug!("fetch_quorum_cert: qc={:?}", qc);
+        // Cloned so the retriever is still borrowed mutably below.
+        let protocol_handler =
+            retriever.network.network_sender().protocol_handler.clone();
+        let sync_status = &protocol_handler.sync_status;
         let mut pending = vec![];
         let mut retrieve_qc = qc.clone();
         loop {
@@ -130,6 +148,7 @@ impl BlockStore {
             // This will not underflow because of the check above.
             let round_gap =
                 retrieve_qc.certified_block().round() - self.root().round();
+            sync_status.on_retrieval_started(round_gap);
             let mut blocks = retriever
                 .retrieve_block_for_qc(
                     &retrieve_qc,
@@ -187,7 +206,9 @@ impl BlockStore {
             }
         }
 
-        self.insert_single_quorum_cert(qc)
+        self.insert_single_quorum_cert(qc)?;
+        sync_status.on_caught_up();
+        Ok(())
     }
 
     pub async fn fast_forward_sync<'a>(
@@ -241,15 +262,27 @@ impl BlockStore {
 pub struct BlockRetriever {
     network: ConsensusNetworkSender,
     preferred_peer: Author,
+    /// Decides how many blocks are asked in one request.
+    chunk_size: ChunkSizeController,
+    /// The retries shared by all the requests of this retriever.
+    retry_budget: RetryBudget,
 }
 
 impl BlockRetriever {
     pub fn new(
         network: ConsensusNetworkSender, preferred_peer: Author,
     ) -> Self {
+        let protocol_config =
+            &network.network_sender().protocol_handler.protocol_config;
+        let chunk_size =
+            ChunkSizeController::new(ChunkSizeConfig::from(protocol_config));
+        let retry_budget =
+            RetryBudget::new(protocol_config.pos_sync_retry_budget);
         Self {
             network,
             preferred_peer,
+            chunk_size,
+            retry_budget,
         }
     }
 
@@ -283,9 +316,54 @@ impl BlockRetriever {
         }
     }
 
+    /// Retrieve `num_blocks` blocks backwards from `block_id`, following the
+    /// cursors of the responses truncated by the byte limit, so a long chain
+    /// is fetched in several bounded responses.
+    ///
+    /// Each request asks at most the chunk size, which adapts to how fast
+    /// the responses arrive.
     async fn request_block(
         &mut self, num_blocks: u64, block_id: HashValue,
     ) -> anyhow::Result<Vec<Block>> {
+        let mut blocks = Vec::new();
+        let mut next_id = block_id;
+        while (blocks.len() as u64) < num_blocks {
+            let remaining = num_blocks - blocks.len() as u64;
+            let page_size = self
+                .chunk_size
+                .chunk_size()
+                .map_or(remaining, |chunk_size| chunk_size.min(remaining));
+            let response = self.request_block_page(page_size, next_id).await?;
+            let next_cursor = response.next_cursor();
+            let page = response.into_blocks();
+            // A full page ends on the chunk size rather than the start of
+            // the chain, so the next page continues from its parent.
+            let full_page_parent = match page.last() {
+                Some(last) if page.len() as u64 == page_size => {
+                    Some(last.parent_id())
+                }
+                _ => None,
+            };
+            blocks.extend(page);
+            match next_cursor.or(full_page_parent) {
+                Some(id) => next_id = id,
+                None => break,
+            }
+        }
+        Ok(blocks)
+    }
+
+    /// Send one block retrieval request, trying the peers one by one until a
+    /// successful response is received.
+    async fn request_block_page(
+        &mut self, num_blocks: u64, block_id: HashValue,
+    ) -> anyhow::Result<BlockRetrievalResponse> {
+        let max_response_bytes = self
+            .network
+            .network_sender()
+            .protocol_handler
+            .protocol_config
+            .pos_block_retrieval_max_response_bytes;
         let mut peers: Vec<AccountAddress> = self
             .network
             .network_sender()
@@ -304,6 +382,13 @@ impl BlockRetriever {
                     attempt
                 );
             }
+            if attempt > 0 && !self.retry_budget.try_acquire(Instant::now()) {
+                bail!(
+                    "Failed to fetch block {} in {} attempts: retry budget exhausted",
+                    block_id,
+                    attempt
+                );
+            }
             let peer = self.pick_peer(attempt, &mut peers);
             attempt += 1;
 
@@ -313,27 +398,49 @@ impl BlockRetriever {
                 "Fetching block, attempt {}",
                 attempt
             );
+            let start = Instant::now();
             let response = self
                 .network
                 .request_block(
-                    BlockRetrievalRequest::new(block_id, num_blocks),
+                    BlockRetrievalRequest::new_with_byte_limit(
+                        block_id,
+                        num_blocks,
+                        max_response_bytes,
+                    ),
                     peer,
                     retrieval_timeout(attempt),
                 )
                 .await;
+            let busy = matches!(
+                &response,
+                Ok(result) if result.status() == BlockRetrievalStatus::Busy
+            );
             match response.and_then(|result| {
                 if result.status() == BlockRetrievalStatus::Succeeded {
-                    Ok(result.blocks().clone())
+                    Ok(result)
                 } else {
                     Err(format_err!("{:?}", result.status()))
                 }
             }) {
-                result @ Ok(_) => return result,
-                Err(e) => diem_warn!(
-                    remote_peer = peer,
-                    block_id = block_id,
-                    error = ?e, "Failed to fetch block, trying another peer",
-                ),
+                Ok(result) => {
+                    self.chunk_size.on_response(
+                        num_blocks,
+                        result.blocks().len() as u64,
+                        start.elapsed(),
+                    );
+                    return Ok(result);
+                }
+                Err(e) => {
+                    // A busy peer sheds the request whatever its size.
+                    if !busy {
+                        self.chunk_size.on_failure();
+                    }
+                    diem_warn!(
+                        remote_peer = peer,
+                        block_id = block_id,
+                        error = ?e, "Failed to fetch block, trying another peer",
+                    )
+                }
             }
         }
     }
@@ -372,3 +479,74 @@ fn retrieval_timeout(attempt: u32) -> Duration {
     let exp = RETRIEVAL_MAX_EXP.min(attempt - 1); // [0..RETRIEVAL_MAX_EXP]
     RETRIEVAL_INITIAL_TIMEOUT * 2_u32.pow(exp)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::BlockRetriever;
+    use crate::{
+        pos::{
+            consensus::network::ConsensusNetworkSender,
+            protocol::{
+                request_manager::retry_budget::RetryBudgetConfig,
+                test_utils::unstarted_sender_with_config,
+            },
+        },
+        sync::ProtocolConfiguration,
+    };
+    use cfx_types::H256;
+    use diem_crypto::HashValue;
+    use diem_types::{
+        account_address::AccountAddress, validator_verifier::ValidatorVerifier,
+    };
+    use futures::executor::block_on;
+    use std::{collections::BTreeMap, time::Duration};
+
+    #[test]
+    fn test_retries_stop_once_budget_exhausted() {
+        let network_sender =
+            unstarted_sender_with_config(ProtocolConfiguration {
+                pos_sync_retry_budget: RetryBudgetConfig {
+                    capacity: 2,
+                    refill_interval: Duration::from_secs(0),
+                },
+                ..Default::default()
+            });
+        // The peers are gone, so each request to them fails at once.
+        for i in 0..5 {
+            network_sender
+                .protocol_handler
+                .pos_peer_mapping
+                .write()
+                .insert(AccountAddress::random(), H256::from_low_u64_be(i));
+        }
+        let network = ConsensusNetworkSender::new(
+            AccountAddress::random(),
+            network_sender,
+            ValidatorVerifier::new(BTreeMap::new()),
+        );
+        let mut retriever =
+            BlockRetriever::new(network, AccountAddress::random());
+
+        // The first request takes the whole budget.
+        let error =
+            block_on(retriever.request_block_page(1, HashValue::zero()))
+                .unwrap_err()
+                .to_string();
+        assert!(
+            error.ends_with("in 3 attempts: retry budget exhausted"),
+            "{}",
+            error
+        );
+
+        // The next ones do not retry.
+        let error =
+            block_on(retriever.request_block_page(1, HashValue::zero()))
+                .unwrap_err()
+                .to_string();
+        assert!(
+            error.ends_with("in 1 attempts: retry budget exhausted"),
+            "{}",
+            error
+        );
+    }
+}
@@ -21,27 +21,39 @@ pub const MAX_BLOCKS_PER_REQUEST: u64 = 10;
 pub struct BlockRetrievalRequest {
     block_id: HashValue,
     num_blocks: u64,
+    /// The limit of the total size in bytes of the returned blocks, 0 means
+    /// no limit. At least one block is returned even if it exceeds the limit.
+    max_response_bytes: u64,
 }
 
 impl BlockRetrievalRequest {
     pub fn new(block_id: HashValue, num_blocks: u64) -> Self {
+        Self::new_with_byte_limit(block_id, num_blocks, 0)
+    }
+
+    pub fn new_with_byte_limit(
+        block_id: HashValue, num_blocks: u64, max_response_bytes: u64,
+    ) -> Self {
         Self {
             block_id,
             num_blocks,
+            max_response_bytes,
         }
     }
 
     pub fn block_id(&self) -> HashValue { self.block_id }
 
     pub fn num_blocks(&self) -> u64 { self.num_blocks }
+
+    pub fn max_response_bytes(&self) -> u64 { self.max_response_bytes }
 }
 
 impl fmt::Display for BlockRetrievalRequest {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "[BlockRetrievalRequest starting from id {} with {} blocks]",
-            self.block_id, self.num_blocks
+            "[BlockRetrievalRequest starting from id {} with {} blocks, max {} bytes]",
+            self.block_id, self.num_blocks, self.max_response_bytes
         )
     }
 }
@@ -54,6 +66,8 @@ pub enum BlockRetrievalStatus {
     IdNotFound,
     // Can not find enough blocks but find some.
     NotEnoughBlocks,
+    // Too busy to look up the blocks, so another peer is to be asked.
+    Busy,
 }
 
 /// Carries the returned blocks and the retrieval status.
@@ -61,17 +75,40 @@ pub enum BlockRetrievalStatus {
 pub struct BlockRetrievalResponse {
     status: BlockRetrievalStatus,
     blocks: Vec<Block>,
+    /// The id of the next block to request if the response is truncated by
+    /// the byte limit of the request before reaching the requested number of
+    /// blocks.
+    next_cursor: Option<HashValue>,
 }
 
 impl BlockRetrievalResponse {
     pub fn new(status: BlockRetrievalStatus, blocks: Vec<Block>) -> Self {
-        Self { status, blocks }
+        Self::new_with_cursor(status, blocks, None)
+    }
+
+    pub fn new_with_cursor(
+        status: BlockRetrievalStatus, blocks: Vec<Block>,
+        next_cursor: Option<HashValue>,
+    ) -> Self
+    {
+        Self {
+            status,
+            blocks,
+            next_cursor,
+        }
     }
 
     pub fn status(&self) -> BlockRetrievalStatus { self.status.clone() }
 
     pub fn blocks(&self) -> &Vec<Block> { &self.blocks }
 
+    pub fn into_blocks(self) -> Vec<Block> { self.blocks }
+
+    pub fn next_cursor(&self) -> Option<HashValue> { self.next_cursor }
+
+    /// Whether more blocks can be requested from `next_cursor`.
+    pub fn has_more(&self) -> bool { self.next_cursor.is_some() }
+
     pub fn verify(
         &self, block_id: HashValue, num_blocks: u64,
         sig_verifier: &ValidatorVerifier,
@@ -85,6 +122,14 @@ impl BlockRetrievalResponse {
             num_blocks,
             self.blocks.len(),
         );
+        // The cursor must continue the returned chain.
+        ensure!(
+            self.next_cursor.is_none()
+                || self.next_cursor
+                    == self.blocks.last().map(|block| block.parent_id()),
+            "next cursor {:?} doesn't continue the returned blocks",
+            self.next_cursor,
+        );
         self.blocks
             .iter()
             .try_fold(block_id, |expected_id, block| {
@@ -0,0 +1,90 @@
+// Copyright 2021 Conflux Foundation. All rights reserved.
+// Conflux is free software and distributed under GNU General Public License.
+// See http://www.gnu.org/licenses/
+
+use crate::common::{Author, Round};
+use anyhow::Context;
+use diem_types::{
+    ledger_info::LedgerInfo, validator_config::ConsensusSignature,
+    validator_signer::ValidatorSigner, validator_verifier::ValidatorVerifier,
+};
+use serde::{Deserialize, Serialize};
+use short_hex_str::AsShortHexStr;
+use std::fmt::{Debug, Display, Formatter};
+
+/// CommitVoteMsg is sent by a validator in the commit phase to vote for
+/// committing the block in `ledger_info`. It is exchanged separately from the
+/// round vote (`VoteMsg`), so it does not take part in the round bookkeeping.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct CommitVoteMsg {
+    /// The identity of the voter.
+    author: Author,
+    /// LedgerInfo of the block that is going to be committed.
+    ledger_info: LedgerInfo,
+    /// Signature of the LedgerInfo
+    signature: ConsensusSignature,
+}
+
+// this is required by structured log
+impl Debug for CommitVoteMsg {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl Display for CommitVoteMsg {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "CommitVoteMsg: [author: {}, {}]",
+            self.author.short_str(),
+            self.ledger_info
+        )
+    }
+}
+
+impl CommitVoteMsg {
+    /// Generates a new CommitVoteMsg by signing the given ledger_info
+    pub fn new(
+        author: Author, ledger_info: LedgerInfo,
+        validator_signer: &ValidatorSigner,
+    ) -> Self
+    {
+        let signature = validator_signer.sign(&ledger_info);
+        Self::new_with_signature(author, ledger_info, signature)
+    }
+
+    /// Generates a new CommitVoteMsg using a signature over the specified
+    /// ledger_info
+    pub fn new_with_signature(
+        author: Author, ledger_info: LedgerInfo, signature: ConsensusSignature,
+    ) -> Self {
+        Self {
+            author,
+            ledger_info,
+            signature,
+        }
+    }
+
+    /// Return the author of the commit vote
+    pub fn author(&self) -> Author { self.author }
+
+    /// Return the LedgerInfo associated with this commit vote
+    pub fn ledger_info(&self) -> &LedgerInfo { &self.ledger_info }
+
+    /// Return the signature of the commit vote
+    pub fn signature(&self) -> &ConsensusSignature { &self.signature }
+
+    /// Return the epoch of the commit vote
+    pub fn epoch(&self) -> u64 { self.ledger_info.epoch() }
+
+    /// Return the round of the block to commit
+    pub fn round(&self) -> Round { self.ledger_info.round() }
+
+    /// Verifies the signature of the LedgerInfo.
+    pub fn verify(&self, validator: &ValidatorVerifier) -> anyhow::Result<()> {
+        validator
+            .verify(self.author(), &self.ledger_info, &self.signature)
+            .context("Failed to verify CommitVoteMsg")
+    }
+}
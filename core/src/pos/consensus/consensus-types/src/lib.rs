@@ -10,6 +10,7 @@
 pub mod block;
 pub mod block_data;
 pub mod block_retrieval;
+pub mod commit_vote_msg;
 pub mod common;
 pub mod db;
 pub mod epoch_retrieval;
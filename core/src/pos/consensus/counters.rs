@@ -7,8 +7,9 @@
 
 use diem_metrics::{
     register_histogram, register_histogram_vec, register_int_counter,
-    register_int_counter_vec, register_int_gauge, DurationHistogram, Histogram,
-    HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+    DurationHistogram, Histogram, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec,
 };
 use once_cell::sync::Lazy;
 
@@ -28,6 +29,17 @@ pub static ERROR_COUNT: Lazy<IntGauge> = Lazy::new(|| {
     .unwrap()
 });
 
+/// Count of the EpochChangeProofs failing the verification, by the peer
+/// sending them and the reason
+pub static INVALID_EPOCH_CHANGE_PROOFS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "diem_consensus_invalid_epoch_change_proof_count",
+        "Count of the EpochChangeProofs failing the verification, by the peer sending them and the reason",
+        &["peer", "reason"]
+    )
+    .unwrap()
+});
+
 /// This counter is set to the round of the highest committed block.
 pub static LAST_COMMITTED_ROUND: Lazy<IntGauge> = Lazy::new(|| {
     register_int_gauge!(
@@ -154,6 +166,27 @@ pub static EPOCH: Lazy<IntGauge> = Lazy::new(|| {
     register_int_gauge!("diem_consensus_epoch", "Current epoch num").unwrap()
 });
 
+/// Count of the epoch change proofs sent to the peers, by whether they are
+/// served from the cache
+pub static EPOCH_PROOF_CACHE: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "diem_consensus_epoch_proof_cache_count",
+        "Count of the epoch change proofs sent to the peers, by whether they are served from the cache",
+        &["result"]
+    )
+    .unwrap()
+});
+
+/// Count of the copies of the votes received and dropped before they are
+/// delivered to consensus
+pub static VOTES_DEDUPED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "diem_consensus_votes_deduped_count",
+        "Count of the copies of the votes received and dropped before they are delivered to consensus"
+    )
+    .unwrap()
+});
+
 /// The number of validators in the current epoch
 pub static CURRENT_EPOCH_VALIDATORS: Lazy<IntGauge> = Lazy::new(|| {
     register_int_gauge!(
@@ -283,3 +316,563 @@ pub static BLOCK_RETRIEVAL_CHANNEL_MSGS: Lazy<IntCounterVec> =
     )
         .unwrap()
     });
+
+///////////////////
+// NETWORK COUNTERS
+///////////////////
+
+/// Count of the PoS messages sent to other peers by message type
+pub static NETWORK_MSGS_SENT: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "diem_consensus_network_msgs_sent_count",
+        "Count of the PoS messages sent to other peers by message type",
+        &["type"]
+    )
+    .unwrap()
+});
+
+/// Bytes of the PoS messages sent to other peers by message type
+pub static NETWORK_BYTES_SENT: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "diem_consensus_network_bytes_sent",
+        "Bytes of the PoS messages sent to other peers by message type",
+        &["type"]
+    )
+    .unwrap()
+});
+
+/// Count of the PoS messages received from peers by message type
+pub static NETWORK_MSGS_RECEIVED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "diem_consensus_network_msgs_received_count",
+        "Count of the PoS messages received from peers by message type",
+        &["type"]
+    )
+    .unwrap()
+});
+
+/// Bytes of the PoS messages received from peers by message type
+pub static NETWORK_BYTES_RECEIVED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "diem_consensus_network_bytes_received",
+        "Bytes of the PoS messages received from peers by message type",
+        &["type"]
+    )
+    .unwrap()
+});
+
+/// Count of the consensus messages not broadcast to the connected peers that
+/// are not validators of the epoch, by message type
+pub static NON_VALIDATOR_SENDS_SKIPPED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "diem_consensus_network_non_validator_sends_skipped_count",
+        "Count of the consensus messages not broadcast to the connected peers that are not validators of the epoch, by message type",
+        &["type"]
+    )
+    .unwrap()
+});
+
+/// Count of the PoS messages shed by the full send queues of the peers, by
+/// message type
+pub static NETWORK_MSGS_SHED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "diem_consensus_network_msgs_shed_count",
+        "Count of the PoS messages shed by the full send queues of the peers, by message type",
+        &["type"]
+    )
+    .unwrap()
+});
+
+/// Count of the consensus messages that fail to be sent to a recipient, by
+/// message type and reason
+pub static CONSENSUS_SEND_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "diem_consensus_network_send_failures_count",
+        "Count of the consensus messages that fail to be sent to a recipient, by message type and reason",
+        &["type", "reason"]
+    )
+    .unwrap()
+});
+
+/// Histogram of the time (in seconds) to decode the PoS messages received,
+/// by message type
+pub static NETWORK_MSG_DECODE_S: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "diem_consensus_network_msg_decode_s",
+        "Histogram of the time (in seconds) to decode the PoS messages received, by message type",
+        &["type"],
+        // Most messages are decoded in microseconds, and a large block
+        // retrieval response in milliseconds.
+        vec![1e-5, 5e-5, 1e-4, 5e-4, 1e-3, 5e-3, 1e-2, 5e-2, 0.1, 0.5, 1.0]
+    )
+    .unwrap()
+});
+
+/// Count of the PoS peer connections by the negotiated protocol version
+pub static NETWORK_NEGOTIATED_PROTOCOL_VERSIONS: Lazy<IntCounterVec> =
+    Lazy::new(|| {
+        register_int_counter_vec!(
+            "diem_consensus_network_negotiated_protocol_versions_count",
+            "Count of the PoS peer connections by the negotiated protocol version",
+            &["version"]
+        )
+        .unwrap()
+    });
+
+/// Count of the PoS messages encoded for peers in the validate-only mode but
+/// not sent, by message type
+pub static NETWORK_MSGS_VALIDATED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "diem_consensus_network_msgs_validated_count",
+        "Count of the PoS messages encoded for peers in the validate-only mode but not sent, by message type",
+        &["message"]
+    )
+    .unwrap()
+});
+
+/// Count of the peers evicted from the PoS peer table as their sessions are
+/// found closed when sending, by message type
+pub static NETWORK_STALE_SESSIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "diem_consensus_network_stale_sessions_count",
+        "Count of the PoS peers evicted for closed sessions when sending",
+        &["type"]
+    )
+    .unwrap()
+});
+
+/// Count of the PoS messages not sent to peers that are over their send rate
+/// limit, by peer
+pub static NETWORK_MSGS_RATE_LIMITED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "diem_consensus_network_msgs_rate_limited_count",
+        "Count of the PoS messages not sent to peers that are over their send rate limit, by peer",
+        &["peer"]
+    )
+    .unwrap()
+});
+
+/// Count of the PoS messages from peers rejected for exceeding the size
+/// limit, by msg id
+pub static NETWORK_MSGS_OVERSIZED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "diem_consensus_network_msgs_oversized_count",
+        "Count of the PoS messages from peers rejected for exceeding the size limit, by msg id",
+        &["msg_id"]
+    )
+    .unwrap()
+});
+
+/// Count of the PoS peers disconnected for presenting another chain id in
+/// the handshake
+pub static NETWORK_CROSS_CHAIN_PEERS_REJECTED: Lazy<IntCounter> = Lazy::new(
+    || {
+        register_int_counter!(
+            "diem_consensus_network_cross_chain_peers_rejected_count",
+            "Count of the PoS peers disconnected for presenting another chain id in the handshake"
+        )
+        .unwrap()
+    },
+);
+
+/// Count of the PoS peers disconnected for missing too many liveness pongs
+pub static NETWORK_PEERS_UNRESPONSIVE: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "diem_consensus_network_peers_unresponsive_count",
+        "Count of the PoS peers disconnected for missing too many liveness pongs"
+    )
+    .unwrap()
+});
+
+/// Count of the PoS messages dropped for arriving before the chain id
+/// handshake of their peer, by msg id
+pub static NETWORK_MSGS_BEFORE_HANDSHAKE: Lazy<IntCounterVec> = Lazy::new(
+    || {
+        register_int_counter_vec!(
+            "diem_consensus_network_msgs_before_handshake_count",
+            "Count of the PoS messages dropped for arriving before the chain id handshake of their peer, by msg id",
+            &["msg_id"]
+        )
+        .unwrap()
+    },
+);
+
+/// Count of the PoS messages from peers dropped for failing to decode, by msg
+/// id
+pub static NETWORK_MSGS_MALFORMED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "diem_consensus_network_msgs_malformed_count",
+        "Count of the PoS messages from peers dropped for failing to decode, by msg id",
+        &["msg_id"]
+    )
+    .unwrap()
+});
+
+/// Count of the times the consensus message queue reaches its high water mark
+/// and stops accepting sync and retrieval messages from peers
+pub static CONSENSUS_QUEUE_BACKPRESSURE_ENGAGED: Lazy<IntCounter> = Lazy::new(
+    || {
+        register_int_counter!(
+            "diem_consensus_queue_backpressure_engaged_count",
+            "Count of the times the consensus message queue reaches its high water mark"
+        )
+        .unwrap()
+    },
+);
+
+/// Count of the PoS messages from peers dropped under the backpressure of the
+/// consensus message queue, by message type
+pub static NETWORK_MSGS_BACKPRESSURED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "diem_consensus_network_msgs_backpressured_count",
+        "Count of the PoS messages from peers dropped under the backpressure of the consensus message queue, by message type",
+        &["message"]
+    )
+    .unwrap()
+});
+
+/// Count of the equivocating proposals received from peers
+pub static NETWORK_EQUIVOCATING_PROPOSALS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "diem_consensus_network_equivocating_proposals_count",
+        "Count of the equivocating proposals received from peers"
+    )
+    .unwrap()
+});
+
+/// Count of the proposals held back until their parents are received
+pub static NETWORK_PROPOSALS_HELD: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "diem_consensus_network_proposals_held_count",
+        "Count of the proposals held back until their parents are received"
+    )
+    .unwrap()
+});
+
+/// Count of the epoch change proofs dropped before all their chunks are
+/// received, by reason
+pub static NETWORK_PARTIAL_PROOFS_DROPPED: Lazy<IntCounterVec> = Lazy::new(
+    || {
+        register_int_counter_vec!(
+            "diem_consensus_network_partial_proofs_dropped_count",
+            "Count of the epoch change proofs dropped before all their chunks are received, by reason",
+            &["reason"]
+        )
+        .unwrap()
+    },
+);
+
+/// Count of the peers blacklisted for their protocol violations
+pub static NETWORK_PEERS_BLACKLISTED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "diem_consensus_network_peers_blacklisted_count",
+        "Count of the peers blacklisted for their protocol violations"
+    )
+    .unwrap()
+});
+
+/// Count of the PoS messages dropped for their peers being blacklisted, by
+/// direction
+pub static NETWORK_MSGS_BLACKLISTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "diem_consensus_network_msgs_blacklisted_count",
+        "Count of the PoS messages dropped for their peers being blacklisted, by direction",
+        &["direction"]
+    )
+    .unwrap()
+});
+
+/// Count of the PoS peer connection events, by event and reason
+pub static NETWORK_PEER_EVENTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "diem_consensus_network_peer_events_count",
+        "Count of the PoS peer connection events, by event and reason",
+        &["event", "reason"]
+    )
+    .unwrap()
+});
+
+/// Number of the PoS RPC requests that are waiting for responses
+pub static INFLIGHT_RPC_REQUESTS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "diem_consensus_inflight_rpc_requests",
+        "Number of the PoS RPC requests that are waiting for responses"
+    )
+    .unwrap()
+});
+
+/// Number of the permits of the outstanding PoS RPC requests in use, see
+/// `pos_max_concurrent_rpcs`
+pub static RPC_PERMITS_IN_USE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "diem_consensus_rpc_permits_in_use",
+        "Number of the permits of the outstanding PoS RPC requests in use"
+    )
+    .unwrap()
+});
+
+/// Number of the PoS RPC requests tracked by the request manager, see
+/// `pos_max_pending_requests`
+pub static RPC_PENDING_REQUESTS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "diem_consensus_rpc_pending_requests",
+        "Number of the PoS RPC requests tracked by the request manager"
+    )
+    .unwrap()
+});
+
+/// Count of the PoS RPC requests rejected because the request manager
+/// tracks too many requests
+pub static RPC_REJECTED_TOO_MANY_PENDING: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "diem_consensus_rpc_rejected_too_many_pending_count",
+        "Count of the PoS RPC requests rejected because the request manager tracks too many requests"
+    )
+    .unwrap()
+});
+
+/// Count of the PoS RPC requests also sent to another peer because the
+/// first peers are slow to answer, see `NetworkSender::send_rpc_hedged`
+pub static RPC_HEDGED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "diem_consensus_rpc_hedged_count",
+        "Count of the PoS RPC requests also sent to another peer because the first peers are slow to answer"
+    )
+    .unwrap()
+});
+
+/// Count of the PoS RPC requests answered by an identical request in flight
+/// instead of being sent
+pub static RPC_COALESCED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "diem_consensus_rpc_coalesced_count",
+        "Count of the PoS RPC requests answered by an identical request in flight instead of being sent"
+    )
+    .unwrap()
+});
+
+/// Count of the PoS RPC requests dropped locally before their responses
+/// arrive, by request type
+pub static RPC_CANCELED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "diem_consensus_rpc_canceled_count",
+        "Count of the PoS RPC requests dropped locally before their responses arrive, by request type",
+        &["type"]
+    )
+    .unwrap()
+});
+
+/// Histogram of the time (in seconds) an RPC waits for its response, by
+/// request type
+pub static RPC_LATENCY_S: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "diem_consensus_rpc_latency_s",
+        "Histogram of the time (in seconds) an RPC waits for its response, by request type",
+        &["type"]
+    )
+    .unwrap()
+});
+
+/// The estimated quantiles of the RPC response latency of each PoS peer,
+/// in milliseconds
+pub static RPC_PEER_LATENCY_MS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "diem_consensus_rpc_peer_latency_ms",
+        "The estimated quantiles of the RPC response latency of each PoS peer, in milliseconds",
+        &["peer", "quantile"]
+    )
+    .unwrap()
+});
+
+/// Count of the outgoing consensus messages an observer drops for its queue
+/// being full
+pub static CONSENSUS_MSG_OBSERVER_DROPPED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "diem_consensus_msg_observer_dropped_count",
+        "Count of the outgoing consensus messages an observer drops for its queue being full"
+    )
+    .unwrap()
+});
+
+/// Count of the votes refused on the send path for another vote being sent
+/// in the same round
+pub static EQUIVOCATING_VOTES_REFUSED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "diem_consensus_equivocating_votes_refused_count",
+        "Count of the votes refused on the send path for another vote being sent in the same round"
+    )
+    .unwrap()
+});
+
+/// Count of the re-sends of the votes sent before, see `VoteRebroadcaster`
+pub static VOTE_REBROADCAST_ATTEMPTS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "diem_consensus_vote_rebroadcast_attempts_count",
+        "Count of the re-sends of the votes sent before"
+    )
+    .unwrap()
+});
+
+/// Count of the votes no longer sent again, by whether their rounds are
+/// superseded or their attempts are exhausted
+pub static VOTE_REBROADCASTS_STOPPED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "diem_consensus_vote_rebroadcasts_stopped_count",
+        "Count of the votes no longer sent again, by whether their rounds are superseded or their attempts are exhausted",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+/// Count of the transitions of the circuit breakers of the peers failing
+/// the RPC requests, by the state transited to
+pub static RPC_CIRCUIT_BREAKER_TRANSITIONS: Lazy<IntCounterVec> = Lazy::new(
+    || {
+        register_int_counter_vec!(
+            "diem_consensus_rpc_circuit_breaker_transitions_count",
+            "Count of the transitions of the circuit breakers of the peers failing the RPC requests, by the state transited to",
+            &["state"]
+        )
+        .unwrap()
+    },
+);
+
+/// Count of the epoch retrievals answered busy for as many epoch change
+/// proofs being assembled as allowed at once
+pub static EPOCH_RETRIEVALS_THROTTLED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "diem_consensus_epoch_retrievals_throttled_count",
+        "Count of the epoch retrievals answered busy for as many epoch change proofs being assembled as allowed at once"
+    )
+    .unwrap()
+});
+
+/// Count of the PoS messages from peers dropped for a sequence number
+/// processed before, by peer
+pub static NETWORK_MSGS_REPLAYED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "diem_consensus_network_msgs_replayed_count",
+        "Count of the PoS messages from peers dropped for a sequence number processed before, by peer",
+        &["peer"]
+    )
+    .unwrap()
+});
+
+/// Count of the consensus messages copied to the mirror, by message type
+pub static CONSENSUS_MSGS_MIRRORED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "diem_consensus_network_msgs_mirrored_count",
+        "Count of the consensus messages copied to the mirror, by message type",
+        &["type"]
+    )
+    .unwrap()
+});
+
+/// Count of the consensus messages that fail to be copied to the mirror, by
+/// message type and reason
+pub static CONSENSUS_MIRROR_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "diem_consensus_network_mirror_failures_count",
+        "Count of the consensus messages that fail to be copied to the mirror, by message type and reason",
+        &["type", "reason"]
+    )
+    .unwrap()
+});
+
+/// Count of the epoch retrievals timing out, by whether they are sent to
+/// another peer, no other peer is ahead or their attempts are exhausted
+pub static EPOCH_RETRIEVAL_FAILOVERS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "diem_consensus_epoch_retrieval_failovers_count",
+        "Count of the epoch retrievals timing out, by whether they are sent to another peer, no other peer is ahead or their attempts are exhausted",
+        &["outcome"]
+    )
+    .unwrap()
+});
+
+/// Count of the proposals and votes not delivered to consensus for a copy
+/// received from a peer before, by message type
+pub static NETWORK_MSGS_SEEN: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "diem_consensus_network_msgs_seen_count",
+        "Count of the proposals and votes not delivered to consensus for a copy received from a peer before, by message type",
+        &["type"]
+    )
+    .unwrap()
+});
+
+/// Count of the messages of the later epochs than ours, by message type and
+/// whether they trigger an epoch retrieval or are debounced
+pub static EPOCH_SYNC_TRIGGERS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "diem_consensus_epoch_sync_triggers_count",
+        "Count of the messages of the later epochs than ours, by message type and whether they trigger an epoch retrieval or are debounced",
+        &["type", "result"]
+    )
+    .unwrap()
+});
+
+/// Number of the rounds the node is behind while retrieving blocks, 0 once
+/// it is synced, see `SyncStatus`
+pub static SYNC_BEHIND_BY_ROUNDS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "diem_consensus_sync_behind_by_rounds",
+        "Number of the rounds the node is behind while retrieving blocks, 0 once it is synced"
+    )
+    .unwrap()
+});
+
+/// Count of the retries of the block retrievals asked from the retry budget
+/// of their sync session, by whether they are granted or the budget is
+/// exhausted
+pub static RETRY_BUDGET_RETRIES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "diem_consensus_retry_budget_retries_count",
+        "Count of the retries of the block retrievals asked from the retry budget of their sync session, by whether they are granted or the budget is exhausted",
+        &["result"]
+    )
+    .unwrap()
+});
+
+/// Number of the retries left in the retry budget of the last sync session
+/// retrying
+pub static RETRY_BUDGET_REMAINING: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "diem_consensus_retry_budget_remaining",
+        "Number of the retries left in the retry budget of the last sync session retrying"
+    )
+    .unwrap()
+});
+
+/// Count of the sessions of the PoS peers refreshed in the PoS peer mapping,
+/// by whether a session is found or the peer is removed
+pub static PEER_SESSION_REFRESHES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "diem_network_peer_session_refreshes_count",
+        "Count of the sessions of the PoS peers refreshed in the PoS peer mapping, by whether a session is found or the peer is removed",
+        &["result"]
+    )
+    .unwrap()
+});
+
+/// Histogram of the time (in seconds) from the connection with a PoS peer to
+/// its chain id handshake
+pub static NETWORK_HANDSHAKE_DURATION_S: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "diem_network_handshake_duration_s",
+        "Histogram of the time (in seconds) from the connection with a PoS peer to its chain id handshake"
+    )
+    .unwrap()
+});
+
+/// Count of the connections with the PoS peers failing to be set up, by
+/// whether the protocol version or the chain id mismatches or the handshake
+/// times out
+pub static NETWORK_HANDSHAKE_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "diem_network_handshake_failures_count",
+        "Count of the connections with the PoS peers failing to be set up, by whether the protocol version or the chain id mismatches or the handshake times out",
+        &["reason"]
+    )
+    .unwrap()
+});
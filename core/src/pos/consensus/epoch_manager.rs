@@ -8,7 +8,11 @@
 use super::{
     block_storage::BlockStore,
     counters,
-    error::{error_kind, DbError},
+    epoch_proof_cache::{EpochProofCache, EPOCH_PROOF_CACHE_SIZE},
+    epoch_retrieval_failover::EpochRetrievalFailover,
+    epoch_retrieval_limiter::EpochRetrievalLimiter,
+    epoch_sync_debounce::EpochSyncDebounce,
+    error::{error_kind, DbError, InvalidEpochChangeProof},
     liveness::{
         proposal_generator::ProposalGenerator,
         proposer_election::ProposerElection,
@@ -32,11 +36,17 @@ use super::{
     },
     state_replication::{StateComputer, TxnManager},
     util::time_service::TimeService,
+    vote_record::{
+        FileVoteRecordStore, LastVoteRecord, VoteRecorder, LAST_VOTE_FILE,
+    },
 };
 use crate::pos::{
     consensus::{liveness::vrf_proposer_election::VrfProposer, TestCommand},
     mempool::SubmissionStatus,
-    protocol::network_sender::NetworkSender,
+    protocol::{
+        message::epoch_retrieval_busy::EpochRetrievalBusy,
+        network_sender::NetworkSender,
+    },
 };
 use anyhow::{anyhow, bail, ensure, Context};
 use channel::diem_channel;
@@ -52,14 +62,14 @@ use diem_metrics::monitor;
 use diem_types::{
     account_address::AccountAddress,
     block_info::PivotBlockDecision,
-    epoch_change::EpochChangeProof,
+    epoch_change::{EpochChangeProof, EpochChangeProofError},
     epoch_state::EpochState,
     on_chain_config::{OnChainConfigPayload, ValidatorSet},
     transaction::{SignedTransaction, TransactionPayload},
 };
 use futures::{
     channel::{mpsc, oneshot},
-    select_biased, StreamExt,
+    select_biased, FutureExt, StreamExt,
 };
 use pow_types::PowInterface;
 use safety_rules::SafetyRulesManager;
@@ -69,7 +79,7 @@ use std::{
         atomic::{AtomicBool, Ordering as AtomicOrdering},
         Arc,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 /// RecoveryManager is used to process events in order to sync up with peer if
@@ -111,6 +121,8 @@ pub struct EpochManager {
     txn_manager: Arc<dyn TxnManager>,
     state_computer: Arc<dyn StateComputer>,
     storage: Arc<dyn PersistentLivenessStorage>,
+    /// The epoch change proofs sent to the peers recently.
+    epoch_proof_cache: Arc<EpochProofCache>,
     safety_rules_manager: SafetyRulesManager,
     processor: Option<RoundProcessor>,
     reconfig_events: diem_channel::Receiver<(), OnChainConfigPayload>,
@@ -122,6 +134,15 @@ pub struct EpochManager {
         oneshot::Sender<anyhow::Result<SubmissionStatus>>,
     )>,
     is_voting: bool,
+    /// The last vote sent, shared by the network senders of all the epochs.
+    vote_recorder: Arc<VoteRecorder>,
+    /// Bounds the epoch change proofs assembled for the peers at once.
+    epoch_retrieval_limiter: EpochRetrievalLimiter,
+    /// Sends the epoch retrievals timing out to the other peers ahead.
+    epoch_retrieval_failover: EpochRetrievalFailover,
+    /// Keeps the messages of the later epochs from triggering a retrieval
+    /// each.
+    epoch_sync_debounce: EpochSyncDebounce,
 }
 
 impl EpochManager {
@@ -150,6 +171,30 @@ impl EpochManager {
         let sr_config = &node_config.consensus.safety_rules;
         let safety_rules_manager = SafetyRulesManager::new(sr_config);
         diem_debug!("EpochManager.author={:?}", author);
+        let vote_recorder = Arc::new(
+            VoteRecorder::new(Arc::new(FileVoteRecordStore::new(
+                node_config.storage.dir().join(LAST_VOTE_FILE),
+            )))
+            .expect("Unable to load the last sent vote"),
+        );
+        let epoch_retrieval_limiter = EpochRetrievalLimiter::new(
+            network_sender
+                .protocol_handler
+                .protocol_config
+                .pos_max_concurrent_epoch_retrievals,
+        );
+        let epoch_retrieval_failover = EpochRetrievalFailover::new(
+            network_sender
+                .protocol_handler
+                .protocol_config
+                .pos_epoch_retrieval_failover,
+        );
+        let epoch_sync_debounce = EpochSyncDebounce::new(
+            network_sender
+                .protocol_handler
+                .protocol_config
+                .pos_epoch_sync_debounce,
+        );
         Self {
             author,
             config,
@@ -162,6 +207,9 @@ impl EpochManager {
             txn_manager,
             state_computer,
             storage,
+            epoch_proof_cache: Arc::new(EpochProofCache::new(
+                EPOCH_PROOF_CACHE_SIZE,
+            )),
             safety_rules_manager,
             processor: None,
             reconfig_events,
@@ -169,6 +217,10 @@ impl EpochManager {
             election_control: Arc::new(AtomicBool::new(true)),
             tx_sender,
             is_voting: started_as_voter,
+            vote_recorder,
+            epoch_retrieval_limiter,
+            epoch_retrieval_failover,
+            epoch_sync_debounce,
         }
     }
 
@@ -262,25 +314,46 @@ impl EpochManager {
             "[EpochManager] receive {}",
             request,
         );
-        let proof = self
-            .storage
-            .pos_ledger_db()
-            .get_epoch_ending_ledger_infos(
-                request.start_epoch,
-                request.end_epoch,
-            )
-            .map_err(DbError::from)
-            .context("[EpochManager] Failed to get epoch proof")?;
-        let msg = ConsensusMsg::EpochChangeProof(Box::new(proof));
-        self.network_sender.send_to(peer_id, &msg).context(format!(
-            "[EpochManager] Failed to send epoch proof to {}",
+        let busy = EpochRetrievalBusy {
+            start_epoch: request.start_epoch,
+            end_epoch: request.end_epoch,
+        };
+        let storage = self.storage.clone();
+        let epoch_proof_cache = self.epoch_proof_cache.clone();
+        let mut network_sender = self.network_sender.clone();
+        let served = self.epoch_retrieval_limiter.try_serve(move || {
+            if let Err(e) = send_epoch_proof(
+                &*storage,
+                &epoch_proof_cache,
+                &mut network_sender,
+                request,
+                peer_id,
+            ) {
+                counters::ERROR_COUNT.inc();
+                diem_error!(error = ?e, kind = error_kind(&e));
+            }
+        });
+        if served {
+            return Ok(());
+        }
+        diem_debug!(
+            "[EpochManager] Too many epoch proofs being sent, answer {} busy",
+            peer_id
+        );
+        self.network_sender.send_to(peer_id, &busy).context(format!(
+            "[EpochManager] Failed to send epoch retrieval busy to {}",
             peer_id
         ))
     }
 
+    /// Handle the message `msg_name` of `different_epoch` from `peer_id`: a
+    /// peer behind is sent the proof to our epoch, and a peer ahead is asked
+    /// for the proof to its epoch, see `EpochSyncDebounce`.
     async fn process_different_epoch(
-        &mut self, different_epoch: u64, peer_id: AccountAddress,
-    ) -> anyhow::Result<()> {
+        &mut self, msg_name: &'static str, different_epoch: u64,
+        peer_id: AccountAddress,
+    ) -> anyhow::Result<()>
+    {
         diem_debug!(
             LogSchema::new(LogEvent::ReceiveMessageFromDifferentEpoch)
                 .remote_peer(peer_id)
@@ -301,6 +374,15 @@ impl EpochManager {
             }
             // We request proof to join higher epoch
             Ordering::Greater => {
+                self.epoch_retrieval_failover
+                    .observe_epoch(peer_id, different_epoch);
+                if !self.epoch_sync_debounce.try_trigger(
+                    msg_name,
+                    different_epoch,
+                    Instant::now(),
+                ) {
+                    return Ok(());
+                }
                 let request = EpochRetrievalRequest {
                     start_epoch: self.epoch(),
                     end_epoch: different_epoch,
@@ -310,7 +392,10 @@ impl EpochManager {
                 self.network_sender.send_to(peer_id, &msg).context(format!(
                     "[EpochManager] Failed to send epoch retrieval to {}",
                     peer_id
-                ))
+                ))?;
+                self.epoch_retrieval_failover
+                    .on_request_sent(peer_id, Instant::now());
+                Ok(())
             }
             Ordering::Equal => {
                 bail!("[EpochManager] Same epoch should not come to process_different_epoch");
@@ -318,12 +403,34 @@ impl EpochManager {
         }
     }
 
+    /// Send the epoch retrieval timing out to another peer ahead of us, see
+    /// `EpochRetrievalFailover`.
+    fn process_epoch_retrieval_timeout(&mut self) -> anyhow::Result<()> {
+        let (peer_id, request) = match self
+            .epoch_retrieval_failover
+            .next_attempt(self.epoch(), Instant::now())
+        {
+            Some(attempt) => attempt,
+            None => return Ok(()),
+        };
+        diem_debug!(
+            "[EpochManager] Epoch retrieval timed out, send {} to {}",
+            request,
+            peer_id
+        );
+        let msg = ConsensusMsg::EpochRetrievalRequest(Box::new(request));
+        self.network_sender.send_to(peer_id, &msg).context(format!(
+            "[EpochManager] Failed to send epoch retrieval to {}",
+            peer_id
+        ))
+    }
+
     async fn start_new_epoch(
         &mut self, proof: EpochChangeProof, peer_id: AccountAddress,
     ) -> anyhow::Result<()> {
         let ledger_info = proof
             .verify(self.epoch_state())
-            .context("[EpochManager] Invalid EpochChangeProof")?;
+            .map_err(|e| invalid_epoch_change_proof(peer_id, e))?;
         diem_debug!(
             LogSchema::new(LogEvent::NewEpoch)
                 .epoch(ledger_info.ledger_info().next_block_epoch()),
@@ -384,6 +491,24 @@ impl EpochManager {
             "Starting new epoch",
         );
         let last_vote = recovery_data.last_vote();
+        if let (Some(vote), Some(sent)) =
+            (&last_vote, self.vote_recorder.last_vote())
+        {
+            // The safety rules and the network sender persist the vote
+            // separately, so they only disagree if a vote is lost.
+            let recovered = LastVoteRecord::from_vote(vote);
+            if (recovered.epoch, recovered.round) == (sent.epoch, sent.round)
+                && recovered.vote_hash != sent.vote_hash
+            {
+                diem_error!(
+                    epoch = epoch,
+                    "The recovered last vote {:?} differs from the last \
+                     sent vote {:?}",
+                    vote,
+                    sent
+                );
+            }
+        }
 
         diem_info!(epoch = epoch, "Create BlockStore");
         let block_store = Arc::new(BlockStore::new(
@@ -394,6 +519,9 @@ impl EpochManager {
             Arc::clone(&self.time_service),
             self.pow_handler.clone(),
         ));
+        self.network_sender
+            .protocol_handler
+            .set_block_stream_source(block_store.clone());
 
         diem_info!(epoch = epoch, "Update SafetyRules");
 
@@ -462,7 +590,8 @@ impl EpochManager {
             self.network_sender.clone(),
             //self.self_sender.clone(),
             epoch_state.verifier().clone(),
-        );
+        )
+        .with_vote_recorder(self.vote_recorder.clone());
 
         let mut processor = RoundManager::new(
             epoch_state,
@@ -506,7 +635,8 @@ impl EpochManager {
             self.network_sender.clone(),
             //self.self_sender.clone(),
             epoch_state.verifier().clone(),
-        );
+        )
+        .with_vote_recorder(self.vote_recorder.clone());
         self.processor = Some(RoundProcessor::Recovery(RecoveryManager::new(
             epoch_state,
             network_sender,
@@ -537,6 +667,23 @@ impl EpochManager {
             )
         });
         diem_debug!("start_processor: epoch_state={:?}", epoch_state);
+        // The proofs cached may stop before the epoch committed.
+        self.epoch_proof_cache.invalidate();
+        let protocol_handler = &self.network_sender.protocol_handler;
+        protocol_handler.set_validators(epoch_state.verifier());
+        let validator_peers = protocol_handler
+            .pos_node_id_cache
+            .read()
+            .iter()
+            .filter(|(account, _)| {
+                epoch_state.verifier().get_public_key(account).is_some()
+            })
+            .map(|(_, node_id)| *node_id)
+            .collect();
+        protocol_handler.set_validator_peers(validator_peers);
+        self.epoch_retrieval_failover
+            .on_new_epoch(epoch_state.epoch);
+        self.epoch_sync_debounce.on_new_epoch();
 
         match self.storage.start() {
             LivenessStorageData::RecoveryData(initial_data) => {
@@ -592,20 +739,28 @@ impl EpochManager {
         match msg {
             ConsensusMsg::ProposalMsg(_)
             | ConsensusMsg::SyncInfo(_)
-            | ConsensusMsg::VoteMsg(_) => {
+            | ConsensusMsg::VoteMsg(_)
+            | ConsensusMsg::CommitVote(_) => {
+                let msg_name = msg.name();
                 let event: UnverifiedEvent = msg.into();
                 if event.epoch() == self.epoch() {
                     return Ok(Some(event));
                 } else {
                     monitor!(
                         "process_different_epoch_consensus_msg",
-                        self.process_different_epoch(event.epoch(), peer_id)
-                            .await?
+                        self.process_different_epoch(
+                            msg_name,
+                            event.epoch(),
+                            peer_id
+                        )
+                        .await?
                     );
                 }
             }
             ConsensusMsg::EpochChangeProof(proof) => {
-                let msg_epoch = proof.epoch()?;
+                let msg_epoch = proof
+                    .epoch()
+                    .map_err(|e| invalid_epoch_change_proof(peer_id, e))?;
                 diem_debug!(
                     LogSchema::new(LogEvent::ReceiveEpochChangeProof)
                         .remote_peer(peer_id)
@@ -618,6 +773,16 @@ impl EpochManager {
                         "process_epoch_proof",
                         self.start_new_epoch(*proof, peer_id).await?
                     );
+                } else if msg_epoch > self.epoch() {
+                    // We never ask for a proof starting after our epoch.
+                    return Err(invalid_epoch_change_proof(
+                        peer_id,
+                        EpochChangeProofError::WrongEpoch {
+                            expected: self.epoch(),
+                            actual: msg_epoch,
+                        }
+                        .into(),
+                    ));
                 } else {
                     debug!(
                         "[EpochManager] Unexpected epoch proof from epoch {}, local epoch {}",
@@ -658,6 +823,8 @@ impl EpochManager {
                     VerifiedEvent::SyncInfo(sync_info) => {
                         p.sync_up(&sync_info, peer_id).await
                     }
+                    // Commit votes cannot help the recovery.
+                    VerifiedEvent::CommitVote(_) => return Ok(()),
                 }?;
                 let epoch_state = p.epoch_state().clone();
                 diem_info!("Recovered from SyncProcessor");
@@ -676,6 +843,10 @@ impl EpochManager {
                     "process_sync_info",
                     p.process_sync_info_msg(*sync_info, peer_id).await
                 ),
+                VerifiedEvent::CommitVote(commit_vote) => monitor!(
+                    "process_commit_vote",
+                    p.process_commit_vote_msg(*commit_vote).await
+                ),
             },
         }
     }
@@ -771,8 +942,25 @@ impl EpochManager {
         stopped: Arc<AtomicBool>,
     )
     {
+        if self.config.startup_min_peers > 0 {
+            let connected = self
+                .network_sender
+                .wait_for_peers(
+                    self.config.startup_min_peers,
+                    Duration::from_millis(self.config.startup_peers_timeout_ms),
+                )
+                .await;
+            diem_info!(
+                "{} of {} peers connected before starting consensus",
+                connected,
+                self.config.startup_min_peers
+            );
+        }
         // initial start of the processor
         self.expect_new_epoch().await;
+        let mut epoch_retrieval_check = tokio::time::interval(
+            self.epoch_retrieval_failover.check_interval(),
+        );
         diem_debug!("EpochManager main_loop starts");
         loop {
             if stopped.load(AtomicOrdering::SeqCst) {
@@ -800,6 +988,9 @@ impl EpochManager {
                     block_retrieval = network_receivers.block_retrieval.select_next_some() => {
                         monitor!("process_block_retrieval", self.process_block_retrieval(block_retrieval).await)
                     }
+                    _ = epoch_retrieval_check.tick().fuse() => {
+                        monitor!("process_epoch_retrieval_timeout", self.process_epoch_retrieval_timeout())
+                    }
                 }
             );
             let round_state =
@@ -826,6 +1017,48 @@ impl EpochManager {
     }
 }
 
+/// Turn the verification failure of an `EpochChangeProof` from `peer` into
+/// `InvalidEpochChangeProof` and count it. Other errors are returned as is.
+fn invalid_epoch_change_proof(
+    peer: AccountAddress, e: anyhow::Error,
+) -> anyhow::Error {
+    match e.downcast::<EpochChangeProofError>() {
+        Ok(reason) => {
+            counters::INVALID_EPOCH_CHANGE_PROOFS
+                .with_label_values(&[&peer.to_string(), reason.as_str()])
+                .inc();
+            InvalidEpochChangeProof { peer, reason }.into()
+        }
+        Err(e) => e,
+    }
+}
+
+/// Read the epoch change proof `request` asks for from `cache`, or from
+/// `storage` if it is not cached, and send it to `peer_id`.
+fn send_epoch_proof(
+    storage: &dyn PersistentLivenessStorage, cache: &EpochProofCache,
+    network_sender: &mut NetworkSender, request: EpochRetrievalRequest,
+    peer_id: AccountAddress,
+) -> anyhow::Result<()>
+{
+    let proof =
+        cache.get_or_load(request.start_epoch, request.end_epoch, || {
+            storage
+                .pos_ledger_db()
+                .get_epoch_ending_ledger_infos(
+                    request.start_epoch,
+                    request.end_epoch,
+                )
+                .map_err(DbError::from)
+                .context("[EpochManager] Failed to get epoch proof")
+        })?;
+    let msg = ConsensusMsg::EpochChangeProof(Box::new(proof));
+    network_sender.send_to(peer_id, &msg).context(format!(
+        "[EpochManager] Failed to send epoch proof to {}",
+        peer_id
+    ))
+}
+
 /// The functions used in tests to construct attack cases
 impl EpochManager {
     async fn process_test_command(
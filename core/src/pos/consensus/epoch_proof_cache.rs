@@ -0,0 +1,91 @@
+// Copyright 2021 Conflux Foundation. All rights reserved.
+// Conflux is free software and distributed under GNU General Public License.
+// See http://www.gnu.org/licenses/
+
+//! The cache of the epoch change proofs sent to the peers.
+//!
+//! After a network-wide restart many peers behind ask for the same
+//! `EpochChangeProof` at once, and each `EpochRetrievalRequest` reads the
+//! epoch ending ledger infos of its range again. The last proofs sent are
+//! kept by `(start_epoch, end_epoch)`, so the same request is served without
+//! reading the ledger. A proof whose range ends beyond our epoch stops at
+//! our epoch, so the cache is cleared whenever a new epoch is committed.
+
+use anyhow::Result;
+use diem_infallible::Mutex;
+use diem_types::epoch_change::EpochChangeProof;
+use lru_time_cache::LruCache;
+
+use super::counters;
+
+/// The number of the epoch change proofs kept.
+pub const EPOCH_PROOF_CACHE_SIZE: usize = 16;
+
+/// The recent epoch change proofs, see the module doc.
+pub struct EpochProofCache {
+    capacity: usize,
+    proofs: Mutex<LruCache<(u64, u64), EpochChangeProof>>,
+}
+
+impl EpochProofCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            proofs: Mutex::new(LruCache::with_capacity(capacity)),
+        }
+    }
+
+    /// The proof from `start_epoch` to `end_epoch`, loaded with `load` if
+    /// it is not cached.
+    pub fn get_or_load<F>(
+        &self, start_epoch: u64, end_epoch: u64, load: F,
+    ) -> Result<EpochChangeProof>
+    where F: FnOnce() -> Result<EpochChangeProof> {
+        let key = (start_epoch, end_epoch);
+        if let Some(proof) = self.proofs.lock().get(&key) {
+            counters::EPOCH_PROOF_CACHE
+                .with_label_values(&["hit"])
+                .inc();
+            return Ok(proof.clone());
+        }
+        counters::EPOCH_PROOF_CACHE
+            .with_label_values(&["miss"])
+            .inc();
+        let proof = load()?;
+        self.proofs.lock().insert(key, proof.clone());
+        Ok(proof)
+    }
+
+    /// Drop the proofs cached, once a new epoch is committed.
+    pub fn invalidate(&self) {
+        *self.proofs.lock() = LruCache::with_capacity(self.capacity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EpochProofCache;
+    use diem_types::epoch_change::EpochChangeProof;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_same_request_served_from_cache() {
+        let cache = EpochProofCache::new(4);
+        let loads = Cell::new(0);
+        let load = || {
+            loads.set(loads.get() + 1);
+            Ok(EpochChangeProof::new(vec![], false))
+        };
+        cache.get_or_load(0, 1, load).unwrap();
+        cache.get_or_load(0, 1, load).unwrap();
+        assert_eq!(loads.get(), 1);
+
+        // Another range is loaded.
+        cache.get_or_load(1, 2, load).unwrap();
+        assert_eq!(loads.get(), 2);
+
+        cache.invalidate();
+        cache.get_or_load(0, 1, load).unwrap();
+        assert_eq!(loads.get(), 3);
+    }
+}
@@ -0,0 +1,222 @@
+// Copyright 2021 Conflux Foundation. All rights reserved.
+// Conflux is free software and distributed under GNU General Public License.
+// See http://www.gnu.org/licenses/
+
+//! The failover of the epoch retrievals to the other peers ahead of us.
+//!
+//! A node behind by epochs asks the peer whose message shows a later epoch
+//! for the epoch change proof, and an unresponsive peer stalls the catch-up
+//! as long as only it is asked. With `EpochRetrievalFailoverConfig` enabled,
+//! the epochs the peers advertise in their messages are remembered, and if
+//! no proof arrives within `timeout` of the first request, the request is
+//! sent to another peer advertising a later epoch than ours, at most
+//! `max_attempts` times in total before giving up until the next request.
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use consensus_types::{common::Author, epoch_retrieval::EpochRetrievalRequest};
+
+use super::counters;
+
+/// The least interval of checking the timeouts of the epoch retrievals.
+const MIN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The failover of the epoch retrievals, see `EpochRetrievalFailover`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EpochRetrievalFailoverConfig {
+    /// How long a peer is waited for the proof before the next one is
+    /// asked.
+    pub timeout: Duration,
+    /// The most peers asked for one proof, including the first one. 0 or 1
+    /// disables the failover.
+    pub max_attempts: u32,
+}
+
+impl EpochRetrievalFailoverConfig {
+    /// Whether the epoch retrievals are sent to the other peers.
+    pub fn is_enabled(&self) -> bool {
+        self.max_attempts > 1 && self.timeout > Duration::from_secs(0)
+    }
+}
+
+/// The epoch retrieval waiting for its proof.
+struct PendingRetrieval {
+    tried: HashSet<Author>,
+    attempts: u32,
+    deadline: Instant,
+}
+
+/// Chooses the peers the epoch retrievals fail over to, see the module doc.
+pub struct EpochRetrievalFailover {
+    config: EpochRetrievalFailoverConfig,
+    /// The latest epoch each peer advertises in its messages.
+    peer_epochs: HashMap<Author, u64>,
+    pending: Option<PendingRetrieval>,
+}
+
+impl EpochRetrievalFailover {
+    pub fn new(config: EpochRetrievalFailoverConfig) -> Self {
+        Self {
+            config,
+            peer_epochs: HashMap::new(),
+            pending: None,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool { self.config.is_enabled() }
+
+    /// How often `next_attempt` is checked, so a retrieval fails over
+    /// within a half of `timeout` after it times out.
+    pub fn check_interval(&self) -> Duration {
+        (self.config.timeout / 2).max(MIN_CHECK_INTERVAL)
+    }
+
+    /// Observe a message of `epoch` from `peer`.
+    pub fn observe_epoch(&mut self, peer: Author, epoch: u64) {
+        if self.is_enabled() {
+            self.peer_epochs.insert(peer, epoch);
+        }
+    }
+
+    /// Observe the epoch retrieval sent to `peer` at `now`. The failover
+    /// starts from the first one sent while none is pending.
+    pub fn on_request_sent(&mut self, peer: Author, now: Instant) {
+        if !self.is_enabled() {
+            return;
+        }
+        let timeout = self.config.timeout;
+        self.pending
+            .get_or_insert_with(|| PendingRetrieval {
+                tried: HashSet::new(),
+                attempts: 1,
+                deadline: now + timeout,
+            })
+            .tried
+            .insert(peer);
+    }
+
+    /// Observe the move to `epoch`, which ends the pending retrieval. The
+    /// next message of a later epoch starts another one.
+    pub fn on_new_epoch(&mut self, epoch: u64) {
+        self.pending = None;
+        self.peer_epochs.retain(|_, peer_epoch| *peer_epoch > epoch);
+    }
+
+    /// The peer to send the epoch retrieval from `local_epoch` to and the
+    /// request, if the pending retrieval times out at `now`. The peer not
+    /// asked yet which advertises the latest epoch is chosen, and the
+    /// pending retrieval is dropped once `max_attempts` peers are asked or
+    /// no other peer is ahead of us.
+    pub fn next_attempt(
+        &mut self, local_epoch: u64, now: Instant,
+    ) -> Option<(Author, EpochRetrievalRequest)> {
+        let pending = self.pending.as_mut()?;
+        if pending.deadline > now {
+            return None;
+        }
+        if pending.attempts >= self.config.max_attempts {
+            counters::EPOCH_RETRIEVAL_FAILOVERS
+                .with_label_values(&["exhausted"])
+                .inc();
+            self.pending = None;
+            return None;
+        }
+        let next = self
+            .peer_epochs
+            .iter()
+            .filter(|(peer, epoch)| {
+                **epoch > local_epoch && !pending.tried.contains(*peer)
+            })
+            .max_by_key(|(_, epoch)| **epoch)
+            .map(|(peer, epoch)| (*peer, *epoch));
+        let (peer, end_epoch) = match next {
+            Some(next) => next,
+            None => {
+                counters::EPOCH_RETRIEVAL_FAILOVERS
+                    .with_label_values(&["no_peer"])
+                    .inc();
+                self.pending = None;
+                return None;
+            }
+        };
+        pending.tried.insert(peer);
+        pending.attempts += 1;
+        pending.deadline = now + self.config.timeout;
+        counters::EPOCH_RETRIEVAL_FAILOVERS
+            .with_label_values(&["retried"])
+            .inc();
+        Some((
+            peer,
+            EpochRetrievalRequest {
+                start_epoch: local_epoch,
+                end_epoch,
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EpochRetrievalFailover, EpochRetrievalFailoverConfig};
+    use diem_types::account_address::AccountAddress;
+    use std::time::{Duration, Instant};
+
+    fn new_failover(max_attempts: u32) -> EpochRetrievalFailover {
+        EpochRetrievalFailover::new(EpochRetrievalFailoverConfig {
+            timeout: Duration::from_secs(5),
+            max_attempts,
+        })
+    }
+
+    #[test]
+    fn test_timed_out_retrieval_sent_to_peer_ahead() {
+        let mut failover = new_failover(3);
+        let timeout = Duration::from_secs(5);
+        let (stalled, ahead, behind) = (
+            AccountAddress::random(),
+            AccountAddress::random(),
+            AccountAddress::random(),
+        );
+        failover.observe_epoch(stalled, 5);
+        failover.observe_epoch(ahead, 4);
+        failover.observe_epoch(behind, 3);
+        let now = Instant::now();
+        failover.on_request_sent(stalled, now);
+        assert!(failover.next_attempt(3, now + timeout / 2).is_none());
+
+        // The stalled peer times out, and only the other peer ahead of us
+        // is asked.
+        let (peer, request) = failover.next_attempt(3, now + timeout).unwrap();
+        assert_eq!(peer, ahead);
+        assert_eq!((request.start_epoch, request.end_epoch), (3, 4));
+
+        // The peer serves the proof.
+        failover.on_new_epoch(4);
+        assert!(failover.next_attempt(4, now + timeout * 3).is_none());
+    }
+
+    #[test]
+    fn test_failover_attempts_bounded() {
+        let mut failover = new_failover(2);
+        let timeout = Duration::from_secs(5);
+        let peers: Vec<_> = (0..3).map(|_| AccountAddress::random()).collect();
+        for peer in &peers {
+            failover.observe_epoch(*peer, 2);
+        }
+        let now = Instant::now();
+        failover.on_request_sent(peers[0], now);
+        assert!(failover.next_attempt(1, now + timeout).is_some());
+        assert!(failover.next_attempt(1, now + timeout * 2).is_none());
+        // The retrieval is dropped until the next request.
+        assert!(failover.next_attempt(1, now + timeout * 3).is_none());
+
+        // Never failed over if disabled.
+        let mut failover = new_failover(1);
+        failover.observe_epoch(peers[1], 2);
+        failover.on_request_sent(peers[0], now);
+        assert!(failover.next_attempt(1, now + timeout).is_none());
+    }
+}
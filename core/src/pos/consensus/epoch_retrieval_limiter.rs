@@ -0,0 +1,141 @@
+// Copyright 2021 Conflux Foundation. All rights reserved.
+// Conflux is free software and distributed under GNU General Public License.
+// See http://www.gnu.org/licenses/
+
+//! The bound on the epoch change proofs assembled at the same time.
+//!
+//! Serving an `EpochRetrievalRequest` reads the epoch ending ledger infos of
+//! the requested epochs and encodes them, which is expensive for a long
+//! range, and after a network-wide restart many peers request proofs at
+//! once. With a bound, the proofs are assembled on the blocking threads, at
+//! most `max_concurrent` at once, and the requests beyond that are answered
+//! with an `EpochRetrievalBusy` so the requesters can ask again later.
+
+use std::sync::Arc;
+
+use tokio::sync::Semaphore;
+
+use super::counters;
+
+/// Bounds the epoch change proofs being assembled, see the module doc.
+pub struct EpochRetrievalLimiter {
+    permits: Option<Arc<Semaphore>>,
+}
+
+impl EpochRetrievalLimiter {
+    /// At most `max_concurrent` proofs are assembled at once, 0 means no
+    /// limit.
+    pub fn new(max_concurrent: usize) -> Self {
+        Self {
+            permits: match max_concurrent {
+                0 => None,
+                n => Some(Arc::new(Semaphore::new(n))),
+            },
+        }
+    }
+
+    /// Assemble and send a proof with `serve` on a blocking thread, unless
+    /// `max_concurrent` proofs are being assembled already. Without a bound
+    /// `serve` runs in place.
+    ///
+    /// Returns whether `serve` is run, otherwise the request is throttled.
+    pub fn try_serve<F>(&self, serve: F) -> bool
+    where F: FnOnce() + Send + 'static {
+        let permits = match &self.permits {
+            Some(permits) => permits,
+            None => {
+                serve();
+                return true;
+            }
+        };
+        match permits.clone().try_acquire_owned() {
+            Ok(permit) => {
+                tokio::task::spawn_blocking(move || {
+                    serve();
+                    drop(permit);
+                });
+                true
+            }
+            Err(_) => {
+                counters::EPOCH_RETRIEVALS_THROTTLED.inc();
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EpochRetrievalLimiter;
+    use crate::pos::consensus::counters;
+    use std::{
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    /// Wait until `value` reaches `expected`, at most for a second.
+    async fn wait_for(value: &AtomicUsize, expected: usize) {
+        for _ in 0..1000 {
+            if value.load(Ordering::SeqCst) == expected {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        panic!("{} instead of {}", value.load(Ordering::SeqCst), expected);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_retrievals_bounded() {
+        let limiter = EpochRetrievalLimiter::new(2);
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(AtomicUsize::new(0));
+        let release = Arc::new(AtomicBool::new(false));
+        let throttled_before = counters::EPOCH_RETRIEVALS_THROTTLED.get();
+        let serve = || {
+            let in_flight = in_flight.clone();
+            let max_in_flight = max_in_flight.clone();
+            let done = done.clone();
+            let release = release.clone();
+            move || {
+                let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                max_in_flight.fetch_max(current, Ordering::SeqCst);
+                while !release.load(Ordering::SeqCst) {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                done.fetch_add(1, Ordering::SeqCst);
+            }
+        };
+
+        let served = (0..5).filter(|_| limiter.try_serve(serve())).count();
+        assert_eq!(served, 2);
+        assert!(
+            counters::EPOCH_RETRIEVALS_THROTTLED.get() >= throttled_before + 3
+        );
+        wait_for(&in_flight, 2).await;
+
+        // The permits are given back once the proofs are sent.
+        release.store(true, Ordering::SeqCst);
+        wait_for(&done, 2).await;
+        assert!(limiter.try_serve(serve()));
+        wait_for(&done, 3).await;
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_unbounded_served_in_place() {
+        let limiter = EpochRetrievalLimiter::new(0);
+        let served = Arc::new(AtomicUsize::new(0));
+        for _ in 0..10 {
+            let served = served.clone();
+            assert!(limiter.try_serve(move || {
+                served.fetch_add(1, Ordering::SeqCst);
+            }));
+        }
+        assert_eq!(served.load(Ordering::SeqCst), 10);
+    }
+}
@@ -0,0 +1,91 @@
+// Copyright 2021 Conflux Foundation. All rights reserved.
+// Conflux is free software and distributed under GNU General Public License.
+// See http://www.gnu.org/licenses/
+
+//! The debounce of the epoch retrievals triggered by the messages of the
+//! later epochs.
+//!
+//! Each message showing a later epoch than ours, e.g. the `SyncInfo`
+//! pushed by every peer ahead, would trigger another `EpochRetrievalRequest`
+//! while the proof of the first one is still on the way. Within `interval`
+//! after a retrieval is triggered, the messages of the same or an earlier
+//! epoch trigger nothing, while a later epoch still triggers one at once.
+
+use std::time::{Duration, Instant};
+
+use super::counters;
+
+/// Debounces the epoch retrievals, see the module doc.
+pub struct EpochSyncDebounce {
+    interval: Duration,
+    /// The end epoch and the time of the last retrieval triggered.
+    last: Option<(u64, Instant)>,
+}
+
+impl EpochSyncDebounce {
+    /// A zero `interval` disables the debounce.
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: None,
+        }
+    }
+
+    /// Whether the message `msg_name` of the later `epoch` received at `now`
+    /// triggers an epoch retrieval.
+    pub fn try_trigger(
+        &mut self, msg_name: &'static str, epoch: u64, now: Instant,
+    ) -> bool {
+        if let Some((last_epoch, last_time)) = self.last {
+            if epoch <= last_epoch && now < last_time + self.interval {
+                counters::EPOCH_SYNC_TRIGGERS
+                    .with_label_values(&[msg_name, "debounced"])
+                    .inc();
+                return false;
+            }
+        }
+        self.last = Some((epoch, now));
+        counters::EPOCH_SYNC_TRIGGERS
+            .with_label_values(&[msg_name, "triggered"])
+            .inc();
+        true
+    }
+
+    /// Forget the last retrieval once the epoch changes.
+    pub fn on_new_epoch(&mut self) { self.last = None; }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EpochSyncDebounce;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_higher_epoch_sync_info_triggers_once() {
+        let interval = Duration::from_secs(1);
+        let mut debounce = EpochSyncDebounce::new(interval);
+        let now = Instant::now();
+        let triggered = (0..10)
+            .filter(|i| {
+                debounce.try_trigger(
+                    "SyncInfo",
+                    3,
+                    now + Duration::from_millis(*i),
+                )
+            })
+            .count();
+        assert_eq!(triggered, 1);
+
+        // A later epoch, or the same epoch after the interval, triggers
+        // again.
+        assert!(debounce.try_trigger("SyncInfo", 4, now));
+        assert!(debounce.try_trigger("SyncInfo", 4, now + interval));
+        debounce.on_new_epoch();
+        assert!(debounce.try_trigger("SyncInfo", 4, now + interval));
+
+        // Never debounced if disabled.
+        let mut debounce = EpochSyncDebounce::new(Duration::from_secs(0));
+        assert!(debounce.try_trigger("SyncInfo", 3, now));
+        assert!(debounce.try_trigger("SyncInfo", 3, now));
+    }
+}
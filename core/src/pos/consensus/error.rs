@@ -5,6 +5,9 @@
 // Conflux is free software and distributed under GNU General Public License.
 // See http://www.gnu.org/licenses/
 
+use diem_types::{
+    account_address::AccountAddress, epoch_change::EpochChangeProofError,
+};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -41,6 +44,16 @@ pub struct VerifyError {
     inner: anyhow::Error,
 }
 
+/// An `EpochChangeProof` received from `peer`, either pushed or as the
+/// response to an epoch retrieval, fails the verification.
+#[derive(Debug, Error)]
+#[error("invalid EpochChangeProof from {peer}: {reason}")]
+pub struct InvalidEpochChangeProof {
+    pub peer: AccountAddress,
+    #[source]
+    pub reason: EpochChangeProofError,
+}
+
 pub fn error_kind(e: &anyhow::Error) -> &'static str {
     if e.downcast_ref::<executor_types::Error>().is_some() {
         return "Execution";
@@ -63,13 +76,21 @@ pub fn error_kind(e: &anyhow::Error) -> &'static str {
     if e.downcast_ref::<VerifyError>().is_some() {
         return "VerifyError";
     }
+    if e.downcast_ref::<InvalidEpochChangeProof>().is_some() {
+        return "InvalidEpochChangeProof";
+    }
     "InternalError"
 }
 
 #[cfg(test)]
 mod tests {
-    use super::super::error::{error_kind, StateSyncError};
+    use super::super::error::{
+        error_kind, InvalidEpochChangeProof, StateSyncError,
+    };
     use anyhow::Context;
+    use diem_types::{
+        account_address::AccountAddress, epoch_change::EpochChangeProofError,
+    };
 
     #[test]
     fn conversion_and_downcast() {
@@ -80,4 +101,26 @@ mod tests {
         let upper: anyhow::Result<()> = Err(typed_error).context("Context!");
         assert_eq!(error_kind(&upper.unwrap_err()), "Execution");
     }
+
+    #[test]
+    fn invalid_epoch_change_proof_kind() {
+        let error = InvalidEpochChangeProof {
+            peer: AccountAddress::random(),
+            reason: EpochChangeProofError::WrongEpoch {
+                expected: 1,
+                actual: 3,
+            },
+        };
+        let upper: anyhow::Result<()> = Err(error).context("Context!");
+        let upper = upper.unwrap_err();
+        assert_eq!(error_kind(&upper), "InvalidEpochChangeProof");
+        assert_eq!(
+            upper
+                .downcast_ref::<InvalidEpochChangeProof>()
+                .unwrap()
+                .reason
+                .as_str(),
+            "wrong_epoch"
+        );
+    }
 }
@@ -0,0 +1,263 @@
+// Copyright 2021 Conflux Foundation. All rights reserved.
+// Conflux is free software and distributed under GNU General Public License.
+// See http://www.gnu.org/licenses/
+
+//! A fault-injecting wrapper of `ConsensusNetworkSender` for testing the
+//! liveness of consensus under message loss, delay and duplication.
+//!
+//! The faults are drawn from a seeded rng, so a test run is reproducible with
+//! the same seed and message order.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use diem_logger::prelude::*;
+use parking_lot::Mutex;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use consensus_types::common::Author;
+
+use crate::pos::{
+    consensus::network::{ConsensusMsg, ConsensusNetworkSender},
+    protocol::{
+        error::{BroadcastOutcome, NetworkError},
+        network_sender::NetworkSender,
+    },
+};
+
+/// The faults to inject into the sent messages.
+#[derive(Clone, Copy, Debug)]
+pub struct LossyConfig {
+    /// The probability of dropping a message.
+    pub drop_rate: f64,
+    /// The probability of delaying a message that is not dropped.
+    pub delay_rate: f64,
+    /// A delayed message is delayed by a random duration up to this.
+    pub max_delay: Duration,
+    /// The probability of sending a message that is not dropped twice.
+    pub duplicate_rate: f64,
+    /// The seed of the rng drawing the faults.
+    pub seed: u64,
+}
+
+impl Default for LossyConfig {
+    /// No fault is injected by default.
+    fn default() -> Self {
+        Self {
+            drop_rate: 0.0,
+            delay_rate: 0.0,
+            max_delay: Duration::from_millis(0),
+            duplicate_rate: 0.0,
+            seed: 0,
+        }
+    }
+}
+
+/// How a message is handled by the lossy transport.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fate {
+    /// The message is dropped.
+    Drop,
+    /// The message is sent `copies` times after `delay`.
+    Deliver {
+        /// The delay before sending the message.
+        delay: Duration,
+        /// The number of times the message is sent.
+        copies: usize,
+    },
+}
+
+/// Draws the fate of each message according to `LossyConfig`.
+pub struct FaultInjector {
+    config: LossyConfig,
+    rng: StdRng,
+}
+
+impl FaultInjector {
+    /// Create an injector seeded by `config.seed`.
+    pub fn new(config: LossyConfig) -> Self {
+        Self {
+            config,
+            rng: StdRng::seed_from_u64(config.seed),
+        }
+    }
+
+    /// Draw the fate of the next message.
+    pub fn next_fate(&mut self) -> Fate {
+        if self.rng.gen_bool(self.config.drop_rate) {
+            return Fate::Drop;
+        }
+        let delay = if self.rng.gen_bool(self.config.delay_rate) {
+            self.config.max_delay.mul_f64(self.rng.gen::<f64>())
+        } else {
+            Duration::from_millis(0)
+        };
+        let copies = if self.rng.gen_bool(self.config.duplicate_rate) {
+            2
+        } else {
+            1
+        };
+        Fate::Deliver { delay, copies }
+    }
+}
+
+/// The numbers of the faults injected by a `LossyNetworkSender`.
+#[derive(Debug, Default)]
+pub struct LossyCounters {
+    /// Messages passed to the sender.
+    pub total: AtomicU64,
+    /// Messages dropped.
+    pub dropped: AtomicU64,
+    /// Messages delayed.
+    pub delayed: AtomicU64,
+    /// Messages sent twice.
+    pub duplicated: AtomicU64,
+}
+
+/// A `ConsensusNetworkSender` wrapper which drops, delays and duplicates the
+/// messages sent with `send_to` and `send_to_many`.
+#[derive(Clone)]
+pub struct LossyNetworkSender {
+    inner: ConsensusNetworkSender,
+    injector: Arc<Mutex<FaultInjector>>,
+    counters: Arc<LossyCounters>,
+}
+
+impl LossyNetworkSender {
+    /// Wrap `inner` with the faults in `config`.
+    pub fn new(inner: ConsensusNetworkSender, config: LossyConfig) -> Self {
+        Self {
+            inner,
+            injector: Arc::new(Mutex::new(FaultInjector::new(config))),
+            counters: Arc::new(LossyCounters::default()),
+        }
+    }
+
+    /// The wrapped sender, which sends without faults.
+    pub fn inner(&self) -> &ConsensusNetworkSender { &self.inner }
+
+    /// The numbers of the faults injected so far.
+    pub fn counters(&self) -> &LossyCounters { &self.counters }
+
+    /// Send `msg` to `recipient` subject to the injected faults. A delayed
+    /// message is sent by a spawned task, so later messages may overtake it.
+    pub fn send_to(
+        &self, recipient: Author, msg: ConsensusMsg,
+    ) -> Result<(), NetworkError> {
+        self.counters.total.fetch_add(1, Ordering::Relaxed);
+        let (delay, copies) = match self.injector.lock().next_fate() {
+            Fate::Drop => {
+                self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                diem_debug!(
+                    "lossy network drops {} to {}",
+                    msg.name(),
+                    recipient
+                );
+                return Ok(());
+            }
+            Fate::Deliver { delay, copies } => (delay, copies),
+        };
+        if copies > 1 {
+            self.counters.duplicated.fetch_add(1, Ordering::Relaxed);
+        }
+        let mut network_sender = self.inner.network_sender().clone();
+        if delay == Duration::from_millis(0) {
+            return Self::send_copies(
+                &mut network_sender,
+                recipient,
+                &msg,
+                copies,
+            );
+        }
+        self.counters.delayed.fetch_add(1, Ordering::Relaxed);
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            if let Err(e) =
+                Self::send_copies(&mut network_sender, recipient, &msg, copies)
+            {
+                diem_debug!("lossy network fails to send delayed msg: {:?}", e);
+            }
+        });
+        Ok(())
+    }
+
+    /// Send `msg` to each of the `recipients` subject to the injected faults,
+    /// which are drawn independently for each recipient. The dropped
+    /// messages are counted as sent, as they would be lost in the network.
+    pub fn send_to_many(
+        &self, recipients: impl Iterator<Item = Author>, msg: ConsensusMsg,
+    ) -> BroadcastOutcome {
+        let mut outcome = BroadcastOutcome::default();
+        for recipient in recipients {
+            match self.send_to(recipient, msg.clone()) {
+                Ok(()) => outcome.sent += 1,
+                Err(e) => outcome.failed.push((recipient, e)),
+            }
+        }
+        outcome
+    }
+
+    fn send_copies(
+        network_sender: &mut NetworkSender, recipient: Author,
+        msg: &ConsensusMsg, copies: usize,
+    ) -> Result<(), NetworkError>
+    {
+        for _ in 0..copies {
+            network_sender.send_to(recipient, msg)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Fate, FaultInjector, LossyConfig};
+    use std::time::Duration;
+
+    #[test]
+    fn test_no_fault_by_default() {
+        let mut injector = FaultInjector::new(LossyConfig::default());
+        for _ in 0..100 {
+            assert_eq!(
+                injector.next_fate(),
+                Fate::Deliver {
+                    delay: Duration::from_millis(0),
+                    copies: 1
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn test_fault_injection() {
+        let config = LossyConfig {
+            drop_rate: 0.3,
+            delay_rate: 0.5,
+            max_delay: Duration::from_millis(100),
+            duplicate_rate: 0.2,
+            seed: 7,
+        };
+        let fates: Vec<_> = {
+            let mut injector = FaultInjector::new(config);
+            (0..1000).map(|_| injector.next_fate()).collect()
+        };
+        let dropped = fates.iter().filter(|f| **f == Fate::Drop).count();
+        assert!(dropped > 200 && dropped < 400);
+        for fate in &fates {
+            if let Fate::Deliver { delay, copies } = fate {
+                assert!(*delay <= config.max_delay);
+                assert!(*copies == 1 || *copies == 2);
+            }
+        }
+
+        // The faults are reproducible with the same seed.
+        let mut injector = FaultInjector::new(config);
+        let replay: Vec<_> = (0..1000).map(|_| injector.next_fate()).collect();
+        assert_eq!(fates, replay);
+    }
+}
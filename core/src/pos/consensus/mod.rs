@@ -17,12 +17,19 @@
 
 mod block_storage;
 mod consensusdb;
-mod counters;
+pub(crate) mod counters;
 mod epoch_manager;
+mod epoch_proof_cache;
+pub(crate) mod epoch_retrieval_failover;
+mod epoch_retrieval_limiter;
+mod epoch_sync_debounce;
 mod error;
 mod liveness;
 mod logging;
+#[cfg(any(test, feature = "testonly_code"))]
+pub(crate) mod lossy_network;
 mod metrics_safety_rules;
+pub(crate) mod msg_observer;
 pub(crate) mod network;
 #[cfg(test)]
 mod network_tests;
@@ -37,11 +44,17 @@ mod test_utils;
 mod twins;
 mod txn_manager;
 mod util;
+pub(crate) mod vote_rebroadcast;
+pub(crate) mod vote_record;
 
 /// DiemBFT implementation
 pub mod consensus_provider;
 
-pub use self::network::NetworkTask;
+pub use self::{
+    epoch_retrieval_failover::EpochRetrievalFailoverConfig,
+    network::{BackpressureConfig, ConsensusQueueConfig, NetworkTask},
+    vote_rebroadcast::VoteRebroadcastConfig,
+};
 pub use consensusdb::ConsensusDB;
 #[cfg(feature = "fuzzing")]
 pub use round_manager::round_manager_fuzzing;
@@ -0,0 +1,158 @@
+// Copyright 2021 Conflux Foundation. All rights reserved.
+// Conflux is free software and distributed under GNU General Public License.
+// See http://www.gnu.org/licenses/
+
+//! Observation of the outgoing `ConsensusMsg`s, e.g. to mirror them to an
+//! audit sink.
+//!
+//! An observer is called on the send path before each message is handed to
+//! the network, so it must return quickly. `ChannelMsgObserver` only queues
+//! a copy of the message for another task, and drops it if the queue is
+//! full.
+
+use network::node_table::NodeId;
+use tokio::sync::mpsc;
+
+use super::{counters, network::ConsensusMsg};
+
+/// Observes the `ConsensusMsg`s sent to peers.
+pub trait ConsensusMsgObserver: Send + Sync {
+    /// Called once for each peer `msg` is sent to, before it is sent.
+    fn on_send(&self, recipient: &NodeId, msg: &ConsensusMsg);
+}
+
+/// Observes nothing.
+pub struct NoopConsensusMsgObserver;
+
+impl ConsensusMsgObserver for NoopConsensusMsgObserver {
+    fn on_send(&self, _recipient: &NodeId, _msg: &ConsensusMsg) {}
+}
+
+/// Queues the observed messages with their recipients in a bounded channel.
+pub struct ChannelMsgObserver {
+    tx: mpsc::Sender<(NodeId, ConsensusMsg)>,
+}
+
+impl ChannelMsgObserver {
+    /// The observer and the receiver of the observed messages. At most
+    /// `capacity` messages are queued.
+    pub fn new(
+        capacity: usize,
+    ) -> (Self, mpsc::Receiver<(NodeId, ConsensusMsg)>) {
+        let (tx, rx) = mpsc::channel(capacity);
+        (Self { tx }, rx)
+    }
+}
+
+impl ConsensusMsgObserver for ChannelMsgObserver {
+    fn on_send(&self, recipient: &NodeId, msg: &ConsensusMsg) {
+        // Never wait for the receiver, so the sending is not slowed down.
+        if self.tx.try_send((*recipient, msg.clone())).is_err() {
+            counters::CONSENSUS_MSG_OBSERVER_DROPPED.inc();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChannelMsgObserver, ConsensusMsgObserver};
+    use crate::{
+        pos::{
+            consensus::{
+                counters,
+                network::{
+                    ConsensusMsg, ConsensusNetworkSender,
+                    NetworkTask as ConsensusNetworkTask,
+                },
+            },
+            mempool::network::NetworkTask as MempoolNetworkTask,
+            protocol::{
+                network_sender::NetworkSender,
+                sync_protocol::HotStuffSynchronizationProtocol,
+            },
+        },
+        sync::ProtocolConfiguration,
+    };
+    use cfx_types::H256;
+    use consensus_types::epoch_retrieval::EpochRetrievalRequest;
+    use diem_types::{
+        account_address::AccountAddress, validator_verifier::ValidatorVerifier,
+    };
+    use network::{
+        node_table::NodeId, DiscoveryConfiguration, NetworkConfiguration,
+        NetworkService,
+    };
+    use std::{collections::BTreeMap, sync::Arc};
+
+    fn msg(start_epoch: u64) -> ConsensusMsg {
+        ConsensusMsg::EpochRetrievalRequest(Box::new(EpochRetrievalRequest {
+            start_epoch,
+            end_epoch: start_epoch + 1,
+        }))
+    }
+
+    fn start_epoch(msg: &ConsensusMsg) -> u64 {
+        match msg {
+            ConsensusMsg::EpochRetrievalRequest(request) => request.start_epoch,
+            _ => panic!("unexpected message"),
+        }
+    }
+
+    #[test]
+    fn test_channel_observer_never_blocks() {
+        let (observer, mut rx) = ChannelMsgObserver::new(2);
+        let peer = NodeId::from_low_u64_be(1);
+        let dropped = counters::CONSENSUS_MSG_OBSERVER_DROPPED.get();
+        for start_epoch in 0..3 {
+            observer.on_send(&peer, &msg(start_epoch));
+        }
+        // The message beyond the capacity is dropped.
+        assert!(counters::CONSENSUS_MSG_OBSERVER_DROPPED.get() > dropped);
+        let mut observed = vec![];
+        while let Ok((recipient, msg)) = rx.try_recv() {
+            assert_eq!(recipient, peer);
+            observed.push(start_epoch(&msg));
+        }
+        assert_eq!(observed, vec![0, 1]);
+    }
+
+    #[test]
+    fn test_sent_msgs_observed() {
+        // The network service is not started, so the messages are observed
+        // but fail to be sent.
+        let protocol_handler = Arc::new(HotStuffSynchronizationProtocol::new(
+            H256::zero(),
+            ConsensusNetworkTask::new().0,
+            MempoolNetworkTask::new().0,
+            ProtocolConfiguration::default(),
+        ));
+        let peer = AccountAddress::random();
+        let peer_id = NodeId::from_low_u64_be(1);
+        protocol_handler
+            .pos_node_id_cache
+            .write()
+            .insert(peer, peer_id);
+        let network_sender = NetworkSender {
+            network: Arc::new(NetworkService::new(NetworkConfiguration::new(
+                1,
+                DiscoveryConfiguration::default(),
+            ))),
+            protocol_handler,
+        };
+        let (observer, mut rx) = ChannelMsgObserver::new(16);
+        let sender = ConsensusNetworkSender::new(
+            AccountAddress::random(),
+            network_sender,
+            ValidatorVerifier::new(BTreeMap::new()),
+        )
+        .with_observer(Arc::new(observer));
+
+        assert!(sender.send_to(peer, &msg(7)).is_err());
+        // An unknown recipient is not observed.
+        assert!(sender.send_to(AccountAddress::random(), &msg(8)).is_err());
+        let (recipient, observed) = rx.try_recv().unwrap();
+        assert_eq!(recipient, peer_id);
+        assert_eq!(start_epoch(&observed), 7);
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -5,15 +5,37 @@
 // Conflux is free software and distributed under GNU General Public License.
 // See http://www.gnu.org/licenses/
 
-use std::{mem::Discriminant, time::Duration};
+use std::{
+    future::Future,
+    mem::{discriminant, Discriminant},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context as TaskContext, Poll},
+    time::Duration,
+};
 
-use anyhow::{anyhow, bail, ensure, format_err};
+use anyhow::{ensure, format_err};
+use futures::stream::{self, FusedStream, Stream, StreamExt};
+use parking_lot::Mutex;
+use rand::{rngs::StdRng, seq::SliceRandom, thread_rng, SeedableRng};
 use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
 
-use channel::{self, diem_channel, message_queues::QueueStyle};
+use channel::{
+    self,
+    diem_channel::{self, TryPushError},
+    message_queues::QueueStyle,
+};
 use consensus_types::{
-    block_retrieval::{BlockRetrievalRequest, BlockRetrievalResponse},
-    common::Author,
+    block::Block,
+    block_retrieval::{
+        BlockRetrievalRequest, BlockRetrievalResponse, BlockRetrievalStatus,
+    },
+    commit_vote_msg::CommitVoteMsg,
+    common::{Author, Round},
     epoch_retrieval::EpochRetrievalRequest,
     proposal_msg::ProposalMsg,
     sync_info::SyncInfo,
@@ -30,15 +52,23 @@ use network::node_table::NodeId;
 use crate::{
     message::RequestId,
     pos::protocol::{
+        error::{BroadcastOutcome, NetworkError},
+        log_context::SendLogContext,
         message::{
             block_retrieval::BlockRetrievalRpcRequest,
             block_retrieval_response::BlockRetrievalRpcResponse,
         },
-        network_sender::NetworkSender,
+        network_sender::{dedup_node_ids, NetworkSender},
+        HSB_PROTOCOL_V13,
     },
 };
 
-use super::counters;
+use super::{
+    counters,
+    msg_observer::ConsensusMsgObserver,
+    vote_rebroadcast::VoteRebroadcaster,
+    vote_record::{LastVoteRecord, VoteRecorder},
+};
 
 /// Network type for consensus
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -63,6 +93,508 @@ pub enum ConsensusMsg {
     /// VoteMsg is the struct that is ultimately sent by the voter in response
     /// for receiving a proposal.
     VoteMsg(Box<VoteMsg>),
+    /// CommitVoteMsg is sent by the validators in the commit phase. It is
+    /// kept apart from VoteMsg so it is queued under its own discriminant.
+    CommitVote(Box<CommitVoteMsg>),
+}
+
+impl ConsensusMsg {
+    /// The name of the variant, which is used as the message name in logs
+    /// and metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ConsensusMsg::BlockRetrievalRequest(_) => "BlockRetrievalRequest",
+            ConsensusMsg::BlockRetrievalResponse(_) => "BlockRetrievalResponse",
+            ConsensusMsg::EpochRetrievalRequest(_) => "EpochRetrievalRequest",
+            ConsensusMsg::ProposalMsg(_) => "ProposalMsg",
+            ConsensusMsg::SyncInfo(_) => "SyncInfo",
+            ConsensusMsg::EpochChangeProof(_) => "EpochChangeProof",
+            ConsensusMsg::VoteMsg(_) => "VoteMsg",
+            ConsensusMsg::CommitVote(_) => "CommitVote",
+        }
+    }
+
+    /// The epoch and the round the message is about, for the logs. The
+    /// retrievals have neither, and an epoch change proof has no round.
+    pub fn epoch_round(&self) -> (Option<u64>, Option<u64>) {
+        match self {
+            ConsensusMsg::BlockRetrievalRequest(_)
+            | ConsensusMsg::BlockRetrievalResponse(_)
+            | ConsensusMsg::EpochRetrievalRequest(_) => (None, None),
+            ConsensusMsg::ProposalMsg(proposal) => {
+                (Some(proposal.epoch()), Some(proposal.proposal().round()))
+            }
+            ConsensusMsg::SyncInfo(sync_info) => {
+                (Some(sync_info.epoch()), Some(sync_info.highest_round()))
+            }
+            ConsensusMsg::EpochChangeProof(proof) => (proof.epoch().ok(), None),
+            ConsensusMsg::VoteMsg(vote_msg) => (
+                Some(vote_msg.vote().epoch()),
+                Some(vote_msg.vote().vote_data().proposed().round()),
+            ),
+            ConsensusMsg::CommitVote(commit_vote) => {
+                (Some(commit_vote.epoch()), Some(commit_vote.round()))
+            }
+        }
+    }
+
+    /// The priority class of the message. Consensus-critical messages are
+    /// delivered before sync and retrieval messages.
+    pub fn priority(&self) -> MessagePriority {
+        match self {
+            ConsensusMsg::ProposalMsg(_)
+            | ConsensusMsg::VoteMsg(_)
+            | ConsensusMsg::CommitVote(_)
+            | ConsensusMsg::SyncInfo(_) => MessagePriority::High,
+            ConsensusMsg::BlockRetrievalRequest(_)
+            | ConsensusMsg::BlockRetrievalResponse(_)
+            | ConsensusMsg::EpochRetrievalRequest(_)
+            | ConsensusMsg::EpochChangeProof(_) => MessagePriority::Low,
+        }
+    }
+
+    /// Estimate the length of the BCS encoded message without encoding it.
+    ///
+    /// The fixed-size parts are counted exactly, the certificates by their
+    /// numbers of signatures and validators, and the transactions of the
+    /// blocks by an average size, so the estimate is only as accurate as
+    /// the average transaction size.
+    pub fn estimated_encoded_len(&self) -> usize {
+        // The variant tag.
+        1 + match self {
+            ConsensusMsg::BlockRetrievalRequest(_) => {
+                estimate::BLOCK_RETRIEVAL_REQUEST_LEN
+            }
+            ConsensusMsg::BlockRetrievalResponse(response) => {
+                // The status, and the cursor with its option tag.
+                1 + estimate::vec_len(response.blocks(), estimate::block_len)
+                    + 1
+                    + response.next_cursor().map_or(0, |_| estimate::HASH_LEN)
+            }
+            ConsensusMsg::EpochRetrievalRequest(_) => 16,
+            ConsensusMsg::ProposalMsg(proposal) => {
+                estimate::block_len(proposal.proposal())
+                    + estimate::sync_info_len(proposal.sync_info())
+            }
+            ConsensusMsg::SyncInfo(sync_info) => {
+                estimate::sync_info_len(sync_info)
+            }
+            ConsensusMsg::EpochChangeProof(proof) => {
+                estimate::vec_len(
+                    &proof.ledger_info_with_sigs,
+                    estimate::ledger_info_with_sigs_len,
+                ) + 1
+            }
+            ConsensusMsg::VoteMsg(vote_msg) => {
+                estimate::vote_len(vote_msg.vote())
+                    + estimate::sync_info_len(vote_msg.sync_info())
+            }
+            ConsensusMsg::CommitVote(commit_vote) => {
+                estimate::ADDRESS_LEN
+                    + estimate::ledger_info_len(commit_vote.ledger_info())
+                    + estimate::SIGNATURE_LEN
+            }
+        }
+    }
+}
+
+/// The BCS encoded lengths of the parts of the consensus messages, used by
+/// `ConsensusMsg::estimated_encoded_len`.
+mod estimate {
+    use consensus_types::{
+        block::Block, quorum_cert::QuorumCert, sync_info::SyncInfo,
+        timeout_certificate::TimeoutCertificate, vote::Vote,
+    };
+    use diem_types::{
+        block_info::BlockInfo,
+        ledger_info::{LedgerInfo, LedgerInfoWithSignatures},
+    };
+
+    /// A `HashValue` is encoded as bytes with a length prefix.
+    pub const HASH_LEN: usize = 1 + 32;
+    pub const ADDRESS_LEN: usize = 32;
+    /// A BLS signature with its length prefix.
+    pub const SIGNATURE_LEN: usize = 1 + 96;
+    /// A VRF proof with its length prefix.
+    const VRF_PROOF_LEN: usize = 1 + 81;
+    /// The address, BLS public key, optional VRF public key and voting power
+    /// of a validator.
+    const VALIDATOR_INFO_LEN: usize = ADDRESS_LEN + (1 + 48) + (2 + 33) + 8;
+    /// The average length of the transactions in the block payloads.
+    const TRANSACTION_LEN: usize = 256;
+    /// The block id, number of blocks and byte limit.
+    pub const BLOCK_RETRIEVAL_REQUEST_LEN: usize = HASH_LEN + 8 + 8;
+
+    /// The length prefix of a sequence is a ULEB128 integer.
+    fn length_prefix_len(len: usize) -> usize {
+        let mut prefix_len = 1;
+        let mut len = len >> 7;
+        while len > 0 {
+            prefix_len += 1;
+            len >>= 7;
+        }
+        prefix_len
+    }
+
+    pub fn vec_len<T>(items: &[T], item_len: fn(&T) -> usize) -> usize {
+        length_prefix_len(items.len())
+            + items.iter().map(item_len).sum::<usize>()
+    }
+
+    fn signatures_len(num_signatures: usize) -> usize {
+        length_prefix_len(num_signatures)
+            + num_signatures * (ADDRESS_LEN + SIGNATURE_LEN)
+    }
+
+    fn block_info_len(info: &BlockInfo) -> usize {
+        // The epoch, round, id, executed state id, version, timestamp, and
+        // the option tags of the next epoch state and the pivot decision.
+        let base = 8 + 8 + HASH_LEN + HASH_LEN + 8 + 8 + 1 + 1;
+        let epoch_state = info.next_epoch_state().map_or(0, |state| {
+            // The epoch, validators, quorum and total voting power, and the
+            // VRF seed.
+            let num_validators = state.verifier().len();
+            8 + length_prefix_len(num_validators)
+                + num_validators * VALIDATOR_INFO_LEN
+                + 8
+                + 8
+                + length_prefix_len(state.vrf_seed.len())
+                + state.vrf_seed.len()
+        });
+        // The height and block hash of the pivot decision.
+        let pivot = info.pivot_decision().map_or(0, |_| 8 + 32);
+        base + epoch_state + pivot
+    }
+
+    pub fn ledger_info_len(ledger_info: &LedgerInfo) -> usize {
+        block_info_len(ledger_info.commit_info()) + HASH_LEN
+    }
+
+    pub fn ledger_info_with_sigs_len(
+        ledger_info: &LedgerInfoWithSignatures,
+    ) -> usize {
+        // The version tag.
+        1 + ledger_info_len(ledger_info.ledger_info())
+            + signatures_len(ledger_info.signatures().len())
+    }
+
+    fn quorum_cert_len(qc: &QuorumCert) -> usize {
+        block_info_len(qc.certified_block())
+            + block_info_len(qc.parent_block())
+            + ledger_info_with_sigs_len(qc.ledger_info())
+    }
+
+    fn timeout_cert_len(tc: &TimeoutCertificate) -> usize {
+        // The epoch and round of the timeout.
+        8 + 8 + signatures_len(tc.signatures().len())
+    }
+
+    pub fn sync_info_len(sync_info: &SyncInfo) -> usize {
+        let highest_quorum_cert = sync_info.highest_quorum_cert();
+        // The commit cert is only encoded if it differs from the highest
+        // quorum cert, which is returned in its place otherwise.
+        let commit_cert = sync_info.highest_commit_cert();
+        let commit_cert_len = if std::ptr::eq(commit_cert, highest_quorum_cert)
+        {
+            0
+        } else {
+            quorum_cert_len(commit_cert)
+        };
+        quorum_cert_len(highest_quorum_cert)
+            + 1
+            + commit_cert_len
+            + 1
+            + sync_info
+                .highest_timeout_certificate()
+                .map_or(0, timeout_cert_len)
+    }
+
+    pub fn block_len(block: &Block) -> usize {
+        // The epoch, round, timestamp and the block type tag.
+        let block_data = 8 + 8 + 8 + quorum_cert_len(block.quorum_cert()) + 1;
+        let proposal = match (block.payload(), block.author()) {
+            (Some(payload), Some(_)) => {
+                length_prefix_len(payload.len())
+                    + payload.len() * TRANSACTION_LEN
+                    + ADDRESS_LEN
+            }
+            _ => 0,
+        };
+        // The option tags of the signature and the VRF nonce and proof.
+        block_data
+            + proposal
+            + 1
+            + block.signature().map_or(0, |_| SIGNATURE_LEN)
+            + 1
+            + block.vrf_proof().map_or(0, |_| 8 + VRF_PROOF_LEN)
+    }
+
+    pub fn vote_len(vote: &Vote) -> usize {
+        block_info_len(vote.vote_data().proposed())
+            + block_info_len(vote.vote_data().parent())
+            + ADDRESS_LEN
+            + ledger_info_len(vote.ledger_info())
+            + SIGNATURE_LEN
+            + 1
+            + vote.timeout_signature().map_or(0, |_| SIGNATURE_LEN)
+    }
+}
+
+/// The priority class of a consensus message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessagePriority {
+    /// Messages that drive the rounds, e.g. proposals and votes.
+    High,
+    /// Messages for synchronization and retrieval.
+    Low,
+}
+
+/// The key a message is queued by, see `peer_msg_key` and `self_msg_key`.
+type ConsensusMsgKey = (
+    AccountAddress,
+    Discriminant<ConsensusMsg>,
+    (Option<u64>, Option<Round>),
+);
+type ConsensusMsgItem = (AccountAddress, ConsensusMsg);
+
+/// The key of `msg` from the peer `author` in the consensus message queue.
+/// The messages of each kind from a peer share one key, so a peer cannot
+/// take more than its share of the queue.
+pub fn peer_msg_key(
+    author: AccountAddress, msg: &ConsensusMsg,
+) -> ConsensusMsgKey {
+    (author, discriminant(msg), (None, None))
+}
+
+/// The key of `msg` sent to self in the consensus message queue. The epoch
+/// and the round of the message are a part of the key, so the messages to
+/// self of different epochs or rounds, e.g. the ones buffered across an
+/// epoch change, do not replace each other.
+pub fn self_msg_key(
+    author: AccountAddress, msg: &ConsensusMsg,
+) -> ConsensusMsgKey {
+    (author, discriminant(msg), msg.epoch_round())
+}
+
+/// The water marks of the consensus message queue for backpressure.
+///
+/// Once the queue holds `high_water_mark` messages, the messages of low
+/// priority are no longer accepted until the queue is drained to
+/// `low_water_mark`, so a slow consensus is not flooded with sync and
+/// retrieval messages while it still receives votes and proposals.
+#[derive(Clone, Copy, Debug)]
+pub struct BackpressureConfig {
+    pub high_water_mark: usize,
+    pub low_water_mark: usize,
+}
+
+/// The occupancy of the consensus message queue, shared by both of its ends.
+struct QueueBackpressure {
+    config: Option<BackpressureConfig>,
+    engaged: AtomicBool,
+    occupancy_tx: watch::Sender<usize>,
+}
+
+impl QueueBackpressure {
+    fn new(config: Option<BackpressureConfig>) -> Self {
+        Self {
+            config,
+            engaged: AtomicBool::new(false),
+            occupancy_tx: watch::channel(0).0,
+        }
+    }
+
+    fn is_engaged(&self) -> bool { self.engaged.load(Ordering::Relaxed) }
+
+    /// Publish the occupancy, and engage or release the backpressure by the
+    /// water marks.
+    fn update(&self, occupancy: usize) {
+        self.occupancy_tx.send_replace(occupancy);
+        let config = match &self.config {
+            Some(config) => config,
+            None => return,
+        };
+        if occupancy >= config.high_water_mark {
+            if !self.engaged.swap(true, Ordering::Relaxed) {
+                counters::CONSENSUS_QUEUE_BACKPRESSURE_ENGAGED.inc();
+                diem_info!(
+                    "consensus queue backpressure engaged: occupancy={}",
+                    occupancy
+                );
+            }
+        } else if occupancy <= config.low_water_mark
+            && self.engaged.swap(false, Ordering::Relaxed)
+        {
+            diem_info!(
+                "consensus queue backpressure released: occupancy={}",
+                occupancy
+            );
+        }
+    }
+}
+
+/// The sending end of the consensus message queue. Messages of each
+/// priority class are queued in a separate channel.
+#[derive(Clone)]
+pub struct ConsensusMessageSender {
+    high: diem_channel::Sender<ConsensusMsgKey, ConsensusMsgItem>,
+    low: diem_channel::Sender<ConsensusMsgKey, ConsensusMsgItem>,
+    backpressure: Arc<QueueBackpressure>,
+}
+
+impl ConsensusMessageSender {
+    fn channel(
+        &self, msg: &ConsensusMsg,
+    ) -> &diem_channel::Sender<ConsensusMsgKey, ConsensusMsgItem> {
+        match msg.priority() {
+            MessagePriority::High => &self.high,
+            MessagePriority::Low => &self.low,
+        }
+    }
+
+    /// Queue the message in the channel of its priority, see
+    /// `diem_channel::Sender::push`. A message of low priority is dropped
+    /// under backpressure.
+    pub fn push(
+        &self, key: ConsensusMsgKey, item: ConsensusMsgItem,
+    ) -> anyhow::Result<()> {
+        if self.is_paused(&item.1) {
+            return Ok(());
+        }
+        self.channel(&item.1).push(key, item)?;
+        self.backpressure.update(self.len());
+        Ok(())
+    }
+
+    /// Queue the message in the channel of its priority, see
+    /// `diem_channel::Sender::try_push`. A message of low priority is
+    /// rejected as `TryPushError::Full` under backpressure.
+    pub fn try_push(
+        &self, key: ConsensusMsgKey, item: ConsensusMsgItem,
+    ) -> Result<(), TryPushError<ConsensusMsgItem>> {
+        if self.is_paused(&item.1) {
+            return Err(TryPushError::Full {
+                message: item,
+                depth: self.len(),
+            });
+        }
+        self.channel(&item.1).try_push(key, item)?;
+        self.backpressure.update(self.len());
+        Ok(())
+    }
+
+    /// Queue the messages in order like `try_push`, locking the channel once
+    /// for each run of consecutive messages of the same priority. Stops at
+    /// the first message that is rejected, and returns it in the error
+    /// together with the number of the messages queued before it.
+    pub fn try_push_all(
+        &self, messages: Vec<(ConsensusMsgKey, ConsensusMsgItem)>,
+    ) -> Result<usize, (usize, TryPushError<ConsensusMsgItem>)> {
+        let mut pushed = 0;
+        let mut messages = messages.into_iter().peekable();
+        let mut result = Ok(());
+        while let Some((key, item)) = messages.next() {
+            if self.is_paused(&item.1) {
+                result = Err(TryPushError::Full {
+                    message: item,
+                    depth: self.len(),
+                });
+                break;
+            }
+            let priority = item.1.priority();
+            let mut run = vec![(key, item)];
+            while let Some((key, item)) =
+                messages.next_if(|(_, item)| item.1.priority() == priority)
+            {
+                run.push((key, item));
+            }
+            // The backpressure is only checked at the start of each run.
+            match self.channel(&run[0].1 .1).try_push_all(run) {
+                Ok(n) => pushed += n,
+                Err((n, e)) => {
+                    pushed += n;
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        self.backpressure.update(self.len());
+        result.map(|()| pushed).map_err(|e| (pushed, e))
+    }
+
+    /// The number of messages in the queue.
+    pub fn len(&self) -> usize { self.high.len() + self.low.len() }
+
+    /// Returns whether the queue has no message.
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    /// Returns whether the messages of low priority are not accepted now
+    /// because the queue is over its high water mark.
+    pub fn is_backpressured(&self) -> bool { self.backpressure.is_engaged() }
+
+    /// Watch the number of messages in the queue, which is updated whenever
+    /// a message is queued or received.
+    pub fn subscribe_occupancy(&self) -> watch::Receiver<usize> {
+        self.backpressure.occupancy_tx.subscribe()
+    }
+
+    fn is_paused(&self, msg: &ConsensusMsg) -> bool {
+        if msg.priority() == MessagePriority::Low
+            && self.backpressure.is_engaged()
+        {
+            counters::NETWORK_MSGS_BACKPRESSURED
+                .with_label_values(&[msg.name()])
+                .inc();
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The receiving end of the consensus message queue. A queued message of
+/// high priority is always received before the ones of low priority.
+pub struct ConsensusMessageReceiver {
+    high: diem_channel::Receiver<ConsensusMsgKey, ConsensusMsgItem>,
+    low: diem_channel::Receiver<ConsensusMsgKey, ConsensusMsgItem>,
+    backpressure: Arc<QueueBackpressure>,
+}
+
+impl ConsensusMessageReceiver {
+    fn poll_channels(
+        &mut self, cx: &mut TaskContext<'_>,
+    ) -> Poll<Option<ConsensusMsgItem>> {
+        let high = self.high.poll_next_unpin(cx);
+        if let Poll::Ready(Some(item)) = high {
+            return Poll::Ready(Some(item));
+        }
+        match self.low.poll_next_unpin(cx) {
+            Poll::Ready(Some(item)) => Poll::Ready(Some(item)),
+            // Terminate after both channels are terminated.
+            Poll::Ready(None) if high.is_ready() => Poll::Ready(None),
+            _ => Poll::Pending,
+        }
+    }
+}
+
+impl Stream for ConsensusMessageReceiver {
+    type Item = ConsensusMsgItem;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let next = self.poll_channels(cx);
+        if let Poll::Ready(Some(_)) = &next {
+            self.backpressure.update(self.high.len() + self.low.len());
+        }
+        next
+    }
+}
+
+impl FusedStream for ConsensusMessageReceiver {
+    fn is_terminated(&self) -> bool {
+        self.high.is_terminated() && self.low.is_terminated()
+    }
 }
 
 /// The block retrieval request is used internally for implementing RPC: the
@@ -77,21 +609,75 @@ pub struct IncomingBlockRetrievalRequest {
 /// Just a convenience struct to keep all the network proxy receiving queues in
 /// one place. Will be returned by the NetworkTask upon startup.
 pub struct NetworkReceivers {
-    /// Provide a LIFO buffer for each (Author, MessageType) key
-    pub consensus_messages: diem_channel::Receiver<
-        (AccountAddress, Discriminant<ConsensusMsg>),
-        (AccountAddress, ConsensusMsg),
-    >,
+    /// Provide a buffer for each (Author, MessageType) key, see
+    /// `ConsensusQueueConfig` and `MessagePriority`
+    pub consensus_messages: ConsensusMessageReceiver,
     pub block_retrieval:
         diem_channel::Receiver<AccountAddress, IncomingBlockRetrievalRequest>,
 }
 
+/// The sending interface of consensus, so the consensus logic can be tested
+/// with `MockConsensusNetwork` instead of a live network service.
+#[async_trait::async_trait]
+pub trait ConsensusNetwork: Send + Sync {
+    /// Send `msg` to `recipient`.
+    fn send_to(
+        &self, recipient: Author, msg: &ConsensusMsg,
+    ) -> Result<(), NetworkError>;
+
+    /// Send `msg` to each of the `recipients`. All of them are tried even if
+    /// some fail, and the outcome of each is returned.
+    fn send_to_many(
+        &self, recipients: Vec<Author>, msg: &ConsensusMsg,
+    ) -> BroadcastOutcome;
+
+    /// Retrieve the blocks of `request` from `from` with a block retrieval
+    /// RPC, and verify the response.
+    async fn send_rpc(
+        &self, request: BlockRetrievalRequest, from: Author, timeout: Duration,
+    ) -> anyhow::Result<BlockRetrievalResponse>;
+
+    /// Deliver `msg` to the consensus of this node.
+    async fn send_self_msg(
+        &self, msg: ConsensusMsg,
+    ) -> Result<(), NetworkError>;
+
+    /// Send `msg` to self and all the connected peers except `exclude`.
+    async fn broadcast(
+        &mut self, msg: ConsensusMsg, exclude: Vec<AccountAddress>,
+    ) -> BroadcastOutcome;
+}
+
 /// Implements the actual networking support for all consensus messaging.
 #[derive(Clone)]
 pub struct ConsensusNetworkSender {
     pub author: Author,
     network_sender: NetworkSender,
     validators: ValidatorVerifier,
+    /// Encode the messages to peers and resolve their recipients without
+    /// sending them, e.g. for a shadow validator.
+    validate_only: bool,
+    /// Called with the messages to peers before they are sent.
+    observer: Option<Arc<dyn ConsensusMsgObserver>>,
+    /// Records the votes before they are sent to peers.
+    vote_recorder: Option<Arc<VoteRecorder>>,
+    /// Draws the peers of `broadcast_sample`.
+    sample_rng: Arc<Mutex<StdRng>>,
+    /// Sends the votes again until their rounds are superseded.
+    vote_rebroadcaster: Arc<VoteRebroadcaster>,
+    /// The messages to peers recorded instead of sent in the capture mode,
+    /// see `with_capture`.
+    #[cfg(any(test, feature = "testonly_code"))]
+    captured: Option<Arc<Mutex<Vec<(NodeId, ConsensusMsg)>>>>,
+    /// Also sends each message to peers, see `with_mirror`.
+    mirror: Option<Arc<ConsensusMirror>>,
+}
+
+/// The passive node on a secondary network that the messages to peers are
+/// copied to.
+struct ConsensusMirror {
+    sender: ConsensusNetworkSender,
+    peer: Author,
 }
 
 impl ConsensusNetworkSender {
@@ -100,15 +686,460 @@ impl ConsensusNetworkSender {
         validators: ValidatorVerifier,
     ) -> Self
     {
+        let vote_rebroadcaster = Arc::new(VoteRebroadcaster::new(
+            network_sender
+                .protocol_handler
+                .protocol_config
+                .pos_vote_rebroadcast,
+        ));
         ConsensusNetworkSender {
             author,
             network_sender,
             validators,
+            validate_only: false,
+            observer: None,
+            vote_recorder: None,
+            sample_rng: Arc::new(Mutex::new(StdRng::from_entropy())),
+            vote_rebroadcaster,
+            #[cfg(any(test, feature = "testonly_code"))]
+            captured: None,
+            mirror: None,
+        }
+    }
+
+    /// Also send a copy of each message to peers to `peer` with `sender`,
+    /// e.g. to keep a hot standby validator on a secondary network warm.
+    /// The copies are best-effort: failing to send one is only counted, and
+    /// never fails the send to the peers. The messages to self and the ones
+    /// only validated are not copied.
+    pub fn with_mirror(
+        mut self, sender: ConsensusNetworkSender, peer: Author,
+    ) -> Self {
+        self.mirror = Some(Arc::new(ConsensusMirror { sender, peer }));
+        self
+    }
+
+    /// Send a copy of `msg` to the mirror with `send`, if there is one.
+    fn mirror(
+        &self, msg: &ConsensusMsg,
+        send: impl FnOnce(
+            &ConsensusNetworkSender,
+            Author,
+        ) -> Result<(), NetworkError>,
+    )
+    {
+        let mirror = match &self.mirror {
+            Some(mirror) => mirror,
+            None => return,
+        };
+        match send(&mirror.sender, mirror.peer) {
+            Ok(()) => counters::CONSENSUS_MSGS_MIRRORED
+                .with_label_values(&[msg.name()])
+                .inc(),
+            Err(e) => {
+                diem_debug!(
+                    "Failed to mirror a message, {}: {:?}",
+                    SendLogContext::of(msg),
+                    e
+                );
+                counters::CONSENSUS_MIRROR_FAILURES
+                    .with_label_values(&[msg.name(), e.failure_reason()])
+                    .inc();
+            }
+        }
+    }
+
+    /// Record the messages to peers instead of sending them, for the tests
+    /// to take with `drain_pending_outgoing`. The clones of the sender share
+    /// the recorded messages. The messages to self are still delivered.
+    #[cfg(any(test, feature = "testonly_code"))]
+    pub fn with_capture(mut self) -> Self {
+        self.captured = Some(Default::default());
+        self
+    }
+
+    /// Take the messages recorded in the capture mode, in the order they
+    /// are sent. Empty if the sender is not in the capture mode.
+    #[cfg(any(test, feature = "testonly_code"))]
+    pub fn drain_pending_outgoing(&self) -> Vec<(NodeId, ConsensusMsg)> {
+        match &self.captured {
+            Some(captured) => std::mem::take(&mut *captured.lock()),
+            None => Vec::new(),
         }
     }
 
+    #[cfg(any(test, feature = "testonly_code"))]
+    fn is_capturing(&self) -> bool { self.captured.is_some() }
+
+    #[cfg(not(any(test, feature = "testonly_code")))]
+    fn is_capturing(&self) -> bool { false }
+
+    /// Record `msg` to `peer_ids` in the capture mode.
+    #[cfg(any(test, feature = "testonly_code"))]
+    fn capture(&self, peer_ids: &[NodeId], msg: &ConsensusMsg) {
+        if let Some(captured) = &self.captured {
+            captured
+                .lock()
+                .extend(peer_ids.iter().map(|peer_id| (*peer_id, msg.clone())));
+        }
+    }
+
+    #[cfg(not(any(test, feature = "testonly_code")))]
+    fn capture(&self, _peer_ids: &[NodeId], _msg: &ConsensusMsg) {}
+
+    /// Draw the peers of `broadcast_sample` from an rng seeded by `seed`, so
+    /// the same peers are drawn in each run.
+    pub fn with_sample_seed(mut self, seed: u64) -> Self {
+        self.sample_rng = Arc::new(Mutex::new(StdRng::seed_from_u64(seed)));
+        self
+    }
+
+    /// Call `observer` with each message to a peer before sending it. The
+    /// messages only validated are not observed.
+    pub fn with_observer(
+        mut self, observer: Arc<dyn ConsensusMsgObserver>,
+    ) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Record each vote with `vote_recorder` before sending it to peers,
+    /// and drop the votes it refuses.
+    pub fn with_vote_recorder(
+        mut self, vote_recorder: Arc<VoteRecorder>,
+    ) -> Self {
+        self.vote_recorder = Some(vote_recorder);
+        self
+    }
+
+    /// The last vote sent to peers, including the one sent before a restart,
+    /// or `None` if no vote is recorded.
+    pub fn last_sent_vote(&self) -> Option<LastVoteRecord> {
+        self.vote_recorder.as_ref()?.last_vote()
+    }
+
+    /// Record `msg` if it is a vote, before it is sent to peers.
+    fn record_vote(&self, msg: &ConsensusMsg) -> Result<(), NetworkError> {
+        match (&self.vote_recorder, msg) {
+            (Some(recorder), ConsensusMsg::VoteMsg(vote_msg)) => {
+                Ok(recorder.record(vote_msg.vote())?)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Observe the round consensus has reached by `msg`, which stops the
+    /// re-sends of the votes of the earlier rounds.
+    fn observe_round(&self, msg: &ConsensusMsg) {
+        if !self.vote_rebroadcaster.is_enabled() {
+            return;
+        }
+        match msg {
+            ConsensusMsg::VoteMsg(vote_msg) => {
+                let vote = vote_msg.vote();
+                self.vote_rebroadcaster.observe_round(
+                    vote.epoch(),
+                    vote.vote_data().proposed().round(),
+                );
+                self.observe_sync_info(vote_msg.sync_info());
+            }
+            ConsensusMsg::ProposalMsg(proposal) => {
+                self.observe_sync_info(proposal.sync_info())
+            }
+            ConsensusMsg::SyncInfo(sync_info) => {
+                self.observe_sync_info(sync_info)
+            }
+            _ => {}
+        }
+    }
+
+    /// Observe `sync_info`, e.g. one received from a peer, which stops the
+    /// re-sends of the votes of the rounds it certifies.
+    pub fn observe_sync_info(&self, sync_info: &SyncInfo) {
+        self.vote_rebroadcaster.observe_sync_info(sync_info);
+    }
+
+    /// Send the vote `msg` to `recipient` again until its round is
+    /// superseded, if the re-sends are enabled, see `VoteRebroadcaster`.
+    fn rebroadcast_vote(&self, recipient: Author, msg: &ConsensusMsg) {
+        let vote_msg = match msg {
+            ConsensusMsg::VoteMsg(vote_msg)
+                if self.vote_rebroadcaster.is_enabled() =>
+            {
+                vote_msg
+            }
+            _ => return,
+        };
+        let vote = vote_msg.vote();
+        let mut network_sender = self.network_sender.clone();
+        let msg = msg.clone();
+        self.vote_rebroadcaster.rebroadcast(
+            vote.epoch(),
+            vote.vote_data().proposed().round(),
+            move || network_sender.send_to(recipient, &msg),
+        );
+    }
+
+    fn observe(&self, peer_ids: &[NodeId], msg: &ConsensusMsg) {
+        if let Some(observer) = &self.observer {
+            for peer_id in peer_ids {
+                observer.on_send(peer_id, msg);
+            }
+        }
+    }
+
+    /// Add the connected peers that are not validators of the epoch to
+    /// `exclude` if the broadcasts are only to the validators, see
+    /// `ProtocolConfiguration::pos_broadcast_to_validators_only`.
+    fn exclude_non_validators(
+        &self, exclude: &mut Vec<AccountAddress>, msg: &ConsensusMsg,
+    ) {
+        if !self.is_broadcast_to_validators_only() {
+            return;
+        }
+        let mut skipped = 0;
+        for (peer, peer_id) in self.network_sender.connected_peers() {
+            if !exclude.contains(&peer)
+                && !self.is_validator_peer(&peer, &peer_id)
+            {
+                exclude.push(peer);
+                skipped += 1;
+            }
+        }
+        counters::NON_VALIDATOR_SENDS_SKIPPED
+            .with_label_values(&[msg.name()])
+            .inc_by(skipped);
+    }
+
+    fn is_broadcast_to_validators_only(&self) -> bool {
+        self.network_sender
+            .protocol_handler
+            .protocol_config
+            .pos_broadcast_to_validators_only
+    }
+
+    fn is_validator(&self, author: &AccountAddress) -> bool {
+        self.validators.get_public_key(author).is_some()
+    }
+
+    /// Whether the connected PoS peer `author` of the session `peer_id` is
+    /// a validator, by the validator sessions set on the epoch change, or by
+    /// `author` before they are set.
+    fn is_validator_peer(
+        &self, author: &AccountAddress, peer_id: &NodeId,
+    ) -> bool {
+        self.network_sender
+            .protocol_handler
+            .is_validator_peer(peer_id)
+            .unwrap_or_else(|| self.is_validator(author))
+    }
+
+    /// The connected PoS peers except `exclude`, with their `NodeId`s.
+    fn peers_except(
+        &self, exclude: &[AccountAddress],
+    ) -> Vec<(Author, NodeId)> {
+        self.network_sender
+            .connected_peers()
+            .into_iter()
+            .filter(|(peer, _)| !exclude.contains(peer))
+            .collect()
+    }
+
+    /// Send `msg` to the `recipients` resolved to their `NodeId`s, or only
+    /// validate it in the validate-only mode. The sends are delayed by
+    /// random jitters if `is_jittered(msg)`, and then the recipients are
+    /// counted as sent once the sends are scheduled.
+    fn send_to_recipients(
+        &self, recipients: &[(Author, NodeId)], msg: &ConsensusMsg,
+    ) -> BroadcastOutcome {
+        let peer_ids = dedup_node_ids(
+            recipients.iter().map(|(_, peer_id)| *peer_id).collect(),
+        );
+        let all_sent = BroadcastOutcome {
+            sent: recipients.len(),
+            failed: Vec::new(),
+        };
+        if self.validate_only {
+            self.network_sender.validate_send(&peer_ids, msg);
+            return all_sent;
+        }
+        if let Err(e) = self.record_vote(msg) {
+            diem_error!(error = ?e, "Error recording the vote to send");
+            let reason = format!("{:#}", e);
+            return BroadcastOutcome {
+                sent: 0,
+                failed: recipients
+                    .iter()
+                    .map(|(recipient, _)| {
+                        (
+                            *recipient,
+                            NetworkError::Internal(format_err!("{}", reason)),
+                        )
+                    })
+                    .collect(),
+            };
+        }
+        self.observe_round(msg);
+        self.observe(&peer_ids, msg);
+        self.mirror(msg, |mirror, peer| mirror.send_to(peer, msg));
+        if self.is_capturing() {
+            self.capture(&peer_ids, msg);
+            return all_sent;
+        }
+        if self.is_jittered(msg) {
+            self.send_jittered(&peer_ids, msg);
+            return all_sent;
+        }
+        self.network_sender.fan_out_to(recipients, msg)
+    }
+
+    /// Whether the sends of `msg` to many peers are delayed by random
+    /// jitters, see `SendJitterConfig`.
+    fn is_jittered(&self, msg: &ConsensusMsg) -> bool {
+        self.network_sender
+            .protocol_handler
+            .protocol_config
+            .pos_send_jitter
+            .is_enabled_for(msg)
+            // The delayed sends are spawned in the runtime.
+            && tokio::runtime::Handle::try_current().is_ok()
+    }
+
+    /// Send `msg` to each of `peer_ids` after its random jitter. Only
+    /// called if `is_jittered(msg)`.
+    fn send_jittered(&self, peer_ids: &[NodeId], msg: &ConsensusMsg) {
+        let schedule = self
+            .network_sender
+            .protocol_handler
+            .protocol_config
+            .pos_send_jitter
+            .schedule(peer_ids, msg, &mut thread_rng());
+        // Encoded once for all the peers, instead of once for each delayed
+        // send.
+        let encoded = Arc::new(self.network_sender.encode_for(peer_ids, msg));
+        for (peer_id, delay) in schedule {
+            let network_sender = self.network_sender.clone();
+            let encoded = encoded.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                if let Err(e) =
+                    network_sender.send_encoded_to_node(&peer_id, &encoded)
+                {
+                    diem_debug!(
+                        "Failed to send a jittered message, {}: {:?}",
+                        encoded.log_context().to_peer(&peer_id),
+                        e
+                    );
+                }
+            });
+        }
+    }
+
+    /// Only validate the messages to peers instead of sending them. The
+    /// messages to self are still delivered.
+    pub fn with_validate_only(mut self, validate_only: bool) -> Self {
+        self.validate_only = validate_only;
+        self
+    }
+
+    pub fn is_validate_only(&self) -> bool { self.validate_only }
+
     pub fn network_sender(&self) -> &NetworkSender { &self.network_sender }
 
+    /// Send `msg` to `recipient`, or only encode it in the validate-only
+    /// mode.
+    pub fn send_to(
+        &self, recipient: Author, msg: &ConsensusMsg,
+    ) -> Result<(), NetworkError> {
+        let result = if self.validate_only {
+            self.network_sender
+                .validate_send_to(recipient, msg)
+                .map(|_| ())
+        } else {
+            self.try_send_to(recipient, msg)
+        };
+        if let Err(e) = &result {
+            count_send_failures(msg, std::iter::once(e));
+        }
+        result
+    }
+
+    /// Send `msg` to `recipient` like `send_to`, without counting the
+    /// failure.
+    fn try_send_to(
+        &self, recipient: Author, msg: &ConsensusMsg,
+    ) -> Result<(), NetworkError> {
+        self.record_vote(msg)?;
+        self.observe_round(msg);
+        self.mirror(msg, |mirror, peer| mirror.send_to(peer, msg));
+        if self.is_capturing() {
+            let peer_id = self.network_sender.resolve_node_id(&recipient)?;
+            self.observe(&[peer_id], msg);
+            self.capture(&[peer_id], msg);
+            return Ok(());
+        }
+        // Registered before sending, so a vote failing to be sent at first
+        // is still sent again.
+        self.rebroadcast_vote(recipient, msg);
+        if self.observer.is_some() {
+            let peer_id = self.network_sender.resolve_node_id(&recipient)?;
+            self.observe(&[peer_id], msg);
+        }
+        match msg {
+            // A large proof is sent in chunks.
+            ConsensusMsg::EpochChangeProof(proof) => {
+                let peer_id =
+                    self.network_sender.resolve_node_id(&recipient)?;
+                self.network_sender
+                    .send_epoch_change_proof(&[peer_id], proof)
+            }
+            _ => self.network_sender.clone().send_to(recipient, msg),
+        }
+    }
+
+    /// Send `msg` to `recipient` together with `sync_info`, in one frame if
+    /// the recipient supports it, or only encode `msg` in the validate-only
+    /// mode.
+    pub fn send_with_sync_info(
+        &self, recipient: Author, msg: &ConsensusMsg, sync_info: &SyncInfo,
+    ) -> Result<(), NetworkError> {
+        if self.validate_only {
+            self.network_sender.validate_send_to(recipient, msg)?;
+            return Ok(());
+        }
+        let peer_id = self.network_sender.resolve_node_id(&recipient)?;
+        self.record_vote(msg)?;
+        self.observe_round(msg);
+        self.observe_sync_info(sync_info);
+        self.mirror(msg, |mirror, peer| {
+            mirror.send_with_sync_info(peer, msg, sync_info)
+        });
+        if self.observer.is_some() || self.is_capturing() {
+            let sync_info_msg =
+                ConsensusMsg::SyncInfo(Box::new(sync_info.clone()));
+            self.observe(&[peer_id], &sync_info_msg);
+            self.observe(&[peer_id], msg);
+            if self.is_capturing() {
+                self.capture(&[peer_id], &sync_info_msg);
+                self.capture(&[peer_id], msg);
+                return Ok(());
+            }
+        }
+        self.network_sender
+            .send_with_sync_info(&[peer_id], msg, sync_info)
+    }
+
+    /// The connected PoS peers and the `NodeId`s of their sessions, for
+    /// checking the connectivity to the validator set.
+    pub fn connected_peers(&self) -> Vec<(Author, NodeId)> {
+        self.network_sender.connected_peers()
+    }
+
+    /// The number of connected PoS peers.
+    pub fn connected_peer_count(&self) -> usize {
+        self.network_sender.connected_peers().len()
+    }
+
     /// Tries to retrieve num of blocks backwards starting from id from the
     /// given peer: the function returns a future that is fulfilled with
     /// BlockRetrievalResponse.
@@ -119,30 +1150,28 @@ impl ConsensusNetworkSender {
     {
         ensure!(from != self.author, "Retrieve block from self");
 
-        let peer_hash = self
+        let peer_id = self.network_sender.resolve_node_id(&from)?;
+        // The peers before `HSB_PROTOCOL_V13` cannot decode the byte limit,
+        // so they are asked for the blocks without it.
+        let retrieval_request = match self
             .network_sender
             .protocol_handler
-            .pos_peer_mapping
-            .read()
-            .get(&from)
-            .cloned()
-            .ok_or(anyhow!(
-                "request_block: recipient {:?} has been removed",
-                from
-            ))?;
-        let peer_state =
-            self.network_sender.protocol_handler.peers.get(&peer_hash);
-        if peer_state.is_none() {
-            bail!("peer not found");
-        }
-        let peer_state = peer_state.unwrap();
-        let peer_id = peer_state.read().get_id().clone();
+            .peers
+            .protocol_version(&peer_id)
+        {
+            Some(version) if version >= HSB_PROTOCOL_V13 => retrieval_request,
+            _ => BlockRetrievalRequest::new(
+                retrieval_request.block_id(),
+                retrieval_request.num_blocks(),
+            ),
+        };
 
         let request = BlockRetrievalRpcRequest {
             request_id: 0,
             request: retrieval_request.clone(),
             is_empty: false,
             response_tx: None,
+            coalesced_tx: Vec::new(),
             timeout,
         };
 
@@ -153,15 +1182,8 @@ impl ConsensusNetworkSender {
                 .await
                 .map_err(|_| { format_err!("rpc call failed") })?
         );
-        let response = match rpc_response
-            .as_any()
-            .downcast_ref::<BlockRetrievalRpcResponse>()
-        {
-            Some(r) => r.clone(),
-            None => {
-                bail!("response downcast failed");
-            }
-        };
+        let response =
+            *rpc_response.into_typed::<BlockRetrievalRpcResponse>()?;
 
         response
             .response
@@ -181,23 +1203,77 @@ impl ConsensusNetworkSender {
         Ok(response.response)
     }
 
+    /// Measure the round trip to the peer `peer`, see
+    /// `NetworkSender::measure_latency`.
+    pub async fn measure_latency(
+        &self, peer: Author,
+    ) -> anyhow::Result<Duration> {
+        ensure!(peer != self.author, "Measure latency to self");
+        let peer_id = self.network_sender.resolve_node_id(&peer)?;
+        self.network_sender.measure_latency(peer_id).await
+    }
+
+    /// Retrieve the `ranges` of blocks concurrently from up to `fanout`
+    /// distinct peers, and return the blocks of all the ranges in order.
+    ///
+    /// The ranges must form one chain backwards: the block id of each range
+    /// is the parent of the last block of the previous range, so all but the
+    /// last range must be returned completely. A range that fails on one peer
+    /// is requested again from the other peers.
+    pub async fn send_block_retrieval_parallel(
+        &self, ranges: Vec<BlockRetrievalRequest>, fanout: usize,
+        timeout: Duration,
+    ) -> anyhow::Result<Vec<Block>>
+    {
+        let mut peers: Vec<Author> = self
+            .network_sender
+            .protocol_handler
+            .pos_peer_mapping
+            .read()
+            .keys()
+            .filter(|peer| **peer != self.author)
+            .cloned()
+            .collect();
+        ensure!(!peers.is_empty(), "No peer to retrieve blocks from");
+        peers.shuffle(&mut thread_rng());
+        let fanout = fanout.max(1).min(peers.len());
+
+        retrieve_ranges(
+            &|request, peer| {
+                let mut sender = self.clone();
+                async move { sender.request_block(request, peer, timeout).await }
+            },
+            ranges,
+            &peers,
+            fanout,
+        )
+        .await
+    }
+
     /// Tries to send the given msg to all the participants.
     ///
-    /// The future is fulfilled as soon as the message put into the mpsc channel
-    /// to network internal(to provide back pressure), it does not indicate
-    /// the message is delivered or sent out. It does not give indication
-    /// about when the message is delivered to the recipients, as well as
-    /// there is no indication about the network failures.
+    /// The message is counted as sent as soon as it is put into the queue of
+    /// the network (or of self), which does not indicate it is delivered or
+    /// sent out. The failed recipients are returned in the outcome, e.g. the
+    /// peers disconnected while sending.
+    ///
+    /// With `pos_broadcast_to_validators_only`, the peers that are not
+    /// validators of the epoch are excluded.
     pub async fn broadcast(
-        &mut self, msg: ConsensusMsg, exclude: Vec<AccountAddress>,
-    ) {
+        &mut self, msg: ConsensusMsg, mut exclude: Vec<AccountAddress>,
+    ) -> BroadcastOutcome {
+        let mut outcome = BroadcastOutcome::default();
         if !exclude.contains(&self.author) {
-            if let Err(err) = self
+            match self
                 .network_sender
                 .send_self_msg(self.author, msg.clone())
                 .await
             {
-                diem_error!("Error broadcasting to self: {:?}", err);
+                Ok(()) => outcome.sent += 1,
+                Err(err) => {
+                    diem_error!("Error broadcasting to self: {:?}", err);
+                    outcome.failed.push((self.author, err));
+                }
             }
         }
 
@@ -224,9 +1300,64 @@ impl ConsensusNetworkSender {
          */
         // TODO(lpl): It may be sufficient to broadcast some messages to only
         // validators.
-        if let Err(err) = self.network_sender.send_to_others(&msg, &exclude) {
-            diem_error!(error = ?err, "Error broadcasting message");
+        self.exclude_non_validators(&mut exclude, &msg);
+        let peers = self.peers_except(&exclude);
+        let peers_outcome = self.send_to_recipients(&peers, &msg);
+        if !peers_outcome.is_complete() {
+            diem_error!(
+                failed = ?peers_outcome.failed,
+                context = %SendLogContext::of(&msg),
+                "Error broadcasting message"
+            );
         }
+        outcome.extend(peers_outcome);
+        count_send_failures(&msg, outcome.errors());
+        outcome
+    }
+
+    /// Sends the given msg to all the other validators of the current epoch
+    /// that are connected, one copy per connection.
+    ///
+    /// The validators that cannot be reached are returned in the outcome,
+    /// together with the reasons.
+    pub fn broadcast_to_validators(
+        &mut self, msg: &ConsensusMsg,
+    ) -> BroadcastOutcome {
+        let mut recipients = Vec::new();
+        let mut outcome = BroadcastOutcome::default();
+        for author in self.validators.get_ordered_account_addresses_iter() {
+            if author == self.author {
+                continue;
+            }
+            match self.network_sender.resolve_node_id(&author) {
+                Ok(peer_id) => recipients.push((author, peer_id)),
+                Err(e) => outcome.failed.push((author, e)),
+            }
+        }
+        outcome.extend(self.send_to_recipients(&recipients, msg));
+        count_send_failures(msg, outcome.errors());
+        outcome
+    }
+
+    /// Sends the given msg to `fanout` distinct connected peers drawn at
+    /// random, or to all of them if fewer are connected, and relies on the
+    /// peers to propagate it further. This is for the large messages that are
+    /// not time critical, e.g. `EpochChangeProof`.
+    pub fn broadcast_sample(
+        &mut self, msg: &ConsensusMsg, fanout: usize,
+    ) -> BroadcastOutcome {
+        let mut exclude = Vec::new();
+        self.exclude_non_validators(&mut exclude, msg);
+        let mut peers = self.peers_except(&exclude);
+        // Sorted so the sample only depends on the rng and the peers.
+        peers.sort_by_key(|(_, peer_id)| *peer_id);
+        let peers: Vec<_> = peers
+            .choose_multiple(&mut *self.sample_rng.lock(), fanout)
+            .cloned()
+            .collect();
+        let outcome = self.send_to_recipients(&peers, msg);
+        count_send_failures(msg, outcome.errors());
+        outcome
     }
 
     // This is unused because we always broadcast votes now.
@@ -243,7 +1374,7 @@ impl ConsensusNetworkSender {
     /// about when the message is delivered to the recipients, as well as
     /// there is no indication about the network failures.
     pub async fn send_vote(&self, vote_msg: VoteMsg, recipients: Vec<Author>) {
-        let mut network_sender = self.network_sender.clone();
+        let network_sender = self.network_sender.clone();
         let msg = ConsensusMsg::VoteMsg(Box::new(vote_msg));
         for peer in recipients {
             if self.author == peer {
@@ -254,9 +1385,10 @@ impl ConsensusNetworkSender {
                 }
                 continue;
             }
-            if let Err(e) = network_sender.send_to(peer, &msg) {
+            if let Err(e) = self.send_to(peer, &msg) {
                 diem_error!(
                     remote_peer = peer,
+                    context = %SendLogContext::of(&msg),
                     error = ?e, "Failed to send a vote to peer",
                 );
             }
@@ -269,10 +1401,10 @@ impl ConsensusNetworkSender {
     /// or sent out).
     pub fn send_sync_info(&self, sync_info: SyncInfo, recipient: Author) {
         let msg = ConsensusMsg::SyncInfo(Box::new(sync_info));
-        let mut network_sender = self.network_sender.clone();
-        if let Err(e) = network_sender.send_to(recipient, &msg) {
+        if let Err(e) = self.send_to(recipient, &msg) {
             diem_warn!(
                 remote_peer = recipient,
+                context = %SendLogContext::of(&msg),
                 error = "Failed to send a sync info msg to peer {:?}",
                 "{:?}",
                 e
@@ -281,27 +1413,265 @@ impl ConsensusNetworkSender {
     }
 }
 
+/// Count the `errors` of sending `msg`, by the message type and the reason.
+fn count_send_failures<'a>(
+    msg: &ConsensusMsg, errors: impl Iterator<Item = &'a NetworkError>,
+) {
+    for error in errors {
+        counters::CONSENSUS_SEND_FAILURES
+            .with_label_values(&[msg.name(), error.failure_reason()])
+            .inc();
+    }
+}
+
+/// Retrieve the `ranges` with `fetch`, at most `fanout` of them at a time,
+/// and return the blocks of all the ranges in order. See
+/// `ConsensusNetworkSender::send_block_retrieval_parallel`.
+async fn retrieve_ranges<F, Fut>(
+    fetch: &F, ranges: Vec<BlockRetrievalRequest>, peers: &[Author],
+    fanout: usize,
+) -> anyhow::Result<Vec<Block>>
+where
+    F: Fn(BlockRetrievalRequest, Author) -> Fut,
+    Fut: Future<Output = anyhow::Result<BlockRetrievalResponse>>,
+{
+    let num_ranges = ranges.len();
+    // `buffered` keeps the order of the ranges.
+    let responses: Vec<_> = stream::iter(ranges.into_iter().enumerate())
+        .map(|(i, range)| {
+            retrieve_range(fetch, range, peers, i % fanout, i + 1 < num_ranges)
+        })
+        .buffered(fanout)
+        .collect()
+        .await;
+
+    let mut blocks: Vec<Block> = Vec::new();
+    for response in responses {
+        let (range, range_blocks) = response?;
+        if let Some(last) = blocks.last() {
+            ensure!(
+                last.parent_id() == range.block_id(),
+                "{} does not continue the previous range ending at {}",
+                range,
+                last.id()
+            );
+        }
+        blocks.extend(range_blocks);
+    }
+    Ok(blocks)
+}
+
+/// Retrieve one range of blocks with `fetch`, trying the peers in order
+/// starting from `peers[first_peer]`.
+///
+/// A response truncated by the byte limit is continued from its
+/// `next_cursor` on the same peer. A peer that fails is replaced by the next
+/// one, which continues from the blocks already received.
+async fn retrieve_range<F, Fut>(
+    fetch: &F, range: BlockRetrievalRequest, peers: &[Author],
+    first_peer: usize, require_complete: bool,
+) -> anyhow::Result<(BlockRetrievalRequest, Vec<Block>)>
+where
+    F: Fn(BlockRetrievalRequest, Author) -> Fut,
+    Fut: Future<Output = anyhow::Result<BlockRetrievalResponse>>,
+{
+    let mut blocks = Vec::new();
+    let mut next = range.clone();
+    let mut last_error = None;
+    for attempt in 0..peers.len() {
+        let peer = peers[(first_peer + attempt) % peers.len()];
+        let error = loop {
+            let response = match fetch(next.clone(), peer).await {
+                Ok(response)
+                    if response.status() == BlockRetrievalStatus::Succeeded =>
+                {
+                    response
+                }
+                Ok(response) => break format_err!("{:?}", response.status()),
+                Err(e) => break e,
+            };
+            let next_cursor = response.next_cursor();
+            let page = response.into_blocks();
+            let remaining = next.num_blocks().saturating_sub(page.len() as u64);
+            let page_parent = page.last().map(Block::parent_id);
+            blocks.extend(page);
+            match next_cursor {
+                Some(cursor) if remaining > 0 => {
+                    next = BlockRetrievalRequest::new_with_byte_limit(
+                        cursor,
+                        remaining,
+                        next.max_response_bytes(),
+                    );
+                }
+                _ if !require_complete || remaining == 0 => {
+                    return Ok((range, blocks));
+                }
+                _ => {
+                    // The next peer continues after the blocks received.
+                    if let Some(parent) = page_parent {
+                        next = BlockRetrievalRequest::new_with_byte_limit(
+                            parent,
+                            remaining,
+                            next.max_response_bytes(),
+                        );
+                    }
+                    break format_err!(
+                        "incomplete response, {} blocks missing",
+                        remaining
+                    );
+                }
+            }
+        };
+        diem_warn!(
+            remote_peer = peer,
+            error = ?error,
+            "Failed to retrieve {}, trying another peer", next
+        );
+        last_error = Some(error);
+    }
+    Err(last_error
+        .unwrap_or_else(|| format_err!("No peer available"))
+        .context(format!("Failed to retrieve {}", range)))
+}
+
+#[async_trait::async_trait]
+impl ConsensusNetwork for ConsensusNetworkSender {
+    fn send_to(
+        &self, recipient: Author, msg: &ConsensusMsg,
+    ) -> Result<(), NetworkError> {
+        ConsensusNetworkSender::send_to(self, recipient, msg)
+    }
+
+    fn send_to_many(
+        &self, recipients: Vec<Author>, msg: &ConsensusMsg,
+    ) -> BroadcastOutcome {
+        let mut resolved = Vec::new();
+        let mut outcome = BroadcastOutcome::default();
+        for recipient in recipients {
+            match self.network_sender.resolve_node_id(&recipient) {
+                Ok(peer_id) => resolved.push((recipient, peer_id)),
+                Err(e) => outcome.failed.push((recipient, e)),
+            }
+        }
+        if self.is_broadcast_to_validators_only() {
+            let num_recipients = resolved.len() + outcome.failed.len();
+            resolved.retain(|(recipient, peer_id)| {
+                self.is_validator_peer(recipient, peer_id)
+            });
+            // The recipients not connected are only known by their authors.
+            outcome
+                .failed
+                .retain(|(recipient, _)| self.is_validator(recipient));
+            counters::NON_VALIDATOR_SENDS_SKIPPED
+                .with_label_values(&[msg.name()])
+                .inc_by(
+                    (num_recipients - resolved.len() - outcome.failed.len())
+                        as u64,
+                );
+        }
+        outcome.extend(self.send_to_recipients(&resolved, msg));
+        count_send_failures(msg, outcome.errors());
+        outcome
+    }
+
+    async fn send_rpc(
+        &self, request: BlockRetrievalRequest, from: Author, timeout: Duration,
+    ) -> anyhow::Result<BlockRetrievalResponse> {
+        self.clone().request_block(request, from, timeout).await
+    }
+
+    async fn send_self_msg(
+        &self, msg: ConsensusMsg,
+    ) -> Result<(), NetworkError> {
+        self.network_sender.send_self_msg(self.author, msg).await
+    }
+
+    async fn broadcast(
+        &mut self, msg: ConsensusMsg, exclude: Vec<AccountAddress>,
+    ) -> BroadcastOutcome {
+        ConsensusNetworkSender::broadcast(self, msg, exclude).await
+    }
+}
+
 /// Consensus network task
 pub struct NetworkTask {
     /// consensus message sender
-    pub consensus_messages_tx: diem_channel::Sender<
-        (AccountAddress, Discriminant<ConsensusMsg>),
-        (AccountAddress, ConsensusMsg),
-    >,
+    pub consensus_messages_tx: ConsensusMessageSender,
     /// block retrieval message sender
     pub block_retrieval_tx:
         diem_channel::Sender<AccountAddress, IncomingBlockRetrievalRequest>,
 }
 
+/// The configuration of the queue buffering the consensus messages received
+/// from the network.
+///
+/// Messages are queued per (Author, MessageType) key, and each key holds at
+/// most `max_queue_size_per_key` messages. When a key is full:
+/// - `LIFO` drops the oldest message and delivers the newest first. This
+///   keeps only the freshest votes and proposals, which is usually what
+///   consensus wants, but messages may be processed out of order.
+/// - `FIFO` drops the newest message and keeps the delivery order, so a
+///   burst of old messages can hide fresh ones.
+/// - `KLAST` drops the oldest message like `LIFO`, but delivers the remaining
+///   ones in order, so the last `max_queue_size_per_key` messages are kept.
+///
+/// A larger capacity loses fewer messages, at the cost of processing more
+/// stale ones under load. The messages of each `MessagePriority` are queued
+/// separately with the same configuration.
+#[derive(Clone, Copy, Debug)]
+pub struct ConsensusQueueConfig {
+    /// The drop and delivery policy of the queue.
+    pub queue_style: QueueStyle,
+    /// The maximum number of messages buffered for each key.
+    pub max_queue_size_per_key: usize,
+    /// The water marks of the whole queue, or `None` to accept the messages
+    /// regardless of the occupancy.
+    pub backpressure: Option<BackpressureConfig>,
+}
+
+impl Default for ConsensusQueueConfig {
+    fn default() -> Self {
+        Self {
+            queue_style: QueueStyle::LIFO,
+            max_queue_size_per_key: 1,
+            backpressure: None,
+        }
+    }
+}
+
 impl NetworkTask {
     /// Establishes the initial connections with the peers and returns the
     /// receivers.
     pub fn new() -> (NetworkTask, NetworkReceivers) {
-        let (consensus_messages_tx, consensus_messages) = diem_channel::new(
-            QueueStyle::LIFO,
-            1,
-            Some(&counters::CONSENSUS_CHANNEL_MSGS),
-        );
+        Self::new_with_queue_config(ConsensusQueueConfig::default())
+    }
+
+    /// Same as `new`, but the consensus message queue is built with
+    /// `queue_config`.
+    pub fn new_with_queue_config(
+        queue_config: ConsensusQueueConfig,
+    ) -> (NetworkTask, NetworkReceivers) {
+        let new_channel = || {
+            diem_channel::new(
+                queue_config.queue_style,
+                queue_config.max_queue_size_per_key,
+                Some(&counters::CONSENSUS_CHANNEL_MSGS),
+            )
+        };
+        let (high_tx, high_rx) = new_channel();
+        let (low_tx, low_rx) = new_channel();
+        let backpressure =
+            Arc::new(QueueBackpressure::new(queue_config.backpressure));
+        let consensus_messages_tx = ConsensusMessageSender {
+            high: high_tx,
+            low: low_tx,
+            backpressure: backpressure.clone(),
+        };
+        let consensus_messages = ConsensusMessageReceiver {
+            high: high_rx,
+            low: low_rx,
+            backpressure,
+        };
         let (block_retrieval_tx, block_retrieval) = diem_channel::new(
             QueueStyle::LIFO,
             1,
@@ -322,3 +1692,811 @@ impl NetworkTask {
     /// start
     pub async fn start(self) {}
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        peer_msg_key, retrieve_ranges, self_msg_key, BackpressureConfig,
+        ConsensusMsg, ConsensusNetwork, ConsensusNetworkSender,
+        ConsensusQueueConfig, NetworkTask,
+    };
+    use crate::{
+        pos::{
+            consensus::{counters, msg_observer::ChannelMsgObserver},
+            protocol::test_utils::{
+                unstarted_sender, unstarted_sender_with_config,
+            },
+        },
+        sync::ProtocolConfiguration,
+    };
+    use anyhow::format_err;
+    use channel::{diem_channel::TryPushError, message_queues::QueueStyle};
+    use consensus_types::{
+        block::{block_test_utils::placeholder_certificate_for_block, Block},
+        block_retrieval::{
+            BlockRetrievalRequest, BlockRetrievalResponse, BlockRetrievalStatus,
+        },
+        commit_vote_msg::CommitVoteMsg,
+        epoch_retrieval::EpochRetrievalRequest,
+        proposal_msg::ProposalMsg,
+        quorum_cert::QuorumCert,
+        sync_info::SyncInfo,
+        vote::Vote,
+        vote_data::VoteData,
+        vote_msg::VoteMsg,
+    };
+    use diem_crypto::HashValue;
+    use diem_types::{
+        account_address::AccountAddress, block_info::BlockInfo,
+        epoch_change::EpochChangeProof, ledger_info::LedgerInfo,
+        validator_signer::ValidatorSigner,
+        validator_verifier::ValidatorVerifier,
+    };
+    use futures::{executor::block_on, future, FutureExt, StreamExt};
+    use keccak_hash::keccak;
+    use network::node_table::NodeId;
+    use parking_lot::Mutex;
+    use std::{collections::BTreeMap, sync::Arc};
+
+    /// Push three messages from the same author into a queue holding two
+    /// messages per key, and return the start epochs of the received messages.
+    fn fill_queue(queue_style: QueueStyle) -> Vec<u64> {
+        let (task, receivers) =
+            NetworkTask::new_with_queue_config(ConsensusQueueConfig {
+                queue_style,
+                max_queue_size_per_key: 2,
+                backpressure: None,
+            });
+        let author = AccountAddress::random();
+        for start_epoch in 1..=3 {
+            let msg = ConsensusMsg::EpochRetrievalRequest(Box::new(
+                EpochRetrievalRequest {
+                    start_epoch,
+                    end_epoch: start_epoch + 1,
+                },
+            ));
+            task.consensus_messages_tx
+                .push(peer_msg_key(author, &msg), (author, msg))
+                .unwrap();
+        }
+        // Drop the senders so the receiver terminates after draining.
+        drop(task);
+        block_on(
+            receivers
+                .consensus_messages
+                .map(|(_, msg)| match msg {
+                    ConsensusMsg::EpochRetrievalRequest(request) => {
+                        request.start_epoch
+                    }
+                    _ => unreachable!(),
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_consensus_queue_style() {
+        // The oldest is dropped, the newest is delivered first.
+        assert_eq!(fill_queue(QueueStyle::LIFO), vec![3, 2]);
+        // The newest is dropped.
+        assert_eq!(fill_queue(QueueStyle::FIFO), vec![1, 2]);
+        // The oldest is dropped, the rest are delivered in order.
+        assert_eq!(fill_queue(QueueStyle::KLAST), vec![2, 3]);
+    }
+
+    #[test]
+    fn test_self_msgs_of_epochs_kept() {
+        let (task, receivers) =
+            NetworkTask::new_with_queue_config(ConsensusQueueConfig {
+                queue_style: QueueStyle::KLAST,
+                max_queue_size_per_key: 1,
+                backpressure: None,
+            });
+        let signer = ValidatorSigner::from_int(1);
+        let author = signer.author();
+        // The proposals of the first round of the epochs after `epoch`.
+        let proposal = |epoch| {
+            let ledger_info = LedgerInfo::new(
+                BlockInfo::new(
+                    epoch,
+                    0,
+                    HashValue::zero(),
+                    HashValue::zero(),
+                    0,
+                    0,
+                    None,
+                    None,
+                ),
+                HashValue::zero(),
+            );
+            let qc = QuorumCert::certificate_for_genesis_from_ledger_info(
+                &ledger_info,
+                HashValue::zero(),
+            );
+            ConsensusMsg::ProposalMsg(Box::new(ProposalMsg::new(
+                Block::new_proposal(vec![], 1, 1, qc.clone(), &signer),
+                SyncInfo::new(qc.clone(), qc, None),
+            )))
+        };
+        for epoch in 0..2 {
+            let msg = proposal(epoch);
+            task.consensus_messages_tx
+                .push(self_msg_key(author, &msg), (author, msg))
+                .unwrap();
+        }
+        // The proposals from a peer still replace each other.
+        let peer = AccountAddress::random();
+        for epoch in 0..2 {
+            let msg = proposal(epoch);
+            task.consensus_messages_tx
+                .push(peer_msg_key(peer, &msg), (peer, msg))
+                .unwrap();
+        }
+        drop(task);
+
+        let received: Vec<_> = block_on(
+            receivers
+                .consensus_messages
+                .map(|(sender, msg)| match msg {
+                    ConsensusMsg::ProposalMsg(proposal) => {
+                        (sender == author, proposal.epoch())
+                    }
+                    _ => unreachable!(),
+                })
+                .collect(),
+        );
+        let mut from_self: Vec<_> = received
+            .iter()
+            .filter(|(is_self, _)| *is_self)
+            .map(|(_, epoch)| *epoch)
+            .collect();
+        from_self.sort();
+        assert_eq!(from_self, vec![1, 2]);
+        assert_eq!(received.len(), 3);
+        assert!(received.contains(&(false, 2)));
+    }
+
+    #[test]
+    fn test_consensus_msg_priority() {
+        let (task, mut receivers) =
+            NetworkTask::new_with_queue_config(ConsensusQueueConfig {
+                queue_style: QueueStyle::FIFO,
+                max_queue_size_per_key: 16,
+                backpressure: None,
+            });
+        let author = AccountAddress::random();
+        for _ in 0..10 {
+            let msg = ConsensusMsg::BlockRetrievalResponse(Box::new(
+                BlockRetrievalResponse::new(
+                    BlockRetrievalStatus::Succeeded,
+                    vec![Block::make_genesis_block()],
+                ),
+            ));
+            task.consensus_messages_tx
+                .push(peer_msg_key(author, &msg), (author, msg))
+                .unwrap();
+        }
+        let signer = ValidatorSigner::random(None);
+        let ledger_info =
+            LedgerInfo::new(BlockInfo::empty(), HashValue::zero());
+        let vote = Vote::new(
+            VoteData::new(BlockInfo::empty(), BlockInfo::empty()),
+            signer.author(),
+            ledger_info.clone(),
+            &signer,
+        );
+        let qc = QuorumCert::certificate_for_genesis_from_ledger_info(
+            &ledger_info,
+            HashValue::zero(),
+        );
+        let msg = ConsensusMsg::VoteMsg(Box::new(VoteMsg::new(
+            vote,
+            SyncInfo::new(qc.clone(), qc, None),
+        )));
+        task.consensus_messages_tx
+            .push(peer_msg_key(author, &msg), (author, msg))
+            .unwrap();
+
+        // The vote is received before the flood of block responses.
+        let received = block_on(receivers.consensus_messages.next()).unwrap();
+        assert!(matches!(received.1, ConsensusMsg::VoteMsg(_)));
+        drop(task);
+        let rest: Vec<_> = block_on(receivers.consensus_messages.collect());
+        assert_eq!(rest.len(), 10);
+        assert!(rest.iter().all(|(_, msg)| matches!(
+            msg,
+            ConsensusMsg::BlockRetrievalResponse(_)
+        )));
+    }
+
+    #[test]
+    fn test_consensus_queue_backpressure() {
+        let (task, mut receivers) =
+            NetworkTask::new_with_queue_config(ConsensusQueueConfig {
+                queue_style: QueueStyle::FIFO,
+                max_queue_size_per_key: 16,
+                backpressure: Some(BackpressureConfig {
+                    high_water_mark: 4,
+                    low_water_mark: 1,
+                }),
+            });
+        let sender = &task.consensus_messages_tx;
+        let occupancy = sender.subscribe_occupancy();
+        let author = AccountAddress::random();
+        let push_retrieval = || {
+            let msg = ConsensusMsg::EpochRetrievalRequest(Box::new(
+                EpochRetrievalRequest {
+                    start_epoch: 0,
+                    end_epoch: 1,
+                },
+            ));
+            sender
+                .push(peer_msg_key(author, &msg), (author, msg))
+                .unwrap();
+        };
+
+        for _ in 0..4 {
+            push_retrieval();
+        }
+        assert!(sender.is_backpressured());
+        // The retrieval messages are dropped under backpressure.
+        push_retrieval();
+        assert_eq!(sender.len(), 4);
+        // The messages of high priority are still accepted.
+        let qc = QuorumCert::certificate_for_genesis_from_ledger_info(
+            &LedgerInfo::new(BlockInfo::empty(), HashValue::zero()),
+            HashValue::zero(),
+        );
+        let msg = ConsensusMsg::SyncInfo(Box::new(SyncInfo::new(
+            qc.clone(),
+            qc,
+            None,
+        )));
+        sender
+            .push(peer_msg_key(author, &msg), (author, msg))
+            .unwrap();
+        assert_eq!(*occupancy.borrow(), 5);
+
+        // The backpressure is kept until the queue is drained to the low
+        // water mark.
+        for _ in 0..3 {
+            block_on(receivers.consensus_messages.next()).unwrap();
+        }
+        assert_eq!(*occupancy.borrow(), 2);
+        assert!(sender.is_backpressured());
+        block_on(receivers.consensus_messages.next()).unwrap();
+        assert!(!sender.is_backpressured());
+        push_retrieval();
+        assert_eq!(sender.len(), 2);
+    }
+
+    #[test]
+    fn test_consensus_queue_try_push_all() {
+        let (task, mut receivers) =
+            NetworkTask::new_with_queue_config(ConsensusQueueConfig {
+                queue_style: QueueStyle::FIFO,
+                max_queue_size_per_key: 2,
+                backpressure: None,
+            });
+        let sender = &task.consensus_messages_tx;
+        let author = AccountAddress::random();
+        let qc = QuorumCert::certificate_for_genesis_from_ledger_info(
+            &LedgerInfo::new(BlockInfo::empty(), HashValue::zero()),
+            HashValue::zero(),
+        );
+        let sync_info = ConsensusMsg::SyncInfo(Box::new(SyncInfo::new(
+            qc.clone(),
+            qc,
+            None,
+        )));
+        let retrieval = |start_epoch| {
+            ConsensusMsg::EpochRetrievalRequest(Box::new(
+                EpochRetrievalRequest {
+                    start_epoch,
+                    end_epoch: start_epoch + 1,
+                },
+            ))
+        };
+        let items = vec![retrieval(0), sync_info, retrieval(1), retrieval(2)]
+            .into_iter()
+            .map(|msg| (peer_msg_key(author, &msg), (author, msg)))
+            .collect();
+
+        // The third retrieval does not fit in the queue of its key.
+        match sender.try_push_all(items) {
+            Err((pushed, TryPushError::Full { message, .. })) => {
+                assert_eq!(pushed, 3);
+                assert!(matches!(
+                    &message.1,
+                    ConsensusMsg::EpochRetrievalRequest(request)
+                        if request.start_epoch == 2
+                ));
+            }
+            _ => panic!("the queue is not full"),
+        }
+        let mut received = vec![];
+        while let Some(Some((_, msg))) =
+            receivers.consensus_messages.next().now_or_never()
+        {
+            received.push(msg);
+        }
+        assert!(matches!(received[0], ConsensusMsg::SyncInfo(_)));
+        let epochs: Vec<u64> = received[1..]
+            .iter()
+            .map(|msg| match msg {
+                ConsensusMsg::EpochRetrievalRequest(request) => {
+                    request.start_epoch
+                }
+                _ => panic!("unexpected message"),
+            })
+            .collect();
+        assert_eq!(epochs, vec![0, 1]);
+    }
+
+    #[test]
+    fn test_estimated_encoded_len() {
+        let signer = ValidatorSigner::random(None);
+        let ledger_info =
+            LedgerInfo::new(BlockInfo::empty(), HashValue::zero());
+        let qc = QuorumCert::certificate_for_genesis_from_ledger_info(
+            &ledger_info,
+            HashValue::zero(),
+        );
+        let sync_info = SyncInfo::new(qc.clone(), qc.clone(), None);
+        let vote = Vote::new(
+            VoteData::new(BlockInfo::empty(), BlockInfo::empty()),
+            signer.author(),
+            ledger_info.clone(),
+            &signer,
+        );
+        let msgs = vec![
+            ConsensusMsg::BlockRetrievalRequest(Box::new(
+                BlockRetrievalRequest::new(HashValue::zero(), 10),
+            )),
+            ConsensusMsg::BlockRetrievalResponse(Box::new(
+                BlockRetrievalResponse::new_with_cursor(
+                    BlockRetrievalStatus::Succeeded,
+                    vec![Block::make_genesis_block(); 8],
+                    Some(HashValue::zero()),
+                ),
+            )),
+            ConsensusMsg::EpochRetrievalRequest(Box::new(
+                EpochRetrievalRequest {
+                    start_epoch: 1,
+                    end_epoch: 2,
+                },
+            )),
+            ConsensusMsg::ProposalMsg(Box::new(ProposalMsg::new(
+                Block::make_genesis_block(),
+                sync_info.clone(),
+            ))),
+            ConsensusMsg::SyncInfo(Box::new(sync_info.clone())),
+            ConsensusMsg::EpochChangeProof(Box::new(EpochChangeProof::new(
+                vec![qc.ledger_info().clone(); 3],
+                false,
+            ))),
+            ConsensusMsg::VoteMsg(Box::new(VoteMsg::new(vote, sync_info))),
+            ConsensusMsg::CommitVote(Box::new(CommitVoteMsg::new(
+                signer.author(),
+                ledger_info,
+                &signer,
+            ))),
+        ];
+        for msg in msgs {
+            let actual = bcs::to_bytes(&msg).unwrap().len();
+            let estimated = msg.estimated_encoded_len();
+            assert!(
+                estimated * 4 >= actual * 3 && estimated * 4 <= actual * 5,
+                "{}: estimated {} bytes, actual {} bytes",
+                msg.name(),
+                estimated,
+                actual
+            );
+        }
+    }
+
+    #[test]
+    fn test_default_consensus_queue_config() {
+        let config = ConsensusQueueConfig::default();
+        assert!(matches!(config.queue_style, QueueStyle::LIFO));
+        assert_eq!(config.max_queue_size_per_key, 1);
+    }
+
+    #[test]
+    fn test_broadcast_sample() {
+        let network_sender = unstarted_sender();
+        let handler = &network_sender.protocol_handler;
+        for i in 1..=5 {
+            let peer_id = NodeId::from_low_u64_be(i);
+            handler.peers.insert(keccak(&peer_id), peer_id, None);
+            handler
+                .pos_peer_mapping
+                .write()
+                .insert(AccountAddress::random(), keccak(&peer_id));
+        }
+        let msg = ConsensusMsg::EpochChangeProof(Box::new(
+            EpochChangeProof::new(vec![], false),
+        ));
+        // The network is not started, so every peer tried is a failure.
+        let sample = |seed, fanout| {
+            let mut sender = ConsensusNetworkSender::new(
+                AccountAddress::random(),
+                network_sender.clone(),
+                ValidatorVerifier::new(BTreeMap::new()),
+            )
+            .with_sample_seed(seed);
+            let outcome = sender.broadcast_sample(&msg, fanout);
+            assert_eq!(outcome.sent, 0);
+            let mut tried = outcome.failed_recipients();
+            tried.sort();
+            tried
+        };
+
+        let tried = sample(7, 3);
+        assert_eq!(tried.len(), 3);
+        let mut distinct = tried.clone();
+        distinct.dedup();
+        assert_eq!(distinct, tried);
+        // The same seed draws the same peers.
+        assert_eq!(sample(7, 3), tried);
+        assert_eq!(sample(7, 10).len(), 5);
+    }
+
+    #[test]
+    fn test_broadcast_to_validators_only() {
+        let network_sender =
+            unstarted_sender_with_config(ProtocolConfiguration {
+                pos_broadcast_to_validators_only: true,
+                ..Default::default()
+            });
+        let signer = ValidatorSigner::from_int(1);
+        let non_validator = AccountAddress::random();
+        let handler = &network_sender.protocol_handler;
+        for (i, peer) in [signer.author(), non_validator].iter().enumerate() {
+            let peer_id = NodeId::from_low_u64_be(i as u64 + 1);
+            handler.peers.insert(keccak(&peer_id), peer_id, None);
+            handler
+                .pos_peer_mapping
+                .write()
+                .insert(*peer, keccak(&peer_id));
+            handler.pos_node_id_cache.write().insert(*peer, peer_id);
+        }
+        // The network is not started, so the messages are observed but fail
+        // to be sent.
+        let (observer, mut rx) = ChannelMsgObserver::new(16);
+        let author = AccountAddress::random();
+        let mut sender = ConsensusNetworkSender::new(
+            author,
+            network_sender,
+            ValidatorVerifier::new_single(
+                signer.author(),
+                signer.public_key(),
+                None,
+            ),
+        )
+        .with_observer(Arc::new(observer));
+
+        let ledger_info =
+            LedgerInfo::new(BlockInfo::empty(), HashValue::zero());
+        let qc = QuorumCert::certificate_for_genesis_from_ledger_info(
+            &ledger_info,
+            HashValue::zero(),
+        );
+        let proposal = ConsensusMsg::ProposalMsg(Box::new(ProposalMsg::new(
+            Block::new_proposal(vec![], 1, 1, qc.clone(), &signer),
+            SyncInfo::new(qc.clone(), qc, None),
+        )));
+        let skipped = || {
+            counters::NON_VALIDATOR_SENDS_SKIPPED
+                .with_label_values(&[proposal.name()])
+                .get()
+        };
+        let skipped_before = skipped();
+        block_on(sender.broadcast(proposal.clone(), vec![author]));
+        let (recipient, _) = rx.try_recv().unwrap();
+        assert_eq!(recipient, NodeId::from_low_u64_be(1));
+        assert!(rx.try_recv().is_err());
+        assert_eq!(skipped(), skipped_before + 1);
+    }
+
+    #[test]
+    fn test_send_failures_counted() {
+        let sender = ConsensusNetworkSender::new(
+            AccountAddress::random(),
+            unstarted_sender(),
+            ValidatorVerifier::new(BTreeMap::new()),
+        );
+        let signer = ValidatorSigner::from_int(1);
+        let ledger_info =
+            LedgerInfo::new(BlockInfo::empty(), HashValue::zero());
+        let qc = QuorumCert::certificate_for_genesis_from_ledger_info(
+            &ledger_info,
+            HashValue::zero(),
+        );
+        let vote_msg = ConsensusMsg::VoteMsg(Box::new(VoteMsg::new(
+            Vote::new(
+                VoteData::new(BlockInfo::empty(), BlockInfo::empty()),
+                signer.author(),
+                ledger_info,
+                &signer,
+            ),
+            SyncInfo::new(qc.clone(), qc, None),
+        )));
+        let not_found = counters::CONSENSUS_SEND_FAILURES
+            .with_label_values(&["VoteMsg", "peer_not_found"]);
+        let count = not_found.get();
+
+        // The recipient is not connected.
+        assert!(sender.send_to(AccountAddress::random(), &vote_msg).is_err());
+        // Other tests may fail to send votes at the same time.
+        assert!(not_found.get() > count);
+    }
+
+    #[test]
+    fn test_drain_pending_outgoing() {
+        let network_sender = unstarted_sender();
+        let handler = &network_sender.protocol_handler;
+        let peers: Vec<_> = (1..=2u64)
+            .map(|i| (AccountAddress::random(), NodeId::from_low_u64_be(i)))
+            .collect();
+        for (peer, peer_id) in &peers {
+            handler.peers.insert(keccak(peer_id), *peer_id, None);
+            handler
+                .pos_peer_mapping
+                .write()
+                .insert(*peer, keccak(peer_id));
+            handler.pos_node_id_cache.write().insert(*peer, *peer_id);
+        }
+        let mut sender = ConsensusNetworkSender::new(
+            AccountAddress::random(),
+            network_sender,
+            ValidatorVerifier::new(BTreeMap::new()),
+        )
+        .with_capture();
+        let msg = ConsensusMsg::EpochChangeProof(Box::new(
+            EpochChangeProof::new(vec![], false),
+        ));
+
+        // The network is not started, so the messages are only recorded.
+        sender.send_to(peers[0].0, &msg).unwrap();
+        let author = sender.author;
+        let outcome = block_on(sender.broadcast(msg.clone(), vec![author]));
+        assert_eq!(outcome.sent, 2);
+        let mut captured: Vec<_> = sender
+            .clone()
+            .drain_pending_outgoing()
+            .into_iter()
+            .map(|(peer_id, msg)| (peer_id, msg.name()))
+            .collect();
+        captured[1..].sort();
+        assert_eq!(
+            captured,
+            vec![
+                (peers[0].1, "EpochChangeProof"),
+                (peers[0].1, "EpochChangeProof"),
+                (peers[1].1, "EpochChangeProof"),
+            ]
+        );
+        assert!(sender.drain_pending_outgoing().is_empty());
+    }
+
+    #[test]
+    fn test_messages_mirrored() {
+        let network_sender = unstarted_sender();
+        let peer = AccountAddress::random();
+        let peer_id = NodeId::from_low_u64_be(1);
+        let handler = &network_sender.protocol_handler;
+        handler.peers.insert(keccak(&peer_id), peer_id, None);
+        handler
+            .pos_peer_mapping
+            .write()
+            .insert(peer, keccak(&peer_id));
+        handler.pos_node_id_cache.write().insert(peer, peer_id);
+
+        let mirror_sender = unstarted_sender();
+        let mirror = AccountAddress::random();
+        let mirror_id = NodeId::from_low_u64_be(2);
+        let handler = &mirror_sender.protocol_handler;
+        handler.peers.insert(keccak(&mirror_id), mirror_id, None);
+        handler
+            .pos_peer_mapping
+            .write()
+            .insert(mirror, keccak(&mirror_id));
+        handler.pos_node_id_cache.write().insert(mirror, mirror_id);
+        let mirror_sender = ConsensusNetworkSender::new(
+            AccountAddress::random(),
+            mirror_sender,
+            ValidatorVerifier::new(BTreeMap::new()),
+        )
+        .with_capture();
+
+        let sender = ConsensusNetworkSender::new(
+            AccountAddress::random(),
+            network_sender,
+            ValidatorVerifier::new(BTreeMap::new()),
+        )
+        .with_capture();
+        let mut mirrored =
+            sender.clone().with_mirror(mirror_sender.clone(), mirror);
+        let msg = ConsensusMsg::EpochChangeProof(Box::new(
+            EpochChangeProof::new(vec![], false),
+        ));
+        let mirrored_before = counters::CONSENSUS_MSGS_MIRRORED
+            .with_label_values(&[msg.name()])
+            .get();
+
+        mirrored.send_to(peer, &msg).unwrap();
+        let author = mirrored.author;
+        let outcome = block_on(mirrored.broadcast(msg.clone(), vec![author]));
+        assert_eq!(outcome.sent, 1);
+        let to_names = |captured: Vec<(NodeId, ConsensusMsg)>| {
+            captured
+                .into_iter()
+                .map(|(peer_id, msg)| (peer_id, msg.name()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            to_names(sender.drain_pending_outgoing()),
+            vec![(peer_id, "EpochChangeProof"); 2]
+        );
+        assert_eq!(
+            to_names(mirror_sender.drain_pending_outgoing()),
+            vec![(mirror_id, "EpochChangeProof"); 2]
+        );
+        assert!(
+            counters::CONSENSUS_MSGS_MIRRORED
+                .with_label_values(&[msg.name()])
+                .get()
+                >= mirrored_before + 2
+        );
+
+        // An unreachable mirror does not fail the send to the peer.
+        let failures_before = counters::CONSENSUS_MIRROR_FAILURES
+            .with_label_values(&[msg.name(), "peer_not_found"])
+            .get();
+        let unreachable =
+            sender.with_mirror(mirror_sender, AccountAddress::random());
+        unreachable.send_to(peer, &msg).unwrap();
+        assert_eq!(unreachable.drain_pending_outgoing().len(), 1);
+        assert!(
+            counters::CONSENSUS_MIRROR_FAILURES
+                .with_label_values(&[msg.name(), "peer_not_found"])
+                .get()
+                > failures_before
+        );
+    }
+
+    #[test]
+    fn test_broadcast_to_validator_peers() {
+        let network_sender =
+            unstarted_sender_with_config(ProtocolConfiguration {
+                pos_broadcast_to_validators_only: true,
+                ..Default::default()
+            });
+        let handler = &network_sender.protocol_handler;
+        let peers: Vec<_> = (1..=3u64)
+            .map(|i| (AccountAddress::random(), NodeId::from_low_u64_be(i)))
+            .collect();
+        for (peer, peer_id) in &peers {
+            handler.peers.insert(keccak(peer_id), *peer_id, None);
+            handler
+                .pos_peer_mapping
+                .write()
+                .insert(*peer, keccak(peer_id));
+            handler.pos_node_id_cache.write().insert(*peer, *peer_id);
+        }
+        // None of the peers is in the validator verifier, so only the
+        // validator sessions decide.
+        handler.set_validator_peers(
+            vec![peers[0].1, peers[2].1].into_iter().collect(),
+        );
+        let mut sender = ConsensusNetworkSender::new(
+            AccountAddress::random(),
+            network_sender,
+            ValidatorVerifier::new(BTreeMap::new()),
+        )
+        .with_capture();
+        let msg = ConsensusMsg::EpochChangeProof(Box::new(
+            EpochChangeProof::new(vec![], false),
+        ));
+
+        let author = sender.author;
+        let outcome = block_on(sender.broadcast(msg.clone(), vec![author]));
+        assert_eq!(outcome.sent, 2);
+        let outcome = sender
+            .send_to_many(peers.iter().map(|(peer, _)| *peer).collect(), &msg);
+        assert_eq!(outcome.sent, 2);
+        let mut captured: Vec<_> = sender
+            .drain_pending_outgoing()
+            .into_iter()
+            .map(|(peer_id, _)| peer_id)
+            .collect();
+        captured.sort();
+        assert_eq!(
+            captured,
+            vec![peers[0].1, peers[0].1, peers[2].1, peers[2].1]
+        );
+    }
+
+    #[test]
+    fn test_retrieve_ranges_with_failover() {
+        let signer = ValidatorSigner::from_int(1);
+        // The chain from the newest block, as it is retrieved.
+        let mut chain = vec![Block::make_genesis_block()];
+        for round in 1..=8 {
+            let parent = chain.last().unwrap();
+            let qc = placeholder_certificate_for_block(
+                vec![&signer],
+                parent.id(),
+                parent.round(),
+                parent.parent_id(),
+                parent.round().saturating_sub(1),
+            );
+            chain.push(Block::new_proposal(vec![], round, round, qc, &signer));
+        }
+        chain.reverse();
+        chain.pop();
+
+        // The first peer times out, the others return at most two blocks
+        // per response as if the byte limit was reached.
+        let peers: Vec<_> = (0..3).map(|_| AccountAddress::random()).collect();
+        let requests = Mutex::new(Vec::new());
+        let fetch = |request: BlockRetrievalRequest, peer: AccountAddress| {
+            requests.lock().push((peer, request.block_id()));
+            if peer == peers[0] {
+                return future::ready(Err(format_err!("rpc call failed")));
+            }
+            let start = chain
+                .iter()
+                .position(|block| block.id() == request.block_id())
+                .unwrap();
+            let end =
+                (start + request.num_blocks().min(2) as usize).min(chain.len());
+            let page = chain[start..end].to_vec();
+            let next_cursor = if (page.len() as u64) < request.num_blocks() {
+                page.last().map(Block::parent_id)
+            } else {
+                None
+            };
+            future::ready(Ok(BlockRetrievalResponse::new_with_cursor(
+                BlockRetrievalStatus::Succeeded,
+                page,
+                next_cursor,
+            )))
+        };
+        let ranges = vec![
+            BlockRetrievalRequest::new_with_byte_limit(chain[0].id(), 4, 1),
+            BlockRetrievalRequest::new_with_byte_limit(chain[4].id(), 4, 1),
+        ];
+
+        let blocks =
+            block_on(retrieve_ranges(&fetch, ranges, &peers, 2)).unwrap();
+        let ids = |blocks: &[Block]| -> Vec<_> {
+            blocks.iter().map(Block::id).collect()
+        };
+        assert_eq!(ids(&blocks), ids(&chain));
+        let mut sent = requests.lock().clone();
+        sent.sort_by_key(|(_, block_id)| {
+            chain.iter().position(|block| block.id() == *block_id)
+        });
+        // The first range failed over to the second peer once, and each
+        // range is continued from the cursor on the same peer.
+        assert_eq!(
+            sent,
+            vec![
+                (peers[0], chain[0].id()),
+                (peers[1], chain[0].id()),
+                (peers[1], chain[2].id()),
+                (peers[1], chain[4].id()),
+                (peers[1], chain[6].id()),
+            ]
+        );
+
+        // A range that does not continue the previous one is rejected.
+        let ranges = vec![
+            BlockRetrievalRequest::new(chain[0].id(), 2),
+            BlockRetrievalRequest::new(chain[4].id(), 4),
+        ];
+        assert!(block_on(retrieve_ranges(&fetch, ranges, &peers, 2)).is_err());
+    }
+}
@@ -24,6 +24,7 @@ use serde::Serialize;
 use consensus_types::{
     block::Block,
     block_retrieval::{BlockRetrievalResponse, BlockRetrievalStatus},
+    commit_vote_msg::CommitVoteMsg,
     common::{Author, Round},
     proposal_msg::ProposalMsg,
     quorum_cert::QuorumCert,
@@ -85,6 +86,7 @@ pub enum UnverifiedEvent {
     ProposalMsg(Box<ProposalMsg>),
     VoteMsg(Box<VoteMsg>),
     SyncInfo(Box<SyncInfo>),
+    CommitVote(Box<CommitVoteMsg>),
 }
 
 impl UnverifiedEvent {
@@ -104,6 +106,10 @@ impl UnverifiedEvent {
                 s.verify(validator)?;
                 VerifiedEvent::SyncInfo(s)
             }
+            UnverifiedEvent::CommitVote(v) => {
+                v.verify(validator)?;
+                VerifiedEvent::CommitVote(v)
+            }
         })
     }
 
@@ -112,6 +118,7 @@ impl UnverifiedEvent {
             UnverifiedEvent::ProposalMsg(p) => p.epoch(),
             UnverifiedEvent::VoteMsg(v) => v.epoch(),
             UnverifiedEvent::SyncInfo(s) => s.epoch(),
+            UnverifiedEvent::CommitVote(v) => v.epoch(),
         }
     }
 }
@@ -122,6 +129,7 @@ impl From<ConsensusMsg> for UnverifiedEvent {
             ConsensusMsg::ProposalMsg(m) => UnverifiedEvent::ProposalMsg(m),
             ConsensusMsg::VoteMsg(m) => UnverifiedEvent::VoteMsg(m),
             ConsensusMsg::SyncInfo(m) => UnverifiedEvent::SyncInfo(m),
+            ConsensusMsg::CommitVote(m) => UnverifiedEvent::CommitVote(m),
             _ => unreachable!("Unexpected conversion"),
         }
     }
@@ -131,6 +139,7 @@ pub enum VerifiedEvent {
     ProposalMsg(Box<ProposalMsg>),
     VoteMsg(Box<VoteMsg>),
     SyncInfo(Box<SyncInfo>),
+    CommitVote(Box<CommitVoteMsg>),
 }
 
 #[cfg(test)]
@@ -1078,6 +1087,17 @@ impl RoundManager {
         Ok(())
     }
 
+    /// Process a verified commit vote.
+    ///
+    /// Commit votes do not take part in the round vote bookkeeping, and the
+    /// commit phase does not aggregate them yet.
+    pub async fn process_commit_vote_msg(
+        &mut self, commit_vote: CommitVoteMsg,
+    ) -> anyhow::Result<()> {
+        diem_debug!("Receive commit vote: {}", commit_vote);
+        Ok(())
+    }
+
     /// Add a vote to the pending votes.
     /// If a new QC / TC is formed then
     /// 1) fetch missing dependencies if required, and then
@@ -1206,6 +1226,10 @@ impl RoundManager {
     /// an initial parent id, returning with <n (as many as possible) if
     /// id or its ancestors can not be found.
     ///
+    /// If the blocks exceed the byte limit of the request, the response is
+    /// truncated and carries the id of the next block as the cursor for the
+    /// following request.
+    ///
     /// The current version of the function is not really async, but keeping it
     /// this way for future possible changes.
     pub async fn process_block_retrieval(
@@ -1217,26 +1241,34 @@ impl RoundManager {
         let mut blocks = vec![];
         let mut status = BlockRetrievalStatus::Succeeded;
         let mut id = request.req.block_id();
+        let max_bytes = request.req.max_response_bytes();
+        let mut total_bytes = 0u64;
+        let mut next_cursor = None;
         while (blocks.len() as u64) < request.req.num_blocks() {
-            if let Some(executed_block) = self.block_store.get_block(id) {
-                if executed_block.block().is_genesis_block() {
-                    break;
-                }
-                id = executed_block.parent_id();
-                blocks.push(executed_block.block().clone());
+            let block = if let Some(executed_block) =
+                self.block_store.get_block(id)
+            {
+                executed_block.block().clone()
             } else if let Ok(Some(block)) =
                 self.block_store.get_ledger_block(&id)
             {
-                if block.is_genesis_block() {
-                    break;
-                }
-                id = block.parent_id();
-                blocks.push(block);
+                block
             } else {
                 // TODO(lpl): This error may be needed in the future.
                 // status = BlockRetrievalStatus::NotEnoughBlocks;
                 break;
+            };
+            if block.is_genesis_block() {
+                break;
+            }
+            total_bytes += bcs::serialized_size(&block)? as u64;
+            if max_bytes != 0 && total_bytes > max_bytes && !blocks.is_empty()
+            {
+                next_cursor = Some(id);
+                break;
             }
+            id = block.parent_id();
+            blocks.push(block);
         }
 
         if blocks.is_empty() {
@@ -1245,11 +1277,15 @@ impl RoundManager {
 
         let response = BlockRetrievalRpcResponse {
             request_id: request.request_id,
-            response: BlockRetrievalResponse::new(status, blocks),
+            response: BlockRetrievalResponse::new_with_cursor(
+                status,
+                blocks,
+                next_cursor,
+            ),
         };
         self.network
             .network_sender()
-            .send_message_with_peer_id(&request.peer_id, &response)?;
+            .send_to_node(&request.peer_id, &response)?;
         Ok(())
     }
 
@@ -0,0 +1,181 @@
+// Copyright 2021 Conflux Foundation. All rights reserved.
+// Conflux is free software and distributed under GNU General Public License.
+// See http://www.gnu.org/licenses/
+
+use crate::pos::{
+    consensus::network::{ConsensusMsg, ConsensusNetwork},
+    protocol::error::{BroadcastOutcome, NetworkError},
+};
+use consensus_types::{
+    block_retrieval::{BlockRetrievalRequest, BlockRetrievalResponse},
+    common::Author,
+};
+use diem_infallible::Mutex;
+use diem_types::account_address::AccountAddress;
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+
+/// A `ConsensusNetwork` without a network behind it. The sent messages are
+/// recorded, and the block retrieval RPCs are answered with the responses
+/// pushed by the test, in order.
+#[derive(Clone)]
+pub struct MockConsensusNetwork {
+    author: Author,
+    /// The peers `broadcast` sends to.
+    peers: Vec<Author>,
+    sent: Arc<Mutex<Vec<(Author, ConsensusMsg)>>>,
+    rpc_requests: Arc<Mutex<Vec<(Author, BlockRetrievalRequest)>>>,
+    rpc_responses: Arc<Mutex<VecDeque<anyhow::Result<BlockRetrievalResponse>>>>,
+}
+
+impl MockConsensusNetwork {
+    pub fn new(author: Author, peers: Vec<Author>) -> Self {
+        Self {
+            author,
+            peers,
+            sent: Default::default(),
+            rpc_requests: Default::default(),
+            rpc_responses: Default::default(),
+        }
+    }
+
+    /// Answer the next RPC with `response`. An RPC without any response
+    /// left fails with `NetworkError::RpcTimeout`.
+    pub fn push_rpc_response(
+        &self, response: anyhow::Result<BlockRetrievalResponse>,
+    ) {
+        self.rpc_responses.lock().push_back(response);
+    }
+
+    /// Take the messages sent so far with their recipients, including the
+    /// ones to self.
+    pub fn take_sent(&self) -> Vec<(Author, ConsensusMsg)> {
+        std::mem::take(&mut *self.sent.lock())
+    }
+
+    /// The RPCs sent so far with their recipients.
+    pub fn rpc_requests(&self) -> Vec<(Author, BlockRetrievalRequest)> {
+        self.rpc_requests.lock().clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl ConsensusNetwork for MockConsensusNetwork {
+    fn send_to(
+        &self, recipient: Author, msg: &ConsensusMsg,
+    ) -> Result<(), NetworkError> {
+        self.sent.lock().push((recipient, msg.clone()));
+        Ok(())
+    }
+
+    fn send_to_many(
+        &self, recipients: Vec<Author>, msg: &ConsensusMsg,
+    ) -> BroadcastOutcome {
+        let mut outcome = BroadcastOutcome::default();
+        for recipient in recipients {
+            match self.send_to(recipient, msg) {
+                Ok(()) => outcome.sent += 1,
+                Err(e) => outcome.failed.push((recipient, e)),
+            }
+        }
+        outcome
+    }
+
+    async fn send_rpc(
+        &self, request: BlockRetrievalRequest, from: Author, _timeout: Duration,
+    ) -> anyhow::Result<BlockRetrievalResponse> {
+        self.rpc_requests.lock().push((from, request));
+        self.rpc_responses
+            .lock()
+            .pop_front()
+            .unwrap_or_else(|| Err(NetworkError::RpcTimeout.into()))
+    }
+
+    async fn send_self_msg(
+        &self, msg: ConsensusMsg,
+    ) -> Result<(), NetworkError> {
+        self.sent.lock().push((self.author, msg));
+        Ok(())
+    }
+
+    async fn broadcast(
+        &mut self, msg: ConsensusMsg, exclude: Vec<AccountAddress>,
+    ) -> BroadcastOutcome {
+        let recipients = std::iter::once(self.author)
+            .chain(self.peers.iter().cloned())
+            .filter(|peer| !exclude.contains(peer));
+        let mut outcome = BroadcastOutcome::default();
+        for recipient in recipients {
+            self.sent.lock().push((recipient, msg.clone()));
+            outcome.sent += 1;
+        }
+        outcome
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MockConsensusNetwork;
+    use crate::pos::consensus::network::{ConsensusMsg, ConsensusNetwork};
+    use consensus_types::{
+        block_retrieval::{
+            BlockRetrievalRequest, BlockRetrievalResponse, BlockRetrievalStatus,
+        },
+        epoch_retrieval::EpochRetrievalRequest,
+    };
+    use diem_crypto::HashValue;
+    use diem_types::account_address::AccountAddress;
+    use std::time::Duration;
+
+    fn msg() -> ConsensusMsg {
+        ConsensusMsg::EpochRetrievalRequest(Box::new(EpochRetrievalRequest {
+            start_epoch: 0,
+            end_epoch: 1,
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_mock_consensus_network() {
+        let author = AccountAddress::random();
+        let peers = vec![AccountAddress::random(), AccountAddress::random()];
+        let mut network = MockConsensusNetwork::new(author, peers.clone());
+        // Used through the trait, as the consensus logic would.
+        let dyn_network: &mut dyn ConsensusNetwork = &mut network;
+
+        dyn_network.send_to(peers[0], &msg()).unwrap();
+        let outcome = dyn_network.send_to_many(peers.clone(), &msg());
+        assert_eq!(outcome.sent, 2);
+        assert!(outcome.is_complete());
+        let outcome = dyn_network.broadcast(msg(), vec![peers[1]]).await;
+        assert_eq!(outcome.sent, 2);
+        let recipients: Vec<_> = network
+            .take_sent()
+            .into_iter()
+            .map(|(recipient, _)| recipient)
+            .collect();
+        assert_eq!(
+            recipients,
+            vec![peers[0], peers[0], peers[1], author, peers[0]]
+        );
+        assert!(network.take_sent().is_empty());
+
+        network.push_rpc_response(Ok(BlockRetrievalResponse::new(
+            BlockRetrievalStatus::IdNotFound,
+            vec![],
+        )));
+        let request = BlockRetrievalRequest::new(HashValue::zero(), 1);
+        let timeout = Duration::from_secs(1);
+        let response = network
+            .send_rpc(request.clone(), peers[0], timeout)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), BlockRetrievalStatus::IdNotFound);
+        // No canned response is left.
+        assert!(network.send_rpc(request, peers[1], timeout).await.is_err());
+        let rpc_peers: Vec<_> = network
+            .rpc_requests()
+            .into_iter()
+            .map(|(peer, _)| peer)
+            .collect();
+        assert_eq!(rpc_peers, peers);
+    }
+}
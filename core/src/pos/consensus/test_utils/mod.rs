@@ -21,6 +21,7 @@ use pow_types::FakePowHandler;
 use std::{future::Future, sync::Arc, time::Duration};
 use tokio::{runtime, time::timeout};
 
+mod mock_network;
 mod mock_state_computer;
 mod mock_storage;
 #[cfg(any(test, feature = "fuzzing"))]
@@ -31,6 +32,7 @@ use consensus_types::{
     block::block_test_utils::gen_test_certificate, common::Payload,
 };
 use diem_types::block_info::BlockInfo;
+pub use mock_network::MockConsensusNetwork;
 pub use mock_state_computer::{EmptyStateComputer, MockStateComputer};
 pub use mock_storage::{EmptyStorage, MockSharedStorage, MockStorage};
 pub use mock_txn_manager::MockTransactionManager;
@@ -0,0 +1,211 @@
+// Copyright 2021 Conflux Foundation. All rights reserved.
+// Conflux is free software and distributed under GNU General Public License.
+// See http://www.gnu.org/licenses/
+
+//! The re-sends of the votes that may be lost on the way.
+//!
+//! On a lossy network a vote can be dropped, and its round times out even
+//! though the voter voted. With `VoteRebroadcastConfig` enabled, a vote sent
+//! to a peer is sent again after `initial_interval`, and again after twice
+//! the previous interval each time, at most `max_attempts` times. The peers
+//! do not acknowledge the votes, so the re-sends stop at once when the round
+//! of the vote is superseded instead, i.e. a `SyncInfo` certifying the round
+//! or a vote of a later round is observed.
+
+use std::time::Duration;
+
+use consensus_types::{common::Round, sync_info::SyncInfo};
+use diem_logger::prelude::*;
+use parking_lot::Mutex;
+use tokio::sync::watch;
+
+use crate::pos::protocol::error::NetworkError;
+
+use super::counters;
+
+/// The re-sends of the votes, see `VoteRebroadcaster`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VoteRebroadcastConfig {
+    /// The delay of the first re-send after a vote is sent.
+    pub initial_interval: Duration,
+    /// The most re-sends of a vote. 0 disables the re-sends.
+    pub max_attempts: u32,
+}
+
+impl VoteRebroadcastConfig {
+    /// Whether the votes are sent again.
+    pub fn is_enabled(&self) -> bool {
+        self.max_attempts > 0 && self.initial_interval > Duration::from_secs(0)
+    }
+}
+
+/// Re-sends the votes until their rounds are superseded.
+pub struct VoteRebroadcaster {
+    config: VoteRebroadcastConfig,
+    /// The highest epoch and round consensus is known to have reached.
+    current: Mutex<watch::Sender<(u64, Round)>>,
+    /// Kept so the sender always has a receiver, and is cloned for each
+    /// vote.
+    current_rx: watch::Receiver<(u64, Round)>,
+}
+
+impl VoteRebroadcaster {
+    pub fn new(config: VoteRebroadcastConfig) -> Self {
+        let (tx, current_rx) = watch::channel((0, 0));
+        Self {
+            config,
+            current: Mutex::new(tx),
+            current_rx,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool { self.config.is_enabled() }
+
+    /// Observe that consensus has reached `round` of `epoch`, which
+    /// supersedes the votes of the earlier rounds.
+    pub fn observe_round(&self, epoch: u64, round: Round) {
+        let current = self.current.lock();
+        if (epoch, round) > *current.borrow() {
+            // Never fails, as `current_rx` is kept.
+            let _ = current.send((epoch, round));
+        }
+    }
+
+    /// Observe `sync_info`, whose highest certified or timed out round is
+    /// over.
+    pub fn observe_sync_info(&self, sync_info: &SyncInfo) {
+        self.observe_round(sync_info.epoch(), sync_info.highest_round() + 1);
+    }
+
+    /// Send the vote of `round` of `epoch` again with `send`, with the
+    /// exponential backoff until its round is superseded or the attempts
+    /// run out. Does nothing if the re-sends are disabled or there is no
+    /// runtime to spawn them in.
+    pub fn rebroadcast<F>(&self, epoch: u64, round: Round, mut send: F)
+    where F: FnMut() -> Result<(), NetworkError> + Send + 'static {
+        if !self.is_enabled() || tokio::runtime::Handle::try_current().is_err()
+        {
+            return;
+        }
+        self.observe_round(epoch, round);
+        let config = self.config;
+        let mut current_rx = self.current_rx.clone();
+        tokio::spawn(async move {
+            let mut interval = config.initial_interval;
+            for attempt in 1..=config.max_attempts {
+                let sleep = tokio::time::sleep(interval);
+                tokio::pin!(sleep);
+                loop {
+                    if *current_rx.borrow() > (epoch, round) {
+                        counters::VOTE_REBROADCASTS_STOPPED
+                            .with_label_values(&["superseded"])
+                            .inc();
+                        return;
+                    }
+                    tokio::select! {
+                        _ = &mut sleep => break,
+                        changed = current_rx.changed() => {
+                            // The sender of the votes is dropped.
+                            if changed.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                }
+                counters::VOTE_REBROADCAST_ATTEMPTS.inc();
+                if let Err(e) = send() {
+                    diem_debug!(
+                        "Failed to send again the vote of epoch {} round {}, attempt {}: {:?}",
+                        epoch, round, attempt, e
+                    );
+                }
+                interval = interval.checked_mul(2).unwrap_or(interval);
+            }
+            counters::VOTE_REBROADCASTS_STOPPED
+                .with_label_values(&["exhausted"])
+                .inc();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{VoteRebroadcastConfig, VoteRebroadcaster};
+    use crate::pos::protocol::error::NetworkError;
+    use diem_types::account_address::AccountAddress;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    /// Wait until `sends` reaches `count`, at most for a second.
+    async fn wait_for_sends(sends: &AtomicUsize, count: usize) {
+        for _ in 0..1000 {
+            if sends.load(Ordering::SeqCst) >= count {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        panic!("only {} sends", sends.load(Ordering::SeqCst));
+    }
+
+    fn rebroadcaster() -> VoteRebroadcaster {
+        VoteRebroadcaster::new(VoteRebroadcastConfig {
+            initial_interval: Duration::from_millis(10),
+            max_attempts: 3,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_resent_after_dropped_send() {
+        let rebroadcaster = rebroadcaster();
+        let sends = Arc::new(AtomicUsize::new(0));
+        // The vote is dropped when it is sent first, and only the re-sends
+        // are counted.
+        let counted = sends.clone();
+        rebroadcaster.rebroadcast(1, 5, move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+            Err(NetworkError::PeerNotConnected(AccountAddress::random()))
+        });
+        wait_for_sends(&sends, 1).await;
+
+        // The attempts run out even if every re-send fails.
+        wait_for_sends(&sends, 3).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(sends.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_stopped_when_superseded() {
+        let rebroadcaster = rebroadcaster();
+        let sends = Arc::new(AtomicUsize::new(0));
+        let counted = sends.clone();
+        rebroadcaster.rebroadcast(1, 5, move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+        // Neither the round of the vote nor an earlier one supersedes it.
+        rebroadcaster.observe_round(1, 5);
+        rebroadcaster.observe_round(0, 9);
+        wait_for_sends(&sends, 1).await;
+
+        // No re-send after a later round is reached.
+        rebroadcaster.observe_round(1, 6);
+        let sent = sends.load(Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(sends.load(Ordering::SeqCst), sent);
+    }
+
+    #[test]
+    fn test_disabled() {
+        assert!(!VoteRebroadcastConfig::default().is_enabled());
+        assert!(!VoteRebroadcastConfig {
+            initial_interval: Duration::from_millis(10),
+            max_attempts: 0,
+        }
+        .is_enabled());
+    }
+}
@@ -0,0 +1,253 @@
+// Copyright 2021 Conflux Foundation. All rights reserved.
+// Conflux is free software and distributed under GNU General Public License.
+// See http://www.gnu.org/licenses/
+
+//! A write-ahead record of the last vote sent by this node.
+//!
+//! The safety rules should never sign two different votes for one round, but
+//! the network sender is the last point a vote passes before it leaves the
+//! node. `VoteRecorder` persists the round and the hash of each vote before
+//! it is sent, and refuses to send another vote for a recorded round, so a
+//! node that crashes mid-round does not equivocate after it restarts.
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{bail, Context};
+use consensus_types::{common::Round, vote::Vote};
+use diem_crypto::{hash::CryptoHash, HashValue};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use super::counters;
+
+/// The name of the file `FileVoteRecordStore` keeps the record in.
+pub const LAST_VOTE_FILE: &str = "last_sent_vote";
+
+/// The last vote sent, identified by the hash of its `VoteData`, so a
+/// timeout vote resending the same vote data is the same vote.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LastVoteRecord {
+    pub epoch: u64,
+    pub round: Round,
+    pub vote_hash: HashValue,
+}
+
+impl LastVoteRecord {
+    pub fn from_vote(vote: &Vote) -> Self {
+        Self {
+            epoch: vote.epoch(),
+            round: vote.vote_data().proposed().round(),
+            vote_hash: vote.vote_data().hash(),
+        }
+    }
+
+    fn epoch_round(&self) -> (u64, Round) { (self.epoch, self.round) }
+}
+
+/// Where the last vote record is persisted.
+pub trait VoteRecordStore: Send + Sync {
+    /// Persist `record`, replacing the previous one. The record must be
+    /// durable when this returns.
+    fn save(&self, record: &LastVoteRecord) -> anyhow::Result<()>;
+
+    /// The record last saved, or `None` if nothing is saved.
+    fn load(&self) -> anyhow::Result<Option<LastVoteRecord>>;
+}
+
+/// Keeps the record in memory, for tests.
+#[derive(Default)]
+pub struct InMemoryVoteRecordStore {
+    record: Mutex<Option<LastVoteRecord>>,
+}
+
+impl VoteRecordStore for InMemoryVoteRecordStore {
+    fn save(&self, record: &LastVoteRecord) -> anyhow::Result<()> {
+        *self.record.lock() = Some(*record);
+        Ok(())
+    }
+
+    fn load(&self) -> anyhow::Result<Option<LastVoteRecord>> {
+        Ok(*self.record.lock())
+    }
+}
+
+/// Keeps the record BCS encoded in a file. The record is written to a
+/// temporary file and renamed over the old one, so a crash while saving
+/// leaves either the old or the new record.
+pub struct FileVoteRecordStore {
+    path: PathBuf,
+}
+
+impl FileVoteRecordStore {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    fn tmp_path(&self) -> PathBuf { self.path.with_extension("tmp") }
+}
+
+impl VoteRecordStore for FileVoteRecordStore {
+    fn save(&self, record: &LastVoteRecord) -> anyhow::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp_path = self.tmp_path();
+        let mut file = fs::File::create(&tmp_path)
+            .with_context(|| format!("create {:?}", tmp_path))?;
+        file.write_all(&bcs::to_bytes(record)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("rename to {:?}", self.path))?;
+        Ok(())
+    }
+
+    fn load(&self) -> anyhow::Result<Option<LastVoteRecord>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let bytes = fs::read(&self.path)
+            .with_context(|| format!("read {:?}", self.path))?;
+        Ok(Some(bcs::from_bytes(&bytes)?))
+    }
+}
+
+/// Records the votes before they are sent, see the module doc.
+pub struct VoteRecorder {
+    store: Arc<dyn VoteRecordStore>,
+    last: Mutex<Option<LastVoteRecord>>,
+}
+
+impl VoteRecorder {
+    /// A recorder starting from the record persisted in `store`.
+    pub fn new(store: Arc<dyn VoteRecordStore>) -> anyhow::Result<Self> {
+        let last = store.load()?;
+        Ok(Self {
+            store,
+            last: Mutex::new(last),
+        })
+    }
+
+    /// The last vote recorded, including the one persisted before a restart.
+    pub fn last_vote(&self) -> Option<LastVoteRecord> { *self.last.lock() }
+
+    /// Record `vote` before it is sent. It fails if another vote is recorded
+    /// for the same round, or if the record cannot be persisted, and then
+    /// the vote must not be sent.
+    ///
+    /// Resending the recorded vote and sending a vote older than the recorded
+    /// one, which the safety rules have signed before, do not change the
+    /// record.
+    pub fn record(&self, vote: &Vote) -> anyhow::Result<()> {
+        let record = LastVoteRecord::from_vote(vote);
+        let mut last = self.last.lock();
+        if let Some(recorded) = last.as_ref() {
+            if recorded.epoch_round() == record.epoch_round() {
+                if recorded.vote_hash != record.vote_hash {
+                    counters::EQUIVOCATING_VOTES_REFUSED.inc();
+                    bail!(
+                        "refuse to send vote {} for epoch {} round {}, \
+                         which has voted {}",
+                        record.vote_hash,
+                        record.epoch,
+                        record.round,
+                        recorded.vote_hash
+                    );
+                }
+                return Ok(());
+            }
+            if recorded.epoch_round() > record.epoch_round() {
+                return Ok(());
+            }
+        }
+        // The record in memory is only updated after it is persisted, so a
+        // vote that fails to persist is persisted again when it is resent.
+        self.store.save(&record)?;
+        *last = Some(record);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        FileVoteRecordStore, InMemoryVoteRecordStore, LastVoteRecord,
+        VoteRecordStore, VoteRecorder, LAST_VOTE_FILE,
+    };
+    use consensus_types::{vote::Vote, vote_data::VoteData};
+    use diem_crypto::HashValue;
+    use diem_temppath::TempPath;
+    use diem_types::{
+        block_info::BlockInfo, ledger_info::LedgerInfo,
+        validator_signer::ValidatorSigner,
+    };
+    use std::sync::Arc;
+
+    fn vote(epoch: u64, round: u64, block_id: HashValue) -> Vote {
+        let signer = ValidatorSigner::from_int(1);
+        let proposed = BlockInfo::new(
+            epoch,
+            round,
+            block_id,
+            HashValue::zero(),
+            0,
+            0,
+            None,
+            None,
+        );
+        Vote::new(
+            VoteData::new(proposed, BlockInfo::empty()),
+            signer.author(),
+            LedgerInfo::new(BlockInfo::empty(), HashValue::zero()),
+            &signer,
+        )
+    }
+
+    #[test]
+    fn test_refuse_equivocating_vote() {
+        let store = Arc::new(InMemoryVoteRecordStore::default());
+        let recorder = VoteRecorder::new(store.clone()).unwrap();
+        assert_eq!(recorder.last_vote(), None);
+
+        let first = vote(1, 5, HashValue::random());
+        recorder.record(&first).unwrap();
+        // Resending the same vote is fine.
+        recorder.record(&first).unwrap();
+        assert!(recorder.record(&vote(1, 5, HashValue::random())).is_err());
+        recorder.record(&vote(1, 4, HashValue::random())).unwrap();
+        assert_eq!(
+            recorder.last_vote(),
+            Some(LastVoteRecord::from_vote(&first))
+        );
+
+        // The record survives a restart.
+        let recorder = VoteRecorder::new(store).unwrap();
+        assert!(recorder.record(&vote(1, 5, HashValue::random())).is_err());
+        let next = vote(2, 1, HashValue::random());
+        recorder.record(&next).unwrap();
+        assert_eq!(
+            recorder.last_vote(),
+            Some(LastVoteRecord::from_vote(&next))
+        );
+    }
+
+    #[test]
+    fn test_file_store() {
+        let dir = TempPath::new();
+        dir.create_as_dir().unwrap();
+        let store = FileVoteRecordStore::new(dir.path().join(LAST_VOTE_FILE));
+        assert_eq!(store.load().unwrap(), None);
+
+        let record =
+            LastVoteRecord::from_vote(&vote(3, 7, HashValue::random()));
+        store.save(&record).unwrap();
+        let store = FileVoteRecordStore::new(dir.path().join(LAST_VOTE_FILE));
+        assert_eq!(store.load().unwrap(), Some(record));
+    }
+}
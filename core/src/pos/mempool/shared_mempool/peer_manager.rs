@@ -296,7 +296,7 @@ impl PeerManager {
             return;
         }
 
-        if let Err(e) = smp.network_sender.send_message_with_peer_id(
+        if let Err(e) = smp.network_sender.send_to_node(
             &peer,
             &MempoolSyncMsg::BroadcastTransactionsRequest {
                 request_id: bcs::to_bytes(&batch_id)
@@ -139,10 +139,7 @@ pub(crate) async fn process_transaction_broadcast(
     log_txn_process_results(&results, Some(peer.clone()));
 
     let ack_response = gen_ack_response(request_id, results, &peer);
-    if let Err(e) = smp
-        .network_sender
-        .send_message_with_peer_id(&peer, &ack_response)
-    {
+    if let Err(e) = smp.network_sender.send_to_node(&peer, &ack_response) {
         counters::network_send_fail_inc(counters::ACK_TXNS);
         diem_error!(LogSchema::event_log(
             LogEntry::BroadcastACK,
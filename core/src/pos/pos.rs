@@ -79,6 +79,7 @@ pub struct PosDropHandle {
         oneshot::Sender<anyhow::Result<SubmissionStatus>>,
     )>,
     pub stopped: Arc<AtomicBool>,
+    network_sender: NetworkSender,
     _mempool: Runtime,
     _state_sync_bootstrapper: StateSyncBootstrapper,
     _consensus_runtime: Runtime,
@@ -325,7 +326,7 @@ pub fn setup_pos_environment(
     let (consensus_runtime, pow_handler, stopped, consensus_db) =
         start_consensus(
             node_config,
-            network_sender,
+            network_sender.clone(),
             consensus_network_receiver,
             consensus_to_mempool_sender,
             state_sync_client,
@@ -354,6 +355,7 @@ pub fn setup_pos_environment(
         cached_db: db_with_cache,
         consensus_db,
         tx_sender: mp_client_sender,
+        network_sender,
     }
 }
 
@@ -361,6 +363,7 @@ impl Drop for PosDropHandle {
     fn drop(&mut self) {
         debug!("Drop PosDropHandle");
         self.stopped.store(true, Ordering::SeqCst);
+        self.network_sender.shutdown();
         self.pow_handler.stop();
     }
 }
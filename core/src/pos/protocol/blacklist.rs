@@ -0,0 +1,158 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+//! The peers shunned for a while after repeated protocol violations.
+//!
+//! The protocol violations of each peer are recorded, e.g. the malformed
+//! messages and the equivocating proposals, and a peer with
+//! `max_violations` violations within `ttl` is blacklisted for `ttl`.
+//! Nothing is received from or sent to a blacklisted peer, and it is
+//! disconnected at once if it connects again before its entry expires.
+//!
+//! The violations are kept over the reconnections of the peer, as a peer is
+//! often disconnected for a violation.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use network::node_table::NodeId;
+use parking_lot::Mutex;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PeerBlacklistConfig {
+    /// How long a peer is blacklisted, which is also the window its
+    /// violations are counted in. 0 disables the blacklist.
+    pub ttl: Duration,
+    /// The number of the violations within `ttl` that get a peer
+    /// blacklisted, at least 1.
+    pub max_violations: u32,
+}
+
+impl PeerBlacklistConfig {
+    pub fn is_enabled(&self) -> bool { self.ttl > Duration::from_secs(0) }
+}
+
+pub struct PeerBlacklist {
+    config: PeerBlacklistConfig,
+    /// The times of the recent violations of each peer.
+    violations: Mutex<HashMap<NodeId, VecDeque<Instant>>>,
+    /// The blacklisted peers, with when their entries expire.
+    blacklisted: Mutex<HashMap<NodeId, Instant>>,
+}
+
+impl PeerBlacklist {
+    pub fn new(config: &PeerBlacklistConfig) -> Self {
+        Self {
+            config: *config,
+            violations: Default::default(),
+            blacklisted: Default::default(),
+        }
+    }
+
+    /// Record a protocol violation of `peer`. Returns whether the peer is
+    /// blacklisted for it.
+    pub fn on_violation(&self, peer: &NodeId) -> bool {
+        self.on_violation_at(peer, Instant::now())
+    }
+
+    fn on_violation_at(&self, peer: &NodeId, now: Instant) -> bool {
+        if !self.config.is_enabled() || self.is_blacklisted_at(peer, now) {
+            return false;
+        }
+        let ttl = self.config.ttl;
+        let mut violations = self.violations.lock();
+        // Forget the violations out of the window, of all the peers.
+        violations.retain(|_, times| {
+            while let Some(time) = times.front() {
+                if now.saturating_duration_since(*time) < ttl {
+                    break;
+                }
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+        let times = violations.entry(*peer).or_default();
+        times.push_back(now);
+        if times.len() < self.config.max_violations.max(1) as usize {
+            return false;
+        }
+        violations.remove(peer);
+        self.blacklisted.lock().insert(*peer, now + ttl);
+        true
+    }
+
+    /// Whether `peer` is blacklisted now.
+    pub fn is_blacklisted(&self, peer: &NodeId) -> bool {
+        self.is_blacklisted_at(peer, Instant::now())
+    }
+
+    fn is_blacklisted_at(&self, peer: &NodeId, now: Instant) -> bool {
+        if !self.config.is_enabled() {
+            return false;
+        }
+        let mut blacklisted = self.blacklisted.lock();
+        match blacklisted.get(peer) {
+            Some(expiry) if *expiry > now => true,
+            Some(_) => {
+                blacklisted.remove(peer);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// The blacklisted peers with how long they are still blacklisted, for
+    /// debugging.
+    pub fn entries(&self) -> Vec<(NodeId, Duration)> {
+        let now = Instant::now();
+        self.blacklisted
+            .lock()
+            .iter()
+            .filter(|(_, expiry)| **expiry > now)
+            .map(|(peer, expiry)| (*peer, *expiry - now))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PeerBlacklist, PeerBlacklistConfig};
+    use network::node_table::NodeId;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_blacklisted_after_violations() {
+        let ttl = Duration::from_secs(60);
+        let blacklist = PeerBlacklist::new(&PeerBlacklistConfig {
+            ttl,
+            max_violations: 2,
+        });
+        let peer = NodeId::from_low_u64_be(1);
+        let now = Instant::now();
+
+        // The violations out of the window are not counted.
+        assert!(!blacklist.on_violation_at(&peer, now));
+        assert!(!blacklist.on_violation_at(&peer, now + ttl));
+        assert!(!blacklist.is_blacklisted_at(&peer, now + ttl));
+        assert!(blacklist.on_violation_at(&peer, now + ttl * 3 / 2));
+        assert!(blacklist.is_blacklisted_at(&peer, now + ttl * 2));
+        assert_eq!(blacklist.entries().len(), 1);
+
+        // The entry expires after the ttl.
+        assert!(!blacklist.is_blacklisted_at(&peer, now + ttl * 5 / 2));
+        assert!(blacklist.entries().is_empty());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let blacklist = PeerBlacklist::new(&PeerBlacklistConfig::default());
+        let peer = NodeId::from_low_u64_be(1);
+        for _ in 0..10 {
+            assert!(!blacklist.on_violation(&peer));
+        }
+        assert!(!blacklist.is_blacklisted(&peer));
+    }
+}
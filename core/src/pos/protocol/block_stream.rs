@@ -0,0 +1,515 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+//! The blocks pushed by a peer in a stream under the flow control of the
+//! receiver.
+//!
+//! A deep sync retrieving the blocks a chunk per RPC pays a round trip for
+//! each chunk. A stream is opened instead by one `BlockStreamOpen`, after
+//! which the peer pushes the blocks from the requested one backwards in
+//! `BlockStreamChunk`s without being asked again. The receiver grants a
+//! window of credit with the open request, and the peer pushes at most
+//! `window` blocks the receiver has not acknowledged. The receiver
+//! acknowledges the blocks of each chunk it consumes with a
+//! `BlockStreamCredit`, which lets the peer push as many more. The pushing
+//! side also caps the window at `pos_block_stream_max_in_flight`, so a
+//! receiver granting too much cannot make it queue unbounded blocks, and
+//! pushes at most `MAX_OUTGOING_STREAMS_PER_PEER` streams to each peer. The
+//! opens beyond it, of an empty window or of the id of a stream still pushed
+//! are closed at once.
+
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering as AtomicOrdering},
+};
+
+use consensus_types::{block::Block, block_retrieval::BlockRetrievalRequest};
+use diem_crypto::HashValue;
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    StreamExt,
+};
+use network::node_table::NodeId;
+use parking_lot::Mutex;
+
+use crate::pos::protocol::{
+    message::block_stream::{BlockStreamChunk, BlockStreamCredit},
+    network_sender::NetworkSender,
+};
+
+/// The most blocks pushed in one `BlockStreamChunk`.
+pub const MAX_STREAM_CHUNK_BLOCKS: u32 = 16;
+
+/// The most streams pushed to one peer at a time.
+pub const MAX_OUTGOING_STREAMS_PER_PEER: usize = 8;
+
+/// The blocks a stream is pushed from, i.e. the block store of consensus.
+pub trait BlockStreamSource: Send + Sync {
+    /// The block `block_id`, if it is known.
+    fn get_block(&self, block_id: &HashValue) -> Option<Block>;
+}
+
+/// The pushing side of a stream, see the module doc.
+#[derive(Debug)]
+pub struct OutgoingBlockStream {
+    /// The block pushed next, or None once the blocks run out.
+    next_block_id: Option<HashValue>,
+    /// The blocks left to push.
+    remaining: u64,
+    window: u32,
+    /// The blocks pushed and not acknowledged.
+    in_flight: u32,
+}
+
+impl OutgoingBlockStream {
+    /// Push the blocks of `request` within `window`, capped by
+    /// `max_in_flight` unless it is 0.
+    pub fn new(
+        request: &BlockRetrievalRequest, window: u32, max_in_flight: u32,
+    ) -> Self {
+        Self {
+            next_block_id: Some(request.block_id()),
+            remaining: request.num_blocks(),
+            window: if max_in_flight == 0 {
+                window
+            } else {
+                window.min(max_in_flight)
+            },
+            in_flight: 0,
+        }
+    }
+
+    pub fn in_flight(&self) -> u32 { self.in_flight }
+
+    /// Note `acked` blocks consumed by the receiver.
+    pub fn on_credit(&mut self, acked: u32) {
+        self.in_flight -= acked.min(self.in_flight);
+    }
+
+    /// The next chunk of blocks from `source` within the window, or None if
+    /// the window is full. A chunk is returned for all the blocks pushed.
+    /// The last chunk is `done`, and may be empty if the blocks run out.
+    pub fn next_chunk(
+        &mut self, stream_id: u64, source: &dyn BlockStreamSource,
+    ) -> Option<BlockStreamChunk> {
+        let next_block_id = self.next_block_id?;
+        let room = (self.window - self.in_flight).min(MAX_STREAM_CHUNK_BLOCKS);
+        if room == 0 && self.remaining > 0 {
+            return None;
+        }
+        let mut blocks = Vec::new();
+        let mut block_id = Some(next_block_id);
+        while (blocks.len() as u64) < self.remaining.min(room as u64) {
+            let block = match block_id.and_then(|id| source.get_block(&id)) {
+                Some(block) if !block.is_genesis_block() => block,
+                _ => {
+                    block_id = None;
+                    break;
+                }
+            };
+            block_id = Some(block.parent_id());
+            blocks.push(block);
+        }
+        self.remaining -= blocks.len() as u64;
+        self.in_flight += blocks.len() as u32;
+        self.next_block_id = block_id.filter(|_| self.remaining > 0);
+        Some(BlockStreamChunk {
+            stream_id,
+            blocks,
+            done: self.next_block_id.is_none(),
+        })
+    }
+}
+
+/// The receiving side of a stream, see the module doc.
+struct IncomingBlockStream {
+    window: u32,
+    /// The blocks received and not consumed.
+    in_flight: u32,
+    blocks_tx: UnboundedSender<Vec<Block>>,
+}
+
+/// The streams opened with or by the peers.
+#[derive(Default)]
+pub struct BlockStreams {
+    next_stream_id: AtomicU64,
+    outgoing: Mutex<HashMap<(NodeId, u64), OutgoingBlockStream>>,
+    incoming: Mutex<HashMap<(NodeId, u64), IncomingBlockStream>>,
+}
+
+impl BlockStreams {
+    /// Open a stream received from `peer` with `window`, and return its
+    /// id with the receiver of the chunks.
+    pub fn open_incoming(
+        &self, peer: &NodeId, window: u32,
+    ) -> (u64, UnboundedReceiver<Vec<Block>>) {
+        let stream_id =
+            self.next_stream_id.fetch_add(1, AtomicOrdering::SeqCst);
+        let (blocks_tx, blocks_rx) = mpsc::unbounded();
+        self.incoming.lock().insert(
+            (*peer, stream_id),
+            IncomingBlockStream {
+                window,
+                in_flight: 0,
+                blocks_tx,
+            },
+        );
+        (stream_id, blocks_rx)
+    }
+
+    /// Pass the chunk received from `peer` to the receiver of its stream.
+    /// Returns an error if the peer pushes more than the window, and
+    /// ignores the chunks of the streams closed.
+    pub fn on_chunk(
+        &self, peer: &NodeId, chunk: BlockStreamChunk,
+    ) -> Result<(), String> {
+        let key = (*peer, chunk.stream_id);
+        let mut incoming = self.incoming.lock();
+        let stream = match incoming.get_mut(&key) {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+        let in_flight = stream.in_flight + chunk.blocks.len() as u32;
+        if in_flight > stream.window {
+            incoming.remove(&key);
+            return Err(format!(
+                "stream {} pushes {} blocks beyond the window {}",
+                chunk.stream_id, in_flight, stream.window
+            ));
+        }
+        stream.in_flight = in_flight;
+        // The receiver may be dropped, after which the stream is closed.
+        let _ = stream.blocks_tx.unbounded_send(chunk.blocks);
+        if chunk.done {
+            incoming.remove(&key);
+        }
+        Ok(())
+    }
+
+    /// Note `consumed` blocks of the stream consumed by the receiver, and
+    /// return whether the stream is still open.
+    pub fn on_consumed(
+        &self, peer: &NodeId, stream_id: u64, consumed: u32,
+    ) -> bool {
+        match self.incoming.lock().get_mut(&(*peer, stream_id)) {
+            Some(stream) => {
+                stream.in_flight -= consumed.min(stream.in_flight);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Close the stream from `peer` whose receiver is dropped, and return
+    /// whether it is still open.
+    pub fn close_incoming(&self, peer: &NodeId, stream_id: u64) -> bool {
+        self.incoming.lock().remove(&(*peer, stream_id)).is_some()
+    }
+
+    /// Start pushing the stream `stream_id` opened by `peer`. Returns an
+    /// error without opening it if its window is empty, its id is of a
+    /// stream still pushed, or the peer has too many streams open.
+    pub fn open_outgoing(
+        &self, peer: &NodeId, stream_id: u64, stream: OutgoingBlockStream,
+    ) -> Result<(), String> {
+        if stream.window == 0 {
+            return Err(format!("stream {} has an empty window", stream_id));
+        }
+        let key = (*peer, stream_id);
+        let mut outgoing = self.outgoing.lock();
+        if outgoing.contains_key(&key) {
+            return Err(format!("stream {} is already open", stream_id));
+        }
+        let open = outgoing.keys().filter(|(p, _)| p == peer).count();
+        if open >= MAX_OUTGOING_STREAMS_PER_PEER {
+            return Err(format!(
+                "{} streams are already open, stream {} is not opened",
+                open, stream_id
+            ));
+        }
+        outgoing.insert(key, stream);
+        Ok(())
+    }
+
+    /// Note the credit from `peer`, or close the stream if it is
+    /// canceled.
+    pub fn on_credit(&self, peer: &NodeId, credit: &BlockStreamCredit) {
+        let key = (*peer, credit.stream_id);
+        let mut outgoing = self.outgoing.lock();
+        if credit.cancel {
+            outgoing.remove(&key);
+        } else if let Some(stream) = outgoing.get_mut(&key) {
+            stream.on_credit(credit.acked);
+        }
+    }
+
+    /// The chunks of the stream to `peer` that fit in its window now. The
+    /// stream is removed once its last chunk is taken.
+    pub fn next_chunks(
+        &self, peer: &NodeId, stream_id: u64, source: &dyn BlockStreamSource,
+    ) -> Vec<BlockStreamChunk> {
+        let key = (*peer, stream_id);
+        let mut outgoing = self.outgoing.lock();
+        let stream = match outgoing.get_mut(&key) {
+            Some(stream) => stream,
+            None => return Vec::new(),
+        };
+        let mut chunks = Vec::new();
+        while let Some(chunk) = stream.next_chunk(stream_id, source) {
+            let done = chunk.done;
+            chunks.push(chunk);
+            if done {
+                outgoing.remove(&key);
+                break;
+            }
+        }
+        chunks
+    }
+
+    /// Drop the streams with the disconnected `peer`, whose receivers then
+    /// end.
+    pub fn remove_peer(&self, peer: &NodeId) {
+        self.outgoing.lock().retain(|(p, _), _| p != peer);
+        self.incoming.lock().retain(|(p, _), _| p != peer);
+    }
+}
+
+/// A stream of blocks opened with a peer, see
+/// `NetworkSender::open_block_stream`. The blocks are not verified.
+pub struct BlockStream {
+    network_sender: NetworkSender,
+    peer_id: NodeId,
+    stream_id: u64,
+    blocks_rx: UnboundedReceiver<Vec<Block>>,
+    done: bool,
+}
+
+impl BlockStream {
+    pub fn new(
+        network_sender: NetworkSender, peer_id: NodeId, stream_id: u64,
+        blocks_rx: UnboundedReceiver<Vec<Block>>,
+    ) -> Self
+    {
+        Self {
+            network_sender,
+            peer_id,
+            stream_id,
+            blocks_rx,
+            done: false,
+        }
+    }
+
+    /// The next chunk of blocks, whose blocks the peer is credited with,
+    /// or None once the stream ends or the peer is disconnected.
+    pub async fn next_chunk(&mut self) -> Option<Vec<Block>> {
+        let blocks = match self.blocks_rx.next().await {
+            Some(blocks) => blocks,
+            None => {
+                self.done = true;
+                return None;
+            }
+        };
+        let streams = &self.network_sender.protocol_handler.block_streams;
+        if streams.on_consumed(
+            &self.peer_id,
+            self.stream_id,
+            blocks.len() as u32,
+        ) {
+            self.send_credit(blocks.len() as u32, false);
+        }
+        Some(blocks)
+    }
+
+    fn send_credit(&self, acked: u32, cancel: bool) {
+        let credit = BlockStreamCredit {
+            stream_id: self.stream_id,
+            acked,
+            cancel,
+        };
+        if let Err(e) = self.network_sender.send_to_node(&self.peer_id, &credit)
+        {
+            debug!(
+                "failed to send block stream credit to {}: {:?}",
+                self.peer_id, e
+            );
+        }
+    }
+}
+
+// The peer stops pushing the stream dropped before it ends.
+impl Drop for BlockStream {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        if self
+            .network_sender
+            .protocol_handler
+            .block_streams
+            .close_incoming(&self.peer_id, self.stream_id)
+        {
+            self.send_credit(0, true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        BlockStreamSource, BlockStreams, OutgoingBlockStream,
+        MAX_OUTGOING_STREAMS_PER_PEER, MAX_STREAM_CHUNK_BLOCKS,
+    };
+    use crate::pos::protocol::message::block_stream::BlockStreamCredit;
+    use consensus_types::{
+        block::{
+            block_test_utils::{
+                certificate_for_genesis, placeholder_certificate_for_block,
+            },
+            Block,
+        },
+        block_retrieval::BlockRetrievalRequest,
+    };
+    use diem_crypto::HashValue;
+    use diem_types::validator_signer::ValidatorSigner;
+    use futures::{FutureExt, StreamExt};
+    use network::node_table::NodeId;
+    use std::collections::HashMap;
+
+    struct Chain(HashMap<HashValue, Block>);
+
+    impl BlockStreamSource for Chain {
+        fn get_block(&self, block_id: &HashValue) -> Option<Block> {
+            self.0.get(block_id).cloned()
+        }
+    }
+
+    /// A chain of `len` blocks above genesis, and the id of the last one.
+    fn chain(len: u64) -> (Chain, HashValue) {
+        let signer = ValidatorSigner::from_int(1);
+        let genesis = Block::make_genesis_block();
+        let (mut parent_id, mut parent_round) = (genesis.id(), 0);
+        let mut qc = certificate_for_genesis();
+        let mut blocks = HashMap::new();
+        let mut tip = genesis.id();
+        blocks.insert(genesis.id(), genesis);
+        for round in 1..=len {
+            let block =
+                Block::new_proposal(vec![], round, round, qc.clone(), &signer);
+            tip = block.id();
+            qc = placeholder_certificate_for_block(
+                vec![&signer],
+                tip,
+                round,
+                parent_id,
+                parent_round,
+            );
+            parent_id = tip;
+            parent_round = round;
+            blocks.insert(tip, block);
+        }
+        (Chain(blocks), tip)
+    }
+
+    #[test]
+    fn test_in_flight_blocks_bounded_by_window() {
+        let (chain, tip) = chain(10);
+        let peer = NodeId::from_low_u64_be(1);
+        let (sender, receiver) =
+            (BlockStreams::default(), BlockStreams::default());
+        let window = 3;
+        let (stream_id, mut blocks_rx) = receiver.open_incoming(&peer, window);
+        let request = BlockRetrievalRequest::new(tip, 10);
+        sender
+            .open_outgoing(
+                &peer,
+                stream_id,
+                OutgoingBlockStream::new(
+                    &request,
+                    window,
+                    MAX_STREAM_CHUNK_BLOCKS,
+                ),
+            )
+            .unwrap();
+
+        let mut received = Vec::new();
+        let mut chunks = sender.next_chunks(&peer, stream_id, &chain);
+        while !chunks.is_empty() {
+            let pushed: usize =
+                chunks.iter().map(|chunk| chunk.blocks.len()).sum();
+            assert!(pushed <= window as usize);
+            for chunk in chunks {
+                receiver.on_chunk(&peer, chunk).unwrap();
+            }
+            // Nothing more is pushed before the blocks are acknowledged.
+            assert!(sender.next_chunks(&peer, stream_id, &chain).is_empty());
+            while let Some(Some(blocks)) = blocks_rx.next().now_or_never() {
+                let acked = blocks.len() as u32;
+                receiver.on_consumed(&peer, stream_id, acked);
+                sender.on_credit(
+                    &peer,
+                    &BlockStreamCredit {
+                        stream_id,
+                        acked,
+                        cancel: false,
+                    },
+                );
+                received.extend(blocks);
+            }
+            chunks = sender.next_chunks(&peer, stream_id, &chain);
+        }
+        assert_eq!(received.len(), 10);
+        assert_eq!(received[0].id(), tip);
+        assert!(received
+            .windows(2)
+            .all(|blocks| blocks[0].parent_id() == blocks[1].id()));
+
+        // A peer pushing beyond the window is rejected.
+        let (stream_id, _blocks_rx) = receiver.open_incoming(&peer, 1);
+        let mut stream = OutgoingBlockStream::new(&request, 2, 0);
+        let chunk = stream.next_chunk(stream_id, &chain).unwrap();
+        assert_eq!(stream.in_flight(), 2);
+        assert!(receiver.on_chunk(&peer, chunk).is_err());
+    }
+
+    #[test]
+    fn test_outgoing_streams_bounded() {
+        let (chain, tip) = chain(10);
+        let streams = BlockStreams::default();
+        let (peer, other_peer) =
+            (NodeId::from_low_u64_be(1), NodeId::from_low_u64_be(2));
+        let request = BlockRetrievalRequest::new(tip, 10);
+        let stream = || OutgoingBlockStream::new(&request, 1, 0);
+        let last_id = MAX_OUTGOING_STREAMS_PER_PEER as u64;
+
+        // A stream of an empty window would never be pushed.
+        assert!(streams
+            .open_outgoing(&peer, 0, OutgoingBlockStream::new(&request, 0, 0))
+            .is_err());
+
+        // The id of a stream still pushed is not reused.
+        streams.open_outgoing(&peer, 0, stream()).unwrap();
+        assert_eq!(streams.next_chunks(&peer, 0, &chain).len(), 1);
+        assert!(streams.open_outgoing(&peer, 0, stream()).is_err());
+        // The stream left open is the first one, with its block in flight.
+        assert!(streams.next_chunks(&peer, 0, &chain).is_empty());
+
+        for stream_id in 1..last_id {
+            streams.open_outgoing(&peer, stream_id, stream()).unwrap();
+        }
+        assert!(streams.open_outgoing(&peer, last_id, stream()).is_err());
+        // The other peers are not limited by it.
+        streams.open_outgoing(&other_peer, 0, stream()).unwrap();
+
+        // A stream closed makes room for another.
+        streams.on_credit(
+            &peer,
+            &BlockStreamCredit {
+                stream_id: 0,
+                acked: 0,
+                cancel: true,
+            },
+        );
+        streams.open_outgoing(&peer, last_id, stream()).unwrap();
+    }
+}
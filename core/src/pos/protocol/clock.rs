@@ -0,0 +1,23 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+//! The source of the current time of the request manager and the keepalive.
+//!
+//! The timeouts, the backoff delays and the liveness of the peers read the
+//! time from a `Clock` instead of `Instant::now`, so the tests can move the
+//! time forward with `test_utils::MockClock` rather than sleeping.
+
+use std::time::Instant;
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The wall clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant { Instant::now() }
+}
@@ -0,0 +1,139 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+//! Optional compression of large encoded PoS messages.
+//!
+//! A compressed message is the deflated original message (payload followed
+//! by its msg id) followed by the `COMPRESSED` msg id, so the receiver can
+//! tell it apart from plain messages by the last byte, just like any other
+//! message. Only the peers of `HSB_PROTOCOL_V12` or later know the
+//! `COMPRESSED` msg id, the older peers are sent the plain messages.
+//!
+//! The chunks of a large `EpochChangeProof` are deflated on their own, see
+//! `deflate` and `inflate`.
+
+use std::io::{self, Read, Write};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+
+use super::message::msgid;
+use crate::message::MsgId;
+
+/// Compress `encoded` if it is larger than `threshold` bytes and compression
+/// actually reduces its size. A `threshold` of 0 disables compression.
+pub fn maybe_compress(encoded: Vec<u8>, threshold: usize) -> Vec<u8> {
+    if threshold == 0 || encoded.len() <= threshold {
+        return encoded;
+    }
+    match compress(&encoded) {
+        Ok(compressed) if compressed.len() < encoded.len() => compressed,
+        _ => encoded,
+    }
+}
+
+/// Returns the message framed with the `COMPRESSED` msg id.
+pub fn compress(encoded: &[u8]) -> io::Result<Vec<u8>> {
+    let mut compressed = deflate(encoded)?;
+    compressed.push(msgid::COMPRESSED as u8);
+    Ok(compressed)
+}
+
+/// Deflate `data` without any framing.
+pub fn deflate(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Inflate the data deflated by `deflate`, failing if it is larger than
+/// `max_size` bytes.
+pub fn inflate(deflated: &[u8], max_size: usize) -> io::Result<Vec<u8>> {
+    let mut inflated = Vec::new();
+    DeflateDecoder::new(deflated)
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut inflated)?;
+    if inflated.len() > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "inflated data too large",
+        ));
+    }
+    Ok(inflated)
+}
+
+/// Decompress the payload of a `COMPRESSED` message (without its msg id) to
+/// the original encoded message. At most `max_size + 1` bytes are
+/// decompressed, so a small malicious message cannot blow up the memory.
+pub fn decompress(payload: &[u8], max_size: usize) -> io::Result<Vec<u8>> {
+    let decompressed = inflate(payload, max_size)?;
+    // A valid message has a non-empty payload and is not compressed again.
+    match decompressed.last() {
+        Some(id)
+            if decompressed.len() >= 2 && *id as MsgId != msgid::COMPRESSED =>
+        {
+            Ok(decompressed)
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid compressed message",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compress, decompress, maybe_compress};
+    use crate::{
+        message::Message,
+        pos::protocol::message::{
+            block_retrieval_response::BlockRetrievalRpcResponse, msgid,
+        },
+    };
+    use consensus_types::{
+        block::Block,
+        block_retrieval::{BlockRetrievalResponse, BlockRetrievalStatus},
+    };
+
+    #[test]
+    fn test_compress_block_retrieval_response() {
+        let blocks = vec![Block::make_genesis_block(); 32];
+        let response = BlockRetrievalRpcResponse {
+            request_id: 1,
+            response: BlockRetrievalResponse::new(
+                BlockRetrievalStatus::Succeeded,
+                blocks,
+            ),
+        };
+        let encoded = response.encode();
+
+        let compressed = maybe_compress(encoded.clone(), 1024);
+        assert!(compressed.len() < encoded.len());
+        assert_eq!(*compressed.last().unwrap() as u16, msgid::COMPRESSED);
+
+        let decompressed =
+            decompress(&compressed[..compressed.len() - 1], encoded.len())
+                .unwrap();
+        assert_eq!(decompressed, encoded);
+    }
+
+    #[test]
+    fn test_decompress_over_limit() {
+        // 16 MiB of zeros compress to a few KiB.
+        let mut encoded = vec![0u8; 16 * 1024 * 1024];
+        encoded.push(msgid::BLOCK_RETRIEVAL_RESPONSE as u8);
+        let compressed = compress(&encoded).unwrap();
+        assert!(compressed.len() < 1024 * 1024);
+
+        let payload = &compressed[..compressed.len() - 1];
+        assert!(decompress(payload, 1024 * 1024).is_err());
+        assert_eq!(decompress(payload, encoded.len()).unwrap(), encoded);
+    }
+
+    #[test]
+    fn test_small_message_not_compressed() {
+        let encoded = vec![1, 2, 3, msgid::VOTE as u8];
+        assert_eq!(maybe_compress(encoded.clone(), 1024), encoded);
+        assert_eq!(maybe_compress(encoded.clone(), 0), encoded);
+    }
+}
@@ -0,0 +1,309 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+//! The reassembly of the `EpochChangeProof`s received in chunks.
+//!
+//! The proof sent to a node offline across many epochs can be large, so the
+//! sender splits it into `EpochChangeChunk`s instead of sending one huge
+//! frame. The chunks of a proof may arrive in any order, and are buffered
+//! until all of them are received.
+//!
+//! At most `MAX_PARTIAL_PROOFS` proofs are buffered at a time, and the oldest
+//! one is dropped to make room for another. A proof still missing chunks
+//! after `PARTIAL_PROOF_TIMEOUT` is dropped, and consensus asks for the
+//! epochs again.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    time::{Duration, Instant},
+};
+
+use network::node_table::NodeId;
+use parking_lot::Mutex;
+use thiserror::Error;
+
+use super::{
+    compression::inflate, message::epoch_change_chunk::EpochChangeChunk,
+};
+use crate::pos::consensus::counters;
+
+/// The most proofs buffered at a time.
+pub const MAX_PARTIAL_PROOFS: usize = 8;
+
+/// How long the chunks of a proof are buffered.
+pub const PARTIAL_PROOF_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Why a chunk is rejected. The proof it belongs to is dropped.
+#[derive(Debug, Error)]
+pub enum InvalidChunk {
+    #[error("chunk {index} of {num_chunks} chunks")]
+    IndexOutOfRange { index: u32, num_chunks: u32 },
+    #[error("{num_chunks} chunks while {expected} chunks are expected")]
+    InconsistentNumChunks { num_chunks: u32, expected: u32 },
+    #[error("empty chunk")]
+    Empty,
+    #[error("proof larger than {max_size} bytes")]
+    TooLarge { max_size: usize },
+    #[error("failed to inflate the chunk: {0}")]
+    Inflate(io::Error),
+}
+
+struct PartialProof {
+    num_chunks: u32,
+    chunks: BTreeMap<u32, Vec<u8>>,
+    size: usize,
+    started: Instant,
+}
+
+#[derive(Default)]
+pub struct EpochChangeReassembly {
+    /// The proofs by their senders and ids.
+    partial: Mutex<HashMap<(NodeId, u64), PartialProof>>,
+}
+
+impl EpochChangeReassembly {
+    pub fn new() -> Self { Self::default() }
+
+    /// Add `chunk` received from `peer`, and return the encoded proof once
+    /// all its chunks are received. A proof larger than `max_size` bytes is
+    /// rejected.
+    pub fn add(
+        &self, peer: NodeId, chunk: EpochChangeChunk, max_size: usize,
+    ) -> Result<Option<Vec<u8>>, InvalidChunk> {
+        self.add_at(peer, chunk, max_size, Instant::now())
+    }
+
+    fn add_at(
+        &self, peer: NodeId, chunk: EpochChangeChunk, max_size: usize,
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>, InvalidChunk>
+    {
+        let mut partial = self.partial.lock();
+        let expired = partial.len();
+        partial.retain(|_, proof| {
+            now.saturating_duration_since(proof.started) < PARTIAL_PROOF_TIMEOUT
+        });
+        count_dropped("timeout", expired - partial.len());
+
+        let key = (peer, chunk.proof_id);
+        let data = match Self::check(&partial, &key, &chunk, max_size) {
+            Ok(data) => data,
+            Err(e) => {
+                count_dropped("invalid", partial.remove(&key).map_or(0, |_| 1));
+                return Err(e);
+            }
+        };
+        if chunk.num_chunks == 1 {
+            return Ok(Some(data));
+        }
+        if !partial.contains_key(&key) && partial.len() >= MAX_PARTIAL_PROOFS {
+            let oldest = partial
+                .iter()
+                .min_by_key(|(_, proof)| proof.started)
+                .map(|(oldest, _)| *oldest)
+                .expect("not empty");
+            partial.remove(&oldest);
+            count_dropped("evicted", 1);
+        }
+        let proof = partial.entry(key).or_insert_with(|| PartialProof {
+            num_chunks: chunk.num_chunks,
+            chunks: BTreeMap::new(),
+            size: 0,
+            started: now,
+        });
+        if !proof.chunks.contains_key(&chunk.index) {
+            proof.size += data.len();
+            proof.chunks.insert(chunk.index, data);
+        }
+        if proof.size > max_size {
+            partial.remove(&key);
+            count_dropped("invalid", 1);
+            return Err(InvalidChunk::TooLarge { max_size });
+        }
+        if proof.chunks.len() < proof.num_chunks as usize {
+            return Ok(None);
+        }
+        let proof = partial.remove(&key).expect("just added");
+        Ok(Some(
+            proof.chunks.into_iter().flat_map(|(_, c)| c).collect(),
+        ))
+    }
+
+    /// Check `chunk` against the chunks of its proof received before, and
+    /// return its data inflated.
+    fn check(
+        partial: &HashMap<(NodeId, u64), PartialProof>, key: &(NodeId, u64),
+        chunk: &EpochChangeChunk, max_size: usize,
+    ) -> Result<Vec<u8>, InvalidChunk>
+    {
+        if chunk.index >= chunk.num_chunks {
+            return Err(InvalidChunk::IndexOutOfRange {
+                index: chunk.index,
+                num_chunks: chunk.num_chunks,
+            });
+        }
+        if let Some(proof) = partial.get(key) {
+            if proof.num_chunks != chunk.num_chunks {
+                return Err(InvalidChunk::InconsistentNumChunks {
+                    num_chunks: chunk.num_chunks,
+                    expected: proof.num_chunks,
+                });
+            }
+        }
+        let data = if chunk.compressed {
+            inflate(&chunk.data, max_size).map_err(InvalidChunk::Inflate)?
+        } else {
+            chunk.data.clone()
+        };
+        // Each chunk takes at least one byte, so the number of the chunks
+        // buffered is bounded by the size limit.
+        if data.is_empty() {
+            return Err(InvalidChunk::Empty);
+        }
+        if data.len() > max_size {
+            return Err(InvalidChunk::TooLarge { max_size });
+        }
+        Ok(data)
+    }
+
+    /// Drop the proofs being received from a disconnected peer.
+    pub fn remove_peer(&self, peer: &NodeId) {
+        self.partial.lock().retain(|(sender, _), _| sender != peer);
+    }
+
+    /// The number of the proofs being received.
+    pub fn num_partial(&self) -> usize { self.partial.lock().len() }
+}
+
+fn count_dropped(reason: &str, count: usize) {
+    if count > 0 {
+        counters::NETWORK_PARTIAL_PROOFS_DROPPED
+            .with_label_values(&[reason])
+            .inc_by(count as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        EpochChangeReassembly, InvalidChunk, MAX_PARTIAL_PROOFS,
+        PARTIAL_PROOF_TIMEOUT,
+    };
+    use crate::pos::protocol::message::epoch_change_chunk::EpochChangeChunk;
+    use diem_crypto::HashValue;
+    use diem_types::{
+        block_info::BlockInfo,
+        epoch_change::EpochChangeProof,
+        ledger_info::{LedgerInfo, LedgerInfoWithSignatures},
+    };
+    use network::node_table::NodeId;
+    use std::{collections::BTreeMap, time::Instant};
+
+    const MAX_SIZE: usize = 1024 * 1024;
+
+    fn multi_epoch_proof(num_epochs: u64) -> EpochChangeProof {
+        let ledger_infos = (0..num_epochs)
+            .map(|epoch| {
+                let block_info = BlockInfo::new(
+                    epoch,
+                    0,
+                    HashValue::random(),
+                    HashValue::zero(),
+                    epoch,
+                    0,
+                    None,
+                    None,
+                );
+                LedgerInfoWithSignatures::new(
+                    LedgerInfo::new(block_info, HashValue::zero()),
+                    BTreeMap::new(),
+                )
+            })
+            .collect();
+        EpochChangeProof::new(ledger_infos, false)
+    }
+
+    #[test]
+    fn test_reassemble_out_of_order() {
+        let proof = multi_epoch_proof(32);
+        let encoded = bcs::to_bytes(&proof).unwrap();
+        let peer = NodeId::from_low_u64_be(1);
+        for compress in [false, true].iter() {
+            let mut chunks =
+                EpochChangeChunk::split(&encoded, 7, 256, *compress);
+            assert!(chunks.len() > 2);
+            assert_eq!(chunks.iter().any(|chunk| chunk.compressed), *compress);
+            // The last chunk first, then the others with a resend.
+            chunks.rotate_right(1);
+            chunks.swap(1, 2);
+            chunks.insert(2, chunks[0].clone());
+
+            let reassembly = EpochChangeReassembly::new();
+            let last = chunks.pop().unwrap();
+            for chunk in chunks {
+                assert!(reassembly
+                    .add(peer, chunk, MAX_SIZE)
+                    .unwrap()
+                    .is_none());
+            }
+            assert_eq!(reassembly.num_partial(), 1);
+            let reassembled =
+                reassembly.add(peer, last, MAX_SIZE).unwrap().unwrap();
+            assert_eq!(reassembled, encoded);
+            assert_eq!(
+                bcs::from_bytes::<EpochChangeProof>(&reassembled).unwrap(),
+                proof
+            );
+            assert_eq!(reassembly.num_partial(), 0);
+        }
+    }
+
+    #[test]
+    fn test_partial_proofs_bounded() {
+        let encoded = bcs::to_bytes(&multi_epoch_proof(4)).unwrap();
+        let peer = NodeId::from_low_u64_be(1);
+        let reassembly = EpochChangeReassembly::new();
+        let now = Instant::now();
+        for proof_id in 0..=MAX_PARTIAL_PROOFS as u64 {
+            let chunk = EpochChangeChunk::split(&encoded, proof_id, 64, false)
+                .remove(0);
+            assert!(reassembly
+                .add_at(peer, chunk, MAX_SIZE, now)
+                .unwrap()
+                .is_none());
+        }
+        // The oldest proof makes room for the last one.
+        assert_eq!(reassembly.num_partial(), MAX_PARTIAL_PROOFS);
+
+        // A proof that does not fit is dropped with its chunks.
+        let chunks = EpochChangeChunk::split(&encoded, 100, 64, false);
+        let too_large = chunks[0].data.len();
+        assert!(reassembly
+            .add_at(peer, chunks[0].clone(), too_large, now)
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            reassembly.add_at(peer, chunks[1].clone(), too_large, now),
+            Err(InvalidChunk::TooLarge { .. })
+        ));
+        let mut out_of_range = chunks[0].clone();
+        out_of_range.index = out_of_range.num_chunks;
+        assert!(matches!(
+            reassembly.add_at(peer, out_of_range, MAX_SIZE, now),
+            Err(InvalidChunk::IndexOutOfRange { .. })
+        ));
+
+        // All of them time out.
+        let chunk = chunks[0].clone();
+        let later = now + PARTIAL_PROOF_TIMEOUT;
+        assert!(reassembly
+            .add_at(peer, chunk, MAX_SIZE, later)
+            .unwrap()
+            .is_none());
+        assert_eq!(reassembly.num_partial(), 1);
+        reassembly.remove_peer(&peer);
+        assert_eq!(reassembly.num_partial(), 0);
+    }
+}
@@ -0,0 +1,278 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+use std::collections::HashMap;
+
+use diem_crypto::HashValue;
+use diem_types::account_address::AccountAddress;
+use network::node_table::NodeId;
+use thiserror::Error;
+
+/// Errors returned by the PoS network sending interface.
+#[derive(Debug, Error)]
+pub enum NetworkError {
+    /// The recipient is not (or no longer) in the connected peer table.
+    #[error("peer {0:?} is not connected")]
+    PeerNotConnected(AccountAddress),
+
+    /// Some recipients of a multi-peer send are not connected.
+    #[error("peers {0:?} are not connected")]
+    PeersNotConnected(Vec<AccountAddress>),
+
+    /// Sending fails in the transport for some connected peers, with the
+    /// reasons. The recipients that are not connected are also reported.
+    #[error(
+        "failed to send to peers {failed:?}, not connected: {not_connected:?}"
+    )]
+    SendFailed {
+        failed: Vec<(NodeId, String)>,
+        not_connected: Vec<AccountAddress>,
+    },
+
+    /// The message is dropped before written to the socket of the peer.
+    #[error("message dropped before written to the socket")]
+    MessageDropped,
+
+    /// No response is received before the RPC deadline.
+    #[error("rpc timeout")]
+    RpcTimeout,
+
+    /// The request is dropped locally before the response arrives, e.g.
+    /// because the peer is disconnected.
+    #[error("rpc canceled")]
+    RpcCanceled,
+
+    /// The deadline of the RPC passes before the request is sent.
+    #[error("rpc deadline exceeded")]
+    DeadlineExceeded,
+
+    /// The request manager tracks too many requests, so the RPC is not
+    /// sent, see `pos_max_pending_requests`.
+    #[error("too many pending rpc requests")]
+    TooManyPendingRequests,
+
+    /// The RPC response is not of the type expected by the caller.
+    #[error("unexpected rpc response type: expected {expected}, got {actual}")]
+    UnexpectedRpcResponseType {
+        expected: &'static str,
+        actual: &'static str,
+    },
+
+    /// The returned blocks do not correspond to the block retrieval request.
+    #[error(
+        "mismatched block retrieval response for {num_blocks} blocks from \
+         {block_id}: {reason}"
+    )]
+    MismatchedRetrievalResponse {
+        block_id: HashValue,
+        num_blocks: u64,
+        reason: String,
+    },
+
+    /// The queue delivering messages to the local node is full, so the
+    /// message to self is not queued.
+    #[error("self message queue is full ({depth} queued)")]
+    SelfQueueFull { depth: usize },
+
+    /// The queue delivering messages to the local node is closed.
+    #[error("self message queue is closed")]
+    SelfQueueClosed,
+
+    /// The network sender is shut down, so nothing is sent anymore.
+    #[error("network sender is shut down")]
+    Shutdown,
+
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+impl NetworkError {
+    /// Why a send fails, as the label of the send failure metrics.
+    pub fn failure_reason(&self) -> &'static str {
+        match self {
+            NetworkError::PeerNotConnected(_)
+            | NetworkError::PeersNotConnected(_) => "peer_not_found",
+            NetworkError::SendFailed { .. }
+            | NetworkError::MessageDropped
+            | NetworkError::SelfQueueFull { .. }
+            | NetworkError::SelfQueueClosed
+            | NetworkError::Shutdown => "transport",
+            NetworkError::RpcTimeout
+            | NetworkError::RpcCanceled
+            | NetworkError::DeadlineExceeded
+            | NetworkError::TooManyPendingRequests
+            | NetworkError::UnexpectedRpcResponseType { .. }
+            | NetworkError::MismatchedRetrievalResponse { .. } => "rpc",
+            NetworkError::Internal(_) => "other",
+        }
+    }
+
+    /// A copy of the error for each recipient of a send failing as a whole,
+    /// since `NetworkError` is not `Clone`. An internal error is copied with
+    /// its message only.
+    pub fn duplicate(&self) -> NetworkError {
+        match self {
+            NetworkError::PeerNotConnected(peer) => {
+                NetworkError::PeerNotConnected(*peer)
+            }
+            NetworkError::PeersNotConnected(peers) => {
+                NetworkError::PeersNotConnected(peers.clone())
+            }
+            NetworkError::SendFailed {
+                failed,
+                not_connected,
+            } => NetworkError::SendFailed {
+                failed: failed.clone(),
+                not_connected: not_connected.clone(),
+            },
+            NetworkError::MessageDropped => NetworkError::MessageDropped,
+            NetworkError::RpcTimeout => NetworkError::RpcTimeout,
+            NetworkError::RpcCanceled => NetworkError::RpcCanceled,
+            NetworkError::DeadlineExceeded => NetworkError::DeadlineExceeded,
+            NetworkError::TooManyPendingRequests => {
+                NetworkError::TooManyPendingRequests
+            }
+            NetworkError::UnexpectedRpcResponseType { expected, actual } => {
+                NetworkError::UnexpectedRpcResponseType {
+                    expected: *expected,
+                    actual: *actual,
+                }
+            }
+            NetworkError::MismatchedRetrievalResponse {
+                block_id,
+                num_blocks,
+                reason,
+            } => NetworkError::MismatchedRetrievalResponse {
+                block_id: *block_id,
+                num_blocks: *num_blocks,
+                reason: reason.clone(),
+            },
+            NetworkError::SelfQueueFull { depth } => {
+                NetworkError::SelfQueueFull { depth: *depth }
+            }
+            NetworkError::SelfQueueClosed => NetworkError::SelfQueueClosed,
+            NetworkError::Shutdown => NetworkError::Shutdown,
+            NetworkError::Internal(e) => {
+                NetworkError::Internal(anyhow::anyhow!("{:#}", e))
+            }
+        }
+    }
+}
+
+/// A batch of messages is only partially sent. The first `sent` messages are
+/// sent, and `error` stops the rest.
+#[derive(Debug, Error)]
+#[error("{error} after sending {sent} messages")]
+pub struct PartialSendError {
+    pub sent: usize,
+    #[source]
+    pub error: NetworkError,
+}
+
+/// The outcome of sending a message to many recipients, which may only reach
+/// some of them, so the caller can check whether enough recipients, e.g. a
+/// quorum, are reached, and retry only the failed ones.
+///
+/// The recipients are the PoS nodes, or the sessions for the broadcasts to
+/// the peer table, e.g. `NetworkSender::broadcast`.
+#[derive(Debug)]
+pub struct BroadcastOutcome<R = AccountAddress> {
+    /// The number of recipients the message is handed to the network for.
+    pub sent: usize,
+    /// The recipients the message fails to be sent to, with the errors.
+    pub failed: Vec<(R, NetworkError)>,
+}
+
+impl<R> Default for BroadcastOutcome<R> {
+    fn default() -> Self {
+        Self {
+            sent: 0,
+            failed: Vec::new(),
+        }
+    }
+}
+
+impl BroadcastOutcome {
+    /// The outcome of sending to the `recipients` resolved to their
+    /// sessions, given the `failures` of the sessions. A recipient fails if
+    /// its session fails.
+    pub fn from_failures(
+        recipients: &[(AccountAddress, NodeId)],
+        failures: Vec<(NodeId, String)>,
+    ) -> Self {
+        let failures: HashMap<_, _> = failures.into_iter().collect();
+        let mut outcome = Self::default();
+        for (recipient, node_id) in recipients {
+            match failures.get(node_id) {
+                Some(reason) => outcome.failed.push((
+                    *recipient,
+                    NetworkError::SendFailed {
+                        failed: vec![(*node_id, reason.clone())],
+                        not_connected: Vec::new(),
+                    },
+                )),
+                None => outcome.sent += 1,
+            }
+        }
+        outcome
+    }
+}
+
+impl BroadcastOutcome<NodeId> {
+    /// The outcome of sending to the sessions `peer_ids`, given the
+    /// `failures` of the sessions.
+    pub fn from_node_failures(
+        peer_ids: &[NodeId], failures: Vec<(NodeId, String)>,
+    ) -> Self {
+        let sent = peer_ids.len() - failures.len();
+        let failed = failures
+            .into_iter()
+            .map(|(node_id, reason)| {
+                let error = NetworkError::SendFailed {
+                    failed: vec![(node_id, reason)],
+                    not_connected: Vec::new(),
+                };
+                (node_id, error)
+            })
+            .collect();
+        Self { sent, failed }
+    }
+}
+
+impl<R: Copy> BroadcastOutcome<R> {
+    /// The outcome of sending to the `recipients` when the send fails as a
+    /// whole with `error`, e.g. once the sender is shut down.
+    pub fn all_failed(
+        recipients: impl IntoIterator<Item = R>, error: &NetworkError,
+    ) -> Self {
+        Self {
+            sent: 0,
+            failed: recipients
+                .into_iter()
+                .map(|recipient| (recipient, error.duplicate()))
+                .collect(),
+        }
+    }
+
+    pub fn is_complete(&self) -> bool { self.failed.is_empty() }
+
+    /// The errors of the failed recipients.
+    pub fn errors(&self) -> impl Iterator<Item = &NetworkError> {
+        self.failed.iter().map(|(_, error)| error)
+    }
+
+    /// The recipients to retry.
+    pub fn failed_recipients(&self) -> Vec<R> {
+        self.failed
+            .iter()
+            .map(|(recipient, _)| *recipient)
+            .collect()
+    }
+
+    /// Add the outcome of sending the message to more recipients.
+    pub fn extend(&mut self, other: BroadcastOutcome<R>) {
+        self.sent += other.sent;
+        self.failed.extend(other.failed);
+    }
+}
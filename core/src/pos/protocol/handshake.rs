@@ -0,0 +1,119 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+//! The setup of the connections with the PoS peers.
+//!
+//! A connection is set up once the chain id handshake of the peer is
+//! received, see `ChainIdHandshake`. The time it takes is observed in
+//! `NETWORK_HANDSHAKE_DURATION_S`, and the connections failing to be set up,
+//! e.g. with the peers of an older protocol version after an upgrade, are
+//! counted in `NETWORK_HANDSHAKE_FAILURES` by `HandshakeFailure`.
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use network::node_table::NodeId;
+use parking_lot::Mutex;
+
+use crate::pos::{
+    consensus::counters,
+    protocol::clock::{Clock, SystemClock},
+};
+
+/// Why a connection fails to be set up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HandshakeFailure {
+    /// The peer is of a protocol version below `pos_min_peer_protocol_version`.
+    VersionMismatch,
+    /// The peer is of a PoS network with another chain id.
+    ChainIdMismatch,
+    /// The peer does not send its handshake within `pos_handshake_timeout`.
+    Timeout,
+}
+
+impl HandshakeFailure {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HandshakeFailure::VersionMismatch => "version_mismatch",
+            HandshakeFailure::ChainIdMismatch => "chain_id_mismatch",
+            HandshakeFailure::Timeout => "timeout",
+        }
+    }
+}
+
+/// The connections whose handshakes are not received yet, by the time they
+/// are connected.
+pub struct PendingHandshakes {
+    /// 0 means the handshakes never time out.
+    timeout: Duration,
+    clock: Arc<dyn Clock>,
+    started: Mutex<HashMap<NodeId, Instant>>,
+}
+
+impl PendingHandshakes {
+    pub fn new(timeout: Duration) -> Self {
+        Self::with_clock(timeout, Arc::new(SystemClock))
+    }
+
+    /// Read the time the handshakes start and time out from `clock`.
+    pub fn with_clock(timeout: Duration, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            timeout,
+            clock,
+            started: Default::default(),
+        }
+    }
+
+    /// Wait for the handshake of the peer connected now.
+    pub fn start(&self, peer: &NodeId) {
+        self.started.lock().insert(*peer, self.clock.now());
+    }
+
+    /// Note the handshake received from `peer`, and return how long the
+    /// connection takes to set up, or None if it is not waited for.
+    pub fn complete(&self, peer: &NodeId) -> Option<Duration> {
+        let started = self.started.lock().remove(peer)?;
+        let duration = self.clock.now().saturating_duration_since(started);
+        counters::NETWORK_HANDSHAKE_DURATION_S.observe(duration.as_secs_f64());
+        Some(duration)
+    }
+
+    /// Note the connection with `peer` failing to be set up.
+    pub fn fail(&self, peer: &NodeId, failure: HandshakeFailure) {
+        self.started.lock().remove(peer);
+        counters::NETWORK_HANDSHAKE_FAILURES
+            .with_label_values(&[failure.as_str()])
+            .inc();
+    }
+
+    /// Remove and return the peers whose handshakes time out now.
+    pub fn take_expired(&self) -> Vec<NodeId> {
+        if self.timeout == Duration::from_secs(0) {
+            return Vec::new();
+        }
+        let now = self.clock.now();
+        let mut expired = Vec::new();
+        self.started.lock().retain(|peer, started| {
+            let timed_out =
+                now.saturating_duration_since(*started) >= self.timeout;
+            if timed_out {
+                expired.push(*peer);
+            }
+            !timed_out
+        });
+        for _ in &expired {
+            counters::NETWORK_HANDSHAKE_FAILURES
+                .with_label_values(&[HandshakeFailure::Timeout.as_str()])
+                .inc();
+        }
+        expired
+    }
+
+    pub fn remove_peer(&self, peer: &NodeId) {
+        self.started.lock().remove(peer);
+    }
+}
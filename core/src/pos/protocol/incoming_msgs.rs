@@ -0,0 +1,56 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+//! A tap of the `ConsensusMsg`s received from the peers, for the tools
+//! observing the protocol, e.g. a protocol analyzer.
+//!
+//! A copy of each message delivered to consensus is published through a
+//! broadcast channel, and only if someone subscribes. A subscriber that falls
+//! behind loses the oldest messages, so the dispatch is never blocked.
+
+use futures::{stream, Stream};
+use network::node_table::NodeId;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::pos::consensus::network::ConsensusMsg;
+
+/// The number of messages buffered for each subscriber.
+const INCOMING_MSG_CHANNEL_SIZE: usize = 1024;
+
+pub struct IncomingMsgPublisher {
+    tx: broadcast::Sender<(NodeId, ConsensusMsg)>,
+}
+
+impl Default for IncomingMsgPublisher {
+    fn default() -> Self { Self::new(INCOMING_MSG_CHANNEL_SIZE) }
+}
+
+impl IncomingMsgPublisher {
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+        Self { tx }
+    }
+
+    /// The messages received from now on, with the peers sending them. The
+    /// messages missed by falling behind are skipped.
+    pub fn subscribe(&self) -> impl Stream<Item = (NodeId, ConsensusMsg)> {
+        stream::unfold(self.tx.subscribe(), |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(item) => return Some((item, rx)),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+
+    /// Send a copy of `msg` received from `peer` to the subscribers, if any.
+    pub fn publish(&self, peer: &NodeId, msg: &ConsensusMsg) {
+        if self.tx.receiver_count() > 0 {
+            // Sending only fails if the subscribers are gone.
+            let _ = self.tx.send((*peer, msg.clone()));
+        }
+    }
+}
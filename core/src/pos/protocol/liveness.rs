@@ -0,0 +1,252 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+//! The application level keepalive of the PoS peers.
+//!
+//! An idle connection can die silently, e.g. when a NAT drops its mapping
+//! or the TCP connection is half open, and it is only found dead when a send
+//! fails in the middle of a round. Each peer is pinged every interval, and a
+//! peer that misses `max_missed_pongs` pongs in a row is unhealthy and
+//! disconnected, so the network dials it again.
+//!
+//! Only the peers of `HSB_PROTOCOL_V5` or later answer the pings, but the
+//! time any message is last received is tracked for all the peers.
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use network::node_table::NodeId;
+use parking_lot::Mutex;
+
+use super::clock::{Clock, SystemClock};
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PeerLivenessConfig {
+    /// How often each peer is pinged, 0 disables the pings.
+    pub ping_interval: Duration,
+    /// The number of consecutive pongs a peer may miss before it is
+    /// unhealthy.
+    pub max_missed_pongs: u32,
+}
+
+impl PeerLivenessConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.ping_interval > Duration::from_secs(0)
+    }
+}
+
+/// The liveness of a peer, see `PeerLiveness::status`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerLivenessStatus {
+    /// When the last message of the peer is received, or when it connected
+    /// if it has sent nothing.
+    pub last_seen: Instant,
+    /// The number of the pongs missed in a row.
+    pub missed_pongs: u32,
+    /// Cleared once the peer misses too many pongs.
+    pub healthy: bool,
+}
+
+struct PeerState {
+    status: PeerLivenessStatus,
+    /// Whether the peer answers the pings.
+    pingable: bool,
+    /// The nonce of the ping waiting for its pong.
+    pending_nonce: Option<u64>,
+}
+
+/// What to do with the peers when the liveness timer fires.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct LivenessCheck {
+    /// The peers to ping, with the nonce of each ping.
+    pub pings: Vec<(NodeId, u64)>,
+    /// The peers that have just become unhealthy.
+    pub unhealthy: Vec<NodeId>,
+}
+
+pub struct PeerLiveness {
+    max_missed_pongs: u32,
+    next_nonce: Mutex<u64>,
+    peers: Mutex<HashMap<NodeId, PeerState>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl PeerLiveness {
+    pub fn new(config: &PeerLivenessConfig) -> Self {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+
+    /// Read the time the peers are seen from `clock`.
+    pub fn with_clock(
+        config: &PeerLivenessConfig, clock: Arc<dyn Clock>,
+    ) -> Self {
+        Self {
+            max_missed_pongs: config.max_missed_pongs,
+            next_nonce: Mutex::new(0),
+            peers: Default::default(),
+            clock,
+        }
+    }
+
+    /// Start tracking a connected peer, which is only pinged if `pingable`.
+    pub fn add_peer(&self, peer: &NodeId, pingable: bool) {
+        self.add_peer_at(peer, pingable, self.clock.now())
+    }
+
+    fn add_peer_at(&self, peer: &NodeId, pingable: bool, now: Instant) {
+        self.peers.lock().insert(
+            *peer,
+            PeerState {
+                status: PeerLivenessStatus {
+                    last_seen: now,
+                    missed_pongs: 0,
+                    healthy: true,
+                },
+                pingable,
+                pending_nonce: None,
+            },
+        );
+    }
+
+    /// Note a message received from `peer`.
+    pub fn on_message(&self, peer: &NodeId) {
+        self.on_message_at(peer, self.clock.now())
+    }
+
+    fn on_message_at(&self, peer: &NodeId, now: Instant) {
+        if let Some(state) = self.peers.lock().get_mut(peer) {
+            state.status.last_seen = now;
+        }
+    }
+
+    /// Note the pong of `nonce` from `peer`. A pong answering an older ping
+    /// than the last one is ignored.
+    pub fn on_pong(&self, peer: &NodeId, nonce: u64) {
+        if let Some(state) = self.peers.lock().get_mut(peer) {
+            if state.pending_nonce == Some(nonce) {
+                state.pending_nonce = None;
+                state.status.missed_pongs = 0;
+            }
+        }
+    }
+
+    /// Count the pongs missed since the last check, and pick the next
+    /// pings. Called every ping interval.
+    pub fn check(&self) -> LivenessCheck {
+        let mut check = LivenessCheck::default();
+        let mut next_nonce = self.next_nonce.lock();
+        for (peer, state) in self.peers.lock().iter_mut() {
+            if !state.pingable || !state.status.healthy {
+                continue;
+            }
+            if state.pending_nonce.is_some() {
+                state.status.missed_pongs += 1;
+                if state.status.missed_pongs >= self.max_missed_pongs {
+                    state.status.healthy = false;
+                    state.pending_nonce = None;
+                    check.unhealthy.push(*peer);
+                    continue;
+                }
+            }
+            *next_nonce += 1;
+            state.pending_nonce = Some(*next_nonce);
+            check.pings.push((*peer, *next_nonce));
+        }
+        check
+    }
+
+    /// The liveness of `peer`, or `None` if it is not connected.
+    pub fn status(&self, peer: &NodeId) -> Option<PeerLivenessStatus> {
+        Some(self.peers.lock().get(peer)?.status)
+    }
+
+    /// Forget a disconnected peer.
+    pub fn remove_peer(&self, peer: &NodeId) { self.peers.lock().remove(peer); }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PeerLiveness, PeerLivenessConfig};
+    use crate::pos::protocol::{clock::Clock, test_utils::MockClock};
+    use network::node_table::NodeId;
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn test_missed_pongs() {
+        let liveness = PeerLiveness::new(&PeerLivenessConfig {
+            ping_interval: Duration::from_secs(10),
+            max_missed_pongs: 2,
+        });
+        let responsive = NodeId::from_low_u64_be(1);
+        let silent = NodeId::from_low_u64_be(2);
+        let old = NodeId::from_low_u64_be(3);
+        let start = Instant::now();
+        liveness.add_peer_at(&responsive, true, start);
+        liveness.add_peer_at(&silent, true, start);
+        liveness.add_peer_at(&old, false, start);
+
+        for i in 0..3 {
+            let mut check = liveness.check();
+            check.pings.sort();
+            // The old peer is never pinged, and the silent one is no longer
+            // pinged once it is unhealthy.
+            let expected_peers = if i < 2 {
+                vec![responsive, silent]
+            } else {
+                vec![responsive]
+            };
+            let pinged: Vec<_> =
+                check.pings.iter().map(|(peer, _)| *peer).collect();
+            assert_eq!(pinged, expected_peers);
+            if i == 2 {
+                assert_eq!(check.unhealthy, vec![silent]);
+            } else {
+                assert!(check.unhealthy.is_empty());
+            }
+            let (_, nonce) = check.pings[0];
+            // A pong of another ping is ignored.
+            liveness.on_pong(&responsive, nonce + 100);
+            liveness.on_pong(&responsive, nonce);
+        }
+
+        let responsive_status = liveness.status(&responsive).unwrap();
+        assert!(responsive_status.healthy);
+        assert_eq!(responsive_status.missed_pongs, 0);
+        let silent_status = liveness.status(&silent).unwrap();
+        assert!(!silent_status.healthy);
+        assert_eq!(silent_status.missed_pongs, 2);
+        assert!(liveness.status(&old).unwrap().healthy);
+
+        let later = start + Duration::from_secs(5);
+        liveness.on_message_at(&old, later);
+        assert_eq!(liveness.status(&old).unwrap().last_seen, later);
+        liveness.remove_peer(&silent);
+        assert!(liveness.status(&silent).is_none());
+    }
+
+    #[test]
+    fn test_last_seen_from_clock() {
+        let clock = Arc::new(MockClock::new());
+        let liveness = PeerLiveness::with_clock(
+            &PeerLivenessConfig::default(),
+            clock.clone(),
+        );
+        let peer = NodeId::from_low_u64_be(1);
+        liveness.add_peer(&peer, true);
+        let connected = clock.now();
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(liveness.status(&peer).unwrap().last_seen, connected);
+        liveness.on_message(&peer);
+        assert_eq!(
+            liveness.status(&peer).unwrap().last_seen,
+            connected + Duration::from_secs(30)
+        );
+    }
+}
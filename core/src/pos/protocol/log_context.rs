@@ -0,0 +1,131 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+//! The context of the sends in their logs, so a failed send can be
+//! correlated with the consensus state at that moment.
+//!
+//! Each send-related log line carries `epoch=<epoch> round=<round>
+//! variant=<msg name> peer=<node id>`. The epoch and the round are taken
+//! from the message, see `Message::epoch_round`, and omitted for the
+//! messages without them.
+
+use std::fmt;
+
+use network::node_table::NodeId;
+
+use crate::message::Message;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SendLogContext {
+    pub variant: &'static str,
+    pub epoch: Option<u64>,
+    pub round: Option<u64>,
+}
+
+impl SendLogContext {
+    pub fn of<M: Message + ?Sized>(msg: &M) -> Self {
+        let (epoch, round) = msg.epoch_round();
+        Self {
+            variant: msg.msg_name(),
+            epoch,
+            round,
+        }
+    }
+
+    /// The context of sending to `peer`.
+    pub fn to_peer<'a>(&'a self, peer: &'a NodeId) -> PeerSendLogContext<'a> {
+        PeerSendLogContext {
+            context: self,
+            peer: Some(peer),
+        }
+    }
+
+    /// The context of sending to `peer`, if it is known, e.g. a request not
+    /// assigned to a peer yet.
+    pub fn to_maybe_peer<'a>(
+        &'a self, peer: Option<&'a NodeId>,
+    ) -> PeerSendLogContext<'a> {
+        PeerSendLogContext {
+            context: self,
+            peer,
+        }
+    }
+}
+
+impl fmt::Display for SendLogContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(epoch) = self.epoch {
+            write!(f, "epoch={} ", epoch)?;
+        }
+        if let Some(round) = self.round {
+            write!(f, "round={} ", round)?;
+        }
+        write!(f, "variant={}", self.variant)
+    }
+}
+
+pub struct PeerSendLogContext<'a> {
+    context: &'a SendLogContext,
+    peer: Option<&'a NodeId>,
+}
+
+impl fmt::Display for PeerSendLogContext<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.context)?;
+        match self.peer {
+            Some(peer) => write!(f, " peer={:?}", peer),
+            None => write!(f, " peer=none"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SendLogContext;
+    use crate::pos::consensus::network::ConsensusMsg;
+    use consensus_types::{
+        quorum_cert::QuorumCert, sync_info::SyncInfo, vote::Vote,
+        vote_data::VoteData, vote_msg::VoteMsg,
+    };
+    use diem_crypto::HashValue;
+    use diem_types::{
+        block_info::BlockInfo, epoch_change::EpochChangeProof,
+        ledger_info::LedgerInfo, validator_signer::ValidatorSigner,
+    };
+    use network::node_table::NodeId;
+
+    #[test]
+    fn test_send_log_context() {
+        let signer = ValidatorSigner::from_int(1);
+        let ledger_info =
+            LedgerInfo::new(BlockInfo::empty(), HashValue::zero());
+        let qc = QuorumCert::certificate_for_genesis_from_ledger_info(
+            &ledger_info,
+            HashValue::zero(),
+        );
+        let vote = ConsensusMsg::VoteMsg(Box::new(VoteMsg::new(
+            Vote::new(
+                VoteData::new(BlockInfo::empty(), BlockInfo::empty()),
+                signer.author(),
+                ledger_info,
+                &signer,
+            ),
+            SyncInfo::new(qc.clone(), qc, None),
+        )));
+        let peer = NodeId::from_low_u64_be(1);
+        assert_eq!(
+            SendLogContext::of(&vote).to_peer(&peer).to_string(),
+            format!("epoch=0 round=0 variant=VoteMsg peer={:?}", peer)
+        );
+
+        // An empty proof has no epoch, and no proof has a round.
+        let proof = ConsensusMsg::EpochChangeProof(Box::new(
+            EpochChangeProof::new(vec![], false),
+        ));
+        assert_eq!(
+            SendLogContext::of(&proof).to_maybe_peer(None).to_string(),
+            "variant=EpochChangeProof peer=none"
+        );
+    }
+}
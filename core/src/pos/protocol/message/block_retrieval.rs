@@ -5,19 +5,21 @@
 use crate::{
     message::{Message, RequestId},
     pos::{
-        consensus::network::IncomingBlockRetrievalRequest,
+        consensus::{counters, network::IncomingBlockRetrievalRequest},
         protocol::{
             message::block_retrieval_response::BlockRetrievalRpcResponse,
             request_manager::{AsAny, Request},
-            sync_protocol::{Context, Handleable, RpcResponse},
+            sync_protocol::{Context, Handleable, RpcResponseWithPeer},
+            HSB_PROTOCOL_V11,
         },
     },
-    sync::{Error, ProtocolConfiguration},
+    sync::{Error, ErrorKind, ProtocolConfiguration},
 };
 use channel::diem_channel::ElementStatus;
 use consensus_types::block_retrieval::{
     BlockRetrievalRequest, BlockRetrievalResponse, BlockRetrievalStatus,
 };
+use diem_crypto::HashValue;
 use diem_logger::prelude::diem_debug;
 use futures::channel::oneshot;
 use serde::{Deserialize, Serialize};
@@ -32,11 +34,52 @@ pub struct BlockRetrievalRpcRequest {
     pub is_empty: bool,
     #[serde(skip)]
     pub response_tx:
-        Option<oneshot::Sender<Result<Box<dyn RpcResponse>, Error>>>,
+        Option<oneshot::Sender<Result<RpcResponseWithPeer, Error>>>,
+    /// The callers of the identical requests answered by this one.
+    #[serde(skip)]
+    pub coalesced_tx: Vec<oneshot::Sender<Result<RpcResponseWithPeer, Error>>>,
+    /// The timeout chosen by the caller, or zero for the one of the type in
+    /// `pos_rpc_timeouts`.
     #[serde(skip)]
     pub timeout: Duration,
 }
 
+/// The layout of a `BlockRetrievalRpcRequest` without a byte limit, which is
+/// sent as `msgid::BLOCK_RETRIEVAL` so the peers before `HSB_PROTOCOL_V13`
+/// can decode it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UnlimitedBlockRetrievalRpcRequest {
+    pub request_id: RequestId,
+    pub block_id: HashValue,
+    pub num_blocks: u64,
+}
+
+impl From<&BlockRetrievalRpcRequest> for UnlimitedBlockRetrievalRpcRequest {
+    fn from(request: &BlockRetrievalRpcRequest) -> Self {
+        Self {
+            request_id: request.request_id,
+            block_id: request.request.block_id(),
+            num_blocks: request.request.num_blocks(),
+        }
+    }
+}
+
+impl From<UnlimitedBlockRetrievalRpcRequest> for BlockRetrievalRpcRequest {
+    fn from(request: UnlimitedBlockRetrievalRpcRequest) -> Self {
+        Self {
+            request_id: request.request_id,
+            request: BlockRetrievalRequest::new(
+                request.block_id,
+                request.num_blocks,
+            ),
+            is_empty: false,
+            response_tx: None,
+            coalesced_tx: Vec::new(),
+            timeout: Duration::from_secs(0),
+        }
+    }
+}
+
 impl AsAny for BlockRetrievalRpcRequest {
     fn as_any(&self) -> &dyn Any { self }
 
@@ -44,11 +87,19 @@ impl AsAny for BlockRetrievalRpcRequest {
 }
 
 impl Request for BlockRetrievalRpcRequest {
-    fn timeout(&self, _conf: &ProtocolConfiguration) -> Duration {
-        self.timeout
+    fn timeout(&self, conf: &ProtocolConfiguration) -> Duration {
+        if self.timeout == Duration::from_secs(0) {
+            conf.pos_rpc_timeouts.get(self.msg_name())
+        } else {
+            self.timeout
+        }
     }
 
     fn notify_error(&mut self, error: Error) {
+        for tx in self.coalesced_tx.drain(..) {
+            // The receiver may be dropped, which is fine.
+            let _ = tx.send(Err(copy_error(&error)));
+        }
         let res_tx = self.response_tx.take();
         if let Some(tx) = res_tx {
             if let Err(e) = tx.send(Err(error)) {
@@ -59,10 +110,61 @@ impl Request for BlockRetrievalRpcRequest {
     }
 
     fn set_response_notification(
-        &mut self, res_tx: oneshot::Sender<Result<Box<dyn RpcResponse>, Error>>,
+        &mut self, res_tx: oneshot::Sender<Result<RpcResponseWithPeer, Error>>,
     ) {
         self.response_tx = Some(res_tx);
     }
+
+    fn resend(&self) -> Option<Box<dyn Request>> {
+        Some(Box::new(BlockRetrievalRpcRequest {
+            request_id: 0,
+            request: self.request.clone(),
+            is_empty: self.is_empty,
+            response_tx: None,
+            coalesced_tx: Vec::new(),
+            timeout: self.timeout,
+        }))
+    }
+
+    fn coalescing_key(&self) -> Option<HashValue> {
+        let bytes = bcs::to_bytes(&self.request)
+            .expect("BlockRetrievalRequest serialization should not fail");
+        Some(HashValue::sha3_256_of(&bytes))
+    }
+
+    fn absorb(&mut self, other: &mut dyn Request) -> bool {
+        match other
+            .as_any_mut()
+            .downcast_mut::<BlockRetrievalRpcRequest>()
+        {
+            Some(other) => {
+                self.coalesced_tx.extend(other.response_tx.take());
+                self.coalesced_tx.append(&mut other.coalesced_tx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn is_canceled(&self) -> bool {
+        let mut senders =
+            self.response_tx.iter().chain(&self.coalesced_tx).peekable();
+        senders.peek().is_some() && senders.all(oneshot::Sender::is_canceled)
+    }
+}
+
+/// A copy of `error` for the callers of the coalesced requests, since
+/// `Error` is not `Clone`. The kinds the callers check are kept.
+fn copy_error(error: &Error) -> Error {
+    match error.kind() {
+        ErrorKind::RpcTimeout => ErrorKind::RpcTimeout.into(),
+        ErrorKind::RpcCancelledByDisconnection => {
+            ErrorKind::RpcCancelledByDisconnection.into()
+        }
+        ErrorKind::Shutdown => ErrorKind::Shutdown.into(),
+        ErrorKind::DeadlineExceeded => ErrorKind::DeadlineExceeded.into(),
+        _ => ErrorKind::Msg(error.to_string()).into(),
+    }
 }
 
 impl Handleable for BlockRetrievalRpcRequest {
@@ -74,6 +176,16 @@ impl Handleable for BlockRetrievalRpcRequest {
             req.block_id(),
             self.request_id
         );
+        let consensus_messages_tx =
+            &ctx.manager.consensus_network_task.consensus_messages_tx;
+        if consensus_messages_tx.is_backpressured() {
+            // Consensus is behind, so the request is answered with an empty
+            // response right away instead of being queued.
+            counters::NETWORK_MSGS_BACKPRESSURED
+                .with_label_values(&["BlockRetrievalRequest"])
+                .inc();
+            return Self::respond_busy(ctx, self.request_id);
+        }
         let req_with_callback = IncomingBlockRetrievalRequest {
             req,
             peer_id: ctx.peer,
@@ -93,15 +205,29 @@ impl Handleable for BlockRetrievalRpcRequest {
             )?;
         if let Ok(Some(ElementStatus::Dropped(request))) = status_rx.try_recv()
         {
-            let response = BlockRetrievalRpcResponse {
-                request_id: request.request_id,
-                response: BlockRetrievalResponse::new(
-                    BlockRetrievalStatus::IdNotFound,
-                    vec![],
-                ),
-            };
-            response.send(ctx.io, &ctx.peer)?;
+            Self::respond_busy(ctx, request.request_id)?;
         }
         Ok(())
     }
 }
+
+impl BlockRetrievalRpcRequest {
+    /// Answer the request `request_id` with no block, so the peer does not
+    /// wait for the timeout and asks another peer. The peers before
+    /// `HSB_PROTOCOL_V11` cannot decode `BlockRetrievalStatus::Busy`, so
+    /// they are answered with `IdNotFound`.
+    fn respond_busy(ctx: &Context, request_id: RequestId) -> Result<(), Error> {
+        let status = match ctx.manager.peers.protocol_version(&ctx.peer) {
+            Some(version) if version >= HSB_PROTOCOL_V11 => {
+                BlockRetrievalStatus::Busy
+            }
+            _ => BlockRetrievalStatus::IdNotFound,
+        };
+        let response = BlockRetrievalRpcResponse {
+            request_id,
+            response: BlockRetrievalResponse::new(status, vec![]),
+        };
+        response.send(ctx.io, &ctx.peer)?;
+        Ok(())
+    }
+}
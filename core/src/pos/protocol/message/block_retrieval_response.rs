@@ -5,15 +5,26 @@
 use crate::{
     message::RequestId,
     pos::protocol::{
-        message::block_retrieval::BlockRetrievalRpcRequest,
-        request_manager::AsAny,
-        sync_protocol::{Context, Handleable, RpcResponse},
+        error::NetworkError,
+        message::{
+            block_retrieval::BlockRetrievalRpcRequest,
+            proposal::deliver_pending,
+        },
+        request_manager::{AsAny, Request},
+        sync_protocol::{
+            Context, Handleable, RpcResponse, RpcResponseWithPeer,
+        },
     },
     sync::{Error, ErrorKind},
 };
-use consensus_types::block_retrieval::BlockRetrievalResponse;
+use consensus_types::{
+    block::Block,
+    block_retrieval::{
+        BlockRetrievalRequest, BlockRetrievalResponse, BlockRetrievalStatus,
+    },
+};
 use serde::{Deserialize, Serialize};
-use std::any::Any;
+use std::{any::Any, borrow::Cow};
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct BlockRetrievalRpcResponse {
@@ -22,7 +33,71 @@ pub struct BlockRetrievalRpcResponse {
     pub response: BlockRetrievalResponse,
 }
 
-impl RpcResponse for BlockRetrievalRpcResponse {}
+/// The layout of a `BlockRetrievalRpcResponse` without a cursor, which is
+/// sent as `msgid::BLOCK_RETRIEVAL_RESPONSE` so the peers before
+/// `HSB_PROTOCOL_V13` can decode it. The blocks are borrowed when encoding.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UncursoredBlockRetrievalRpcResponse<'a> {
+    pub request_id: RequestId,
+    pub status: BlockRetrievalStatus,
+    pub blocks: Cow<'a, [Block]>,
+}
+
+impl<'a> From<&'a BlockRetrievalRpcResponse>
+    for UncursoredBlockRetrievalRpcResponse<'a>
+{
+    fn from(response: &'a BlockRetrievalRpcResponse) -> Self {
+        Self {
+            request_id: response.request_id,
+            status: response.response.status(),
+            blocks: Cow::Borrowed(response.response.blocks()),
+        }
+    }
+}
+
+impl From<UncursoredBlockRetrievalRpcResponse<'_>>
+    for BlockRetrievalRpcResponse
+{
+    fn from(response: UncursoredBlockRetrievalRpcResponse) -> Self {
+        Self {
+            request_id: response.request_id,
+            response: BlockRetrievalResponse::new(
+                response.status,
+                response.blocks.into_owned(),
+            ),
+        }
+    }
+}
+
+impl BlockRetrievalRpcResponse {
+    /// Cheaply check that the returned blocks start from the requested block
+    /// and are not more than requested, before the blocks are verified.
+    pub fn check_matches(
+        &self, request: &BlockRetrievalRequest,
+    ) -> Result<(), NetworkError> {
+        let blocks = self.response.blocks();
+        let mismatch = |reason: String| {
+            Err(NetworkError::MismatchedRetrievalResponse {
+                block_id: request.block_id(),
+                num_blocks: request.num_blocks(),
+                reason,
+            })
+        };
+        if blocks.len() as u64 > request.num_blocks() {
+            return mismatch(format!("{} blocks returned", blocks.len()));
+        }
+        match blocks.first() {
+            Some(block) if block.id() != request.block_id() => {
+                mismatch(format!("first block is {}", block.id()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl RpcResponse for BlockRetrievalRpcResponse {
+    fn into_any(self: Box<Self>) -> Box<dyn Any> { self }
+}
 
 impl AsAny for BlockRetrievalRpcResponse {
     fn as_any(&self) -> &dyn Any { self }
@@ -39,9 +114,35 @@ impl Handleable for BlockRetrievalRpcResponse {
             &ctx.manager.request_manager,
         ) {
             Ok(req) => {
+                if let Err(e) = self.check_matches(&req.request) {
+                    req.notify_error(
+                        ErrorKind::UnexpectedMessage(e.to_string()).into(),
+                    );
+                    bail!(ErrorKind::UnexpectedResponse);
+                }
+                // The retrieved blocks may be the parents of the proposals
+                // held back.
+                let mut released = Vec::new();
+                for block in self.response.blocks() {
+                    released
+                        .extend(ctx.manager.pending_proposals.on_block(block));
+                }
+                for pending in released {
+                    deliver_pending(ctx, pending)?;
+                }
+                for tx in req.coalesced_tx.drain(..) {
+                    // The receiver may be dropped, which is fine.
+                    let _ = tx.send(Ok(RpcResponseWithPeer {
+                        peer: ctx.peer,
+                        response: Box::new(self.clone()),
+                    }));
+                }
                 let res_tx = req.response_tx.take();
                 if let Some(tx) = res_tx {
-                    if let Err(e) = tx.send(Ok(Box::new(self))) {
+                    if let Err(e) = tx.send(Ok(RpcResponseWithPeer {
+                        peer: ctx.peer,
+                        response: Box::new(self),
+                    })) {
                         bail!(ErrorKind::UnexpectedMessage(
                             format!("{:?}", e).into()
                         ))
@@ -55,3 +156,148 @@ impl Handleable for BlockRetrievalRpcResponse {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        BlockRetrievalRpcResponse, UncursoredBlockRetrievalRpcResponse,
+    };
+    use crate::{
+        message::{Message, MessageProtocolVersionBound, MsgId, RequestId},
+        pos::protocol::{
+            error::NetworkError,
+            message::{
+                block_retrieval::{
+                    BlockRetrievalRpcRequest, UnlimitedBlockRetrievalRpcRequest,
+                },
+                msgid,
+            },
+            HSB_PROTOCOL_V1, HSB_PROTOCOL_V13,
+        },
+    };
+    use consensus_types::{
+        block::Block,
+        block_retrieval::{
+            BlockRetrievalRequest, BlockRetrievalResponse, BlockRetrievalStatus,
+        },
+    };
+    use diem_crypto::HashValue;
+
+    #[test]
+    fn test_check_matches() {
+        let block = Block::make_genesis_block();
+        let response = BlockRetrievalRpcResponse {
+            request_id: 1,
+            response: BlockRetrievalResponse::new(
+                BlockRetrievalStatus::Succeeded,
+                vec![block.clone()],
+            ),
+        };
+        assert!(response
+            .check_matches(&BlockRetrievalRequest::new(block.id(), 1))
+            .is_ok());
+        assert!(matches!(
+            response.check_matches(&BlockRetrievalRequest::new(block.id(), 0)),
+            Err(NetworkError::MismatchedRetrievalResponse { .. })
+        ));
+        assert!(matches!(
+            response.check_matches(&BlockRetrievalRequest::new(
+                HashValue::random(),
+                1
+            )),
+            Err(NetworkError::MismatchedRetrievalResponse { .. })
+        ));
+
+        // The busy answer of any request has no block.
+        let busy = BlockRetrievalRpcResponse {
+            request_id: 1,
+            response: BlockRetrievalResponse::new(
+                BlockRetrievalStatus::Busy,
+                vec![],
+            ),
+        };
+        assert!(busy
+            .check_matches(&BlockRetrievalRequest::new(HashValue::random(), 1))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_layouts_of_older_peers() {
+        let block = Block::make_genesis_block();
+        let request = |max_response_bytes| BlockRetrievalRpcRequest {
+            request_id: 1,
+            request: BlockRetrievalRequest::new_with_byte_limit(
+                block.id(),
+                2,
+                max_response_bytes,
+            ),
+            is_empty: false,
+            response_tx: None,
+            coalesced_tx: Vec::new(),
+            timeout: Default::default(),
+        };
+        let response = |next_cursor| BlockRetrievalRpcResponse {
+            request_id: 1,
+            response: BlockRetrievalResponse::new_with_cursor(
+                BlockRetrievalStatus::Succeeded,
+                vec![block.clone()],
+                next_cursor,
+            ),
+        };
+        let split = |encoded: &[u8]| {
+            let (msg, id) = encoded.split_at(encoded.len() - 1);
+            (msg.to_vec(), id[0] as MsgId)
+        };
+
+        // Without the byte limit and the cursor, the messages keep the
+        // layouts before `HSB_PROTOCOL_V13`.
+        let unlimited = request(0);
+        assert_eq!(unlimited.version_introduced(), HSB_PROTOCOL_V1);
+        let (msg, id) = split(&unlimited.encode());
+        assert_eq!(id, msgid::BLOCK_RETRIEVAL);
+        assert_eq!(
+            bcs::from_bytes::<(RequestId, HashValue, u64)>(&msg).unwrap(),
+            (1, block.id(), 2)
+        );
+        let decoded: BlockRetrievalRpcRequest =
+            bcs::from_bytes::<UnlimitedBlockRetrievalRpcRequest>(&msg)
+                .unwrap()
+                .into();
+        assert_eq!(decoded.request, unlimited.request);
+
+        let uncursored = response(None);
+        assert_eq!(uncursored.version_introduced(), HSB_PROTOCOL_V1);
+        let (msg, id) = split(&uncursored.encode());
+        assert_eq!(id, msgid::BLOCK_RETRIEVAL_RESPONSE);
+        assert_eq!(
+            bcs::from_bytes::<(RequestId, BlockRetrievalStatus, Vec<Block>)>(
+                &msg
+            )
+            .unwrap(),
+            (1, BlockRetrievalStatus::Succeeded, vec![block.clone()])
+        );
+        let decoded: BlockRetrievalRpcResponse =
+            bcs::from_bytes::<UncursoredBlockRetrievalRpcResponse>(&msg)
+                .unwrap()
+                .into();
+        assert_eq!(decoded, uncursored);
+
+        // The others have their own msg ids for the newer peers.
+        let limited = request(1024);
+        assert_eq!(limited.version_introduced(), HSB_PROTOCOL_V13);
+        let (msg, id) = split(&limited.encode());
+        assert_eq!(id, msgid::BLOCK_RETRIEVAL_PAGE);
+        let decoded =
+            bcs::from_bytes::<BlockRetrievalRpcRequest>(&msg).unwrap();
+        assert_eq!(decoded.request, limited.request);
+
+        let cursored = response(Some(block.parent_id()));
+        assert_eq!(cursored.version_introduced(), HSB_PROTOCOL_V13);
+        let (msg, id) = split(&cursored.encode());
+        assert_eq!(id, msgid::BLOCK_RETRIEVAL_PAGE_RESPONSE);
+        assert_eq!(
+            bcs::from_bytes::<BlockRetrievalRpcResponse>(&msg).unwrap(),
+            cursored
+        );
+    }
+}
@@ -0,0 +1,106 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+use crate::{
+    pos::protocol::{
+        block_stream::OutgoingBlockStream,
+        sync_protocol::{Context, Handleable},
+    },
+    sync::{Error, ErrorKind},
+};
+use consensus_types::{block::Block, block_retrieval::BlockRetrievalRequest};
+use diem_logger::prelude::diem_debug;
+use serde::{Deserialize, Serialize};
+
+/// Opens a stream of the blocks of `request` pushed by the peer within
+/// `window`, see `BlockStreams`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BlockStreamOpen {
+    pub stream_id: u64,
+    pub request: BlockRetrievalRequest,
+    pub window: u32,
+}
+
+impl Handleable for BlockStreamOpen {
+    fn handle(self, ctx: &Context) -> Result<(), Error> {
+        let closed = BlockStreamChunk {
+            stream_id: self.stream_id,
+            blocks: vec![],
+            done: true,
+        };
+        if ctx.manager.block_stream_source().is_none() {
+            // Nothing to push before consensus is started.
+            return ctx.send_response(&closed);
+        }
+        let stream = OutgoingBlockStream::new(
+            &self.request,
+            self.window,
+            ctx.manager.protocol_config.pos_block_stream_max_in_flight,
+        );
+        if let Err(e) = ctx.manager.block_streams.open_outgoing(
+            &ctx.peer,
+            self.stream_id,
+            stream,
+        ) {
+            diem_debug!(
+                "Close the block stream opened by peer {}: {}",
+                ctx.peer,
+                e
+            );
+            return ctx.send_response(&closed);
+        }
+        push_chunks(ctx, self.stream_id)
+    }
+}
+
+/// The blocks pushed in a stream, from the child to the parent. The last
+/// chunk of the stream is `done`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BlockStreamChunk {
+    pub stream_id: u64,
+    pub blocks: Vec<Block>,
+    pub done: bool,
+}
+
+impl Handleable for BlockStreamChunk {
+    fn handle(self, ctx: &Context) -> Result<(), Error> {
+        ctx.manager
+            .block_streams
+            .on_chunk(&ctx.peer, self)
+            .map_err(|e| ErrorKind::UnexpectedMessage(e).into())
+    }
+}
+
+/// Acknowledges `acked` blocks of a stream consumed by the receiver, so the
+/// peer may push as many more, or stops the stream if `cancel`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BlockStreamCredit {
+    pub stream_id: u64,
+    pub acked: u32,
+    pub cancel: bool,
+}
+
+impl Handleable for BlockStreamCredit {
+    fn handle(self, ctx: &Context) -> Result<(), Error> {
+        ctx.manager.block_streams.on_credit(&ctx.peer, &self);
+        push_chunks(ctx, self.stream_id)
+    }
+}
+
+/// Push the chunks of the stream `stream_id` to the peer that fit in its
+/// window.
+fn push_chunks(ctx: &Context, stream_id: u64) -> Result<(), Error> {
+    let source = match ctx.manager.block_stream_source() {
+        Some(source) => source,
+        None => return Ok(()),
+    };
+    for chunk in ctx.manager.block_streams.next_chunks(
+        &ctx.peer,
+        stream_id,
+        source.as_ref(),
+    ) {
+        ctx.send_response(&chunk)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,54 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+use crate::{
+    pos::{
+        consensus::counters,
+        protocol::{
+            handshake::HandshakeFailure,
+            peer_event::ProtocolViolationKind,
+            sync_protocol::{Context, Handleable},
+        },
+    },
+    sync::Error,
+};
+use diem_logger::prelude::diem_debug;
+use serde::{Deserialize, Serialize};
+
+/// Sent first on each connection, so the messages of a PoS network with
+/// another chain id, e.g. the testnet ones on the mainnet, are never
+/// handled. The other messages of a peer are dropped until its handshake
+/// is received.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChainIdHandshake {
+    pub chain_id: u64,
+}
+
+impl Handleable for ChainIdHandshake {
+    fn handle(self, ctx: &Context) -> Result<(), Error> {
+        let own_chain_id = ctx.manager.protocol_config.pos_chain_id;
+        if self.chain_id != own_chain_id {
+            counters::NETWORK_CROSS_CHAIN_PEERS_REJECTED.inc();
+            ctx.manager
+                .pending_handshakes
+                .fail(&ctx.peer, HandshakeFailure::ChainIdMismatch);
+            ctx.manager.disconnect_for_violation(
+                ctx.io,
+                &ctx.peer,
+                ProtocolViolationKind::ChainIdMismatch,
+                &format!(
+                    "chain id {} mismatches ours {}",
+                    self.chain_id, own_chain_id
+                ),
+            );
+            return Ok(());
+        }
+        diem_debug!("Verified the chain id of peer {}", ctx.peer);
+        ctx.manager.pending_handshakes.complete(&ctx.peer);
+        if let Some(peer) = ctx.manager.peers.get(&ctx.peer_hash) {
+            peer.write().set_chain_id(self.chain_id);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,290 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+//! The wire formats of `ConsensusMsg`.
+//!
+//! BCS is used with every peer unless both ends ask for another codec in
+//! `CodecNegotiation`. Each codec has its own msg id, so a frame is decoded
+//! by the codec it is encoded with even while the codec is being switched.
+//! The length-prefixed JSON codec is only for debugging and the interop
+//! tests with the other Diem-derived clients, and it is only built with the
+//! `pos-json-codec` feature.
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "pos-json-codec")]
+use std::convert::TryFrom;
+
+use super::msgid;
+use crate::{message::MsgId, pos::consensus::network::ConsensusMsg};
+
+/// Encodes and decodes the `ConsensusMsg`s, without the msg id.
+pub trait MsgCodec: Send + Sync {
+    fn kind(&self) -> CodecKind;
+
+    fn encode(&self, msg: &ConsensusMsg) -> Result<Vec<u8>, anyhow::Error>;
+
+    fn decode(&self, bytes: &[u8]) -> Result<ConsensusMsg, anyhow::Error>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CodecKind {
+    Bcs,
+    Json,
+}
+
+impl Default for CodecKind {
+    fn default() -> Self { CodecKind::Bcs }
+}
+
+impl CodecKind {
+    /// The codec, or `None` if it is not built in.
+    pub fn codec(&self) -> Option<&'static dyn MsgCodec> {
+        match self {
+            CodecKind::Bcs => Some(&BcsCodec),
+            #[cfg(feature = "pos-json-codec")]
+            CodecKind::Json => Some(&JsonCodec),
+            #[cfg(not(feature = "pos-json-codec"))]
+            CodecKind::Json => None,
+        }
+    }
+
+    /// The msg id of the `ConsensusMsg`s encoded with this codec.
+    pub fn msg_id(&self) -> MsgId {
+        match self {
+            CodecKind::Bcs => msgid::CONSENSUS_MSG,
+            CodecKind::Json => msgid::CONSENSUS_MSG_JSON,
+        }
+    }
+
+    /// The codecs built in, which can be negotiated with peers.
+    pub fn supported() -> Vec<CodecKind> {
+        [CodecKind::Bcs, CodecKind::Json]
+            .iter()
+            .filter(|kind| kind.codec().is_some())
+            .cloned()
+            .collect()
+    }
+}
+
+impl std::str::FromStr for CodecKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bcs" => Ok(CodecKind::Bcs),
+            "json" => Ok(CodecKind::Json),
+            _ => bail!("unknown consensus msg codec {}", s),
+        }
+    }
+}
+
+/// The default codec, which is the same as `Message::encode`.
+pub struct BcsCodec;
+
+impl MsgCodec for BcsCodec {
+    fn kind(&self) -> CodecKind { CodecKind::Bcs }
+
+    fn encode(&self, msg: &ConsensusMsg) -> Result<Vec<u8>, anyhow::Error> {
+        Ok(bcs::to_bytes(msg)?)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<ConsensusMsg, anyhow::Error> {
+        Ok(bcs::from_bytes(bytes)?)
+    }
+}
+
+/// JSON prefixed with its length as a big-endian u32.
+#[cfg(feature = "pos-json-codec")]
+pub struct JsonCodec;
+
+#[cfg(feature = "pos-json-codec")]
+const JSON_LENGTH_PREFIX_SIZE: usize = 4;
+
+#[cfg(feature = "pos-json-codec")]
+impl MsgCodec for JsonCodec {
+    fn kind(&self) -> CodecKind { CodecKind::Json }
+
+    fn encode(&self, msg: &ConsensusMsg) -> Result<Vec<u8>, anyhow::Error> {
+        let json = serde_json::to_vec(msg)?;
+        let len = u32::try_from(json.len()).map_err(|_| {
+            anyhow::format_err!("json too long: {}", json.len())
+        })?;
+        let mut encoded =
+            Vec::with_capacity(JSON_LENGTH_PREFIX_SIZE + json.len());
+        encoded.extend_from_slice(&len.to_be_bytes());
+        encoded.extend_from_slice(&json);
+        Ok(encoded)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<ConsensusMsg, anyhow::Error> {
+        if bytes.len() < JSON_LENGTH_PREFIX_SIZE {
+            bail!("missing length prefix");
+        }
+        let (prefix, json) = bytes.split_at(JSON_LENGTH_PREFIX_SIZE);
+        let mut len = [0u8; JSON_LENGTH_PREFIX_SIZE];
+        len.copy_from_slice(prefix);
+        let len = u32::from_be_bytes(len) as usize;
+        if len != json.len() {
+            bail!("length prefix {} mismatches json size {}", len, json.len());
+        }
+        Ok(serde_json::from_slice(json)?)
+    }
+}
+
+/// The codec used with a peer that prefers `remote`, while this node
+/// prefers `local`. Another codec than BCS is only used if both prefer it
+/// and both support it, so both ends agree on the codec.
+pub fn negotiate(
+    local: CodecKind, remote: CodecKind, remote_supported: &[CodecKind],
+) -> CodecKind {
+    if local == remote
+        && local.codec().is_some()
+        && remote_supported.contains(&remote)
+    {
+        local
+    } else {
+        CodecKind::Bcs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{negotiate, CodecKind, MsgCodec};
+    use crate::pos::consensus::network::ConsensusMsg;
+    use consensus_types::{
+        block::Block,
+        block_retrieval::{
+            BlockRetrievalRequest, BlockRetrievalResponse, BlockRetrievalStatus,
+        },
+        commit_vote_msg::CommitVoteMsg,
+        epoch_retrieval::EpochRetrievalRequest,
+        proposal_msg::ProposalMsg,
+        quorum_cert::QuorumCert,
+        sync_info::SyncInfo,
+        vote::Vote,
+        vote_data::VoteData,
+        vote_msg::VoteMsg,
+    };
+    use diem_crypto::HashValue;
+    use diem_types::{
+        block_info::BlockInfo,
+        epoch_change::EpochChangeProof,
+        ledger_info::{LedgerInfo, LedgerInfoWithSignatures},
+        validator_signer::ValidatorSigner,
+    };
+    use std::collections::{BTreeMap, HashSet};
+
+    /// A message of each variant.
+    fn all_variants() -> Vec<ConsensusMsg> {
+        let signer = ValidatorSigner::from_int(1);
+        let ledger_info =
+            LedgerInfo::new(BlockInfo::empty(), HashValue::zero());
+        let qc = QuorumCert::certificate_for_genesis_from_ledger_info(
+            &ledger_info,
+            HashValue::zero(),
+        );
+        let sync_info = SyncInfo::new(qc.clone(), qc.clone(), None);
+        let block = Block::new_proposal(vec![], 1, 1, qc.clone(), &signer);
+        let msgs = vec![
+            ConsensusMsg::BlockRetrievalRequest(Box::new(
+                BlockRetrievalRequest::new(HashValue::zero(), 1),
+            )),
+            ConsensusMsg::BlockRetrievalResponse(Box::new(
+                BlockRetrievalResponse::new(
+                    BlockRetrievalStatus::Succeeded,
+                    vec![block.clone()],
+                ),
+            )),
+            ConsensusMsg::EpochRetrievalRequest(Box::new(
+                EpochRetrievalRequest {
+                    start_epoch: 0,
+                    end_epoch: 1,
+                },
+            )),
+            ConsensusMsg::ProposalMsg(Box::new(ProposalMsg::new(
+                block,
+                sync_info.clone(),
+            ))),
+            ConsensusMsg::SyncInfo(Box::new(sync_info.clone())),
+            ConsensusMsg::EpochChangeProof(Box::new(EpochChangeProof::new(
+                vec![LedgerInfoWithSignatures::new(
+                    ledger_info.clone(),
+                    BTreeMap::new(),
+                )],
+                false,
+            ))),
+            ConsensusMsg::VoteMsg(Box::new(VoteMsg::new(
+                Vote::new(
+                    VoteData::new(BlockInfo::empty(), BlockInfo::empty()),
+                    signer.author(),
+                    ledger_info.clone(),
+                    &signer,
+                ),
+                sync_info,
+            ))),
+            ConsensusMsg::CommitVote(Box::new(CommitVoteMsg::new(
+                signer.author(),
+                ledger_info,
+                &signer,
+            ))),
+        ];
+        // Keep this in sync with the variants.
+        let names: HashSet<_> = msgs.iter().map(|msg| msg.name()).collect();
+        assert_eq!(names.len(), 8);
+        msgs
+    }
+
+    fn check_round_trip(codec: &dyn MsgCodec) {
+        for msg in all_variants() {
+            let decoded = codec
+                .decode(&codec.encode(&msg).unwrap())
+                .unwrap_or_else(|e| panic!("decode {}: {:?}", msg.name(), e));
+            assert_eq!(decoded.name(), msg.name());
+            // `ConsensusMsg` is not `PartialEq`, so the BCS bytes are
+            // compared instead.
+            assert_eq!(
+                bcs::to_bytes(&decoded).unwrap(),
+                bcs::to_bytes(&msg).unwrap(),
+                "{}",
+                msg.name()
+            );
+        }
+    }
+
+    #[test]
+    fn test_bcs_round_trip() {
+        check_round_trip(CodecKind::Bcs.codec().unwrap());
+    }
+
+    #[cfg(feature = "pos-json-codec")]
+    #[test]
+    fn test_json_round_trip() {
+        let codec = CodecKind::Json.codec().unwrap();
+        check_round_trip(codec);
+
+        let mut encoded = codec.encode(&all_variants().remove(0)).unwrap();
+        encoded.pop();
+        assert!(codec.decode(&encoded).is_err());
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let all = [CodecKind::Bcs, CodecKind::Json];
+        assert_eq!(
+            negotiate(CodecKind::Bcs, CodecKind::Json, &all),
+            CodecKind::Bcs
+        );
+        assert_eq!(
+            negotiate(CodecKind::Json, CodecKind::Json, &[CodecKind::Bcs]),
+            CodecKind::Bcs
+        );
+        let expected = if cfg!(feature = "pos-json-codec") {
+            CodecKind::Json
+        } else {
+            CodecKind::Bcs
+        };
+        assert_eq!(negotiate(CodecKind::Json, CodecKind::Json, &all), expected);
+    }
+}
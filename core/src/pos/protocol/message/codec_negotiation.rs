@@ -0,0 +1,50 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+use crate::{
+    pos::protocol::{
+        message::codec::{negotiate, CodecKind},
+        sync_protocol::{Context, Handleable},
+    },
+    sync::Error,
+};
+use diem_logger::prelude::diem_debug;
+use serde::{Deserialize, Serialize};
+
+/// Sent on connection by a node that prefers another codec than BCS for the
+/// `ConsensusMsg`s. The nodes that use the default codec never send it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CodecNegotiation {
+    pub preferred: CodecKind,
+    pub supported: Vec<CodecKind>,
+}
+
+impl CodecNegotiation {
+    pub fn new(preferred: CodecKind) -> Self {
+        Self {
+            preferred,
+            supported: CodecKind::supported(),
+        }
+    }
+}
+
+impl Handleable for CodecNegotiation {
+    fn handle(self, ctx: &Context) -> Result<(), Error> {
+        let codec = negotiate(
+            ctx.manager.protocol_config.pos_consensus_msg_codec,
+            self.preferred,
+            &self.supported,
+        );
+        diem_debug!(
+            "Negotiated consensus msg codec {:?} with peer {}, preferred {:?}",
+            codec,
+            ctx.peer,
+            self.preferred
+        );
+        if let Some(peer) = ctx.manager.peers.get(&ctx.peer_hash) {
+            peer.write().set_codec(codec);
+        }
+        Ok(())
+    }
+}
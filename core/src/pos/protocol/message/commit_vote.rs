@@ -0,0 +1,25 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+use crate::{
+    pos::{
+        consensus::network::ConsensusMsg,
+        protocol::sync_protocol::{Context, Handleable},
+    },
+    sync::Error,
+};
+use consensus_types::commit_vote_msg::CommitVoteMsg;
+use diem_logger::prelude::diem_debug;
+
+impl Handleable for CommitVoteMsg {
+    fn handle(self, ctx: &Context) -> Result<(), Error> {
+        diem_debug!("on_commit_vote, msg={:?}", &self);
+
+        let peer_address = ctx.get_peer_account_address()?;
+        let author = self.author();
+        let msg = ConsensusMsg::CommitVote(Box::new(self));
+        ctx.manager
+            .deliver_consensus_msg(&ctx.peer, peer_address, author, msg)
+    }
+}
@@ -10,7 +10,6 @@ use crate::{
     sync::Error,
 };
 use diem_logger::prelude::diem_debug;
-use std::mem::discriminant;
 
 impl Handleable for ConsensusMsg {
     fn handle(self, ctx: &Context) -> Result<(), Error> {
@@ -19,12 +18,10 @@ impl Handleable for ConsensusMsg {
         let author = match &self {
             ConsensusMsg::ProposalMsg(p) => p.proposer(),
             ConsensusMsg::VoteMsg(v) => v.vote().author(),
+            ConsensusMsg::CommitVote(v) => v.author(),
             _ => peer_address,
         };
         ctx.manager
-            .consensus_network_task
-            .consensus_messages_tx
-            .push((author, discriminant(&self)), (peer_address, self))?;
-        Ok(())
+            .deliver_consensus_msg(&ctx.peer, peer_address, author, self)
     }
 }
@@ -10,16 +10,16 @@ use crate::{
     sync::Error,
 };
 use diem_types::epoch_change::EpochChangeProof;
-use std::mem::discriminant;
 
 impl Handleable for EpochChangeProof {
     fn handle(self, ctx: &Context) -> Result<(), Error> {
         let peer_address = ctx.get_peer_account_address()?;
         let msg = ConsensusMsg::EpochChangeProof(Box::new(self));
-        ctx.manager
-            .consensus_network_task
-            .consensus_messages_tx
-            .push((peer_address, discriminant(&msg)), (peer_address, msg))?;
-        Ok(())
+        ctx.manager.deliver_consensus_msg(
+            &ctx.peer,
+            peer_address,
+            peer_address,
+            msg,
+        )
     }
 }
@@ -0,0 +1,92 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+use crate::{
+    pos::protocol::{
+        compression::deflate,
+        message::msgid,
+        sync_protocol::{
+            drop_malformed_message, handle_serialized_message, Context,
+            Handleable,
+        },
+    },
+    sync::Error,
+};
+use serde::{Deserialize, Serialize};
+
+/// A part of an encoded `EpochChangeProof` too large to be sent in one
+/// message. The receiver reassembles the proof from its chunks, see
+/// `EpochChangeReassembly`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EpochChangeChunk {
+    /// The same for all the chunks of one proof.
+    pub proof_id: u64,
+    pub index: u32,
+    pub num_chunks: u32,
+    /// Whether `data` is deflated.
+    pub compressed: bool,
+    pub data: Vec<u8>,
+}
+
+impl EpochChangeChunk {
+    /// Split the BCS encoded proof `encoded` into chunks of at most
+    /// `chunk_size` bytes. If `compress`, each chunk is deflated when that
+    /// makes it smaller.
+    pub fn split(
+        encoded: &[u8], proof_id: u64, chunk_size: usize, compress: bool,
+    ) -> Vec<EpochChangeChunk> {
+        let parts: Vec<&[u8]> = encoded.chunks(chunk_size.max(1)).collect();
+        let num_chunks = parts.len() as u32;
+        parts
+            .into_iter()
+            .enumerate()
+            .map(|(index, part)| {
+                let deflated = if compress {
+                    deflate(part).ok().filter(|d| d.len() < part.len())
+                } else {
+                    None
+                };
+                EpochChangeChunk {
+                    proof_id,
+                    index: index as u32,
+                    num_chunks,
+                    compressed: deflated.is_some(),
+                    data: deflated.unwrap_or_else(|| part.to_vec()),
+                }
+            })
+            .collect()
+    }
+}
+
+impl Handleable for EpochChangeChunk {
+    fn handle(self, ctx: &Context) -> Result<(), Error> {
+        let max_size = ctx
+            .manager
+            .protocol_config
+            .pos_message_size_limits
+            .limit(msgid::EPOCH_CHANGE);
+        let size = self.data.len();
+        match ctx
+            .manager
+            .epoch_change_chunks
+            .add(ctx.peer, self, max_size)
+        {
+            // The reassembled proof is handled like one received at once.
+            Ok(Some(encoded)) => {
+                handle_serialized_message(msgid::EPOCH_CHANGE, ctx, &encoded)?;
+                Ok(())
+            }
+            Ok(None) => Ok(()),
+            Err(e) => {
+                drop_malformed_message(
+                    ctx,
+                    msgid::EPOCH_CHANGE_CHUNK,
+                    size,
+                    &e,
+                );
+                Ok(())
+            }
+        }
+    }
+}
@@ -11,7 +11,6 @@ use crate::{
 };
 use consensus_types::epoch_retrieval::EpochRetrievalRequest;
 use diem_logger::prelude::diem_debug;
-use std::mem::discriminant;
 
 impl Handleable for EpochRetrievalRequest {
     fn handle(self, ctx: &Context) -> Result<(), Error> {
@@ -21,10 +20,11 @@ impl Handleable for EpochRetrievalRequest {
             peer_address, self.start_epoch, self.end_epoch
         );
         let msg = ConsensusMsg::EpochRetrievalRequest(Box::new(self));
-        ctx.manager
-            .consensus_network_task
-            .consensus_messages_tx
-            .push((peer_address, discriminant(&msg)), (peer_address, msg))?;
-        Ok(())
+        ctx.manager.deliver_consensus_msg(
+            &ctx.peer,
+            peer_address,
+            peer_address,
+            msg,
+        )
     }
 }
@@ -0,0 +1,30 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+use crate::{
+    pos::protocol::sync_protocol::{Context, Handleable},
+    sync::Error,
+};
+use diem_logger::prelude::diem_debug;
+use serde::{Deserialize, Serialize};
+
+/// Answers an `EpochRetrievalRequest` when the peer is assembling as many
+/// epoch change proofs as it allows at once, see `EpochRetrievalLimiter`.
+/// Nothing is sent back for the request, and the requester asks again when
+/// it receives the next message of a later epoch from the peer.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EpochRetrievalBusy {
+    pub start_epoch: u64,
+    pub end_epoch: u64,
+}
+
+impl Handleable for EpochRetrievalBusy {
+    fn handle(self, ctx: &Context) -> Result<(), Error> {
+        diem_debug!(
+            "Peer {:?} is too busy to send the epoch change proof, start epoch {}, end epoch {}",
+            ctx.peer, self.start_epoch, self.end_epoch
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+use crate::{
+    message::RequestId,
+    pos::protocol::{
+        request_manager::{AsAny, Request},
+        sync_protocol::{
+            Context, Handleable, RpcResponse, RpcResponseWithPeer,
+        },
+    },
+    sync::{Error, ErrorKind},
+};
+use futures::channel::oneshot;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+
+/// A RPC echoed at once by the peer with a `LatencyProbeRpcResponse` of the
+/// same nonce, to measure the round trip to it apart from the load of the
+/// retrievals, see `NetworkSender::measure_latency`. As with the other RPCs,
+/// the latency of the response is recorded in the latencies of the peer.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LatencyProbeRpcRequest {
+    pub request_id: RequestId,
+    pub nonce: u64,
+    #[serde(skip)]
+    pub response_tx:
+        Option<oneshot::Sender<Result<RpcResponseWithPeer, Error>>>,
+}
+
+impl AsAny for LatencyProbeRpcRequest {
+    fn as_any(&self) -> &dyn Any { self }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any { self }
+}
+
+// A probe resent after a timeout would not measure the round trip, so it is
+// not resent.
+impl Request for LatencyProbeRpcRequest {
+    fn notify_error(&mut self, error: Error) {
+        if let Some(tx) = self.response_tx.take() {
+            // The receiver may be dropped, which is fine.
+            let _ = tx.send(Err(error));
+        }
+    }
+
+    fn set_response_notification(
+        &mut self, res_tx: oneshot::Sender<Result<RpcResponseWithPeer, Error>>,
+    ) {
+        self.response_tx = Some(res_tx);
+    }
+}
+
+impl Handleable for LatencyProbeRpcRequest {
+    fn handle(self, ctx: &Context) -> Result<(), Error> {
+        ctx.send_response(&LatencyProbeRpcResponse {
+            request_id: self.request_id,
+            nonce: self.nonce,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LatencyProbeRpcResponse {
+    pub request_id: RequestId,
+    pub nonce: u64,
+}
+
+impl RpcResponse for LatencyProbeRpcResponse {
+    fn into_any(self: Box<Self>) -> Box<dyn Any> { self }
+}
+
+impl AsAny for LatencyProbeRpcResponse {
+    fn as_any(&self) -> &dyn Any { self }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any { self }
+}
+
+impl Handleable for LatencyProbeRpcResponse {
+    fn handle(self, ctx: &Context) -> Result<(), Error> {
+        let mut req = ctx.match_request(self.request_id)?;
+        let req = req.downcast_mut::<LatencyProbeRpcRequest>(
+            ctx.io,
+            &ctx.manager.request_manager,
+        )?;
+        if self.nonce != req.nonce {
+            req.notify_error(
+                ErrorKind::UnexpectedMessage(
+                    format!(
+                        "latency probe nonce {}, expected {}",
+                        self.nonce, req.nonce
+                    )
+                    .into(),
+                )
+                .into(),
+            );
+            bail!(ErrorKind::UnexpectedResponse);
+        }
+        if let Some(tx) = req.response_tx.take() {
+            // The receiver may be dropped, which is fine.
+            let _ = tx.send(Ok(RpcResponseWithPeer {
+                peer: ctx.peer,
+                response: Box::new(self),
+            }));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LatencyProbeRpcRequest, LatencyProbeRpcResponse};
+    use crate::{
+        pos::{
+            consensus::network::NetworkTask as ConsensusNetworkTask,
+            mempool::network::NetworkTask as MempoolNetworkTask,
+            protocol::{
+                sync_protocol::HotStuffSynchronizationProtocol,
+                test_utils::MockNetworkContext,
+            },
+        },
+        sync::ProtocolConfiguration,
+    };
+    use cfx_types::H256;
+    use futures::channel::oneshot;
+    use keccak_hash::keccak;
+    use network::{node_table::NodeId, NetworkProtocolHandler};
+
+    fn handler() -> HotStuffSynchronizationProtocol {
+        HotStuffSynchronizationProtocol::new(
+            H256::zero(),
+            ConsensusNetworkTask::new().0,
+            MempoolNetworkTask::new().0,
+            ProtocolConfiguration::default(),
+        )
+    }
+
+    #[test]
+    fn test_latency_probe_round_trip() {
+        let (local, remote) = (handler(), handler());
+        let (local_io, remote_io) =
+            (MockNetworkContext::default(), MockNetworkContext::default());
+        let local_id = NodeId::from_low_u64_be(1);
+        let remote_id = NodeId::from_low_u64_be(2);
+        local.peers.insert(keccak(&remote_id), remote_id, None);
+        local.request_manager.on_peer_connected(&remote_id);
+        remote.peers.insert(keccak(&local_id), local_id, None);
+
+        let (res_tx, mut res_rx) = oneshot::channel();
+        let request = LatencyProbeRpcRequest {
+            request_id: 0,
+            nonce: 42,
+            response_tx: Some(res_tx),
+        };
+        let request_id = local
+            .request_manager
+            .request_with_delay(
+                &local_io,
+                Box::new(request),
+                Some(remote_id),
+                None,
+                None,
+            )
+            .unwrap();
+
+        // The peer echoes the probe at once.
+        let probe = local_io.payloads.lock().pop().unwrap();
+        remote.on_message(&remote_io, &local_id, &probe);
+        assert_eq!(*remote_io.sent.lock(), vec![local_id]);
+        let echo = remote_io.payloads.lock().pop().unwrap();
+        local.on_message(&local_io, &remote_id, &echo);
+
+        let response = res_rx.try_recv().unwrap().unwrap().unwrap();
+        assert_eq!(response.peer, remote_id);
+        let response = response
+            .response
+            .into_typed::<LatencyProbeRpcResponse>()
+            .unwrap();
+        assert_eq!(
+            *response,
+            LatencyProbeRpcResponse {
+                request_id,
+                nonce: 42,
+            }
+        );
+    }
+}
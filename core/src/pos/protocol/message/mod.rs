@@ -4,32 +4,62 @@
 
 pub mod block_retrieval;
 pub mod block_retrieval_response;
+pub mod block_stream;
+pub mod chain_id_handshake;
+pub mod codec;
+pub mod codec_negotiation;
+pub mod commit_vote;
 pub mod consensus_msg;
 pub mod epoch_change;
+pub mod epoch_change_chunk;
 pub mod epoch_retrieval;
+pub mod epoch_retrieval_busy;
+pub mod latency_probe;
 pub mod mempool_sync_msg;
+pub mod ping;
 pub mod proposal;
 pub mod sync_info;
 pub mod vote;
+pub mod with_sync_info;
 
-use super::HSB_PROTOCOL_VERSION;
+use super::{
+    HSB_PROTOCOL_V1, HSB_PROTOCOL_V2, HSB_PROTOCOL_V3, HSB_PROTOCOL_V4,
+    HSB_PROTOCOL_V5, HSB_PROTOCOL_V6, HSB_PROTOCOL_V7, HSB_PROTOCOL_V9,
+    HSB_PROTOCOL_V10, HSB_PROTOCOL_V13, HSB_PROTOCOL_V14, HSB_PROTOCOL_VERSION,
+};
 
 use crate::{
     message::{
         GetMaybeRequestId, Message, MessageProtocolVersionBound, MsgId,
-        RequestId, SetRequestId,
+        RequestId, SendQueuePriority, SetRequestId,
+    },
+    pos::{
+        consensus::network::{ConsensusMsg, MessagePriority},
+        mempool::network::MempoolSyncMsg,
     },
-    pos::{consensus::network::ConsensusMsg, mempool::network::MempoolSyncMsg},
 };
 
-use block_retrieval::BlockRetrievalRpcRequest;
-use block_retrieval_response::BlockRetrievalRpcResponse;
+use block_retrieval::{
+    BlockRetrievalRpcRequest, UnlimitedBlockRetrievalRpcRequest,
+};
+use block_retrieval_response::{
+    BlockRetrievalRpcResponse, UncursoredBlockRetrievalRpcResponse,
+};
+use block_stream::{BlockStreamChunk, BlockStreamCredit, BlockStreamOpen};
+use chain_id_handshake::ChainIdHandshake;
+use codec::CodecKind;
+use codec_negotiation::CodecNegotiation;
 use consensus_types::{
-    epoch_retrieval::EpochRetrievalRequest, proposal_msg::ProposalMsg,
-    sync_info::SyncInfo, vote_msg::VoteMsg,
+    commit_vote_msg::CommitVoteMsg, epoch_retrieval::EpochRetrievalRequest,
+    proposal_msg::ProposalMsg, sync_info::SyncInfo, vote_msg::VoteMsg,
 };
 use diem_types::epoch_change::EpochChangeProof;
+use epoch_change_chunk::EpochChangeChunk;
+use epoch_retrieval_busy::EpochRetrievalBusy;
+use latency_probe::{LatencyProbeRpcRequest, LatencyProbeRpcResponse};
 use network::service::ProtocolVersion;
+use ping::{Ping, Pong};
+use with_sync_info::WithSyncInfo;
 
 // FIXME: A temporary workaround by avoiding msg_id overlapping
 // with SynchronizationProtocolHandler msg_id.
@@ -43,6 +73,24 @@ build_msgid! {
     EPOCH_RETRIEVAL = 0x56
     CONSENSUS_MSG = 0x57
     MEMPOOL_SYNC_MSG = 0x58
+    COMMIT_VOTE = 0x59
+    COMPRESSED = 0x5a
+    CONSENSUS_MSG_JSON = 0x5b
+    CODEC_NEGOTIATION = 0x5c
+    CHAIN_ID_HANDSHAKE = 0x5d
+    WITH_SYNC_INFO = 0x5e
+    PING = 0x5f
+    PONG = 0x60
+    EPOCH_CHANGE_CHUNK = 0x61
+    EPOCH_RETRIEVAL_BUSY = 0x62
+    SEQUENCED = 0x63
+    LATENCY_PROBE = 0x64
+    LATENCY_PROBE_RESPONSE = 0x65
+    BLOCK_STREAM_OPEN = 0x66
+    BLOCK_STREAM_CHUNK = 0x67
+    BLOCK_STREAM_CREDIT = 0x68
+    BLOCK_RETRIEVAL_PAGE = 0x69
+    BLOCK_RETRIEVAL_PAGE_RESPONSE = 0x6a
     INVALID = 0xff
 }
 
@@ -104,48 +152,248 @@ macro_rules! build_msg_impl_with_request_id_and_serde_serialization {
 }
 
 build_msg_impl_with_serde_serialization_generic! {ProposalMsg, msgid::PROPOSAL, "ProposalMessage"}
+mark_msg_version_bound!(ProposalMsg, HSB_PROTOCOL_V1, HSB_PROTOCOL_VERSION);
+impl GetMaybeRequestId for BlockRetrievalRpcResponse {}
+
+// A response with a cursor is only sent to the peers of `HSB_PROTOCOL_V13`
+// or later, the other responses keep the layout the older peers decode.
+impl Message for BlockRetrievalRpcResponse {
+    fn msg_id(&self) -> MsgId {
+        if self.response.has_more() {
+            msgid::BLOCK_RETRIEVAL_PAGE_RESPONSE
+        } else {
+            msgid::BLOCK_RETRIEVAL_RESPONSE
+        }
+    }
+
+    fn msg_name(&self) -> &'static str { "BlockRetrievalResponseMessage" }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut encoded = if self.response.has_more() {
+            bcs::to_bytes(self)
+        } else {
+            bcs::to_bytes(&UncursoredBlockRetrievalRpcResponse::from(self))
+        }
+        .expect("Failed to serialize.");
+        encoded.push(self.msg_id() as u8);
+        encoded
+    }
+}
+
+impl MessageProtocolVersionBound for BlockRetrievalRpcResponse {
+    fn version_introduced(&self) -> ProtocolVersion {
+        if self.response.has_more() {
+            HSB_PROTOCOL_V13
+        } else {
+            HSB_PROTOCOL_V1
+        }
+    }
+
+    fn version_valid_till(&self) -> ProtocolVersion { HSB_PROTOCOL_VERSION }
+}
+build_msg_impl_with_serde_serialization! {VoteMsg, msgid::VOTE, "VoteMessage"}
+mark_msg_version_bound!(VoteMsg, HSB_PROTOCOL_V1, HSB_PROTOCOL_VERSION);
+build_msg_impl_with_serde_serialization! {CommitVoteMsg, msgid::COMMIT_VOTE, "CommitVoteMessage"}
+mark_msg_version_bound!(CommitVoteMsg, HSB_PROTOCOL_V2, HSB_PROTOCOL_VERSION);
+build_msg_impl_with_serde_serialization! {SyncInfo, msgid::SYNC_INFO, "SyncInfoMessage"}
+mark_msg_version_bound!(SyncInfo, HSB_PROTOCOL_V1, HSB_PROTOCOL_VERSION);
+build_msg_impl_with_serde_serialization! {EpochChangeProof, msgid::EPOCH_CHANGE, "EpochChangeMessage"}
 mark_msg_version_bound!(
-    ProposalMsg,
-    HSB_PROTOCOL_VERSION,
+    EpochChangeProof,
+    HSB_PROTOCOL_V1,
     HSB_PROTOCOL_VERSION
 );
-build_msg_impl_with_serde_serialization_generic! {BlockRetrievalRpcResponse, msgid::BLOCK_RETRIEVAL_RESPONSE, "BlockRetrievalResponseMessage"}
+impl GetMaybeRequestId for ConsensusMsg {}
+
+impl Message for ConsensusMsg {
+    fn msg_id(&self) -> MsgId { msgid::CONSENSUS_MSG }
+
+    // Name each variant separately so they are told apart in the metrics.
+    fn msg_name(&self) -> &'static str { self.name() }
+
+    fn epoch_round(&self) -> (Option<u64>, Option<u64>) {
+        ConsensusMsg::epoch_round(self)
+    }
+
+    // The low priority messages only help peers catch up, so they are sent
+    // after and shed before the others.
+    fn priority(&self) -> SendQueuePriority {
+        match ConsensusMsg::priority(self) {
+            MessagePriority::High => SendQueuePriority::High,
+            MessagePriority::Low => SendQueuePriority::Normal,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut encoded = bcs::to_bytes(self).expect("Failed to serialize.");
+        encoded.push(self.msg_id() as u8);
+        encoded
+    }
+
+    fn encode_with_codec(&self, codec: CodecKind) -> Vec<u8> {
+        match codec.codec() {
+            Some(msg_codec) if codec != CodecKind::Bcs => {
+                let mut encoded =
+                    msg_codec.encode(self).expect("Failed to serialize.");
+                encoded.push(codec.msg_id() as u8);
+                encoded
+            }
+            _ => self.encode(),
+        }
+    }
+}
+// The variants added after V1 cannot be decoded by the older peers, so they
+// are not sent to them.
+impl MessageProtocolVersionBound for ConsensusMsg {
+    fn version_introduced(&self) -> ProtocolVersion {
+        match self {
+            ConsensusMsg::CommitVote(_) => HSB_PROTOCOL_V2,
+            _ => HSB_PROTOCOL_V1,
+        }
+    }
+
+    fn version_valid_till(&self) -> ProtocolVersion { HSB_PROTOCOL_VERSION }
+}
+build_msg_impl_with_serde_serialization! {EpochRetrievalRequest, msgid::EPOCH_RETRIEVAL, "EpochRetrievalMessage"}
 mark_msg_version_bound!(
-    BlockRetrievalRpcResponse,
-    HSB_PROTOCOL_VERSION,
+    EpochRetrievalRequest,
+    HSB_PROTOCOL_V1,
     HSB_PROTOCOL_VERSION
 );
-build_msg_impl_with_serde_serialization! {VoteMsg, msgid::VOTE, "VoteMessage"}
-mark_msg_version_bound!(VoteMsg, HSB_PROTOCOL_VERSION, HSB_PROTOCOL_VERSION);
-build_msg_impl_with_serde_serialization! {SyncInfo, msgid::SYNC_INFO, "SyncInfoMessage"}
-mark_msg_version_bound!(SyncInfo, HSB_PROTOCOL_VERSION, HSB_PROTOCOL_VERSION);
-build_msg_impl_with_serde_serialization! {EpochChangeProof, msgid::EPOCH_CHANGE, "EpochChangeMessage"}
+// A request with a byte limit is only sent to the peers of
+// `HSB_PROTOCOL_V13` or later, the other requests keep the layout the older
+// peers decode.
+impl Message for BlockRetrievalRpcRequest {
+    fn msg_id(&self) -> MsgId {
+        if self.request.max_response_bytes() == 0 {
+            msgid::BLOCK_RETRIEVAL
+        } else {
+            msgid::BLOCK_RETRIEVAL_PAGE
+        }
+    }
+
+    fn msg_name(&self) -> &'static str { "BlockRetrievalMessage" }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut encoded = if self.request.max_response_bytes() == 0 {
+            bcs::to_bytes(&UnlimitedBlockRetrievalRpcRequest::from(self))
+        } else {
+            bcs::to_bytes(self)
+        }
+        .expect("Failed to serialize.");
+        encoded.push(self.msg_id() as u8);
+        encoded
+    }
+}
+
+impl_request_id_methods!(BlockRetrievalRpcRequest);
+
+impl MessageProtocolVersionBound for BlockRetrievalRpcRequest {
+    fn version_introduced(&self) -> ProtocolVersion {
+        if self.request.max_response_bytes() == 0 {
+            HSB_PROTOCOL_V1
+        } else {
+            HSB_PROTOCOL_V13
+        }
+    }
+
+    fn version_valid_till(&self) -> ProtocolVersion { HSB_PROTOCOL_VERSION }
+}
+build_msg_impl_with_serde_serialization! {MempoolSyncMsg, msgid::MEMPOOL_SYNC_MSG, "MempoolSyncMsg"}
+mark_msg_version_bound!(MempoolSyncMsg, HSB_PROTOCOL_V1, HSB_PROTOCOL_VERSION);
+build_msg_impl_with_serde_serialization! {CodecNegotiation, msgid::CODEC_NEGOTIATION, "CodecNegotiation"}
 mark_msg_version_bound!(
-    EpochChangeProof,
-    HSB_PROTOCOL_VERSION,
+    CodecNegotiation,
+    HSB_PROTOCOL_V14,
     HSB_PROTOCOL_VERSION
 );
-build_msg_impl_with_serde_serialization! {ConsensusMsg, msgid::CONSENSUS_MSG, "ConsensusMsg"}
+build_msg_impl_with_serde_serialization! {ChainIdHandshake, msgid::CHAIN_ID_HANDSHAKE, "ChainIdHandshake"}
 mark_msg_version_bound!(
-    ConsensusMsg,
-    HSB_PROTOCOL_VERSION,
+    ChainIdHandshake,
+    HSB_PROTOCOL_V3,
     HSB_PROTOCOL_VERSION
 );
-build_msg_impl_with_serde_serialization! {EpochRetrievalRequest, msgid::EPOCH_RETRIEVAL, "EpochRetrievalMessage"}
+
+impl GetMaybeRequestId for WithSyncInfo {}
+
+impl Message for WithSyncInfo {
+    fn msg_id(&self) -> MsgId { msgid::WITH_SYNC_INFO }
+
+    fn msg_name(&self) -> &'static str { "WithSyncInfo" }
+
+    // Sent with the priority of the message it carries.
+    fn priority(&self) -> SendQueuePriority { Message::priority(&self.msg) }
+
+    fn epoch_round(&self) -> (Option<u64>, Option<u64>) {
+        Message::epoch_round(&self.msg)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut encoded = bcs::to_bytes(self).expect("Failed to serialize.");
+        encoded.push(self.msg_id() as u8);
+        encoded
+    }
+}
+mark_msg_version_bound!(WithSyncInfo, HSB_PROTOCOL_V4, HSB_PROTOCOL_VERSION);
+build_msg_impl_with_serde_serialization! {Ping, msgid::PING, "Ping"}
+mark_msg_version_bound!(Ping, HSB_PROTOCOL_V5, HSB_PROTOCOL_VERSION);
+build_msg_impl_with_serde_serialization! {Pong, msgid::PONG, "Pong"}
+mark_msg_version_bound!(Pong, HSB_PROTOCOL_V5, HSB_PROTOCOL_VERSION);
+
+impl GetMaybeRequestId for EpochChangeChunk {}
+
+impl Message for EpochChangeChunk {
+    fn msg_id(&self) -> MsgId { msgid::EPOCH_CHANGE_CHUNK }
+
+    fn msg_name(&self) -> &'static str { "EpochChangeChunk" }
+
+    // Sent with the priority of the `EpochChangeProof` it is a part of.
+    fn priority(&self) -> SendQueuePriority { SendQueuePriority::Normal }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut encoded = bcs::to_bytes(self).expect("Failed to serialize.");
+        encoded.push(self.msg_id() as u8);
+        encoded
+    }
+}
 mark_msg_version_bound!(
-    EpochRetrievalRequest,
-    HSB_PROTOCOL_VERSION,
+    EpochChangeChunk,
+    HSB_PROTOCOL_V6,
     HSB_PROTOCOL_VERSION
 );
-build_msg_impl_with_request_id_and_serde_serialization! {BlockRetrievalRpcRequest, msgid::BLOCK_RETRIEVAL, "BlockRetrievalMessage"}
+build_msg_impl_with_serde_serialization! {EpochRetrievalBusy, msgid::EPOCH_RETRIEVAL_BUSY, "EpochRetrievalBusy"}
 mark_msg_version_bound!(
-    BlockRetrievalRpcRequest,
-    HSB_PROTOCOL_VERSION,
+    EpochRetrievalBusy,
+    HSB_PROTOCOL_V7,
     HSB_PROTOCOL_VERSION
 );
-build_msg_impl_with_serde_serialization! {MempoolSyncMsg, msgid::MEMPOOL_SYNC_MSG, "MempoolSyncMsg"}
+build_msg_impl_with_request_id_and_serde_serialization! {LatencyProbeRpcRequest, msgid::LATENCY_PROBE, "LatencyProbeMessage"}
+mark_msg_version_bound!(
+    LatencyProbeRpcRequest,
+    HSB_PROTOCOL_V9,
+    HSB_PROTOCOL_VERSION
+);
+build_msg_impl_with_serde_serialization! {LatencyProbeRpcResponse, msgid::LATENCY_PROBE_RESPONSE, "LatencyProbeResponseMessage"}
+mark_msg_version_bound!(
+    LatencyProbeRpcResponse,
+    HSB_PROTOCOL_V9,
+    HSB_PROTOCOL_VERSION
+);
+build_msg_impl_with_serde_serialization! {BlockStreamOpen, msgid::BLOCK_STREAM_OPEN, "BlockStreamOpen"}
+mark_msg_version_bound!(
+    BlockStreamOpen,
+    HSB_PROTOCOL_V10,
+    HSB_PROTOCOL_VERSION
+);
+build_msg_impl_with_serde_serialization! {BlockStreamChunk, msgid::BLOCK_STREAM_CHUNK, "BlockStreamChunk"}
+mark_msg_version_bound!(
+    BlockStreamChunk,
+    HSB_PROTOCOL_V10,
+    HSB_PROTOCOL_VERSION
+);
+build_msg_impl_with_serde_serialization! {BlockStreamCredit, msgid::BLOCK_STREAM_CREDIT, "BlockStreamCredit"}
 mark_msg_version_bound!(
-    MempoolSyncMsg,
-    HSB_PROTOCOL_VERSION,
+    BlockStreamCredit,
+    HSB_PROTOCOL_V10,
     HSB_PROTOCOL_VERSION
 );
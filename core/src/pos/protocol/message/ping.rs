@@ -0,0 +1,35 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+use crate::{
+    pos::protocol::sync_protocol::{Context, Handleable},
+    sync::Error,
+};
+use serde::{Deserialize, Serialize};
+
+/// Sent to each peer every ping interval to tell whether the connection is
+/// still alive, see `PeerLiveness`. It is answered with a `Pong` of the
+/// same nonce.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Ping {
+    pub nonce: u64,
+}
+
+impl Handleable for Ping {
+    fn handle(self, ctx: &Context) -> Result<(), Error> {
+        ctx.send_response(&Pong { nonce: self.nonce })
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Pong {
+    pub nonce: u64,
+}
+
+impl Handleable for Pong {
+    fn handle(self, ctx: &Context) -> Result<(), Error> {
+        ctx.manager.peer_liveness.on_pong(&ctx.peer, self.nonce);
+        Ok(())
+    }
+}
@@ -4,15 +4,26 @@
 
 use crate::{
     pos::{
-        consensus::network::ConsensusMsg,
-        protocol::sync_protocol::{Context, Handleable},
+        consensus::{counters, network::ConsensusMsg},
+        protocol::{
+            message::block_retrieval::BlockRetrievalRpcRequest,
+            peer_event::ProtocolViolationKind,
+            pending_proposals::{ParentCheck, PendingProposal},
+            proposal_tracker::ProposalObservation,
+            sync_protocol::{Context, Handleable},
+        },
     },
     sync::Error,
 };
 
-use consensus_types::proposal_msg::ProposalMsg;
-use diem_logger::prelude::diem_debug;
-use std::mem::discriminant;
+use consensus_types::{
+    block_retrieval::BlockRetrievalRequest, proposal_msg::ProposalMsg,
+};
+use diem_crypto::HashValue;
+use diem_logger::prelude::{diem_debug, diem_warn};
+use diem_types::account_address::AccountAddress;
+use network::node_table::NodeId;
+use std::time::Duration;
 
 impl Handleable for ProposalMsg {
     fn handle(self, ctx: &Context) -> Result<(), Error> {
@@ -25,12 +36,97 @@ impl Handleable for ProposalMsg {
             "proposal received must be from the sending peer"
         );*/
 
-        let author = self.proposer();
-        let msg = ConsensusMsg::ProposalMsg(Box::new(self));
-        ctx.manager
-            .consensus_network_task
-            .consensus_messages_tx
-            .push((author, discriminant(&msg)), (peer_address, msg))?;
-        Ok(())
+        if let ProposalObservation::Equivocation { first_block_id } =
+            ctx.manager.proposal_tracker.observe(&self)
+        {
+            diem_warn!(
+                "equivocating proposal from peer {:?}: {} conflicts with {}",
+                ctx.peer,
+                self,
+                first_block_id
+            );
+            counters::NETWORK_EQUIVOCATING_PROPOSALS.inc();
+            ctx.manager.on_protocol_violation(
+                &ctx.peer,
+                ProtocolViolationKind::EquivocatingProposal,
+            );
+            // The proposal is still forwarded as the evidence for
+            // consensus.
+        }
+
+        for pending in ctx.manager.pending_proposals.take_expired() {
+            deliver_pending(ctx, pending)?;
+        }
+        if let ParentCheck::Missing {
+            parent_id,
+            num_blocks,
+        } = ctx.manager.pending_proposals.check(&self)
+        {
+            match ctx.manager.pending_proposals.hold(
+                ctx.peer,
+                peer_address,
+                self,
+            ) {
+                Ok(()) => {
+                    counters::NETWORK_PROPOSALS_HELD.inc();
+                    request_ancestors(ctx, parent_id, num_blocks);
+                    return Ok(());
+                }
+                Err(proposal) => {
+                    return deliver(ctx, &ctx.peer, peer_address, proposal)
+                }
+            }
+        }
+        deliver(ctx, &ctx.peer, peer_address, self)
+    }
+}
+
+/// Forward `proposal` received from `peer` to consensus, followed by the proposals held back
+/// for it.
+pub fn deliver(
+    ctx: &Context, peer: &NodeId, peer_address: AccountAddress,
+    proposal: ProposalMsg,
+) -> Result<(), Error> {
+    let released = ctx.manager.pending_proposals.on_block(proposal.proposal());
+    let author = proposal.proposer();
+    let msg = ConsensusMsg::ProposalMsg(Box::new(proposal));
+    ctx.manager
+        .deliver_consensus_msg(peer, peer_address, author, msg)?;
+    for pending in released {
+        deliver_pending(ctx, pending)?;
     }
+    Ok(())
+}
+
+/// Forward a proposal held back, once its parent is seen or it expires.
+pub fn deliver_pending(
+    ctx: &Context, pending: PendingProposal,
+) -> Result<(), Error> {
+    diem_debug!(
+        "deliver proposal {} held back from peer {:?}",
+        pending.proposal,
+        pending.peer
+    );
+    deliver(ctx, &pending.peer, pending.peer_address, pending.proposal)
+}
+
+/// Retrieve the missing ancestors of a proposal held back from the peer
+/// that has sent it. The response releases the proposal, see
+/// `BlockRetrievalRpcResponse`.
+fn request_ancestors(ctx: &Context, parent_id: HashValue, num_blocks: u64) {
+    let request = BlockRetrievalRpcRequest {
+        request_id: 0,
+        request: BlockRetrievalRequest::new(parent_id, num_blocks),
+        is_empty: false,
+        response_tx: None,
+        coalesced_tx: Vec::new(),
+        timeout: Duration::from_secs(0),
+    };
+    ctx.manager.request_manager.request_with_delay(
+        ctx.io,
+        Box::new(request),
+        Some(ctx.peer),
+        None,
+        None,
+    );
 }
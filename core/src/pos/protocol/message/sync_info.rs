@@ -11,7 +11,6 @@ use crate::{
 };
 use consensus_types::sync_info::SyncInfo;
 use diem_logger::prelude::diem_debug;
-use std::mem::discriminant;
 
 impl Handleable for SyncInfo {
     fn handle(self, ctx: &Context) -> Result<(), Error> {
@@ -20,10 +19,11 @@ impl Handleable for SyncInfo {
         let peer_address = ctx.get_peer_account_address()?;
 
         let msg = ConsensusMsg::SyncInfo(Box::new(self));
-        ctx.manager
-            .consensus_network_task
-            .consensus_messages_tx
-            .push((peer_address, discriminant(&msg)), (peer_address, msg))?;
-        Ok(())
+        ctx.manager.deliver_consensus_msg(
+            &ctx.peer,
+            peer_address,
+            peer_address,
+            msg,
+        )
     }
 }
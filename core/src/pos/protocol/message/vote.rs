@@ -11,13 +11,16 @@ use crate::{
 };
 use consensus_types::vote_msg::VoteMsg;
 use diem_logger::prelude::diem_debug;
-use std::mem::discriminant;
 
 impl Handleable for VoteMsg {
     fn handle(self, ctx: &Context) -> Result<(), Error> {
         diem_debug!("on_vote, msg={:?}", &self);
 
         let peer_address = ctx.get_peer_account_address()?;
+        if !ctx.manager.vote_dedup.check(self.vote()) {
+            diem_debug!("drop duplicate vote from {}", ctx.peer);
+            return Ok(());
+        }
 
         /*ensure!(
             self.vote().author() == peer_address,
@@ -28,9 +31,6 @@ impl Handleable for VoteMsg {
         let author = self.vote().author();
         let msg = ConsensusMsg::VoteMsg(Box::new(self));
         ctx.manager
-            .consensus_network_task
-            .consensus_messages_tx
-            .push((author, discriminant(&msg)), (peer_address, msg))?;
-        Ok(())
+            .deliver_consensus_msg(&ctx.peer, peer_address, author, msg)
     }
 }
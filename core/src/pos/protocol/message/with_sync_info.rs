@@ -0,0 +1,34 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+use crate::{
+    pos::{
+        consensus::network::ConsensusMsg,
+        protocol::sync_protocol::{Context, Handleable},
+    },
+    sync::Error,
+};
+use consensus_types::sync_info::SyncInfo;
+use diem_logger::prelude::diem_debug;
+use serde::{Deserialize, Serialize};
+
+/// A `ConsensusMsg`, e.g. a proposal or a vote, sent together with the
+/// latest `SyncInfo` of the sender in one frame, so a round takes fewer
+/// messages. It is only sent to the peers of `HSB_PROTOCOL_V4` or later,
+/// and the older peers get the two messages separately.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WithSyncInfo {
+    pub sync_info: SyncInfo,
+    pub msg: ConsensusMsg,
+}
+
+impl Handleable for WithSyncInfo {
+    fn handle(self, ctx: &Context) -> Result<(), Error> {
+        diem_debug!("on_with_sync_info, msg={}", self.msg.name());
+        // The `SyncInfo` is queued first, so consensus may catch up before
+        // handling the message.
+        self.sync_info.handle(ctx)?;
+        self.msg.handle(ctx)
+    }
+}
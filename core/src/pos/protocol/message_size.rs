@@ -0,0 +1,94 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+//! Limits of the size of the PoS messages received from peers, so a peer
+//! cannot make the node decode an arbitrarily large message.
+
+use std::collections::HashMap;
+
+use super::message::msgid;
+use crate::message::MsgId;
+
+/// The size limit of the received messages of each msg id, in bytes. The
+/// limits apply to the encoded message after decompression, and messages of
+/// the msg ids without a specific limit use the default limit.
+#[derive(Clone, Debug)]
+pub struct MessageSizeLimits {
+    default_limit: usize,
+    limits: HashMap<MsgId, usize>,
+}
+
+impl Default for MessageSizeLimits {
+    fn default() -> Self {
+        const MB: usize = 1024 * 1024;
+        Self::new(MB)
+            .with_limit(msgid::PROPOSAL, 8 * MB)
+            .with_limit(msgid::BLOCK_RETRIEVAL_RESPONSE, 64 * MB)
+            .with_limit(msgid::BLOCK_RETRIEVAL_PAGE_RESPONSE, 64 * MB)
+            .with_limit(msgid::BLOCK_STREAM_CHUNK, 64 * MB)
+            .with_limit(msgid::EPOCH_CHANGE, 16 * MB)
+            // A `ConsensusMsg` can carry any consensus message, including
+            // block retrieval responses.
+            .with_limit(msgid::CONSENSUS_MSG, 64 * MB)
+            .with_limit(msgid::CONSENSUS_MSG_JSON, 64 * MB)
+            .with_limit(msgid::MEMPOOL_SYNC_MSG, 16 * MB)
+    }
+}
+
+impl MessageSizeLimits {
+    pub fn new(default_limit: usize) -> Self {
+        Self {
+            default_limit,
+            limits: HashMap::new(),
+        }
+    }
+
+    /// Set the size limit of the messages of `msg_id`.
+    pub fn with_limit(mut self, msg_id: MsgId, limit: usize) -> Self {
+        self.limits.insert(msg_id, limit);
+        self
+    }
+
+    /// The size limit of the messages of `msg_id`.
+    pub fn limit(&self, msg_id: MsgId) -> usize {
+        self.limits
+            .get(&msg_id)
+            .cloned()
+            .unwrap_or(self.default_limit)
+    }
+
+    /// The largest size limit of all the messages, which bounds the size of
+    /// a decompressed message before its msg id is known.
+    pub fn max_limit(&self) -> usize {
+        self.limits
+            .values()
+            .cloned()
+            .fold(self.default_limit, usize::max)
+    }
+
+    /// Whether a message of `msg_id` with `size` bytes is acceptable.
+    pub fn is_allowed(&self, msg_id: MsgId, size: usize) -> bool {
+        size <= self.limit(msg_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MessageSizeLimits;
+    use crate::pos::protocol::message::msgid;
+
+    #[test]
+    fn test_message_size_limits() {
+        let limits = MessageSizeLimits::new(100)
+            .with_limit(msgid::BLOCK_RETRIEVAL_RESPONSE, 1000);
+        assert_eq!(limits.limit(msgid::VOTE), 100);
+        assert_eq!(limits.limit(msgid::BLOCK_RETRIEVAL_RESPONSE), 1000);
+        assert_eq!(limits.max_limit(), 1000);
+
+        assert!(limits.is_allowed(msgid::VOTE, 100));
+        assert!(!limits.is_allowed(msgid::VOTE, 101));
+        assert!(limits.is_allowed(msgid::BLOCK_RETRIEVAL_RESPONSE, 1000));
+        assert!(!limits.is_allowed(msgid::BLOCK_RETRIEVAL_RESPONSE, 1001));
+    }
+}
@@ -2,13 +2,68 @@
 // TreeGraph is free software and distributed under Apache License 2.0.
 // See https://www.apache.org/licenses/LICENSE-2.0
 
+pub mod blacklist;
+pub mod block_stream;
+pub mod clock;
+pub mod compression;
+pub mod epoch_change_reassembly;
+pub mod error;
+pub mod handshake;
+pub mod incoming_msgs;
+pub mod liveness;
+pub mod log_context;
 pub mod message;
+pub mod message_size;
 pub mod network_event;
 pub mod network_sender;
+pub mod peer_activity;
+pub mod peer_event;
+pub mod pending_proposals;
+pub mod proposal_tracker;
+pub mod rate_limit;
+pub mod replay_guard;
 pub mod request_manager;
+pub mod seen_msgs;
+pub mod send_jitter;
+pub mod send_queue;
 pub mod sync_protocol;
+pub mod sync_status;
+#[cfg(test)]
+pub mod test_utils;
+pub mod vote_dedup;
 
 use network::{service::ProtocolVersion, ProtocolId};
 
 pub const HSB_PROTOCOL_ID: ProtocolId = *b"hsb"; // HotStuff Synchronization Protocol
-pub const HSB_PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion(1);
+/// The first version of the protocol.
+pub const HSB_PROTOCOL_V1: ProtocolVersion = ProtocolVersion(1);
+/// Adds the commit votes (`CommitVoteMsg`) exchanged in the commit phase.
+pub const HSB_PROTOCOL_V2: ProtocolVersion = ProtocolVersion(2);
+/// Adds the chain id handshake (`ChainIdHandshake`).
+pub const HSB_PROTOCOL_V3: ProtocolVersion = ProtocolVersion(3);
+/// Adds the `SyncInfo` piggybacked on another message (`WithSyncInfo`).
+pub const HSB_PROTOCOL_V4: ProtocolVersion = ProtocolVersion(4);
+/// Adds the liveness pings (`Ping` and `Pong`).
+pub const HSB_PROTOCOL_V5: ProtocolVersion = ProtocolVersion(5);
+/// Adds the chunks of the large epoch change proofs (`EpochChangeChunk`).
+pub const HSB_PROTOCOL_V6: ProtocolVersion = ProtocolVersion(6);
+/// Adds the busy answers to the epoch retrievals (`EpochRetrievalBusy`).
+pub const HSB_PROTOCOL_V7: ProtocolVersion = ProtocolVersion(7);
+/// Adds the sequence numbers of the consensus messages (`SEQUENCED`).
+pub const HSB_PROTOCOL_V8: ProtocolVersion = ProtocolVersion(8);
+/// Adds the latency probes (`LatencyProbeRpcRequest`).
+pub const HSB_PROTOCOL_V9: ProtocolVersion = ProtocolVersion(9);
+/// Adds the block streams (`BlockStreamOpen`).
+pub const HSB_PROTOCOL_V10: ProtocolVersion = ProtocolVersion(10);
+/// Adds the busy answers to the block retrievals
+/// (`BlockRetrievalStatus::Busy`).
+pub const HSB_PROTOCOL_V11: ProtocolVersion = ProtocolVersion(11);
+/// Adds the compressed messages (`COMPRESSED`).
+pub const HSB_PROTOCOL_V12: ProtocolVersion = ProtocolVersion(12);
+/// Adds the block retrievals limited by the response size
+/// (`BLOCK_RETRIEVAL_PAGE`).
+pub const HSB_PROTOCOL_V13: ProtocolVersion = ProtocolVersion(13);
+/// Adds the codec negotiation of the consensus messages
+/// (`CodecNegotiation`).
+pub const HSB_PROTOCOL_V14: ProtocolVersion = ProtocolVersion(14);
+pub const HSB_PROTOCOL_VERSION: ProtocolVersion = HSB_PROTOCOL_V14;
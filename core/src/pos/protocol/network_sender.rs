@@ -2,26 +2,73 @@
 // TreeGraph is free software and distributed under Apache License 2.0.
 // See https://www.apache.org/licenses/LICENSE-2.0
 
-use std::{mem::discriminant, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use anyhow::format_err;
-use futures::channel::oneshot;
+use anyhow::{format_err, Context};
+use channel::diem_channel::TryPushError;
+use futures::{
+    channel::oneshot,
+    stream::{FuturesUnordered, StreamExt},
+    Future,
+};
 
-use diem_types::account_address::AccountAddress;
-use network::{node_table::NodeId, NetworkService};
+use cfx_types::H256;
+use consensus_types::{
+    block_retrieval::BlockRetrievalRequest, sync_info::SyncInfo,
+};
+use diem_types::{
+    account_address::AccountAddress, epoch_change::EpochChangeProof,
+};
+use network::{
+    node_table::NodeId, service::ProtocolVersion,
+    throttling::THROTTLING_SERVICE, NetworkContext, NetworkService,
+    SendCompletion,
+};
 
 use crate::{
-    message::Message,
+    message::{Message, MessageProtocolVersionBound, MsgId, SendQueuePriority},
     pos::{
-        consensus::network::ConsensusMsg,
+        consensus::{
+            counters,
+            network::{self_msg_key, ConsensusMsg},
+        },
         protocol::{
-            request_manager::Request,
-            sync_protocol::{HotStuffSynchronizationProtocol, RpcResponse},
-            HSB_PROTOCOL_ID,
+            block_stream::BlockStream,
+            compression::maybe_compress,
+            error::{BroadcastOutcome, NetworkError, PartialSendError},
+            liveness::PeerLivenessStatus,
+            log_context::SendLogContext,
+            message::{
+                block_stream::BlockStreamOpen,
+                codec::CodecKind,
+                epoch_change_chunk::EpochChangeChunk,
+                latency_probe::{
+                    LatencyProbeRpcRequest, LatencyProbeRpcResponse,
+                },
+                msgid,
+                with_sync_info::WithSyncInfo,
+            },
+            replay_guard::stamp,
+            request_manager::{peer_score::PeerScore, Request, RpcPermit},
+            send_queue::{QueuedSend, SendOutcome},
+            sync_protocol::{
+                HotStuffSynchronizationProtocol, RpcResponse,
+                RpcResponseWithPeer,
+            },
+            HSB_PROTOCOL_ID, HSB_PROTOCOL_V1, HSB_PROTOCOL_V10,
+            HSB_PROTOCOL_V12, HSB_PROTOCOL_V8, HSB_PROTOCOL_V9,
         },
     },
+    sync::{msg_sender::metric_message, Error, ErrorKind},
 };
 
+/// How often `wait_for_peers` checks the number of the connected peers.
+const WAIT_FOR_PEERS_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 /// The interface from Consensus to Networking layer.
 ///
 /// This is a thin wrapper around a `NetworkSender<ConsensusMsg>`, so it is easy
@@ -39,40 +86,289 @@ pub struct NetworkSender {
 }
 
 impl NetworkSender {
-    /// Send a single message to the destination peer using the
-    /// `CONSENSUS_DIRECT_SEND_PROTOCOL` ProtocolId.
-    pub fn send_to(
-        &mut self, recipient: AccountAddress, msg: &dyn Message,
-    ) -> Result<(), anyhow::Error> {
-        if let Some(peer_hash) = self
+    /// Resolve the `NodeId` of the session currently connected to the PoS
+    /// node `recipient`.
+    ///
+    /// All the sending paths keyed by `AccountAddress` go through this, so
+    /// the lookup policy lives in one place. The `NodeId` cached on connect
+    /// is used if present, and the peer table is only read on a cache miss.
+    pub fn resolve_node_id(
+        &self, recipient: &AccountAddress,
+    ) -> Result<NodeId, NetworkError> {
+        if let Some(node_id) = self
+            .protocol_handler
+            .pos_node_id_cache
+            .read()
+            .get(recipient)
+        {
+            return Ok(*node_id);
+        }
+        let peer_hash = match self
             .protocol_handler
             .pos_peer_mapping
             .read()
-            .get(&recipient)
+            .get(recipient)
         {
-            if let Some(peer) = self.protocol_handler.peers.get(peer_hash) {
-                let peer_id = peer.read().get_id();
-                self.send_message_with_peer_id(&peer_id, msg)?;
-            } else {
+            Some(peer_hash) => *peer_hash,
+            None => {
+                warn!("recipient {:?} has been removed", recipient);
+                return Err(NetworkError::PeerNotConnected(*recipient));
+            }
+        };
+        match self.protocol_handler.peers.get(&peer_hash) {
+            Some(peer) => Ok(peer.read().get_id()),
+            None => {
                 warn!("peer_hash {:?} does not exist", peer_hash);
+                Err(NetworkError::PeerNotConnected(*recipient))
             }
-        } else {
-            warn!("recipient {:?} has been removed", recipient)
         }
-        Ok(())
+    }
+
+    /// Snapshot the connected PoS nodes and the `NodeId`s of their sessions.
+    ///
+    /// The peer mapping is copied out before resolving the sessions, so the
+    /// locks shared with the sending paths are only held briefly.
+    pub fn connected_peers(&self) -> Vec<(AccountAddress, NodeId)> {
+        let mapping: Vec<(AccountAddress, H256)> = self
+            .protocol_handler
+            .pos_peer_mapping
+            .read()
+            .iter()
+            .map(|(peer, peer_hash)| (*peer, *peer_hash))
+            .collect();
+        mapping
+            .into_iter()
+            .filter_map(|(peer, peer_hash)| {
+                let node_id = self
+                    .protocol_handler
+                    .peers
+                    .get(&peer_hash)?
+                    .read()
+                    .get_id();
+                Some((peer, node_id))
+            })
+            .collect()
+    }
+
+    /// Whether the PoS node `peer` is connected with a live session, so the
+    /// messages sent to it reach the network. Unlike `connected_peers`, a
+    /// peer still in the peer table after its session is closed is not
+    /// reachable.
+    ///
+    /// This only looks up the peer tables and the session, so consensus can
+    /// call it each round, e.g. to check that a quorum is reachable before
+    /// proposing.
+    pub fn is_peer_reachable(&self, peer: &AccountAddress) -> bool {
+        let peer_hash =
+            match self.protocol_handler.pos_peer_mapping.read().get(peer) {
+                Some(peer_hash) => *peer_hash,
+                None => return false,
+            };
+        let node_id = match self.protocol_handler.peers.get(&peer_hash) {
+            Some(state) => state.read().get_id(),
+            None => return false,
+        };
+        self.with_context(|io| {
+            self.protocol_handler.is_peer_reachable(io, &node_id)
+        })
+        .unwrap_or(false)
+    }
+
+    /// The number of the PoS nodes that are reachable, see
+    /// `is_peer_reachable`.
+    pub fn reachable_peer_count(&self) -> usize {
+        let peers = self.connected_peers();
+        if peers.is_empty() {
+            return 0;
+        }
+        self.with_context(|io| self.reachable_peer_count_in(io, &peers))
+            .unwrap_or(0)
+    }
+
+    fn reachable_peer_count_in(
+        &self, io: &dyn NetworkContext, peers: &[(AccountAddress, NodeId)],
+    ) -> usize {
+        peers
+            .iter()
+            .filter(|(_, node_id)| {
+                self.protocol_handler.is_peer_reachable(io, node_id)
+            })
+            .count()
+    }
+
+    /// Wait until at least `min_peers` validators are connected or `timeout`
+    /// elapses, and return the number of the connected validators.
+    ///
+    /// The messages sent before the peers are connected reach nobody, so
+    /// this is awaited before the first proposal after startup. Only the
+    /// connected PoS nodes count, and once the validators are set, only
+    /// those that are validators, see
+    /// `HotStuffSynchronizationProtocol::is_validator`.
+    pub async fn wait_for_peers(
+        &self, min_peers: usize, timeout: Duration,
+    ) -> usize {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let connected = self
+                .connected_peers()
+                .iter()
+                .filter(|(peer, _)| {
+                    self.protocol_handler.is_validator(peer).unwrap_or(true)
+                })
+                .count();
+            let now = Instant::now();
+            if connected >= min_peers || now >= deadline {
+                return connected;
+            }
+            tokio::time::sleep(
+                WAIT_FOR_PEERS_POLL_INTERVAL.min(deadline - now),
+            )
+            .await;
+        }
+    }
+
+    /// Stop sending, and fail the RPCs waiting for their responses at once,
+    /// see `HotStuffSynchronizationProtocol::shutdown`.
+    pub fn shutdown(&self) { self.protocol_handler.shutdown(); }
+
+    /// Run `action` within the network context of `HSB_PROTOCOL_ID`, or in
+    /// the tests within the context set by `test_utils::with_mock_context`.
+    fn with_context<R>(
+        &self, action: impl FnOnce(&dyn NetworkContext) -> R,
+    ) -> Result<R, String> {
+        #[cfg(test)]
+        if let Some(io) = super::test_utils::mock_context() {
+            return Ok(action(&*io));
+        }
+        self.network.with_context(
+            self.protocol_handler.clone(),
+            HSB_PROTOCOL_ID,
+            |io| action(io),
+        )
+    }
+
+    /// Send a single message to the destination peer using the
+    /// `CONSENSUS_DIRECT_SEND_PROTOCOL` ProtocolId.
+    ///
+    /// Returns `NetworkError::PeerNotConnected` if the recipient is not in
+    /// the connected peer table, or `NetworkError::SendFailed` if the
+    /// transport fails to send the message.
+    ///
+    /// A failed send refreshes the session of the recipient once, see
+    /// `HotStuffSynchronizationProtocol::refresh_peer`, and is tried again
+    /// if the recipient has reconnected with another `NodeId`.
+    pub fn send_to(
+        &mut self, recipient: AccountAddress, msg: &dyn Message,
+    ) -> Result<(), NetworkError> {
+        let node_id = self.resolve_node_id(&recipient).ok();
+        let result = match &node_id {
+            Some(node_id) => self.send_to_node(node_id, msg),
+            None => Err(NetworkError::PeerNotConnected(recipient)),
+        };
+        if matches!(result, Ok(()) | Err(NetworkError::Shutdown)) {
+            return result;
+        }
+        match self.protocol_handler.refresh_peer(&recipient) {
+            Some(refreshed) if Some(refreshed) != node_id => {
+                self.send_to_node(&refreshed, msg)
+            }
+            _ => result,
+        }
+    }
+
+    /// Resolve `recipient` and encode `msg` like `send_to`, but do not send
+    /// it. Returns the length of the encoded message.
+    pub fn validate_send_to(
+        &self, recipient: AccountAddress, msg: &dyn Message,
+    ) -> Result<usize, NetworkError> {
+        let node_id = self.resolve_node_id(&recipient)?;
+        Ok(self.validate_send(std::slice::from_ref(&node_id), msg))
+    }
+
+    /// Encode `msg` for `peer_ids` like `fan_out`, but do not send it.
+    /// Returns the length of the longest encoding sent to the peers.
+    pub fn validate_send(
+        &self, peer_ids: &[NodeId], msg: &dyn Message,
+    ) -> usize {
+        let encoded_len = self
+            .encode_for(peer_ids, msg)
+            .payloads
+            .iter()
+            .map(|(_, payload)| payload.len())
+            .max()
+            .unwrap_or(0);
+        let validated = peer_ids
+            .iter()
+            .filter(|peer_id| self.is_supported_by_peer(peer_id, msg))
+            .count();
+        counters::NETWORK_MSGS_VALIDATED
+            .with_label_values(&[msg.msg_name()])
+            .inc_by(validated as u64);
+        encoded_len
+    }
+
+    /// Send a single message to the connected session `node_id`, for the
+    /// callers that already hold the `NodeId` of the peer, e.g. to answer
+    /// its request.
+    ///
+    /// Returns `NetworkError::SendFailed` with the transport error if the
+    /// message cannot be sent.
+    pub fn send_to_node(
+        &self, node_id: &NodeId, msg: &dyn Message,
+    ) -> Result<(), NetworkError> {
+        self.send_to_node_ids(std::slice::from_ref(node_id), msg)
     }
 
     /// Send a single message to the destination peers using the
     /// `CONSENSUS_DIRECT_SEND_PROTOCOL` ProtocolId.
+    ///
+    /// The message is encoded only once for all the recipients, and each
+    /// connected peer receives at most one copy even if several recipients
+    /// resolve to it. Recipients that are not connected fail with
+    /// `NetworkError::PeerNotConnected`, and the others are still sent to.
     pub fn send_to_many(
         &mut self, recipients: impl Iterator<Item = AccountAddress>,
         msg: &dyn Message,
-    ) -> Result<(), anyhow::Error>
+    ) -> BroadcastOutcome
     {
+        let (resolved, mut outcome) = self.resolve_recipients(recipients);
+        outcome.extend(self.fan_out_to(&resolved, msg));
+        outcome
+    }
+
+    /// The `NodeId`s of the `recipients`, and the outcome of the recipients
+    /// that cannot be resolved, which have all failed.
+    fn resolve_recipients(
+        &self, recipients: impl Iterator<Item = AccountAddress>,
+    ) -> (Vec<(AccountAddress, NodeId)>, BroadcastOutcome) {
+        let mut resolved = Vec::new();
+        let mut outcome = BroadcastOutcome::default();
         for recipient in recipients {
-            self.send_to(recipient, msg)?;
+            match self.resolve_node_id(&recipient) {
+                Ok(peer_id) => resolved.push((recipient, peer_id)),
+                Err(e) => outcome.failed.push((recipient, e)),
+            }
+        }
+        (resolved, outcome)
+    }
+
+    /// Encode `msg` once and send it to the `recipients` resolved to their
+    /// `NodeId`s, one copy per connection.
+    pub fn fan_out_to(
+        &self, recipients: &[(AccountAddress, NodeId)], msg: &dyn Message,
+    ) -> BroadcastOutcome {
+        let peer_ids = dedup_node_ids(
+            recipients.iter().map(|(_, peer_id)| *peer_id).collect(),
+        );
+        match self.send_encoded(&peer_ids, msg, None) {
+            Ok(failures) => {
+                BroadcastOutcome::from_failures(recipients, failures)
+            }
+            Err(e) => BroadcastOutcome::all_failed(
+                recipients.iter().map(|(recipient, _)| *recipient),
+                &e,
+            ),
         }
-        Ok(())
     }
 
     /// Send a msg to all connected PoS nodes. They may or may not be
@@ -80,6 +376,7 @@ impl NetworkSender {
     pub fn send_to_others(
         &mut self, msg: &dyn Message, exclude: &Vec<AccountAddress>,
     ) -> Result<(), anyhow::Error> {
+        let mut peer_ids = Vec::new();
         // The node itself is not included in pos_peer_mapping.
         for (node_id, peer_hash) in
             self.protocol_handler.pos_peer_mapping.read().iter()
@@ -88,60 +385,2317 @@ impl NetworkSender {
                 continue;
             }
             if let Some(peer) = self.protocol_handler.peers.get(peer_hash) {
-                let peer_id = peer.read().get_id();
-                self.send_message_with_peer_id(&peer_id, msg)?;
+                peer_ids.push(peer.read().get_id());
             } else {
                 warn!("peer_hash {:?} does not exist", peer_hash);
             }
         }
+        self.send_to_node_ids(&peer_ids, msg)?;
         Ok(())
     }
 
+    /// Send a msg to every peer in the connected peer table, one copy per
+    /// connection.
+    ///
+    /// Returns the number of peers the message is handed to and the peers
+    /// for which sending fails together with the errors.
+    pub fn broadcast(&mut self, msg: &dyn Message) -> BroadcastOutcome<NodeId> {
+        let peer_ids = self.all_peer_ids();
+        self.fan_out(&peer_ids, msg)
+    }
+
+    /// Send a msg to the peers in the connected peer table for which
+    /// `predicate` returns true, e.g. to skip a peer known to misbehave.
+    ///
+    /// Returns the same as `broadcast` for the chosen peers.
+    pub fn broadcast_filtered(
+        &mut self, msg: &dyn Message, predicate: impl Fn(&PeerInfo) -> bool,
+    ) -> BroadcastOutcome<NodeId> {
+        let peer_ids: Vec<NodeId> = self
+            .peer_infos()
+            .into_iter()
+            .filter(|info| predicate(info))
+            .map(|info| info.node_id)
+            .collect();
+        self.fan_out(&peer_ids, msg)
+    }
+
+    /// The number of the messages of each name `peer` has sent recently, see
+    /// `PeerActivity`.
+    pub fn peer_activity(
+        &self, peer: &NodeId,
+    ) -> Option<BTreeMap<&'static str, u64>> {
+        self.protocol_handler.peer_activity.recent(peer)
+    }
+
+    /// Snapshot the state of all the peers in the connected peer table, one
+    /// entry per connection.
+    pub fn peer_infos(&self) -> Vec<PeerInfo> {
+        let accounts: HashMap<NodeId, AccountAddress> = self
+            .connected_peers()
+            .into_iter()
+            .map(|(account, node_id)| (node_id, account))
+            .collect();
+        let scores: HashMap<NodeId, PeerScore> = self
+            .protocol_handler
+            .request_manager
+            .peer_scores()
+            .into_iter()
+            .collect();
+        let mut seen = HashSet::new();
+        self.protocol_handler
+            .peers
+            .fold(Vec::new(), |mut peers, peer| {
+                let peer = peer.read();
+                peers.push((peer.get_id(), peer.protocol_version()));
+                peers
+            })
+            .into_iter()
+            .filter(|(node_id, _)| seen.insert(*node_id))
+            .map(|(node_id, protocol_version)| PeerInfo {
+                node_id,
+                account: accounts.get(&node_id).cloned(),
+                protocol_version,
+                score: scores.get(&node_id).cloned(),
+                liveness: self.protocol_handler.peer_liveness.status(&node_id),
+            })
+            .collect()
+    }
+
+    /// Send a msg to every peer in the connected peer table like
+    /// `broadcast`, and wait until the message is written to the socket of
+    /// each peer.
+    ///
+    /// This is much slower than the fire-and-forget sends, as it waits for
+    /// the slowest connection, so it is only for the steps that need the
+    /// confirmation, e.g. the last message before shutting down. Note that
+    /// being written to the socket does not mean being received by the
+    /// peer. Returns the result for each peer, in the order of the peers.
+    pub async fn broadcast_blocking(
+        &mut self, msg: &dyn Message,
+    ) -> Vec<(NodeId, Result<(), NetworkError>)> {
+        let peer_ids = self.all_peer_ids();
+        let send_failed = |peer_id, reason| -> Result<(), NetworkError> {
+            Err(NetworkError::SendFailed {
+                failed: vec![(peer_id, reason)],
+                not_connected: Vec::new(),
+            })
+        };
+        let mut written = Vec::new();
+        let failures =
+            match self.send_encoded(&peer_ids, msg, Some(&mut written)) {
+                Ok(failures) => failures,
+                Err(e) => {
+                    let reason = format!("{:#}", e);
+                    return peer_ids
+                        .into_iter()
+                        .map(|peer_id| {
+                            (peer_id, send_failed(peer_id, reason.clone()))
+                        })
+                        .collect();
+                }
+            };
+        let mut results: HashMap<NodeId, Result<(), NetworkError>> = failures
+            .into_iter()
+            .map(|(peer_id, reason)| (peer_id, send_failed(peer_id, reason)))
+            .collect();
+        for (peer_id, rx) in written {
+            let result = match rx.await {
+                Ok(true) => Ok(()),
+                // The message is dropped before written, e.g. with the
+                // closed session.
+                Ok(false) | Err(_) => Err(NetworkError::MessageDropped),
+            };
+            results.insert(peer_id, result);
+        }
+        peer_ids
+            .into_iter()
+            .map(|peer_id| {
+                let result = results
+                    .remove(&peer_id)
+                    .unwrap_or(Err(NetworkError::MessageDropped));
+                (peer_id, result)
+            })
+            .collect()
+    }
+
+    /// Send a msg to every peer in the connected peer table like
+    /// `broadcast_blocking`, but only wait until the message is written to
+    /// the sockets of `quorum` peers or `timeout` elapses, e.g. for the
+    /// commit votes, for which a quorum is enough.
+    ///
+    /// Returns the number of the peers the message is written for by then,
+    /// which is less than `quorum` on the timeout, or an error if nothing
+    /// can be sent at all. The message is still sent to the other peers.
+    pub async fn broadcast_until_quorum(
+        &mut self, msg: &dyn Message, quorum: usize, timeout: Duration,
+    ) -> Result<usize, NetworkError> {
+        let peer_ids = self.all_peer_ids();
+        let mut written = Vec::new();
+        self.send_encoded(&peer_ids, msg, Some(&mut written))?;
+        Ok(wait_for_quorum(written, quorum, timeout).await)
+    }
+
+    /// The `NodeId`s of all the peers in the connected peer table.
+    fn all_peer_ids(&self) -> Vec<NodeId> {
+        let peer_ids =
+            self.protocol_handler
+                .peers
+                .fold(Vec::new(), |mut ids, peer| {
+                    ids.push(peer.read().get_id());
+                    ids
+                });
+        dedup_node_ids(peer_ids)
+    }
+
+    /// Encode `msg` once and send it to all the `peer_ids`, returning the
+    /// number of successful sends and the failures.
+    pub fn fan_out(
+        &self, peer_ids: &[NodeId], msg: &dyn Message,
+    ) -> BroadcastOutcome<NodeId> {
+        match self.send_encoded(peer_ids, msg, None) {
+            Ok(failures) => {
+                BroadcastOutcome::from_node_failures(peer_ids, failures)
+            }
+            Err(e) => {
+                BroadcastOutcome::all_failed(peer_ids.iter().cloned(), &e)
+            }
+        }
+    }
+
+    /// Encode `msg` once and send the encoded bytes to all `peer_ids` within
+    /// one network context.
+    ///
+    /// All the peers are tried even if sending to some of them fails, and
+    /// the failures are reported together.
+    fn send_to_node_ids(
+        &self, peer_ids: &[NodeId], msg: &dyn Message,
+    ) -> Result<(), NetworkError> {
+        send_result(self.send_encoded(peer_ids, msg, None)?)
+    }
+
+    /// Send the message `encoded` before to the connected session
+    /// `node_id`, without encoding it again, e.g. for the delayed sends of a
+    /// message to many peers.
+    ///
+    /// Returns the same as `send_to_node`.
+    pub fn send_encoded_to_node(
+        &self, node_id: &NodeId, encoded: &EncodedMessage,
+    ) -> Result<(), NetworkError> {
+        send_result(self.send_encoded_message(
+            std::slice::from_ref(node_id),
+            encoded,
+            None,
+        )?)
+    }
+
+    /// Send `msg` and `sync_info` to `peer_ids`. The peers that support
+    /// `WithSyncInfo` get both in one frame, while the other peers get the
+    /// `SyncInfo` and then `msg` as two messages.
+    pub fn send_with_sync_info(
+        &self, peer_ids: &[NodeId], msg: &ConsensusMsg, sync_info: &SyncInfo,
+    ) -> Result<(), NetworkError> {
+        let combined = WithSyncInfo {
+            sync_info: sync_info.clone(),
+            msg: msg.clone(),
+        };
+        let (new_peers, old_peers): (Vec<NodeId>, Vec<NodeId>) = peer_ids
+            .iter()
+            .copied()
+            .partition(|peer_id| self.is_supported_by_peer(peer_id, &combined));
+        let mut result = self.send_to_node_ids(&new_peers, &combined);
+        if !old_peers.is_empty() {
+            let sync_info_msg =
+                ConsensusMsg::SyncInfo(Box::new(combined.sync_info));
+            for part in &[&sync_info_msg, msg] {
+                if let Err(e) = self.send_to_node_ids(&old_peers, *part) {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    /// Send `proof` to `peer_ids`. A proof larger than
+    /// `pos_epoch_change_chunk_size` once encoded is sent in
+    /// `EpochChangeChunk`s to the peers that support them, and in one
+    /// message to the other peers.
+    pub fn send_epoch_change_proof(
+        &self, peer_ids: &[NodeId], proof: &EpochChangeProof,
+    ) -> Result<(), NetworkError> {
+        let config = &self.protocol_handler.protocol_config;
+        let chunk_size = config.pos_epoch_change_chunk_size;
+        let encoded = bcs::to_bytes(proof).expect("Failed to serialize.");
+        let chunks = if chunk_size > 0 && encoded.len() > chunk_size {
+            EpochChangeChunk::split(
+                &encoded,
+                rand::random(),
+                chunk_size,
+                config.pos_epoch_change_chunk_compression,
+            )
+        } else {
+            Vec::new()
+        };
+        let (chunked_peers, old_peers): (Vec<NodeId>, Vec<NodeId>) =
+            match chunks.first() {
+                Some(chunk) => peer_ids.iter().copied().partition(|peer_id| {
+                    self.is_supported_by_peer(peer_id, chunk)
+                }),
+                None => (Vec::new(), peer_ids.to_vec()),
+            };
+        let mut result = Ok(());
+        if !chunked_peers.is_empty() {
+            for chunk in &chunks {
+                if let Err(e) = self.send_to_node_ids(&chunked_peers, chunk) {
+                    result = Err(e);
+                }
+            }
+        }
+        if !old_peers.is_empty() {
+            let msg = ConsensusMsg::EpochChangeProof(Box::new(proof.clone()));
+            if let Err(e) = self.send_to_node_ids(&old_peers, &msg) {
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    /// Returns the per-peer send failures, or an error if nothing can be
+    /// sent at all.
+    ///
+    /// If `written` is given, a receiver is pushed to it for each peer the
+    /// message is handed to, which tells whether the message is written to
+    /// the socket of the peer.
+    fn send_encoded(
+        &self, peer_ids: &[NodeId], msg: &dyn Message,
+        written: Option<&mut Vec<(NodeId, oneshot::Receiver<bool>)>>,
+    ) -> Result<Vec<(NodeId, String)>, NetworkError>
+    {
+        if self.protocol_handler.is_shut_down() {
+            return Err(NetworkError::Shutdown);
+        }
+        if peer_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let encoded = self.encode_for(peer_ids, msg);
+        self.send_encoded_message(peer_ids, &encoded, written)
+    }
+
+    /// Send the message `encoded` before to all `peer_ids` within one
+    /// network context. Returns the same as `send_encoded`.
+    fn send_encoded_message(
+        &self, peer_ids: &[NodeId], encoded: &EncodedMessage,
+        written: Option<&mut Vec<(NodeId, oneshot::Receiver<bool>)>>,
+    ) -> Result<Vec<(NodeId, String)>, NetworkError>
+    {
+        if self.protocol_handler.is_shut_down() {
+            return Err(NetworkError::Shutdown);
+        }
+        if encoded.is_size_sensitive {
+            THROTTLING_SERVICE
+                .read()
+                .check_throttling()
+                .map_err(|e| format_err!("throttled: {:#}", e))?;
+        }
+        let failures = self
+            .with_context(|io| {
+                self.send_encoded_in(io, peer_ids, encoded, written)
+            })
+            .map_err(|e| format_err!("context failed: {:#}", e))?;
+        Ok(failures)
+    }
+
+    /// Send the `encoded` msg to all `peer_ids` within the network context
+    /// `io`, and return the per-peer failures.
+    ///
+    /// A peer in the peer table without a live session is not sent to, and
+    /// it is evicted from the peer table, as the network drops the messages
+    /// to it silently.
+    fn send_encoded_in(
+        &self, io: &dyn NetworkContext, peer_ids: &[NodeId],
+        encoded: &EncodedMessage,
+        mut written: Option<&mut Vec<(NodeId, oneshot::Receiver<bool>)>>,
+    ) -> Vec<(NodeId, String)>
+    {
+        let mut failures = Vec::new();
+        let sheddable = encoded.priority != SendQueuePriority::High;
+        for peer_id in peer_ids {
+            if self.protocol_handler.peer_blacklist.is_blacklisted(peer_id) {
+                counters::NETWORK_MSGS_BLACKLISTED
+                    .with_label_values(&["sent"])
+                    .inc();
+                failures.push((*peer_id, "blacklisted".into()));
+                continue;
+            }
+            if !self.is_supported_by_peer(peer_id, encoded) {
+                failures.push((
+                    *peer_id,
+                    "unsupported by the peer protocol version".into(),
+                ));
+                continue;
+            }
+            if !io.is_peer_self(peer_id)
+                && io.get_peer_connection_origin(peer_id).is_none()
+            {
+                self.evict_stale_session(peer_id, &encoded.log_context);
+                failures.push((*peer_id, "no live session".into()));
+                continue;
+            }
+            if !self
+                .protocol_handler
+                .send_rate_limiter
+                .allow(peer_id, sheddable)
+            {
+                counters::NETWORK_MSGS_RATE_LIMITED
+                    .with_label_values(&[&peer_id.to_string()])
+                    .inc();
+                failures.push((*peer_id, "rate limited".into()));
+                continue;
+            }
+            // The peers that negotiated another codec or that cannot decode
+            // the compressed messages get their own encoding.
+            let mut payload =
+                encoded.payload(self.payload_kind(peer_id)).to_vec();
+            if let Some((epoch, seq)) =
+                self.sequence_number(io, peer_id, encoded)
+            {
+                stamp(&mut payload, epoch, seq);
+            }
+            let payload_len = payload.len();
+            let (completion, written_rx) = match written {
+                Some(_) => {
+                    let (tx, rx) = oneshot::channel();
+                    let completion = SendCompletion::new(move |w| {
+                        let _ = tx.send(w);
+                    });
+                    (Some(completion), Some(rx))
+                }
+                None => (None, None),
+            };
+            let queued = QueuedSend {
+                log_context: encoded.log_context,
+                payload,
+                min_protocol_version: encoded.version_introduced,
+                version_valid_till: encoded.version_valid_till,
+                priority: encoded.priority,
+                completion,
+            };
+            // A message queued behind another sender is counted as sent.
+            let res = match self
+                .protocol_handler
+                .send_queues
+                .send(io, peer_id, queued)
+            {
+                SendOutcome::Sent(res) => res,
+                SendOutcome::Queued => Ok(()),
+                SendOutcome::Shed => {
+                    failures.push((*peer_id, "shed by the send queue".into()));
+                    continue;
+                }
+            };
+            if let (Ok(()), Some(written), Some(rx)) =
+                (&res, written.as_mut(), written_rx)
+            {
+                written.push((*peer_id, rx));
+            }
+            if let Err(e) = res {
+                warn!(
+                    "Error sending message, {}: {:?}",
+                    encoded.log_context.to_peer(peer_id),
+                    e
+                );
+                failures.push((*peer_id, format!("{:#}", e)));
+            } else if !io.is_peer_self(peer_id) {
+                metric_message(encoded.msg_id, payload_len);
+                counters::NETWORK_MSGS_SENT
+                    .with_label_values(&[encoded.msg_name()])
+                    .inc();
+                counters::NETWORK_BYTES_SENT
+                    .with_label_values(&[encoded.msg_name()])
+                    .inc_by(payload_len as u64);
+            }
+        }
+        failures
+    }
+
+    /// The epoch and the sequence number `encoded` is stamped with for
+    /// `peer_id`, see `replay_guard`. Only the consensus messages of an
+    /// epoch sent to the other peers of `HSB_PROTOCOL_V8` or later are
+    /// stamped.
+    fn sequence_number(
+        &self, io: &dyn NetworkContext, peer_id: &NodeId,
+        encoded: &EncodedMessage,
+    ) -> Option<(u64, u64)> {
+        if !self
+            .protocol_handler
+            .protocol_config
+            .pos_consensus_msg_sequencing
+            || !matches!(
+                encoded.msg_id,
+                msgid::CONSENSUS_MSG | msgid::WITH_SYNC_INFO
+            )
+            || io.is_peer_self(peer_id)
+        {
+            return None;
+        }
+        let epoch = encoded.log_context.epoch?;
+        match self.protocol_handler.peers.protocol_version(peer_id) {
+            Some(version) if version >= HSB_PROTOCOL_V8 => {}
+            _ => return None,
+        }
+        let seq = self
+            .protocol_handler
+            .sequence_numbers
+            .next(peer_id, epoch)?;
+        Some((epoch, seq))
+    }
+
+    /// Evict the peer `peer_id` whose session is closed while it is still
+    /// in the peer table.
+    fn evict_stale_session(
+        &self, peer_id: &NodeId, log_context: &SendLogContext,
+    ) {
+        if self.protocol_handler.evict_stale_peer(peer_id) {
+            warn!(
+                "Evict the peer with a closed session when sending message, {}",
+                log_context.to_peer(peer_id)
+            );
+            counters::NETWORK_STALE_SESSIONS
+                .with_label_values(&[log_context.variant])
+                .inc();
+        }
+    }
+
+    /// Encode `msg` once to be sent to `peer_ids`, with each codec the peers
+    /// negotiated and compressed for the peers that can decode it.
+    pub fn encode_for(
+        &self, peer_ids: &[NodeId], msg: &dyn Message,
+    ) -> EncodedMessage {
+        let plain = PayloadKind {
+            codec: CodecKind::Bcs,
+            compressed: false,
+        };
+        let mut payloads = vec![(plain, msg.encode())];
+        for peer_id in peer_ids {
+            let kind = self.payload_kind(peer_id);
+            if payloads.iter().all(|(encoded, _)| *encoded != kind) {
+                payloads.push((kind, self.encode_as(msg, kind)));
+            }
+        }
+        EncodedMessage {
+            msg_id: msg.msg_id(),
+            log_context: SendLogContext::of(msg),
+            version_introduced: msg.version_introduced(),
+            version_valid_till: msg.version_valid_till(),
+            priority: msg.priority(),
+            is_size_sensitive: msg.is_size_sensitive(),
+            payloads,
+        }
+    }
+
+    /// The encoding of the messages to `peer_id`. The peers before
+    /// `HSB_PROTOCOL_V12` do not know the `COMPRESSED` msg id, so they are
+    /// only sent the plain messages.
+    fn payload_kind(&self, peer_id: &NodeId) -> PayloadKind {
+        let codec = self
+            .protocol_handler
+            .peers
+            .codec(peer_id)
+            .unwrap_or(CodecKind::Bcs);
+        let compressed =
+            match self.protocol_handler.peers.protocol_version(peer_id) {
+                Some(version) => version >= HSB_PROTOCOL_V12,
+                None => false,
+            };
+        PayloadKind { codec, compressed }
+    }
+
+    /// Encode `msg` with `kind.codec` for the `ConsensusMsg`s, and compress
+    /// it if it is large and `kind.compressed` is set.
+    fn encode_as(&self, msg: &dyn Message, kind: PayloadKind) -> Vec<u8> {
+        let encoded = msg.encode_with_codec(kind.codec);
+        if !kind.compressed {
+            return encoded;
+        }
+        maybe_compress(
+            encoded,
+            self.protocol_handler
+                .protocol_config
+                .pos_message_compression_threshold,
+        )
+    }
+
+    /// Return whether the protocol version negotiated with `peer_id` can
+    /// decode `msg`. Only the messages introduced after the first version
+    /// need the lookup.
+    fn is_supported_by_peer<M: MessageProtocolVersionBound + ?Sized>(
+        &self, peer_id: &NodeId, msg: &M,
+    ) -> bool {
+        if msg.version_introduced() <= HSB_PROTOCOL_V1 {
+            return true;
+        }
+        match self.protocol_handler.peers.protocol_version(peer_id) {
+            Some(version) => is_supported_by(msg, version),
+            // Let the network decide for the peers not in the peer table,
+            // e.g. the node itself.
+            None => true,
+        }
+    }
+
     /// Send a RPC to the destination peer using the `CONSENSUS_RPC_PROTOCOL`
     /// ProtocolId.
+    ///
+    /// The response is awaited at most for the request timeout plus one
+    /// request checking period for each attempt allowed by the request
+    /// manager retry policy, so a peer that never answers cannot block the
+    /// caller even if the request is never timed out by the request manager.
     pub async fn send_rpc(
-        &self, recipient: Option<NodeId>, mut request: Box<dyn Request>,
+        &self, recipient: Option<NodeId>, request: Box<dyn Request>,
     ) -> Result<Box<dyn RpcResponse>, anyhow::Error> {
-        let (res_tx, res_rx) = oneshot::channel();
-        self.network
-            .with_context(
-                self.protocol_handler.clone(),
-                HSB_PROTOCOL_ID,
-                |io| {
-                    request.set_response_notification(res_tx);
-                    self.protocol_handler
-                        .request_manager
-                        .request_with_delay(io, request, recipient, None)
-                },
-            )
-            .map_err(|e| format_err!("send rpc failed: err={:?}", e))?;
-        Ok(res_rx
+        self.send_rpc_with_deadline(recipient, request, None).await
+    }
+
+    /// Send a RPC like `send_rpc`, but give up at `deadline`, e.g. one
+    /// shared by a batch of retrieval requests. The request is not sent or
+    /// resent after the deadline, and fails with
+    /// `NetworkError::DeadlineExceeded` if it is not sent before.
+    pub async fn send_rpc_with_deadline(
+        &self, recipient: Option<NodeId>, request: Box<dyn Request>,
+        deadline: Option<Instant>,
+    ) -> Result<Box<dyn RpcResponse>, anyhow::Error>
+    {
+        let mut timeout = self.rpc_timeout(&*request);
+        if let Some(deadline) = deadline {
+            timeout =
+                timeout.min(deadline.saturating_duration_since(Instant::now()));
+        }
+        self.start_rpc_with_permit(recipient, request, timeout, deadline)
+            .await?
+            .response()
+            .await
+    }
+
+    /// Send a RPC like `send_rpc`, and also return the peer that answers it,
+    /// which is chosen by the request manager if `recipient` is `None`.
+    pub async fn send_rpc_with_meta(
+        &self, recipient: Option<NodeId>, request: Box<dyn Request>,
+    ) -> Result<RpcResponseWithPeer, anyhow::Error> {
+        let timeout = self.rpc_timeout(&*request);
+        self.start_rpc_with_permit(recipient, request, timeout, None)
+            .await?
+            .response_with_peer()
+            .await
+    }
+
+    /// The longest time to wait for the response of `request` over all the
+    /// resends allowed by the request manager.
+    fn rpc_timeout(&self, request: &dyn Request) -> Duration {
+        let protocol_config = &self.protocol_handler.protocol_config;
+        self.protocol_handler.request_manager.config().max_wait(
+            request.timeout(protocol_config),
+            protocol_config.check_request_period,
+        )
+    }
+
+    /// Send a RPC like `send_rpc`, but fail with `NetworkError::RpcTimeout`
+    /// if no response is received within `timeout`. The inflight request is
+    /// removed from the request manager on timeout, or if the returned
+    /// future is dropped before the response arrives.
+    pub async fn send_rpc_with_timeout(
+        &self, recipient: Option<NodeId>, request: Box<dyn Request>,
+        timeout: Duration,
+    ) -> Result<Box<dyn RpcResponse>, anyhow::Error>
+    {
+        self.start_rpc_with_permit(recipient, request, timeout, None)
             .await?
-            .map_err(|e| format_err!("rpc call failed: err={:?}", e))?)
+            .response()
+            .await
+    }
+
+    /// Measure the round trip to the peer `peer_id` with a latency probe,
+    /// which the peer answers at once apart from the load of the
+    /// retrievals. The latency is also recorded in the latencies of the peer
+    /// like that of the other RPCs.
+    ///
+    /// The peers before `HSB_PROTOCOL_V9` do not know the probes, so they
+    /// are not probed.
+    pub async fn measure_latency(
+        &self, peer_id: NodeId,
+    ) -> Result<Duration, anyhow::Error> {
+        match self.protocol_handler.peers.protocol_version(&peer_id) {
+            Some(version) if version >= HSB_PROTOCOL_V9 => {}
+            Some(version) => {
+                return Err(format_err!(
+                    "peer {} of protocol version {} does not answer latency \
+                     probes",
+                    peer_id,
+                    version.0
+                ))
+            }
+            None => {
+                return Err(format_err!("peer {} is not connected", peer_id))
+            }
+        }
+        let request = LatencyProbeRpcRequest {
+            request_id: 0,
+            nonce: rand::random(),
+            response_tx: None,
+        };
+        let started = Instant::now();
+        self.send_rpc(Some(peer_id), Box::new(request))
+            .await?
+            .into_typed::<LatencyProbeRpcResponse>()?;
+        Ok(started.elapsed())
+    }
+
+    /// Open a stream of the blocks of `request` pushed by the peer
+    /// `peer_id`, of which at most `window` blocks are pushed before they
+    /// are consumed, see `BlockStreams`.
+    ///
+    /// The peers before `HSB_PROTOCOL_V10` do not push block streams, so
+    /// they are retrieved from with the block retrieval RPCs instead.
+    pub fn open_block_stream(
+        &self, peer_id: NodeId, request: BlockRetrievalRequest, window: u32,
+    ) -> Result<BlockStream, anyhow::Error> {
+        match self.protocol_handler.peers.protocol_version(&peer_id) {
+            Some(version) if version >= HSB_PROTOCOL_V10 => {}
+            Some(version) => {
+                return Err(format_err!(
+                    "peer {} of protocol version {} does not push block \
+                     streams",
+                    peer_id,
+                    version.0
+                ))
+            }
+            None => {
+                return Err(format_err!("peer {} is not connected", peer_id))
+            }
+        }
+        if window == 0 {
+            return Err(format_err!("block stream of an empty window"));
+        }
+        let streams = &self.protocol_handler.block_streams;
+        let (stream_id, blocks_rx) = streams.open_incoming(&peer_id, window);
+        let open = BlockStreamOpen {
+            stream_id,
+            request,
+            window,
+        };
+        if let Err(e) = self.send_to_node(&peer_id, &open) {
+            streams.close_incoming(&peer_id, stream_id);
+            return Err(e.into());
+        }
+        Ok(BlockStream::new(
+            self.clone(),
+            peer_id,
+            stream_id,
+            blocks_rx,
+        ))
+    }
+
+    /// Wait until the number of the outstanding RPCs is below the limit of
+    /// the request manager, and start the RPC like `start_rpc`. The permit
+    /// is held by the returned handle.
+    async fn start_rpc_with_permit(
+        &self, recipient: Option<NodeId>, request: Box<dyn Request>,
+        timeout: Duration, deadline: Option<Instant>,
+    ) -> Result<RpcHandle, anyhow::Error>
+    {
+        let permit = self
+            .protocol_handler
+            .request_manager
+            .acquire_rpc_permit()
+            .await;
+        let mut handle = self
+            .start_rpc_with_deadline(recipient, request, timeout, deadline)?;
+        handle._permit = Some(permit);
+        Ok(handle)
+    }
+
+    /// Send a RPC and return the handle to wait for its response within
+    /// `timeout`.
+    ///
+    /// The inflight request is cancelled in the request manager when the
+    /// handle is dropped or `RpcHandle::cancel` is called before the
+    /// response arrives, so a late response does not fire on a dead channel.
+    ///
+    /// If the network context is unavailable the request is never
+    /// registered, so the error is returned at once with the recipient and
+    /// the request type.
+    pub fn start_rpc(
+        &self, recipient: Option<NodeId>, request: Box<dyn Request>,
+        timeout: Duration,
+    ) -> Result<RpcHandle, anyhow::Error>
+    {
+        self.start_rpc_with_deadline(recipient, request, timeout, None)
+    }
+
+    /// Send a RPC like `start_rpc`, which is not sent after `deadline`, see
+    /// `RequestManager::request_with_delay`.
+    pub fn start_rpc_with_deadline(
+        &self, recipient: Option<NodeId>, mut request: Box<dyn Request>,
+        timeout: Duration, deadline: Option<Instant>,
+    ) -> Result<RpcHandle, anyhow::Error>
+    {
+        let request_type = request.msg_name();
+        let log_context = SendLogContext::of(&*request);
+        let (res_tx, res_rx) = oneshot::channel();
+        let request_id = self
+            .with_context(|io| {
+                request.set_response_notification(res_tx);
+                self.protocol_handler
+                    .request_manager
+                    .request_with_delay(io, request, recipient, None, deadline)
+            })
+            .map_err(anyhow::Error::msg)
+            .with_context(|| {
+                format!(
+                    "send rpc failed, {}",
+                    log_context.to_maybe_peer(recipient.as_ref())
+                )
+            })?;
+        Ok(RpcHandle {
+            network_sender: self.clone(),
+            peer: recipient,
+            request_id,
+            res_rx,
+            timeout,
+            finished: false,
+            inflight: InflightRpc::new(request_type),
+            _permit: None,
+        })
+    }
+
+    /// Send a RPC to at most `max_attempts` distinct peers chosen by the
+    /// request manager, until one of them responds successfully.
+    ///
+    /// The request is resent to a different peer after a timeout or an
+    /// error, and the last error is returned if all attempts fail.
+    pub async fn send_rpc_with_retries(
+        &self, request: Box<dyn Request>, max_attempts: usize,
+    ) -> Result<Box<dyn RpcResponse>, anyhow::Error> {
+        let log_context = SendLogContext::of(&*request);
+        let mut tried_peers = HashSet::new();
+        let mut next_request = Some(request);
+        let mut last_error = None;
+        while tried_peers.len() < max_attempts {
+            let request = match next_request.take() {
+                Some(request) => request,
+                None => break,
+            };
+            let peer = match self
+                .protocol_handler
+                .request_manager
+                .select_peer(&tried_peers)
+            {
+                Some(peer) => peer,
+                None => break,
+            };
+            tried_peers.insert(peer);
+            next_request = request.resend();
+            match self.send_rpc(Some(peer), request).await {
+                Ok(response) => return Ok(response),
+                Err(e) => {
+                    debug!(
+                        "send_rpc_with_retries: {} err={:?}",
+                        log_context.to_peer(&peer),
+                        e
+                    );
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            format_err!("send rpc failed: no peer available")
+        }))
+    }
+
+    /// Send a RPC to a peer chosen by the request manager, and if it does
+    /// not answer within `hedge_delay`, also to another distinct peer, up to
+    /// `max_peers` peers. The first response wins, and the other requests
+    /// are cancelled.
+    ///
+    /// Unlike `send_rpc_with_retries`, the next peer is asked before the
+    /// request to the last one fails, so a slow peer costs at most
+    /// `hedge_delay`. A failed request is still followed by another one at
+    /// once.
+    pub async fn send_rpc_hedged(
+        &self, request: Box<dyn Request>, hedge_delay: Duration,
+        max_peers: usize,
+    ) -> Result<Box<dyn RpcResponse>, anyhow::Error>
+    {
+        let timeout = self.rpc_timeout(&*request);
+        let request_manager = &self.protocol_handler.request_manager;
+        let response = hedge_rpc(
+            request,
+            hedge_delay,
+            max_peers,
+            |tried_peers| request_manager.select_peer(tried_peers),
+            |peer, request| {
+                self.start_rpc_with_permit(Some(peer), request, timeout, None)
+            },
+        )
+        .await?;
+        Ok(response.response)
     }
 
     /// Send msg to self
+    ///
+    /// A message to self replaces the stale one of the same key like the
+    /// messages from peers, see `diem_channel::Sender::try_push`. If it
+    /// would be dropped instead, i.e. with a FIFO queue or under
+    /// backpressure, `NetworkError::SelfQueueFull` is returned so the caller
+    /// can retry.
     pub async fn send_self_msg(
         &self, self_author: AccountAddress, msg: ConsensusMsg,
-    ) -> anyhow::Result<(), anyhow::Error> {
+    ) -> Result<(), NetworkError> {
+        self.protocol_handler
+            .consensus_network_task
+            .consensus_messages_tx
+            .try_push(self_msg_key(self_author, &msg), (self_author, msg))
+            .map_err(self_queue_error)
+    }
+
+    /// Send several msgs to self in order, e.g. to replay the buffered ones
+    /// after recovery, without taking the queue lock for each of them.
+    ///
+    /// If the queue fills midway, the error tells how many msgs are queued,
+    /// and the others are not queued.
+    pub async fn send_self_msgs(
+        &self, self_author: AccountAddress, msgs: Vec<ConsensusMsg>,
+    ) -> Result<(), PartialSendError> {
+        let items = msgs
+            .into_iter()
+            .map(|msg| (self_msg_key(self_author, &msg), (self_author, msg)))
+            .collect();
         self.protocol_handler
             .consensus_network_task
             .consensus_messages_tx
-            .push((self_author, discriminant(&msg)), (self_author, msg))
+            .try_push_all(items)
+            .map(|_| ())
+            .map_err(|(sent, e)| PartialSendError {
+                sent,
+                error: self_queue_error(e),
+            })
     }
 
-    /// Send msg to peer
+    /// Send msg to peer. The same as `send_to_node`.
     pub fn send_message_with_peer_id(
         &self, peer_id: &NodeId, msg: &dyn Message,
-    ) -> anyhow::Result<(), anyhow::Error> {
-        self.network
-            .with_context(
-                self.protocol_handler.clone(),
-                HSB_PROTOCOL_ID,
-                |io| msg.send(io, peer_id),
-            )
-            .map_err(|e| format_err!("context failed: {:#}", e))?
-            .map_err(|e| format_err!("send message failed: {:#}", e))?;
+    ) -> Result<(), NetworkError> {
+        self.send_to_node(peer_id, msg)
+    }
+}
+
+/// A message encoded once to be sent to many peers, see
+/// `NetworkSender::encode_for`.
+///
+/// Besides the BCS encoding, it holds the encodings for the other codecs the
+/// peers negotiated when it is encoded. A peer negotiating another codec
+/// later gets the BCS encoding, which every peer decodes.
+pub struct EncodedMessage {
+    msg_id: MsgId,
+    log_context: SendLogContext,
+    version_introduced: ProtocolVersion,
+    version_valid_till: ProtocolVersion,
+    priority: SendQueuePriority,
+    is_size_sensitive: bool,
+    /// The encodings by kind, the plain BCS one first.
+    payloads: Vec<(PayloadKind, Vec<u8>)>,
+}
+
+/// How a message is encoded for a peer, see `NetworkSender::payload_kind`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct PayloadKind {
+    codec: CodecKind,
+    compressed: bool,
+}
+
+impl EncodedMessage {
+    pub fn msg_id(&self) -> MsgId { self.msg_id }
+
+    pub fn msg_name(&self) -> &'static str { self.log_context.variant }
+
+    pub fn log_context(&self) -> &SendLogContext { &self.log_context }
+
+    /// The encoding for a peer that decodes `kind`.
+    fn payload(&self, kind: PayloadKind) -> &[u8] {
+        let (_, payload) = self
+            .payloads
+            .iter()
+            .find(|(encoded, _)| *encoded == kind)
+            .unwrap_or(&self.payloads[0]);
+        payload
+    }
+}
+
+impl MessageProtocolVersionBound for EncodedMessage {
+    fn version_introduced(&self) -> ProtocolVersion { self.version_introduced }
+
+    fn version_valid_till(&self) -> ProtocolVersion { self.version_valid_till }
+}
+
+/// The state of a connected peer, see `NetworkSender::peer_infos`.
+#[derive(Clone, Debug)]
+pub struct PeerInfo {
+    pub node_id: NodeId,
+    /// The PoS node of the peer, `None` before its public key is known.
+    pub account: Option<AccountAddress>,
+    /// The protocol version negotiated with the peer.
+    pub protocol_version: ProtocolVersion,
+    /// How well the peer answers requests recently, `None` if the request
+    /// manager does not track the peer.
+    pub score: Option<PeerScore>,
+    /// When the peer is last seen and whether it answers the liveness
+    /// pings, `None` if the peer is not tracked.
+    pub liveness: Option<PeerLivenessStatus>,
+}
+
+/// An RPC sent by `NetworkSender::start_rpc` and waiting for its response.
+///
+/// Cancelling the handle removes the request from the request manager,
+/// including its resends after timeouts under other request ids. A request
+/// queued as pending behind the other requests to its peer is dropped when
+/// it would be sent.
+pub struct RpcHandle {
+    network_sender: NetworkSender,
+    peer: Option<NodeId>,
+    request_id: Option<u64>,
+    res_rx: oneshot::Receiver<Result<RpcResponseWithPeer, Error>>,
+    timeout: Duration,
+    /// Set when the request is no longer in the request manager.
+    finished: bool,
+    inflight: InflightRpc,
+    /// The permit of the outstanding RPC, if it is limited.
+    _permit: Option<RpcPermit>,
+}
+
+impl RpcHandle {
+    /// The id of the inflight request, or `None` if it is not sent yet.
+    pub fn request_id(&self) -> Option<u64> { self.request_id }
+
+    /// Wait for the response, and fail with `NetworkError::RpcTimeout` if it
+    /// is not received within the timeout of the handle, or with
+    /// `NetworkError::RpcCanceled` if the request is dropped before.
+    pub async fn response(self) -> Result<Box<dyn RpcResponse>, anyhow::Error> {
+        Ok(self.response_with_peer().await?.response)
+    }
+
+    /// Wait for the response like `response`, and also return the peer that
+    /// sends it.
+    ///
+    /// The errors are annotated with the request waited on, see
+    /// `waiting_context`.
+    pub async fn response_with_peer(
+        mut self,
+    ) -> Result<RpcResponseWithPeer, anyhow::Error> {
+        let context = self.waiting_context();
+        self.wait_response().await.context(context)
+    }
+
+    async fn wait_response(
+        &mut self,
+    ) -> Result<RpcResponseWithPeer, anyhow::Error> {
+        match tokio::time::timeout(self.timeout, &mut self.res_rx).await {
+            Ok(Ok(res)) => {
+                // The request manager has dropped the request once it
+                // notifies the result.
+                self.finished = true;
+                Ok(res.map_err(|e| match e.kind() {
+                    ErrorKind::DeadlineExceeded => {
+                        NetworkError::DeadlineExceeded.into()
+                    }
+                    ErrorKind::TooManyPendingRequests => {
+                        NetworkError::TooManyPendingRequests.into()
+                    }
+                    _ => format_err!("rpc call failed: err={:?}", e),
+                })?)
+            }
+            Ok(Err(oneshot::Canceled)) => {
+                // The request is dropped without a result, e.g. with the
+                // disconnected peer.
+                self.finished = true;
+                counters::RPC_CANCELED
+                    .with_label_values(&[self.inflight.request_type])
+                    .inc();
+                Err(NetworkError::RpcCanceled.into())
+            }
+            Err(_) => {
+                // Nobody is waiting for the response anymore, and the peer
+                // is blamed for the timeout.
+                self.remove_request(true);
+                Err(NetworkError::RpcTimeout.into())
+            }
+        }
+    }
+
+    /// The request the handle waits on, e.g. `waiting for the response of
+    /// BlockRetrievalMessage request_id=3 peer=0x..`. The request id is
+    /// `pending` for a request not sent yet, and the peer is `any` for a
+    /// peer to be chosen by the request manager.
+    fn waiting_context(&self) -> String {
+        let request_id = self
+            .request_id
+            .map_or_else(|| "pending".to_string(), |id| id.to_string());
+        let peer = self
+            .peer
+            .map_or_else(|| "any".to_string(), |peer| format!("{:?}", peer));
+        format!(
+            "waiting for the response of {} request_id={} peer={}",
+            self.inflight.request_type, request_id, peer
+        )
+    }
+
+    /// Stop waiting for the response and remove the inflight request.
+    pub fn cancel(mut self) { self.remove_request(false); }
+
+    fn remove_request(&mut self, timed_out: bool) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+        // The resends of the request see the receiver closed, and are no
+        // longer sent, see `Request::is_canceled`.
+        self.res_rx.close();
+        let (peer, request_id) = (self.peer, self.request_id);
+        let request_manager =
+            &self.network_sender.protocol_handler.request_manager;
+        let _ = self.network_sender.with_context(|io| {
+            let removed = match (peer, request_id) {
+                (Some(peer), Some(request_id)) if timed_out => request_manager
+                    .remove_request(io, &peer, request_id)
+                    .is_some(),
+                (Some(peer), Some(request_id)) => {
+                    request_manager.cancel(io, &peer, request_id)
+                }
+                _ => false,
+            };
+            // The request may be resent under another id or to another
+            // peer, which the handle does not know.
+            if !removed {
+                request_manager.discard_canceled_requests(io);
+            }
+        });
+    }
+}
+
+impl Drop for RpcHandle {
+    fn drop(&mut self) { self.remove_request(false); }
+}
+
+/// Tracks an RPC waiting for its response in the RPC counters.
+///
+/// The counters are updated on drop, so the in-flight gauge is restored even
+/// if the waiting future is cancelled.
+struct InflightRpc {
+    request_type: &'static str,
+    start: Instant,
+}
+
+impl InflightRpc {
+    fn new(request_type: &'static str) -> Self {
+        counters::INFLIGHT_RPC_REQUESTS.inc();
+        Self {
+            request_type,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Drop for InflightRpc {
+    fn drop(&mut self) {
+        counters::INFLIGHT_RPC_REQUESTS.dec();
+        counters::RPC_LATENCY_S
+            .with_label_values(&[self.request_type])
+            .observe(self.start.elapsed().as_secs_f64());
+    }
+}
+
+/// `Ok` if there is no failure, or the failures as
+/// `NetworkError::SendFailed`.
+fn send_result(failures: Vec<(NodeId, String)>) -> Result<(), NetworkError> {
+    if failures.is_empty() {
         Ok(())
+    } else {
+        Err(NetworkError::SendFailed {
+            failed: failures,
+            not_connected: Vec::new(),
+        })
+    }
+}
+
+fn self_queue_error<M>(e: TryPushError<M>) -> NetworkError {
+    match e {
+        TryPushError::Full { depth, .. } => {
+            NetworkError::SelfQueueFull { depth }
+        }
+        TryPushError::Closed(_) => NetworkError::SelfQueueClosed,
+    }
+}
+
+/// Wait until `quorum` of the `written` receivers tell the message is
+/// written, or `timeout` elapses, and return the number of them that tell so
+/// by then.
+async fn wait_for_quorum(
+    written: Vec<(NodeId, oneshot::Receiver<bool>)>, quorum: usize,
+    timeout: Duration,
+) -> usize
+{
+    let mut pending: FuturesUnordered<_> =
+        written.into_iter().map(|(_, rx)| rx).collect();
+    let mut acked = 0;
+    let wait = async {
+        while acked < quorum {
+            match pending.next().await {
+                Some(Ok(true)) => acked += 1,
+                // Dropped before written.
+                Some(Ok(false)) | Some(Err(_)) => {}
+                None => break,
+            }
+        }
+    };
+    let _ = tokio::time::timeout(timeout, wait).await;
+    acked
+}
+
+/// Run the hedged RPC of `NetworkSender::send_rpc_hedged`, with the peers
+/// chosen by `select_peer` and the requests sent by `start`.
+///
+/// The requests still waiting for their responses are cancelled when their
+/// handles are dropped on return.
+async fn hedge_rpc<Start, Started>(
+    request: Box<dyn Request>, hedge_delay: Duration, max_peers: usize,
+    mut select_peer: impl FnMut(&HashSet<NodeId>) -> Option<NodeId>,
+    mut start: Start,
+) -> Result<RpcResponseWithPeer, anyhow::Error>
+where
+    Start: FnMut(NodeId, Box<dyn Request>) -> Started,
+    Started: Future<Output = Result<RpcHandle, anyhow::Error>>,
+{
+    let log_context = SendLogContext::of(&*request);
+    let mut tried_peers = HashSet::new();
+    let mut next_request = Some(request);
+    let mut inflight = FuturesUnordered::new();
+    let mut last_error = None;
+    loop {
+        if tried_peers.len() < max_peers {
+            if let Some(request) = next_request.take() {
+                if let Some(peer) = select_peer(&tried_peers) {
+                    tried_peers.insert(peer);
+                    next_request = request.resend();
+                    match start(peer, request).await {
+                        Ok(handle) => {
+                            inflight.push(handle.response_with_peer())
+                        }
+                        Err(e) => last_error = Some(e),
+                    }
+                }
+            }
+        }
+        let can_hedge = next_request.is_some() && tried_peers.len() < max_peers;
+        if inflight.is_empty() {
+            if can_hedge {
+                continue;
+            }
+            return Err(last_error.unwrap_or_else(|| {
+                format_err!("send rpc failed: no peer available")
+            }));
+        }
+        tokio::select! {
+            Some(result) = inflight.next() => match result {
+                Ok(response) => return Ok(response),
+                Err(e) => {
+                    debug!(
+                        "hedge_rpc: attempt failed, {} err={:?}",
+                        log_context, e
+                    );
+                    last_error = Some(e);
+                }
+            },
+            _ = tokio::time::sleep(hedge_delay), if can_hedge => {
+                counters::RPC_HEDGED.inc();
+            }
+        }
+    }
+}
+
+/// Return whether a peer of `peer_version` can decode `msg`.
+pub fn is_supported_by<M: MessageProtocolVersionBound + ?Sized>(
+    msg: &M, peer_version: ProtocolVersion,
+) -> bool {
+    msg.version_introduced() <= peer_version
+}
+
+/// Remove duplicated node ids while keeping the order of their first
+/// occurrences.
+pub fn dedup_node_ids(
+    peer_ids: impl IntoIterator<Item = NodeId>,
+) -> Vec<NodeId> {
+    let mut seen = HashSet::new();
+    peer_ids
+        .into_iter()
+        .filter(|peer_id| seen.insert(*peer_id))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        dedup_node_ids, hedge_rpc, is_supported_by, wait_for_quorum,
+        InflightRpc, PeerInfo, RpcHandle,
+    };
+    use crate::{
+        message::{
+            GetMaybeRequestId, Message, MessageProtocolVersionBound, MsgId,
+            SendQueuePriority,
+        },
+        pos::{
+            consensus::{counters, network::ConsensusMsg},
+            protocol::{
+                compression::maybe_compress,
+                error::{BroadcastOutcome, NetworkError},
+                message::{
+                    block_retrieval::BlockRetrievalRpcRequest,
+                    block_retrieval_response::{
+                        BlockRetrievalRpcResponse,
+                        UncursoredBlockRetrievalRpcResponse,
+                    },
+                    codec::CodecKind,
+                    msgid,
+                },
+                sync_protocol::RpcResponseWithPeer,
+                test_utils::{
+                    unstarted_sender, unstarted_sender_with_config,
+                    with_mock_context, MockNetworkContext,
+                },
+                HSB_PROTOCOL_V1, HSB_PROTOCOL_V5, HSB_PROTOCOL_VERSION,
+            },
+        },
+        sync::ProtocolConfiguration,
+    };
+    use consensus_types::{
+        block::Block,
+        block_retrieval::{
+            BlockRetrievalRequest, BlockRetrievalResponse, BlockRetrievalStatus,
+        },
+        commit_vote_msg::CommitVoteMsg,
+        epoch_retrieval::EpochRetrievalRequest,
+        proposal_msg::ProposalMsg,
+        quorum_cert::QuorumCert,
+        sync_info::SyncInfo,
+    };
+    use diem_crypto::HashValue;
+    use diem_types::{
+        account_address::{from_consensus_public_key, AccountAddress},
+        block_info::BlockInfo,
+        ledger_info::LedgerInfo,
+        validator_signer::ValidatorSigner,
+        validator_verifier::ValidatorVerifier,
+    };
+    use futures::{channel::oneshot, executor::block_on, future::ready};
+    use keccak_hash::keccak;
+    use network::{
+        node_table::NodeId, service::ProtocolVersion, NetworkProtocolHandler,
+    };
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    /// A message of `HSB_PROTOCOL_V1` for the tests of the sending paths.
+    fn epoch_retrieval() -> ConsensusMsg {
+        ConsensusMsg::EpochRetrievalRequest(Box::new(EpochRetrievalRequest {
+            start_epoch: 0,
+            end_epoch: 1,
+        }))
+    }
+
+    #[test]
+    fn test_dedup_node_ids() {
+        let a = NodeId::from_low_u64_be(1);
+        let b = NodeId::from_low_u64_be(2);
+        let c = NodeId::from_low_u64_be(3);
+        let peer_ids = dedup_node_ids(vec![a, b, a, c, b, a]);
+        assert_eq!(peer_ids, vec![a, b, c]);
+        assert!(dedup_node_ids(vec![]).is_empty());
+    }
+
+    #[test]
+    fn test_send_to_older_peer() {
+        let signer = ValidatorSigner::from_int(1);
+        let commit_vote = CommitVoteMsg::new(
+            signer.author(),
+            LedgerInfo::new(BlockInfo::empty(), HashValue::zero()),
+            &signer,
+        );
+        let wrapped_commit_vote =
+            ConsensusMsg::CommitVote(Box::new(commit_vote.clone()));
+
+        // The commit votes are not sent from a v2 node to a v1 peer, while
+        // the messages of v1 still are.
+        assert!(!is_supported_by(&commit_vote, HSB_PROTOCOL_V1));
+        assert!(!is_supported_by(&wrapped_commit_vote, HSB_PROTOCOL_V1));
+        assert!(is_supported_by(&epoch_retrieval(), HSB_PROTOCOL_V1));
+        assert!(is_supported_by(&commit_vote, HSB_PROTOCOL_VERSION));
+        assert!(is_supported_by(&wrapped_commit_vote, HSB_PROTOCOL_VERSION));
+
+        // The same through `send_to` to a peer connected with v1.
+        let threshold = 1024;
+        let mut sender = unstarted_sender_with_config(ProtocolConfiguration {
+            pos_message_compression_threshold: threshold,
+            ..Default::default()
+        });
+        let io = Arc::new(MockNetworkContext::default());
+        let peer = ValidatorSigner::from_int(2);
+        let (public_key, vrf_public_key) =
+            (peer.public_key(), peer.vrf_public_key().unwrap());
+        let recipient = from_consensus_public_key(&public_key, &vrf_public_key);
+        let node_id = NodeId::from_low_u64_be(1);
+        sender.protocol_handler.on_peer_connected(
+            &*io,
+            &node_id,
+            HSB_PROTOCOL_V1,
+            Some((public_key, vrf_public_key)),
+        );
+        assert_eq!(
+            sender.protocol_handler.peers.protocol_version(&node_id),
+            Some(HSB_PROTOCOL_V1)
+        );
+        io.sent.lock().clear();
+        io.payloads.lock().clear();
+        let response = BlockRetrievalRpcResponse {
+            request_id: 1,
+            response: BlockRetrievalResponse::new(
+                BlockRetrievalStatus::Succeeded,
+                vec![Block::make_genesis_block(); 32],
+            ),
+        };
+        assert!(response.encode().len() > threshold);
+        with_mock_context(io.clone(), || {
+            for msg in [&commit_vote as &dyn Message, &wrapped_commit_vote] {
+                assert!(matches!(
+                    sender.send_to(recipient, msg),
+                    Err(NetworkError::SendFailed { .. })
+                ));
+            }
+            assert!(io.payloads.lock().is_empty());
+            sender.send_to(recipient, &response).unwrap();
+        });
+        assert_eq!(*io.sent.lock(), vec![node_id]);
+        // The frame is neither compressed nor cursored, so v1 decodes it.
+        let payloads = io.payloads.lock();
+        let (msg_id, msg) = payloads[0].split_last().unwrap();
+        assert_eq!(*msg_id as MsgId, msgid::BLOCK_RETRIEVAL_RESPONSE);
+        let decoded: UncursoredBlockRetrievalRpcResponse =
+            bcs::from_bytes(msg).unwrap();
+        assert_eq!(decoded.request_id, 1);
+        assert_eq!(decoded.blocks.len(), 32);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_peers() {
+        let sender = unstarted_sender();
+        // The timeout elapses before any peer connects.
+        assert_eq!(
+            sender.wait_for_peers(1, Duration::from_millis(10)).await,
+            0
+        );
+
+        let handler = &sender.protocol_handler;
+        let connect_peer = |i: u64, peer: Option<AccountAddress>| {
+            let node_id = NodeId::from_low_u64_be(i);
+            handler.peers.insert(keccak(&node_id), node_id, None);
+            if let Some(peer) = peer {
+                handler
+                    .pos_peer_mapping
+                    .write()
+                    .insert(peer, keccak(&node_id));
+            }
+        };
+        let connect = async {
+            for i in 1..=2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                connect_peer(i, Some(AccountAddress::random()));
+            }
+        };
+        let (connected, ()) = futures::join!(
+            sender.wait_for_peers(2, Duration::from_secs(60)),
+            connect
+        );
+        assert_eq!(connected, 2);
+
+        // A peer that is not a PoS node does not count, and neither does a
+        // PoS node that is not a validator once the validators are set.
+        connect_peer(3, None);
+        let validator = ValidatorSigner::from_int(1);
+        handler.set_validators(&ValidatorVerifier::new_single(
+            validator.author(),
+            validator.public_key(),
+            None,
+        ));
+        assert_eq!(
+            sender.wait_for_peers(1, Duration::from_millis(10)).await,
+            0
+        );
+        connect_peer(4, Some(validator.author()));
+        assert_eq!(
+            sender.wait_for_peers(1, Duration::from_millis(10)).await,
+            1
+        );
+    }
+
+    #[test]
+    fn test_send_rpc_without_network() {
+        // The network service is not started, so there is no context to
+        // send the request in.
+        let sender = unstarted_sender();
+        let peer = NodeId::from_low_u64_be(1);
+        let request = BlockRetrievalRpcRequest {
+            request_id: 0,
+            request: BlockRetrievalRequest::new(HashValue::zero(), 1),
+            is_empty: false,
+            response_tx: None,
+            coalesced_tx: Vec::new(),
+            timeout: Duration::from_secs(3600),
+        };
+
+        // The error is returned at once instead of waiting for the timeout.
+        let err = block_on(sender.send_rpc_with_timeout(
+            Some(peer),
+            Box::new(request),
+            Duration::from_secs(3600),
+        ))
+        .err()
+        .expect("no network context");
+        // The cause is kept under the context.
+        assert!(err.root_cause().to_string().contains("not started"));
+        let err = format!("{:#}", err);
+        assert!(err.contains("BlockRetrievalMessage"), "{}", err);
+        assert!(err.contains(&format!("{:?}", peer)), "{}", err);
+        assert!(err.contains("not started"), "{}", err);
+    }
+
+    #[test]
+    fn test_validate_send_to() {
+        // Validating does not need a started network, as nothing is sent.
+        let sender = unstarted_sender();
+        let recipient = AccountAddress::random();
+        let msg = epoch_retrieval();
+        assert!(matches!(
+            sender.validate_send_to(recipient, &msg),
+            Err(NetworkError::PeerNotConnected(peer)) if peer == recipient
+        ));
+
+        sender
+            .protocol_handler
+            .pos_node_id_cache
+            .write()
+            .insert(recipient, NodeId::from_low_u64_be(1));
+        assert_eq!(
+            sender.validate_send_to(recipient, &msg).unwrap(),
+            msg.encode().len()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rpc_canceled() {
+        let request_type = "test_rpc_canceled";
+        let (res_tx, res_rx) = oneshot::channel();
+        let handle = RpcHandle {
+            network_sender: unstarted_sender(),
+            peer: Some(NodeId::from_low_u64_be(1)),
+            request_id: Some(0),
+            res_rx,
+            timeout: Duration::from_secs(3600),
+            finished: false,
+            inflight: InflightRpc::new(request_type),
+            _permit: None,
+        };
+        // The request is dropped without a response.
+        drop(res_tx);
+
+        let err = handle.response().await.err().expect("canceled");
+        assert!(matches!(
+            err.downcast_ref::<NetworkError>(),
+            Some(NetworkError::RpcCanceled)
+        ));
+        let err = format!("{:#}", err);
+        assert!(err.contains("rpc canceled"), "{}", err);
+        assert!(
+            err.contains(&format!("{} request_id=0", request_type)),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains(&format!("{:?}", NodeId::from_low_u64_be(1))),
+            "{}",
+            err
+        );
+        assert_eq!(
+            counters::RPC_CANCELED
+                .with_label_values(&[request_type])
+                .get(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_rpc_canceled_during_retry() {
+        let delay = Duration::from_millis(1);
+        let sender = unstarted_sender_with_config(ProtocolConfiguration {
+            pos_request_max_retries: 1,
+            pos_request_retry_base_delay: delay,
+            pos_request_retry_max_delay: delay,
+            max_allowed_timeout_in_observing_period: 10,
+            ..Default::default()
+        });
+        let request_manager = &sender.protocol_handler.request_manager;
+        let io = Arc::new(MockNetworkContext::default());
+        let peers: Vec<_> = (1..=2).map(NodeId::from_low_u64_be).collect();
+        for peer in &peers {
+            request_manager.on_peer_connected(peer);
+        }
+        let request = BlockRetrievalRpcRequest {
+            request_id: 0,
+            request: BlockRetrievalRequest::new(HashValue::zero(), 1),
+            is_empty: false,
+            response_tx: None,
+            coalesced_tx: Vec::new(),
+            timeout: delay,
+        };
+
+        with_mock_context(io.clone(), || {
+            let handle = sender
+                .start_rpc(
+                    Some(peers[0]),
+                    Box::new(request),
+                    Duration::from_secs(60),
+                )
+                .unwrap();
+            assert_eq!(handle.request_id(), Some(0));
+
+            // The request times out, and is resent after the backoff delay
+            // under another request id the handle does not know.
+            std::thread::sleep(delay * 5);
+            request_manager.process_timeout_requests(&*io);
+            std::thread::sleep(delay * 5);
+            request_manager.resend_waiting_requests(&*io);
+            assert_eq!(io.sent.lock().len(), 2);
+
+            handle.cancel();
+        });
+        // The resent request is removed as well, so nothing is left to
+        // cancel.
+        assert_eq!(request_manager.shutdown(), 0);
+        assert_eq!(io.sent.lock().len(), 2);
+    }
+
+    #[test]
+    fn test_broadcast_filtered() {
+        let mut sender = unstarted_sender();
+        let peers: Vec<_> = (1..=3).map(NodeId::from_low_u64_be).collect();
+        for peer in &peers {
+            sender
+                .protocol_handler
+                .peers
+                .insert(keccak(peer), *peer, None);
+        }
+        let excluded = peers[1];
+        let msg = epoch_retrieval();
+
+        let outcome = sender.broadcast_filtered(&msg, |info: &PeerInfo| {
+            assert_eq!(info.protocol_version, HSB_PROTOCOL_V1);
+            info.node_id != excluded
+        });
+        // The network is not started, so every peer tried is a failure.
+        assert_eq!(outcome.sent, 0);
+        assert!(outcome
+            .errors()
+            .all(|e| matches!(e, NetworkError::Internal(_))));
+        let mut tried = outcome.failed_recipients();
+        tried.sort();
+        assert_eq!(tried, vec![peers[0], peers[2]]);
+
+        // Once shut down, every peer fails with `Shutdown`.
+        sender.shutdown();
+        let outcome = sender.broadcast(&msg);
+        assert_eq!(outcome.failed.len(), peers.len());
+        assert!(outcome
+            .errors()
+            .all(|e| matches!(e, NetworkError::Shutdown)));
+    }
+
+    #[test]
+    fn test_stale_session_evicted() {
+        let sender = unstarted_sender();
+        let live = NodeId::from_low_u64_be(1);
+        let dead = NodeId::from_low_u64_be(2);
+        for peer in &[live, dead] {
+            sender
+                .protocol_handler
+                .peers
+                .insert(keccak(peer), *peer, None);
+        }
+        let io = MockNetworkContext::default();
+        io.dead_sessions.lock().insert(dead);
+        let msg = epoch_retrieval();
+        let evicted = || {
+            counters::NETWORK_STALE_SESSIONS
+                .with_label_values(&[msg.msg_name()])
+                .get()
+        };
+
+        let encoded = sender.encode_for(&[live, dead], &msg);
+        let failures =
+            sender.send_encoded_in(&io, &[live, dead], &encoded, None);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].0, dead);
+        assert_eq!(*io.sent.lock(), vec![live]);
+        assert!(sender.protocol_handler.peers.contains(&keccak(&live)));
+        assert!(!sender.protocol_handler.peers.contains(&keccak(&dead)));
+        assert_eq!(evicted(), 1);
+
+        // The evicted peer is counted only once.
+        sender.send_encoded_in(&io, &[dead], &encoded, None);
+        assert_eq!(evicted(), 1);
+    }
+
+    #[test]
+    fn test_peer_reachability() {
+        let sender = unstarted_sender();
+        let live = NodeId::from_low_u64_be(1);
+        let dead = NodeId::from_low_u64_be(2);
+        let accounts: Vec<_> =
+            (0..2).map(|_| AccountAddress::random()).collect();
+        for (account, peer_id) in accounts.iter().zip(&[live, dead]) {
+            sender.protocol_handler.peers.insert(
+                keccak(peer_id),
+                *peer_id,
+                None,
+            );
+            sender
+                .protocol_handler
+                .pos_peer_mapping
+                .write()
+                .insert(*account, keccak(peer_id));
+        }
+        let io = MockNetworkContext::default();
+        io.dead_sessions.lock().insert(dead);
+
+        // The peer with the closed session is still in the peer table, but
+        // not reachable.
+        assert_eq!(sender.connected_peers().len(), 2);
+        assert!(sender.protocol_handler.is_peer_reachable(&io, &live));
+        assert!(!sender.protocol_handler.is_peer_reachable(&io, &dead));
+        assert_eq!(
+            sender.reachable_peer_count_in(&io, &sender.connected_peers()),
+            1
+        );
+
+        // Nothing is reachable without the network.
+        assert!(!sender.is_peer_reachable(&accounts[0]));
+        assert!(!sender.is_peer_reachable(&AccountAddress::random()));
+        assert_eq!(sender.reachable_peer_count(), 0);
+    }
+
+    #[test]
+    fn test_unknown_recipients_not_connected() {
+        let mut sender = unstarted_sender();
+        let msg = epoch_retrieval();
+        let unknown = AccountAddress::random();
+        assert!(matches!(
+            sender.send_to(unknown, &msg),
+            Err(NetworkError::PeerNotConnected(peer)) if peer == unknown
+        ));
+
+        let recipients: Vec<_> =
+            (0..4).map(|_| AccountAddress::random()).collect();
+        for (i, recipient) in recipients.iter().step_by(2).enumerate() {
+            sender
+                .protocol_handler
+                .pos_node_id_cache
+                .write()
+                .insert(*recipient, NodeId::from_low_u64_be(i as u64 + 1));
+        }
+        let outcome = sender.send_to_many(recipients.iter().cloned(), &msg);
+        let not_connected: Vec<_> = outcome
+            .failed
+            .iter()
+            .filter_map(|(recipient, e)| match e {
+                NetworkError::PeerNotConnected(peer) => {
+                    assert_eq!(peer, recipient);
+                    Some(*peer)
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(not_connected, vec![recipients[1], recipients[3]]);
+    }
+
+    #[test]
+    fn test_send_to_many_outcome() {
+        let mut sender = unstarted_sender();
+        let live = NodeId::from_low_u64_be(1);
+        let dead = NodeId::from_low_u64_be(2);
+        let recipients: Vec<_> =
+            (0..3).map(|_| AccountAddress::random()).collect();
+        for (recipient, peer_id) in recipients.iter().zip(&[live, dead]) {
+            sender.protocol_handler.peers.insert(
+                keccak(peer_id),
+                *peer_id,
+                None,
+            );
+            sender
+                .protocol_handler
+                .pos_node_id_cache
+                .write()
+                .insert(*recipient, *peer_id);
+        }
+        let msg = epoch_retrieval();
+        let is_send_failed =
+            |e: &NetworkError| matches!(e, NetworkError::SendFailed { .. });
+
+        // The network is not started, so the connected recipients fail too.
+        let outcome = sender.send_to_many(recipients.iter().cloned(), &msg);
+        assert_eq!(outcome.sent, 0);
+        assert_eq!(outcome.failed.len(), 3);
+        assert!(matches!(
+            outcome.failed[0],
+            (_, NetworkError::PeerNotConnected(peer)) if peer == recipients[2]
+        ));
+        assert!(outcome.failed[1..].iter().all(|(_, e)| is_send_failed(e)));
+
+        // Within a network context only the recipient without a live session
+        // fails to be sent besides the unknown one.
+        let io = MockNetworkContext::default();
+        io.dead_sessions.lock().insert(dead);
+        let (resolved, mut outcome) =
+            sender.resolve_recipients(recipients.iter().cloned());
+        let encoded = sender.encode_for(&[live, dead], &msg);
+        let failures =
+            sender.send_encoded_in(&io, &[live, dead], &encoded, None);
+        outcome.extend(BroadcastOutcome::from_failures(&resolved, failures));
+        assert_eq!(outcome.sent, 1);
+        assert_eq!(
+            outcome.failed_recipients(),
+            vec![recipients[2], recipients[1]]
+        );
+        assert!(is_send_failed(&outcome.failed[1].1));
+        assert_eq!(*io.sent.lock(), vec![live]);
+    }
+
+    #[test]
+    fn test_encoded_once_for_all_peers() {
+        let sender = unstarted_sender();
+        let peers: Vec<_> = (1..=3).map(NodeId::from_low_u64_be).collect();
+        for peer in &peers {
+            sender
+                .protocol_handler
+                .peers
+                .insert(keccak(peer), *peer, None);
+        }
+        sender
+            .protocol_handler
+            .peers
+            .get(&keccak(&peers[2]))
+            .unwrap()
+            .write()
+            .set_codec(CodecKind::Json);
+        let msg = epoch_retrieval();
+
+        // One encoding for each codec of the peers.
+        let encoded = sender.encode_for(&peers, &msg);
+        assert_eq!(encoded.payloads.len(), 2);
+        assert_eq!(encoded.msg_name(), msg.msg_name());
+
+        let io = MockNetworkContext::default();
+        let failures = sender.send_encoded_in(&io, &peers, &encoded, None);
+        assert!(failures.is_empty());
+        assert_eq!(*io.sent.lock(), peers);
+        let payloads = io.payloads.lock();
+        assert_eq!(payloads[0], msg.encode());
+        assert_eq!(payloads[1], msg.encode());
+        assert_eq!(payloads[2], msg.encode_with_codec(CodecKind::Json));
+    }
+
+    #[test]
+    fn test_compressed_only_for_new_peers() {
+        let sender = unstarted_sender_with_config(ProtocolConfiguration {
+            pos_message_compression_threshold: 1024,
+            ..Default::default()
+        });
+        let handler = sender.protocol_handler.clone();
+        let io = MockNetworkContext::default();
+        let old_peer = NodeId::from_low_u64_be(1);
+        let new_peer = NodeId::from_low_u64_be(2);
+        handler.on_peer_connected(&io, &old_peer, HSB_PROTOCOL_V1, None);
+        handler.on_peer_connected(&io, &new_peer, HSB_PROTOCOL_VERSION, None);
+        io.sent.lock().clear();
+        io.payloads.lock().clear();
+        let msg = BlockRetrievalRpcResponse {
+            request_id: 1,
+            response: BlockRetrievalResponse::new(
+                BlockRetrievalStatus::Succeeded,
+                vec![Block::make_genesis_block(); 32],
+            ),
+        };
+        let threshold = 1024;
+        assert!(msg.encode().len() > threshold);
+
+        let peers = [old_peer, new_peer];
+        let encoded = sender.encode_for(&peers, &msg);
+        assert!(sender
+            .send_encoded_in(&io, &peers, &encoded, None)
+            .is_empty());
+        assert_eq!(*io.sent.lock(), peers);
+        // The V1 peer does not know the `COMPRESSED` msg id.
+        let payloads = io.payloads.lock();
+        assert_eq!(payloads[0], msg.encode());
+        assert_eq!(payloads[1], maybe_compress(msg.encode(), threshold));
+        assert_eq!(*payloads[1].last().unwrap() as MsgId, msgid::COMPRESSED);
+    }
+
+    #[test]
+    fn test_node_id_cache() {
+        let sender = unstarted_sender();
+        let handler = sender.protocol_handler.clone();
+        let io = MockNetworkContext::default();
+        let signer = ValidatorSigner::from_int(1);
+        let (public_key, vrf_public_key) =
+            (signer.public_key(), signer.vrf_public_key().unwrap());
+        let recipient = from_consensus_public_key(&public_key, &vrf_public_key);
+        let node_id = NodeId::from_low_u64_be(1);
+
+        // The cache is filled when the node connects.
+        handler.on_peer_connected(
+            &io,
+            &node_id,
+            HSB_PROTOCOL_V5,
+            Some((public_key, vrf_public_key)),
+        );
+        assert_eq!(
+            handler.pos_node_id_cache.read().get(&recipient),
+            Some(&node_id)
+        );
+        assert_eq!(sender.resolve_node_id(&recipient).unwrap(), node_id);
+
+        // A miss falls back to the peer mapping.
+        handler.pos_node_id_cache.write().remove(&recipient);
+        assert_eq!(sender.resolve_node_id(&recipient).unwrap(), node_id);
+
+        // The entry is dropped when the node disconnects.
+        handler.pos_node_id_cache.write().insert(recipient, node_id);
+        handler.on_peer_disconnected(&io, &node_id);
+        assert!(handler.pos_node_id_cache.read().is_empty());
+        assert!(matches!(
+            sender.resolve_node_id(&recipient),
+            Err(NetworkError::PeerNotConnected(peer)) if peer == recipient
+        ));
+    }
+
+    /// Compare the latency of `resolve_node_id` for 128 validators served
+    /// from `pos_node_id_cache` with the lookup through `pos_peer_mapping`
+    /// and the peer table it replaces. Run with
+    /// `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_resolve_node_id_128_validators() {
+        const VALIDATORS: u64 = 128;
+        const ROUNDS: u32 = 1000;
+        let sender = unstarted_sender();
+        let handler = sender.protocol_handler.clone();
+        let recipients: Vec<_> =
+            (0..VALIDATORS).map(|_| AccountAddress::random()).collect();
+        for (i, recipient) in recipients.iter().enumerate() {
+            let node_id = NodeId::from_low_u64_be(i as u64 + 1);
+            handler.peers.insert(keccak(&node_id), node_id, None);
+            handler
+                .pos_peer_mapping
+                .write()
+                .insert(*recipient, keccak(&node_id));
+            handler
+                .pos_node_id_cache
+                .write()
+                .insert(*recipient, node_id);
+        }
+        let resolve_all = || {
+            let started = Instant::now();
+            for _ in 0..ROUNDS {
+                for recipient in &recipients {
+                    sender.resolve_node_id(recipient).unwrap();
+                }
+            }
+            started.elapsed() / ROUNDS
+        };
+
+        let cached = resolve_all();
+        handler.pos_node_id_cache.write().clear();
+        let mapped = resolve_all();
+
+        println!(
+            "resolve {} validators: {:?} cached, {:?} through the peer \
+             mapping",
+            VALIDATORS, cached, mapped
+        );
+        assert!(cached <= mapped);
+    }
+
+    /// A message that counts the times it is encoded.
+    struct CountingMsg {
+        msg: ConsensusMsg,
+        encodes: AtomicUsize,
+    }
+
+    impl MessageProtocolVersionBound for CountingMsg {
+        fn version_introduced(&self) -> ProtocolVersion {
+            self.msg.version_introduced()
+        }
+
+        fn version_valid_till(&self) -> ProtocolVersion {
+            self.msg.version_valid_till()
+        }
+    }
+
+    impl GetMaybeRequestId for CountingMsg {}
+
+    impl Message for CountingMsg {
+        fn msg_id(&self) -> MsgId { self.msg.msg_id() }
+
+        fn msg_name(&self) -> &'static str { self.msg.msg_name() }
+
+        fn priority(&self) -> SendQueuePriority { self.msg.priority() }
+
+        fn encode(&self) -> Vec<u8> {
+            self.encodes.fetch_add(1, Ordering::SeqCst);
+            self.msg.encode()
+        }
+    }
+
+    #[test]
+    fn test_send_to_many_fan_out() {
+        let sender = unstarted_sender();
+        let peers: Vec<_> = (1..=4).map(NodeId::from_low_u64_be).collect();
+        let recipients: Vec<_> =
+            (0..5).map(|_| AccountAddress::random()).collect();
+        for (recipient, peer_id) in recipients.iter().zip(&peers) {
+            sender
+                .protocol_handler
+                .pos_node_id_cache
+                .write()
+                .insert(*recipient, *peer_id);
+        }
+        let io = MockNetworkContext::default();
+        io.failed_sends.lock().insert(peers[1]);
+        let msg = CountingMsg {
+            msg: epoch_retrieval(),
+            encodes: AtomicUsize::new(0),
+        };
+
+        // The path of `send_to_many` within the network context `io`.
+        let (resolved, mut outcome) =
+            sender.resolve_recipients(recipients.iter().cloned());
+        let peer_ids: Vec<_> =
+            resolved.iter().map(|(_, peer_id)| *peer_id).collect();
+        assert_eq!(peer_ids, peers);
+        let encoded = sender.encode_for(&peer_ids, &msg);
+        let failures = sender.send_encoded_in(&io, &peer_ids, &encoded, None);
+        outcome.extend(BroadcastOutcome::from_failures(&resolved, failures));
+
+        assert_eq!(msg.encodes.load(Ordering::SeqCst), 1);
+        // The transport failure of the second peer does not stop the peers
+        // after it from being sent to.
+        assert_eq!(*io.sent.lock(), vec![peers[0], peers[2], peers[3]]);
+        assert!(io
+            .payloads
+            .lock()
+            .iter()
+            .all(|payload| *payload == msg.msg.encode()));
+        assert_eq!(outcome.sent, 3);
+        assert_eq!(
+            outcome.failed_recipients(),
+            vec![recipients[4], recipients[1]]
+        );
+        assert!(matches!(
+            outcome.failed[1].1,
+            NetworkError::SendFailed { .. }
+        ));
+    }
+
+    /// Compare the latency of a broadcast to 128 validators encoding the
+    /// message for each recipient, as it is before `send_to_many` encodes
+    /// it once, with the latency of `send_to_many`. Run with
+    /// `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_send_to_many_128_validators() {
+        const VALIDATORS: u64 = 128;
+        const ROUNDS: u32 = 100;
+        let sender = unstarted_sender();
+        let recipients: Vec<_> =
+            (0..VALIDATORS).map(|_| AccountAddress::random()).collect();
+        for (i, recipient) in recipients.iter().enumerate() {
+            sender
+                .protocol_handler
+                .pos_node_id_cache
+                .write()
+                .insert(*recipient, NodeId::from_low_u64_be(i as u64 + 1));
+        }
+        let msg = BlockRetrievalRpcResponse {
+            request_id: 0,
+            response: BlockRetrievalResponse::new(
+                BlockRetrievalStatus::Succeeded,
+                vec![Block::make_genesis_block(); 64],
+            ),
+        };
+        let io = MockNetworkContext::default();
+
+        let started = Instant::now();
+        for _ in 0..ROUNDS {
+            for recipient in &recipients {
+                let peer_id = [sender.resolve_node_id(recipient).unwrap()];
+                let encoded = sender.encode_for(&peer_id, &msg);
+                sender.send_encoded_in(&io, &peer_id, &encoded, None);
+            }
+            io.payloads.lock().clear();
+        }
+        let per_recipient = started.elapsed() / ROUNDS;
+
+        let started = Instant::now();
+        for _ in 0..ROUNDS {
+            let (resolved, _) =
+                sender.resolve_recipients(recipients.iter().cloned());
+            let peer_ids: Vec<_> =
+                resolved.iter().map(|(_, peer_id)| *peer_id).collect();
+            let encoded = sender.encode_for(&peer_ids, &msg);
+            sender.send_encoded_in(&io, &peer_ids, &encoded, None);
+            io.payloads.lock().clear();
+        }
+        let once = started.elapsed() / ROUNDS;
+
+        println!(
+            "broadcast to {} validators: {:?} encoding per recipient, \
+             {:?} encoding once",
+            VALIDATORS, per_recipient, once
+        );
+        assert!(once <= per_recipient);
+    }
+
+    /// Compare the time of a proposal broadcast to 100 peers encoding the
+    /// proposal for each peer, as it is before `EncodedMessage`, with the
+    /// broadcast encoding it once. Run with
+    /// `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_broadcast_proposal_100_peers() {
+        const PEERS: u64 = 100;
+        const ROUNDS: u32 = 100;
+        let sender = unstarted_sender();
+        for i in 0..PEERS {
+            let peer = NodeId::from_low_u64_be(i + 1);
+            sender
+                .protocol_handler
+                .peers
+                .insert(keccak(&peer), peer, None);
+        }
+        let peer_ids = sender.all_peer_ids();
+        assert_eq!(peer_ids.len(), PEERS as usize);
+        let signer = ValidatorSigner::from_int(1);
+        let qc = QuorumCert::certificate_for_genesis_from_ledger_info(
+            &LedgerInfo::new(BlockInfo::empty(), HashValue::zero()),
+            HashValue::zero(),
+        );
+        let msg = ConsensusMsg::ProposalMsg(Box::new(ProposalMsg::new(
+            Block::new_proposal(vec![], 1, 1, qc.clone(), &signer),
+            SyncInfo::new(qc.clone(), qc, None),
+        )));
+        let io = MockNetworkContext::default();
+
+        let started = Instant::now();
+        for _ in 0..ROUNDS {
+            for peer_id in &peer_ids {
+                let peer_id = std::slice::from_ref(peer_id);
+                let encoded = sender.encode_for(peer_id, &msg);
+                sender.send_encoded_in(&io, peer_id, &encoded, None);
+            }
+            io.payloads.lock().clear();
+        }
+        let per_peer = started.elapsed() / ROUNDS;
+
+        let started = Instant::now();
+        for _ in 0..ROUNDS {
+            let encoded = sender.encode_for(&peer_ids, &msg);
+            sender.send_encoded_in(&io, &peer_ids, &encoded, None);
+            io.payloads.lock().clear();
+        }
+        let once = started.elapsed() / ROUNDS;
+
+        println!(
+            "proposal broadcast to {} peers: {:?} encoding per peer, {:?} \
+             encoding once",
+            PEERS, per_peer, once
+        );
+        assert!(once <= per_peer);
+    }
+
+    #[tokio::test]
+    async fn test_hedged_rpc_fast_peer_wins() {
+        let sender = unstarted_sender();
+        let slow = NodeId::from_low_u64_be(1);
+        let fast = NodeId::from_low_u64_be(2);
+        let peers = [slow, fast];
+        let request = Box::new(BlockRetrievalRpcRequest {
+            request_id: 0,
+            request: BlockRetrievalRequest::new(HashValue::zero(), 1),
+            is_empty: false,
+            response_tx: None,
+            coalesced_tx: Vec::new(),
+            timeout: Duration::from_secs(3600),
+        });
+        let mut started = Vec::new();
+
+        let response = hedge_rpc(
+            request,
+            Duration::from_millis(20),
+            2,
+            |tried| peers.iter().copied().find(|peer| !tried.contains(peer)),
+            |peer, _request| {
+                started.push(peer);
+                // The slow peer would only answer long after the fast one.
+                let delay = if peer == slow {
+                    Duration::from_secs(60)
+                } else {
+                    Duration::from_millis(10)
+                };
+                let (res_tx, res_rx) = oneshot::channel();
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    let _ = res_tx.send(Ok(RpcResponseWithPeer {
+                        peer,
+                        response: Box::new(BlockRetrievalRpcResponse {
+                            request_id: 0,
+                            response: BlockRetrievalResponse::new(
+                                BlockRetrievalStatus::Succeeded,
+                                vec![],
+                            ),
+                        }),
+                    }));
+                });
+                ready(Ok(RpcHandle {
+                    network_sender: sender.clone(),
+                    peer: Some(peer),
+                    request_id: Some(0),
+                    res_rx,
+                    timeout: Duration::from_secs(3600),
+                    finished: false,
+                    inflight: InflightRpc::new("test_hedged_rpc"),
+                    _permit: None,
+                }))
+            },
+        );
+        let response = tokio::time::timeout(Duration::from_secs(10), response)
+            .await
+            .expect("answered by the fast peer")
+            .unwrap();
+        assert_eq!(response.peer, fast);
+        assert_eq!(started, vec![slow, fast]);
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_until_quorum() {
+        // Each peer writes the message after its delay, or drops it.
+        let written = |delays: &[(u64, bool)]| {
+            delays
+                .iter()
+                .enumerate()
+                .map(|(i, (delay_ms, written))| {
+                    let (tx, rx) = oneshot::channel();
+                    let (delay, written) =
+                        (Duration::from_millis(*delay_ms), *written);
+                    tokio::spawn(async move {
+                        tokio::time::sleep(delay).await;
+                        let _ = tx.send(written);
+                    });
+                    (NodeId::from_low_u64_be(i as u64), rx)
+                })
+                .collect::<Vec<_>>()
+        };
+        let slow = 60_000;
+
+        // Returns once the two fast peers write the message, without
+        // waiting for the slow one.
+        let fast_peers = written(&[(10, true), (slow, true), (20, true)]);
+        let acked = tokio::time::timeout(
+            Duration::from_secs(10),
+            wait_for_quorum(fast_peers, 2, Duration::from_secs(3600)),
+        )
+        .await
+        .expect("the quorum is reached");
+        assert_eq!(acked, 2);
+
+        // A dropped message is not counted, and the slow peer is not waited
+        // for beyond the timeout.
+        let too_few = written(&[(10, true), (10, false), (slow, true)]);
+        let acked =
+            wait_for_quorum(too_few, 2, Duration::from_millis(200)).await;
+        assert_eq!(acked, 1);
+    }
+
+    #[test]
+    fn test_sends_hit_new_session_after_reconnection() {
+        let mut sender = unstarted_sender();
+        let handler = sender.protocol_handler.clone();
+        let io = MockNetworkContext::default();
+        let signer = ValidatorSigner::from_int(1);
+        let (public_key, vrf_public_key) =
+            (signer.public_key(), signer.vrf_public_key().unwrap());
+        let recipient = from_consensus_public_key(&public_key, &vrf_public_key);
+        let pos_public_key = Some((public_key, vrf_public_key));
+        let old_session = NodeId::from_low_u64_be(1);
+        let new_session = NodeId::from_low_u64_be(2);
+        handler.on_peer_connected(
+            &io,
+            &old_session,
+            HSB_PROTOCOL_V5,
+            pos_public_key.clone(),
+        );
+        assert_eq!(sender.resolve_node_id(&recipient).unwrap(), old_session);
+
+        // The validator reconnects before its old session is closed.
+        handler.on_peer_connected(
+            &io,
+            &new_session,
+            HSB_PROTOCOL_V5,
+            pos_public_key,
+        );
+        handler.on_peer_disconnected(&io, &old_session);
+        let new_node_id = sender.resolve_node_id(&recipient).unwrap();
+        assert_eq!(new_node_id, new_session);
+        let msg = epoch_retrieval();
+        let encoded = sender.encode_for(&[new_node_id], &msg);
+        io.sent.lock().clear();
+        assert!(sender
+            .send_encoded_in(&io, &[new_node_id], &encoded, None)
+            .is_empty());
+        assert_eq!(*io.sent.lock(), vec![new_session]);
+
+        // A lingering cached session is replaced on a failed send.
+        handler
+            .pos_node_id_cache
+            .write()
+            .insert(recipient, old_session);
+        assert!(sender.send_to(recipient, &msg).is_err());
+        assert_eq!(sender.resolve_node_id(&recipient).unwrap(), new_session);
+
+        // Both are removed once the node is gone.
+        handler.on_peer_disconnected(&io, &new_session);
+        assert_eq!(handler.refresh_peer(&recipient), None);
+        assert!(matches!(
+            sender.send_to(recipient, &msg),
+            Err(NetworkError::PeerNotConnected(peer)) if peer == recipient
+        ));
+        assert!(handler.pos_peer_mapping.read().is_empty());
     }
 }
@@ -0,0 +1,144 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+//! What each PoS peer has sent recently, for debugging a misbehaving peer,
+//! e.g. to tell a dead peer from one that keeps sending block requests.
+//!
+//! The messages are counted by their names, the same as in the
+//! `NETWORK_MSGS_RECEIVED` metrics, over a sliding window. The window is
+//! split into slots, and a whole slot is forgotten once it is out of the
+//! window, so the memory of a peer does not grow with its message rate.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use network::node_table::NodeId;
+use parking_lot::Mutex;
+
+/// The window the messages of a peer are counted over.
+pub const PEER_ACTIVITY_WINDOW: Duration = Duration::from_secs(60);
+
+/// The number of slots the window is split into.
+const SLOTS_PER_WINDOW: u32 = 12;
+
+/// The message counts of a peer, one map for each slot from the oldest.
+#[derive(Default)]
+struct RecentCounts {
+    slots: VecDeque<(Instant, HashMap<&'static str, u64>)>,
+}
+
+impl RecentCounts {
+    /// Forget the slots that have started before `since`.
+    fn prune(&mut self, since: Instant) {
+        while matches!(self.slots.front(), Some((start, _)) if *start < since)
+        {
+            self.slots.pop_front();
+        }
+    }
+}
+
+pub struct PeerActivity {
+    window: Duration,
+    peers: Mutex<HashMap<NodeId, RecentCounts>>,
+}
+
+impl Default for PeerActivity {
+    fn default() -> Self { Self::new(PEER_ACTIVITY_WINDOW) }
+}
+
+impl PeerActivity {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            peers: Default::default(),
+        }
+    }
+
+    /// Count a message named `msg_name` received from `peer`.
+    pub fn record(&self, peer: &NodeId, msg_name: &'static str) {
+        self.record_at(peer, msg_name, Instant::now())
+    }
+
+    fn record_at(&self, peer: &NodeId, msg_name: &'static str, now: Instant) {
+        let slot_len = self.window / SLOTS_PER_WINDOW;
+        let mut peers = self.peers.lock();
+        let counts = peers.entry(*peer).or_default();
+        if let Some(since) = now.checked_sub(self.window) {
+            counts.prune(since);
+        }
+        let in_last_slot = matches!(
+            counts.slots.back(),
+            Some((start, _)) if now < *start + slot_len
+        );
+        if !in_last_slot {
+            counts.slots.push_back((now, HashMap::new()));
+        }
+        let (_, slot) = counts.slots.back_mut().expect("not empty");
+        *slot.entry(msg_name).or_default() += 1;
+    }
+
+    /// The number of the messages of each name received from `peer` in the
+    /// window, or `None` if `peer` is not connected or has sent nothing
+    /// since it connected.
+    pub fn recent(&self, peer: &NodeId) -> Option<BTreeMap<&'static str, u64>> {
+        self.recent_at(peer, Instant::now())
+    }
+
+    fn recent_at(
+        &self, peer: &NodeId, now: Instant,
+    ) -> Option<BTreeMap<&'static str, u64>> {
+        let peers = self.peers.lock();
+        let since = now.checked_sub(self.window);
+        let mut summary = BTreeMap::new();
+        for (start, slot) in &peers.get(peer)?.slots {
+            if since.map_or(false, |since| *start < since) {
+                continue;
+            }
+            for (msg_name, count) in slot {
+                *summary.entry(*msg_name).or_default() += count;
+            }
+        }
+        Some(summary)
+    }
+
+    /// Forget a disconnected peer.
+    pub fn remove_peer(&self, peer: &NodeId) { self.peers.lock().remove(peer); }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PeerActivity;
+    use network::node_table::NodeId;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_recent_activity() {
+        let activity = PeerActivity::new(Duration::from_secs(60));
+        let peer = NodeId::from_low_u64_be(1);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        assert!(activity.recent(&peer).is_none());
+
+        activity.record_at(&peer, "VoteMsg", at(0));
+        activity.record_at(&peer, "VoteMsg", at(1));
+        activity.record_at(&peer, "BlockRetrievalRequest", at(30));
+        let recent = activity.recent_at(&peer, at(30)).unwrap();
+        assert_eq!(recent.get("VoteMsg"), Some(&2));
+        assert_eq!(recent.get("BlockRetrievalRequest"), Some(&1));
+
+        // The votes slide out of the window.
+        let recent = activity.recent_at(&peer, at(70)).unwrap();
+        assert_eq!(recent.get("VoteMsg"), None);
+        assert_eq!(recent.get("BlockRetrievalRequest"), Some(&1));
+        activity.record_at(&peer, "SyncInfo", at(100));
+        assert_eq!(activity.peers.lock()[&peer].slots.len(), 1);
+        // A peer that is silent in the window has an empty summary.
+        assert!(activity.recent_at(&peer, at(200)).unwrap().is_empty());
+
+        activity.remove_peer(&peer);
+        assert!(activity.recent(&peer).is_none());
+    }
+}
@@ -0,0 +1,185 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+//! Events of the PoS peer connections, so operators can tell peers that
+//! misbehave apart from benign disconnects.
+//!
+//! The events are `Copy` and published through a broadcast channel with a
+//! preallocated buffer, so publishing does not allocate. Subscribers that
+//! fall behind lose the oldest events.
+
+use network::node_table::NodeId;
+use tokio::sync::broadcast;
+
+use crate::pos::consensus::counters;
+
+/// The number of events buffered for each subscriber.
+const PEER_EVENT_CHANNEL_SIZE: usize = 1024;
+
+/// The ways a peer violates the protocol.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtocolViolationKind {
+    /// The message cannot be decoded.
+    MalformedMessage,
+    /// The message exceeds the size limit of its msg id.
+    OversizedMessage,
+    /// The response does not match the request it answers.
+    UnexpectedResponse,
+    /// The content of the message is invalid, e.g. an invalid block.
+    InvalidMessage,
+    /// The peer keeps sending after being throttled.
+    Throttled,
+    /// The peer sends two different proposals of the same author for a
+    /// round.
+    EquivocatingProposal,
+    /// The peer is of a PoS network with another chain id.
+    ChainIdMismatch,
+}
+
+impl ProtocolViolationKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProtocolViolationKind::MalformedMessage => "malformed_message",
+            ProtocolViolationKind::OversizedMessage => "oversized_message",
+            ProtocolViolationKind::UnexpectedResponse => "unexpected_response",
+            ProtocolViolationKind::InvalidMessage => "invalid_message",
+            ProtocolViolationKind::Throttled => "throttled",
+            ProtocolViolationKind::EquivocatingProposal => {
+                "equivocating_proposal"
+            }
+            ProtocolViolationKind::ChainIdMismatch => "chain_id_mismatch",
+        }
+    }
+}
+
+/// Why a peer is disconnected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The connection is closed by the peer or the network.
+    Closed,
+    /// The connection is replaced by another connection to the same peer.
+    Replaced,
+    /// The peer is disconnected after an error that is not its fault.
+    Error,
+    /// The peer is disconnected for missing too many liveness pongs.
+    Unresponsive,
+    /// The peer is disconnected for a protocol version below
+    /// `pos_min_peer_protocol_version`.
+    IncompatibleVersion,
+    /// The peer is disconnected for not sending its handshake in time.
+    HandshakeTimeout,
+    /// The peer is disconnected for violating the protocol.
+    ProtocolViolation(ProtocolViolationKind),
+}
+
+impl DisconnectReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DisconnectReason::Closed => "closed",
+            DisconnectReason::Replaced => "replaced",
+            DisconnectReason::Error => "error",
+            DisconnectReason::Unresponsive => "unresponsive",
+            DisconnectReason::IncompatibleVersion => "incompatible_version",
+            DisconnectReason::HandshakeTimeout => "handshake_timeout",
+            DisconnectReason::ProtocolViolation(kind) => kind.as_str(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConsensusPeerEvent {
+    Connected {
+        peer: NodeId,
+    },
+    Disconnected {
+        peer: NodeId,
+        reason: DisconnectReason,
+    },
+    ProtocolViolation {
+        peer: NodeId,
+        kind: ProtocolViolationKind,
+    },
+}
+
+impl ConsensusPeerEvent {
+    fn labels(&self) -> [&'static str; 2] {
+        match self {
+            ConsensusPeerEvent::Connected { .. } => ["connected", ""],
+            ConsensusPeerEvent::Disconnected { reason, .. } => {
+                ["disconnected", reason.as_str()]
+            }
+            ConsensusPeerEvent::ProtocolViolation { kind, .. } => {
+                ["protocol_violation", kind.as_str()]
+            }
+        }
+    }
+}
+
+/// Publishes the peer events to all the subscribers.
+pub struct PeerEventPublisher {
+    tx: broadcast::Sender<ConsensusPeerEvent>,
+}
+
+impl Default for PeerEventPublisher {
+    fn default() -> Self { Self::new(PEER_EVENT_CHANNEL_SIZE) }
+}
+
+impl PeerEventPublisher {
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+        Self { tx }
+    }
+
+    /// Receive the events published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ConsensusPeerEvent> {
+        self.tx.subscribe()
+    }
+
+    /// Count the event and send it to the subscribers, if any.
+    pub fn publish(&self, event: ConsensusPeerEvent) {
+        counters::NETWORK_PEER_EVENTS
+            .with_label_values(&event.labels())
+            .inc();
+        // Sending only fails if there is no subscriber.
+        let _ = self.tx.send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ConsensusPeerEvent, DisconnectReason, PeerEventPublisher,
+        ProtocolViolationKind,
+    };
+    use network::node_table::NodeId;
+
+    #[test]
+    fn test_publish_peer_events() {
+        let publisher = PeerEventPublisher::new(16);
+        let peer = NodeId::from_low_u64_be(1);
+        // Publishing without subscribers is fine.
+        publisher.publish(ConsensusPeerEvent::Connected { peer });
+
+        let mut rx = publisher.subscribe();
+        let events = vec![
+            ConsensusPeerEvent::ProtocolViolation {
+                peer,
+                kind: ProtocolViolationKind::OversizedMessage,
+            },
+            ConsensusPeerEvent::Disconnected {
+                peer,
+                reason: DisconnectReason::ProtocolViolation(
+                    ProtocolViolationKind::OversizedMessage,
+                ),
+            },
+        ];
+        for event in &events {
+            publisher.publish(*event);
+        }
+        for event in events {
+            assert_eq!(rx.try_recv().unwrap(), event);
+        }
+        assert!(rx.try_recv().is_err());
+    }
+}
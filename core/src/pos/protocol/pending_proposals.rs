@@ -0,0 +1,315 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+//! The proposals received before their parents.
+//!
+//! A proposal may arrive before its parent, or the parent may be missed
+//! altogether, and consensus cannot vote for a block without its parent. A
+//! proposal whose parent is not seen is held back, and the missing
+//! ancestors are retrieved from the peer that has sent it. The proposal is
+//! delivered to consensus once its parent is seen, in a proposal or in a
+//! retrieval response, or once it has waited `PENDING_PROPOSAL_TIMEOUT`.
+//!
+//! Only the recent blocks seen by the network are known here, so a parent
+//! is only missing if it is newer than the oldest block seen in its epoch.
+//! Otherwise, e.g. right after the node starts, the proposal is delivered at
+//! once and consensus syncs the ancestors itself.
+
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use consensus_types::{block::Block, common::Round, proposal_msg::ProposalMsg};
+use diem_crypto::HashValue;
+use diem_types::account_address::AccountAddress;
+use network::node_table::NodeId;
+use parking_lot::Mutex;
+
+/// The most proposals held back at a time.
+pub const MAX_PENDING_PROPOSALS: usize = 16;
+
+/// The most ancestors retrieved for a held back proposal.
+pub const MAX_RETRIEVED_ANCESTORS: u64 = 16;
+
+/// How long a proposal is held back before it is delivered without its
+/// parent.
+pub const PENDING_PROPOSAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The number of the latest blocks remembered.
+const MAX_KNOWN_BLOCKS: usize = 1024;
+
+/// Whether the parent of a proposal is seen, see `PendingProposals::check`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParentCheck {
+    Known,
+    /// The parent is not seen, and `num_blocks` ancestors from it should be
+    /// retrieved.
+    Missing {
+        parent_id: HashValue,
+        num_blocks: u64,
+    },
+}
+
+/// A proposal held back, with the peer it is received from.
+pub struct PendingProposal {
+    pub peer: NodeId,
+    pub peer_address: AccountAddress,
+    pub proposal: ProposalMsg,
+    received: Instant,
+}
+
+#[derive(Default)]
+struct KnownBlocks {
+    ids: HashSet<HashValue>,
+    /// The same blocks, ordered so the oldest is forgotten first.
+    blocks: BTreeSet<(u64, Round, HashValue)>,
+}
+
+#[derive(Default)]
+pub struct PendingProposals {
+    known: Mutex<KnownBlocks>,
+    /// The proposals held back, by the ids of their parents.
+    pending: Mutex<HashMap<HashValue, Vec<PendingProposal>>>,
+}
+
+impl PendingProposals {
+    pub fn new() -> Self { Self::default() }
+
+    /// Check whether the parent of `proposal` is seen.
+    pub fn check(&self, proposal: &ProposalMsg) -> ParentCheck {
+        let parent = proposal.proposal().quorum_cert().certified_block();
+        let known = self.known.lock();
+        if known.ids.contains(&parent.id()) {
+            return ParentCheck::Known;
+        }
+        let mut in_epoch = known
+            .blocks
+            .range(
+                (parent.epoch(), 0, HashValue::zero())
+                    ..(parent.epoch(), parent.round(), HashValue::zero()),
+            )
+            .map(|(_, round, _)| *round);
+        let oldest = match in_epoch.next() {
+            Some(round) => round,
+            // Older than all the blocks seen in the epoch.
+            None => return ParentCheck::Known,
+        };
+        // The newest block seen before the parent, which is likely its
+        // closest seen ancestor.
+        let newest = in_epoch.last().unwrap_or(oldest);
+        ParentCheck::Missing {
+            parent_id: parent.id(),
+            num_blocks: (parent.round() - newest).min(MAX_RETRIEVED_ANCESTORS),
+        }
+    }
+
+    /// Hold back `proposal` until its parent is seen. If too many proposals
+    /// are held back, `proposal` is returned to be delivered at once.
+    pub fn hold(
+        &self, peer: NodeId, peer_address: AccountAddress,
+        proposal: ProposalMsg,
+    ) -> Result<(), ProposalMsg> {
+        self.hold_at(peer, peer_address, proposal, Instant::now())
+    }
+
+    fn hold_at(
+        &self, peer: NodeId, peer_address: AccountAddress,
+        proposal: ProposalMsg, now: Instant,
+    ) -> Result<(), ProposalMsg>
+    {
+        let mut pending = self.pending.lock();
+        let block_id = proposal.proposal().id();
+        let held = pending.get(&proposal.proposal().parent_id()).map_or(
+            false,
+            |siblings| {
+                siblings
+                    .iter()
+                    .any(|p| p.proposal.proposal().id() == block_id)
+            },
+        );
+        if held {
+            // A resend of a proposal held back.
+            return Ok(());
+        }
+        if pending.values().map(Vec::len).sum::<usize>()
+            >= MAX_PENDING_PROPOSALS
+        {
+            return Err(proposal);
+        }
+        pending
+            .entry(proposal.proposal().parent_id())
+            .or_default()
+            .push(PendingProposal {
+                peer,
+                peer_address,
+                proposal,
+                received: now,
+            });
+        Ok(())
+    }
+
+    /// Note that `block` is seen, and take the proposals held back for it.
+    pub fn on_block(&self, block: &Block) -> Vec<PendingProposal> {
+        let mut known = self.known.lock();
+        if known.ids.insert(block.id()) {
+            known
+                .blocks
+                .insert((block.epoch(), block.round(), block.id()));
+            if known.blocks.len() > MAX_KNOWN_BLOCKS {
+                let oldest = *known.blocks.iter().next().expect("not empty");
+                known.blocks.remove(&oldest);
+                known.ids.remove(&oldest.2);
+            }
+        }
+        self.pending.lock().remove(&block.id()).unwrap_or_default()
+    }
+
+    /// Take the proposals held back for longer than
+    /// `PENDING_PROPOSAL_TIMEOUT`.
+    pub fn take_expired(&self) -> Vec<PendingProposal> {
+        self.take_expired_at(Instant::now())
+    }
+
+    fn take_expired_at(&self, now: Instant) -> Vec<PendingProposal> {
+        let mut expired = Vec::new();
+        let mut pending = self.pending.lock();
+        for proposals in pending.values_mut() {
+            let mut i = 0;
+            while i < proposals.len() {
+                if now.saturating_duration_since(proposals[i].received)
+                    >= PENDING_PROPOSAL_TIMEOUT
+                {
+                    expired.push(proposals.remove(i));
+                } else {
+                    i += 1;
+                }
+            }
+        }
+        pending.retain(|_, proposals| !proposals.is_empty());
+        expired
+    }
+
+    /// The number of the proposals held back.
+    pub fn num_pending(&self) -> usize {
+        self.pending.lock().values().map(Vec::len).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ParentCheck, PendingProposals, MAX_PENDING_PROPOSALS,
+        PENDING_PROPOSAL_TIMEOUT,
+    };
+    use consensus_types::{
+        block::Block, proposal_msg::ProposalMsg, quorum_cert::QuorumCert,
+        sync_info::SyncInfo, vote_data::VoteData,
+    };
+    use diem_crypto::HashValue;
+    use diem_types::{
+        account_address::AccountAddress,
+        block_info::BlockInfo,
+        ledger_info::{LedgerInfo, LedgerInfoWithSignatures},
+        validator_signer::ValidatorSigner,
+    };
+    use network::node_table::NodeId;
+    use std::{collections::BTreeMap, time::Instant};
+
+    fn child_of(
+        parent: &Block, round: u64, signer: &ValidatorSigner,
+    ) -> ProposalMsg {
+        let qc = QuorumCert::new(
+            VoteData::new(
+                parent.gen_block_info(HashValue::zero(), 0, None, None),
+                parent.quorum_cert().certified_block().clone(),
+            ),
+            LedgerInfoWithSignatures::new(
+                LedgerInfo::new(BlockInfo::empty(), HashValue::zero()),
+                BTreeMap::new(),
+            ),
+        );
+        ProposalMsg::new(
+            Block::new_proposal(vec![], round, round, qc.clone(), signer),
+            SyncInfo::new(qc.clone(), qc, None),
+        )
+    }
+
+    #[test]
+    fn test_hold_until_parent_seen() {
+        let signer = ValidatorSigner::from_int(1);
+        let genesis = Block::make_genesis_block();
+        let first = child_of(&genesis, 1, &signer);
+        let parent = child_of(first.proposal(), 2, &signer);
+        let proposal = child_of(parent.proposal(), 5, &signer);
+        let pending = PendingProposals::new();
+        let peer = NodeId::from_low_u64_be(1);
+
+        // Nothing is seen in the epoch yet.
+        assert_eq!(pending.check(&first), ParentCheck::Known);
+        assert!(pending.on_block(first.proposal()).is_empty());
+        assert_eq!(pending.check(&parent), ParentCheck::Known);
+        assert_eq!(
+            pending.check(&proposal),
+            ParentCheck::Missing {
+                parent_id: parent.proposal().id(),
+                num_blocks: 1,
+            }
+        );
+
+        let now = Instant::now();
+        assert!(pending
+            .hold_at(peer, AccountAddress::random(), proposal.clone(), now)
+            .is_ok());
+        // A resend is not held twice.
+        assert!(pending
+            .hold_at(peer, AccountAddress::random(), proposal.clone(), now)
+            .is_ok());
+        assert_eq!(pending.num_pending(), 1);
+        assert!(pending
+            .take_expired_at(now + PENDING_PROPOSAL_TIMEOUT / 2)
+            .is_empty());
+
+        let released = pending.on_block(parent.proposal());
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].proposal, proposal);
+        assert_eq!(pending.num_pending(), 0);
+        assert_eq!(pending.check(&proposal), ParentCheck::Known);
+    }
+
+    #[test]
+    fn test_pending_bounded() {
+        let signer = ValidatorSigner::from_int(1);
+        let genesis = Block::make_genesis_block();
+        let first = child_of(&genesis, 1, &signer);
+        let pending = PendingProposals::new();
+        pending.on_block(first.proposal());
+        let missing = child_of(first.proposal(), 100, &signer);
+        let peer = NodeId::from_low_u64_be(1);
+
+        match pending.check(&child_of(missing.proposal(), 101, &signer)) {
+            ParentCheck::Missing { num_blocks, .. } => {
+                assert_eq!(num_blocks, super::MAX_RETRIEVED_ANCESTORS)
+            }
+            other => panic!("unexpected check: {:?}", other),
+        }
+
+        let now = Instant::now();
+        for round in 0..MAX_PENDING_PROPOSALS as u64 {
+            let proposal = child_of(missing.proposal(), 101 + round, &signer);
+            assert!(pending
+                .hold_at(peer, AccountAddress::random(), proposal, now)
+                .is_ok());
+        }
+        let extra = child_of(missing.proposal(), 200, &signer);
+        assert!(pending
+            .hold_at(peer, AccountAddress::random(), extra, now)
+            .is_err());
+
+        // All of them are delivered after the timeout.
+        let expired = pending.take_expired_at(now + PENDING_PROPOSAL_TIMEOUT);
+        assert_eq!(expired.len(), MAX_PENDING_PROPOSALS);
+        assert_eq!(pending.num_pending(), 0);
+    }
+}
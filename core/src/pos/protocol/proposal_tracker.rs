@@ -0,0 +1,162 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+//! Detection of equivocating proposals before they reach consensus.
+//!
+//! The block ids of the recent proposals of each author are kept per
+//! (epoch, round), so a different block proposed by the same author for a
+//! round is detected, while a resend of the same proposal is not.
+
+use std::collections::{BTreeMap, HashMap};
+
+use consensus_types::{common::Round, proposal_msg::ProposalMsg};
+use diem_crypto::HashValue;
+use diem_types::account_address::AccountAddress;
+use parking_lot::Mutex;
+
+/// The number of the latest rounds tracked for each author.
+const TRACKED_ROUNDS_PER_AUTHOR: usize = 16;
+
+/// How a received proposal relates to the proposals seen before.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProposalObservation {
+    /// The first proposal of its author for the round.
+    New,
+    /// The same proposal as one seen before.
+    Duplicate,
+    /// A different proposal of the same author for the same round, with the
+    /// id of the first proposal seen.
+    Equivocation { first_block_id: HashValue },
+}
+
+#[derive(Default)]
+pub struct ProposalTracker {
+    proposals:
+        Mutex<HashMap<AccountAddress, BTreeMap<(u64, Round), HashValue>>>,
+}
+
+impl ProposalTracker {
+    pub fn new() -> Self { Self::default() }
+
+    /// Record `proposal` and check it against the proposals of its author.
+    /// A proposal without an author is not tracked and counted as new.
+    pub fn observe(&self, proposal: &ProposalMsg) -> ProposalObservation {
+        let block = proposal.proposal();
+        match block.author() {
+            Some(author) => self.observe_block(
+                author,
+                block.epoch(),
+                block.round(),
+                block.id(),
+            ),
+            None => ProposalObservation::New,
+        }
+    }
+
+    fn observe_block(
+        &self, author: AccountAddress, epoch: u64, round: Round,
+        block_id: HashValue,
+    ) -> ProposalObservation
+    {
+        let mut proposals = self.proposals.lock();
+        let rounds = proposals.entry(author).or_default();
+        if let Some(first_block_id) = rounds.get(&(epoch, round)) {
+            return if *first_block_id == block_id {
+                ProposalObservation::Duplicate
+            } else {
+                ProposalObservation::Equivocation {
+                    first_block_id: *first_block_id,
+                }
+            };
+        }
+        rounds.insert((epoch, round), block_id);
+        // Forget the oldest round, which consensus has moved past.
+        if rounds.len() > TRACKED_ROUNDS_PER_AUTHOR {
+            let oldest = *rounds.keys().next().expect("not empty");
+            rounds.remove(&oldest);
+        }
+        ProposalObservation::New
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        ProposalObservation, ProposalTracker, TRACKED_ROUNDS_PER_AUTHOR,
+    };
+    use consensus_types::{
+        block::Block, proposal_msg::ProposalMsg, quorum_cert::QuorumCert,
+        sync_info::SyncInfo,
+    };
+    use diem_crypto::HashValue;
+    use diem_types::{
+        account_address::AccountAddress, block_info::BlockInfo,
+        ledger_info::LedgerInfo, validator_signer::ValidatorSigner,
+    };
+
+    #[test]
+    fn test_conflicting_proposals() {
+        let signer = ValidatorSigner::from_int(1);
+        let qc = QuorumCert::certificate_for_genesis_from_ledger_info(
+            &LedgerInfo::new(BlockInfo::empty(), HashValue::zero()),
+            HashValue::zero(),
+        );
+        let proposal = |timestamp_usecs| {
+            ProposalMsg::new(
+                Block::new_proposal(
+                    vec![],
+                    1,
+                    timestamp_usecs,
+                    qc.clone(),
+                    &signer,
+                ),
+                SyncInfo::new(qc.clone(), qc.clone(), None),
+            )
+        };
+        let first = proposal(1);
+        let conflicting = proposal(2);
+
+        let tracker = ProposalTracker::new();
+        let observations = vec![
+            tracker.observe(&first),
+            tracker.observe(&first),
+            tracker.observe(&conflicting),
+        ];
+        assert_eq!(observations[0], ProposalObservation::New);
+        assert_eq!(observations[1], ProposalObservation::Duplicate);
+        assert_eq!(
+            observations[2],
+            ProposalObservation::Equivocation {
+                first_block_id: first.proposal().id()
+            }
+        );
+        let violations = observations
+            .iter()
+            .filter(|o| matches!(o, ProposalObservation::Equivocation { .. }))
+            .count();
+        assert_eq!(violations, 1);
+    }
+
+    #[test]
+    fn test_old_rounds_forgotten() {
+        let tracker = ProposalTracker::new();
+        let author = AccountAddress::random();
+        let id = HashValue::random();
+        for round in 0..=TRACKED_ROUNDS_PER_AUTHOR as u64 {
+            assert_eq!(
+                tracker.observe_block(author, 1, round, id),
+                ProposalObservation::New
+            );
+        }
+        // Round 0 is no longer tracked, while the others still are.
+        assert_eq!(
+            tracker.observe_block(author, 1, 0, HashValue::random()),
+            ProposalObservation::New
+        );
+        assert!(matches!(
+            tracker.observe_block(author, 1, 2, HashValue::random()),
+            ProposalObservation::Equivocation { .. }
+        ));
+    }
+}
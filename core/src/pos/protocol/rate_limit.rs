@@ -0,0 +1,152 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+//! Per-peer rate limiting of the PoS messages sent to peers, so a fast node
+//! does not send to a slow peer faster than it can drain its queues.
+//!
+//! Each peer has a token bucket. A message that can be shed is dropped if
+//! the bucket of its peer is empty, while the other messages are always
+//! sent and take a token if any is left, so they are preferred over the
+//! sheddable ones.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use network::node_table::NodeId;
+use parking_lot::Mutex;
+
+/// The rate of the messages sent to each peer.
+#[derive(Clone, Copy, Debug)]
+pub struct SendRateLimit {
+    /// The number of messages per second a peer can receive on average.
+    pub messages_per_sec: f64,
+    /// The number of messages a peer can receive at once.
+    pub burst: f64,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: &SendRateLimit, now: Instant) -> Self {
+        Self {
+            tokens: limit.burst,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &SendRateLimit, now: Instant) {
+        let elapsed = now
+            .checked_duration_since(self.last_refill)
+            .unwrap_or(Duration::from_secs(0));
+        self.tokens = (self.tokens
+            + elapsed.as_secs_f64() * limit.messages_per_sec)
+            .min(limit.burst);
+        self.last_refill = now;
+    }
+
+    /// Take a token for a message, and return whether it is allowed.
+    fn take(
+        &mut self, limit: &SendRateLimit, sheddable: bool, now: Instant,
+    ) -> bool {
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            !sheddable
+        }
+    }
+}
+
+/// The token buckets of all the peers. Without a limit every message is
+/// allowed.
+pub struct PeerRateLimiter {
+    limit: Option<SendRateLimit>,
+    buckets: Mutex<HashMap<NodeId, TokenBucket>>,
+}
+
+impl PeerRateLimiter {
+    pub fn new(limit: Option<SendRateLimit>) -> Self {
+        Self {
+            limit,
+            buckets: Default::default(),
+        }
+    }
+
+    /// Return whether a message can be sent to `peer` now.
+    pub fn allow(&self, peer: &NodeId, sheddable: bool) -> bool {
+        self.allow_at(peer, sheddable, Instant::now())
+    }
+
+    fn allow_at(&self, peer: &NodeId, sheddable: bool, now: Instant) -> bool {
+        let limit = match &self.limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+        self.buckets
+            .lock()
+            .entry(*peer)
+            .or_insert_with(|| TokenBucket::new(limit, now))
+            .take(limit, sheddable, now)
+    }
+
+    /// Forget the bucket of a disconnected peer.
+    pub fn remove_peer(&self, peer: &NodeId) {
+        self.buckets.lock().remove(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PeerRateLimiter, SendRateLimit};
+    use network::node_table::NodeId;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_unlimited() {
+        let limiter = PeerRateLimiter::new(None);
+        let peer = NodeId::from_low_u64_be(1);
+        for _ in 0..1000 {
+            assert!(limiter.allow(&peer, true));
+        }
+    }
+
+    #[test]
+    fn test_shed_over_rate() {
+        let limiter = PeerRateLimiter::new(Some(SendRateLimit {
+            messages_per_sec: 10.0,
+            burst: 5.0,
+        }));
+        let slow = NodeId::from_low_u64_be(1);
+        let other = NodeId::from_low_u64_be(2);
+        let now = Instant::now();
+
+        for _ in 0..5 {
+            assert!(limiter.allow_at(&slow, true, now));
+        }
+        // The bucket is empty: sheddable messages are dropped, the others
+        // are still sent.
+        assert!(!limiter.allow_at(&slow, true, now));
+        assert!(limiter.allow_at(&slow, false, now));
+        // Other peers have their own buckets.
+        assert!(limiter.allow_at(&other, true, now));
+
+        // One token is refilled after 100ms.
+        let later = now + Duration::from_millis(100);
+        assert!(limiter.allow_at(&slow, true, later));
+        assert!(!limiter.allow_at(&slow, true, later));
+
+        // The bucket is refilled up to the burst.
+        let much_later = later + Duration::from_secs(10);
+        for _ in 0..5 {
+            assert!(limiter.allow_at(&slow, true, much_later));
+        }
+        assert!(!limiter.allow_at(&slow, true, much_later));
+    }
+}
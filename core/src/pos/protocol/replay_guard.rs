@@ -0,0 +1,308 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+//! The replay defense of the consensus messages, under the dedup of each
+//! message variant.
+//!
+//! A reconnecting peer may send a batch of its recent messages again. With
+//! `pos_consensus_msg_sequencing`, each consensus message of an epoch sent to
+//! a peer of `HSB_PROTOCOL_V8` or later, alone or `WithSyncInfo`, is stamped
+//! with the epoch and a sequence number increasing with each message sent to
+//! the peer in the epoch. A stamped message is the encoded message followed
+//! by the epoch, the sequence number and the `SEQUENCED` msg id, like a
+//! compressed one. The receiver drops a stamped message whose sequence
+//! number it has processed for the peer and the epoch.
+//!
+//! The network may send a high priority message before the others sent
+//! earlier, so the receiver remembers which of the last `REPLAY_WINDOW`
+//! sequence numbers are processed, and a message further behind is taken as
+//! a replay. The sequence numbers of an epoch start from the time they are
+//! first used, so they keep increasing after the sender restarts. Both sides
+//! only keep the latest `MAX_EPOCHS` epochs of each peer, which are kept
+//! across the reconnections so the replays after reconnecting are caught.
+//! The epochs of the last `MAX_DISCONNECTED_PEERS` peers disconnected are
+//! kept for their reconnections, and the older ones are dropped.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryInto,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use lru_time_cache::LruCache;
+use network::node_table::NodeId;
+use parking_lot::Mutex;
+
+use super::message::msgid;
+use crate::message::MsgId;
+
+/// How far behind the highest sequence number processed a message is still
+/// checked instead of taken as a replay.
+pub const REPLAY_WINDOW: u64 = 128;
+/// The epochs kept for each peer.
+pub const MAX_EPOCHS: usize = 2;
+/// The disconnected peers whose epochs are kept.
+pub const MAX_DISCONNECTED_PEERS: usize = 256;
+/// The epoch, the sequence number and the msg id.
+const STAMP_LEN: usize = 8 + 8 + 1;
+
+/// Stamp the encoded message `payload` with `epoch` and `seq`.
+pub fn stamp(payload: &mut Vec<u8>, epoch: u64, seq: u64) {
+    payload.extend_from_slice(&epoch.to_le_bytes());
+    payload.extend_from_slice(&seq.to_le_bytes());
+    payload.push(msgid::SEQUENCED as u8);
+}
+
+/// Split the stamped message `raw` into the encoded message, its epoch and
+/// its sequence number. Returns None if `raw` is not a valid stamped message.
+pub fn unstamp(raw: &[u8]) -> Option<(&[u8], u64, u64)> {
+    if raw.len() < STAMP_LEN + 2
+        || raw[raw.len() - 1] as MsgId != msgid::SEQUENCED
+    {
+        return None;
+    }
+    let (msg, stamp) = raw.split_at(raw.len() - STAMP_LEN);
+    // A message is not stamped twice.
+    if msg[msg.len() - 1] as MsgId == msgid::SEQUENCED {
+        return None;
+    }
+    let epoch = u64::from_le_bytes(stamp[..8].try_into().ok()?);
+    let seq = u64::from_le_bytes(stamp[8..16].try_into().ok()?);
+    Some((msg, epoch, seq))
+}
+
+/// The entry of `epoch` in `epochs`, added with `init` if it is missing.
+/// Returns None for an epoch older than the `MAX_EPOCHS` ones kept.
+fn epoch_entry<T>(
+    epochs: &mut BTreeMap<u64, T>, epoch: u64, init: impl FnOnce() -> T,
+) -> Option<&mut T> {
+    if !epochs.contains_key(&epoch) && epochs.len() >= MAX_EPOCHS {
+        let oldest = *epochs.keys().next().expect("not empty");
+        if epoch < oldest {
+            return None;
+        }
+        epochs.remove(&oldest);
+    }
+    Some(epochs.entry(epoch).or_insert_with(init))
+}
+
+/// The epochs of the connected peers and of the peers disconnected
+/// recently.
+struct PeerEpochs<T> {
+    connected: HashMap<NodeId, BTreeMap<u64, T>>,
+    disconnected: LruCache<NodeId, BTreeMap<u64, T>>,
+}
+
+impl<T> Default for PeerEpochs<T> {
+    fn default() -> Self {
+        Self {
+            connected: HashMap::new(),
+            disconnected: LruCache::with_capacity(MAX_DISCONNECTED_PEERS),
+        }
+    }
+}
+
+impl<T> PeerEpochs<T> {
+    /// The epochs of `peer`, taken back if it is disconnected recently.
+    fn epochs(&mut self, peer: &NodeId) -> &mut BTreeMap<u64, T> {
+        if !self.connected.contains_key(peer) {
+            let epochs = self.disconnected.remove(peer).unwrap_or_default();
+            self.connected.insert(*peer, epochs);
+        }
+        self.connected.get_mut(peer).expect("inserted")
+    }
+
+    fn remove_peer(&mut self, peer: &NodeId) {
+        if let Some(epochs) = self.connected.remove(peer) {
+            self.disconnected.insert(*peer, epochs);
+        }
+    }
+}
+
+/// The sequence numbers of the messages sent to each peer.
+#[derive(Default)]
+pub struct SequenceNumbers {
+    peers: Mutex<PeerEpochs<u64>>,
+}
+
+impl SequenceNumbers {
+    /// The sequence number of the next message of `epoch` sent to `peer`,
+    /// or None for an epoch older than the ones kept, whose messages are
+    /// sent without the stamp.
+    pub fn next(&self, peer: &NodeId, epoch: u64) -> Option<u64> {
+        let mut peers = self.peers.lock();
+        let seq = epoch_entry(peers.epochs(peer), epoch, || {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_micros() as u64)
+        })?;
+        *seq += 1;
+        Some(*seq)
+    }
+
+    /// Keep the sequence numbers of the disconnected `peer` among the ones
+    /// of the peers disconnected recently.
+    pub fn remove_peer(&self, peer: &NodeId) {
+        self.peers.lock().remove_peer(peer);
+    }
+}
+
+/// The sequence numbers processed recently for one peer and epoch.
+#[derive(Default)]
+struct Window {
+    highest: u64,
+    /// Bit `i` is set if `highest - i` is processed.
+    processed: u128,
+}
+
+impl Window {
+    /// Mark `seq` processed, and return whether it is not processed before.
+    fn check(&mut self, seq: u64) -> bool {
+        if seq > self.highest {
+            let shift = seq - self.highest;
+            self.processed = if shift >= REPLAY_WINDOW {
+                0
+            } else {
+                self.processed << shift
+            };
+            self.processed |= 1;
+            self.highest = seq;
+            return true;
+        }
+        let behind = self.highest - seq;
+        if behind >= REPLAY_WINDOW {
+            return false;
+        }
+        let bit = 1u128 << behind;
+        let fresh = self.processed & bit == 0;
+        self.processed |= bit;
+        fresh
+    }
+}
+
+/// The sequence numbers processed for each peer, see the module doc.
+#[derive(Default)]
+pub struct ReplayGuard {
+    peers: Mutex<PeerEpochs<Window>>,
+}
+
+impl ReplayGuard {
+    /// Mark the message of `epoch` and `seq` from `peer` processed, and
+    /// return whether it is not a replay.
+    pub fn check(&self, peer: &NodeId, epoch: u64, seq: u64) -> bool {
+        let mut peers = self.peers.lock();
+        match epoch_entry(peers.epochs(peer), epoch, Window::default) {
+            Some(window) => window.check(seq),
+            None => false,
+        }
+    }
+
+    /// Keep the sequence numbers processed for the disconnected `peer`
+    /// among the ones of the peers disconnected recently.
+    pub fn remove_peer(&self, peer: &NodeId) {
+        self.peers.lock().remove_peer(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        stamp, unstamp, ReplayGuard, SequenceNumbers, MAX_DISCONNECTED_PEERS,
+        MAX_EPOCHS, REPLAY_WINDOW,
+    };
+    use crate::pos::protocol::message::msgid;
+    use network::node_table::NodeId;
+
+    #[test]
+    fn test_stamp() {
+        let encoded = vec![1, 2, 3, msgid::CONSENSUS_MSG as u8];
+        let mut stamped = encoded.clone();
+        stamp(&mut stamped, 5, 42);
+        assert_eq!(unstamp(&stamped), Some((&encoded[..], 5, 42)));
+
+        assert_eq!(unstamp(&encoded), None);
+        // Nothing but the msg id is stamped.
+        assert_eq!(unstamp(&stamped[encoded.len() - 1..]), None);
+        let mut twice = stamped.clone();
+        stamp(&mut twice, 5, 43);
+        assert_eq!(unstamp(&twice), None);
+    }
+
+    #[test]
+    fn test_replay_guard() {
+        let guard = ReplayGuard::default();
+        let peer = NodeId::from_low_u64_be(1);
+        assert!(guard.check(&peer, 1, 10));
+        assert!(!guard.check(&peer, 1, 10));
+
+        // Reordered messages are still handled once.
+        assert!(guard.check(&peer, 1, 12));
+        assert!(guard.check(&peer, 1, 11));
+        assert!(!guard.check(&peer, 1, 11));
+
+        // The sequence numbers are checked per peer and epoch.
+        assert!(guard.check(&NodeId::from_low_u64_be(2), 1, 10));
+        assert!(guard.check(&peer, 2, 10));
+
+        // Too far behind.
+        assert!(guard.check(&peer, 1, 12 + REPLAY_WINDOW));
+        assert!(!guard.check(&peer, 1, 12));
+
+        // An epoch older than the ones kept.
+        for epoch in 3..3 + MAX_EPOCHS as u64 {
+            assert!(guard.check(&peer, epoch, 1));
+        }
+        assert!(!guard.check(&peer, 1, 100 + REPLAY_WINDOW));
+    }
+
+    #[test]
+    fn test_sequence_numbers() {
+        let numbers = SequenceNumbers::default();
+        let peer = NodeId::from_low_u64_be(1);
+        let first = numbers.next(&peer, 1).unwrap();
+        assert_eq!(numbers.next(&peer, 1), Some(first + 1));
+        assert!(numbers.next(&peer, 2).is_some());
+        assert!(numbers.next(&peer, 3).is_some());
+        assert_eq!(numbers.next(&peer, 1), None);
+
+        // Still increasing after the sender restarts.
+        let restarted = SequenceNumbers::default();
+        assert!(restarted.next(&peer, 3).unwrap() >= first);
+    }
+
+    #[test]
+    fn test_disconnected_peers_bounded() {
+        let (guard, numbers) =
+            (ReplayGuard::default(), SequenceNumbers::default());
+        let peer = NodeId::from_low_u64_be(0);
+        assert!(guard.check(&peer, 1, 10));
+        let first = numbers.next(&peer, 1).unwrap();
+
+        // Kept across a reconnection.
+        guard.remove_peer(&peer);
+        numbers.remove_peer(&peer);
+        assert!(!guard.check(&peer, 1, 10));
+        assert_eq!(numbers.next(&peer, 1), Some(first + 1));
+
+        // Dropped once enough other peers are disconnected after it.
+        guard.remove_peer(&peer);
+        numbers.remove_peer(&peer);
+        for i in 1..=MAX_DISCONNECTED_PEERS as u64 {
+            let other = NodeId::from_low_u64_be(i);
+            guard.check(&other, 1, 10);
+            numbers.next(&other, 1);
+            guard.remove_peer(&other);
+            numbers.remove_peer(&other);
+        }
+        assert!(guard.check(&peer, 1, 10));
+        assert_eq!(
+            guard.peers.lock().disconnected.len(),
+            MAX_DISCONNECTED_PEERS
+        );
+        assert_eq!(
+            numbers.peers.lock().disconnected.len(),
+            MAX_DISCONNECTED_PEERS
+        );
+    }
+}
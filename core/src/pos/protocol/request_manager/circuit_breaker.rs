@@ -0,0 +1,179 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+//! The circuit breakers of the peers failing the RPC requests.
+//!
+//! A peer may keep its connection while it fails every request, e.g. one
+//! too busy to answer, and the peer score only makes it less likely to be
+//! chosen. After `max_failures` consecutive failures the breaker of the peer
+//! opens, and the peer is not chosen for the requests without a recipient
+//! for `cooldown`. Then the breaker is half open, and the peer is chosen
+//! again to probe whether it recovers: a response closes the breaker, while
+//! a failure opens it again for another `cooldown`.
+
+use std::{
+    mem::discriminant,
+    time::{Duration, Instant},
+};
+
+use crate::pos::consensus::counters;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CircuitBreakerConfig {
+    /// The consecutive failures of a peer that open its breaker. 0 disables
+    /// the breakers.
+    pub max_failures: u32,
+    /// How long an open breaker keeps its peer out of the selection.
+    pub cooldown: Duration,
+}
+
+impl CircuitBreakerConfig {
+    pub fn is_enabled(&self) -> bool { self.max_failures > 0 }
+}
+
+/// The state of the breaker of a peer, see `RequestManager::breaker_states`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakerState {
+    /// The peer is chosen as usual.
+    Closed,
+    /// The peer is not chosen until the cooldown is over.
+    Open,
+    /// The peer is chosen again to probe whether it recovers.
+    HalfOpen,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen,
+}
+
+impl State {
+    fn label(&self) -> &'static str {
+        match self {
+            State::Closed { .. } => "closed",
+            State::Open { .. } => "open",
+            State::HalfOpen => "half_open",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CircuitBreaker {
+    state: State,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self {
+            state: State::Closed { failures: 0 },
+        }
+    }
+}
+
+impl CircuitBreaker {
+    /// The state of the breaker at `now`. An open breaker whose cooldown is
+    /// over is half open.
+    pub fn state(&self, now: Instant) -> BreakerState {
+        match self.state {
+            State::Closed { .. } => BreakerState::Closed,
+            State::Open { until } if until > now => BreakerState::Open,
+            State::Open { .. } | State::HalfOpen => BreakerState::HalfOpen,
+        }
+    }
+
+    /// Whether the peer can be chosen at `now`.
+    pub fn allows(&mut self, now: Instant) -> bool {
+        match self.state {
+            State::Open { until } if until > now => false,
+            State::Open { .. } => {
+                self.transit(State::HalfOpen);
+                true
+            }
+            _ => true,
+        }
+    }
+
+    /// Record a response of the peer.
+    pub fn on_success(&mut self) {
+        self.transit(State::Closed { failures: 0 });
+    }
+
+    /// Record a failed request of the peer at `now`.
+    pub fn on_failure(&mut self, config: &CircuitBreakerConfig, now: Instant) {
+        if !config.is_enabled() {
+            return;
+        }
+        let open = State::Open {
+            until: now + config.cooldown,
+        };
+        match self.state {
+            State::Closed { failures }
+                if failures + 1 < config.max_failures =>
+            {
+                self.state = State::Closed {
+                    failures: failures + 1,
+                };
+            }
+            State::Closed { .. } | State::HalfOpen => self.transit(open),
+            // A request sent before the breaker opened.
+            State::Open { .. } => {}
+        }
+    }
+
+    fn transit(&mut self, state: State) {
+        let changed = discriminant(&self.state) != discriminant(&state);
+        self.state = state;
+        if changed {
+            counters::RPC_CIRCUIT_BREAKER_TRANSITIONS
+                .with_label_values(&[state.label()])
+                .inc();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BreakerState, CircuitBreaker, CircuitBreakerConfig};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_circuit_breaker() {
+        let cooldown = Duration::from_secs(30);
+        let config = CircuitBreakerConfig {
+            max_failures: 3,
+            cooldown,
+        };
+        let mut breaker = CircuitBreaker::default();
+        let now = Instant::now();
+
+        // A response resets the consecutive failures.
+        breaker.on_failure(&config, now);
+        breaker.on_failure(&config, now);
+        breaker.on_success();
+        breaker.on_failure(&config, now);
+        breaker.on_failure(&config, now);
+        assert!(breaker.allows(now));
+        breaker.on_failure(&config, now);
+        assert_eq!(breaker.state(now), BreakerState::Open);
+        assert!(!breaker.allows(now + cooldown / 2));
+
+        // A failed probe opens the breaker again, and a response closes it.
+        assert!(breaker.allows(now + cooldown));
+        assert_eq!(breaker.state(now + cooldown), BreakerState::HalfOpen);
+        breaker.on_failure(&config, now + cooldown);
+        assert!(!breaker.allows(now + cooldown * 3 / 2));
+        assert!(breaker.allows(now + cooldown * 2));
+        breaker.on_success();
+        assert_eq!(breaker.state(now + cooldown * 2), BreakerState::Closed);
+
+        // Never opened if disabled.
+        let mut breaker = CircuitBreaker::default();
+        for _ in 0..10 {
+            breaker.on_failure(&CircuitBreakerConfig::default(), now);
+        }
+        assert!(breaker.allows(now));
+    }
+}
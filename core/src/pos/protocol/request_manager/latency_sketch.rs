@@ -0,0 +1,119 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+use std::time::Duration;
+
+/// The upper bound of the first bucket, in microseconds.
+const MIN_LATENCY_US: f64 = 100.0;
+/// Each bucket is this times wider than the previous one, which bounds the
+/// relative error of the quantiles.
+const BUCKET_GROWTH: f64 = 1.25;
+/// The last bucket ends at about 160s and also holds the slower samples.
+const BUCKET_COUNT: usize = 64;
+
+/// A fixed-size histogram of the response latencies of a peer with
+/// exponential buckets, from which the quantiles are estimated.
+#[derive(Clone)]
+pub struct LatencySketch {
+    buckets: [u32; BUCKET_COUNT],
+    count: u64,
+}
+
+impl Default for LatencySketch {
+    fn default() -> Self {
+        Self {
+            buckets: [0; BUCKET_COUNT],
+            count: 0,
+        }
+    }
+}
+
+/// The latency summary of a peer, see `RequestManager::peer_latencies`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub p50: Duration,
+    pub p95: Duration,
+}
+
+impl LatencySketch {
+    pub fn record(&mut self, latency: Duration) {
+        let us = latency.as_secs_f64() * 1e6;
+        let index = if us <= MIN_LATENCY_US {
+            0
+        } else {
+            ((us / MIN_LATENCY_US).ln() / BUCKET_GROWTH.ln()).ceil() as usize
+        };
+        let bucket = &mut self.buckets[index.min(BUCKET_COUNT - 1)];
+        *bucket = bucket.saturating_add(1);
+        self.count += 1;
+    }
+
+    pub fn count(&self) -> u64 { self.count }
+
+    /// The latency that `q` of the samples are not above, rounded up to the
+    /// bound of its bucket. `None` if there is no sample.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank =
+            ((q.max(0.0).min(1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, bucket) in self.buckets.iter().enumerate() {
+            seen += *bucket as u64;
+            if seen >= rank {
+                return Some(Self::upper_bound(index));
+            }
+        }
+        Some(Self::upper_bound(BUCKET_COUNT - 1))
+    }
+
+    pub fn summary(&self) -> Option<LatencySummary> {
+        Some(LatencySummary {
+            count: self.count,
+            p50: self.quantile(0.5)?,
+            p95: self.quantile(0.95)?,
+        })
+    }
+
+    fn upper_bound(index: usize) -> Duration {
+        Duration::from_secs_f64(
+            MIN_LATENCY_US * BUCKET_GROWTH.powi(index as i32) / 1e6,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LatencySketch, BUCKET_GROWTH};
+    use std::time::Duration;
+
+    #[test]
+    fn test_quantiles() {
+        let mut sketch = LatencySketch::default();
+        assert!(sketch.summary().is_none());
+        // 1ms to 100ms.
+        for ms in 1..=100 {
+            sketch.record(Duration::from_millis(ms));
+        }
+        let summary = sketch.summary().unwrap();
+        assert_eq!(summary.count, 100);
+        let within = |estimate: Duration, ms: f64| {
+            let estimate = estimate.as_secs_f64() * 1e3;
+            estimate >= ms && estimate <= ms * BUCKET_GROWTH
+        };
+        assert!(within(summary.p50, 50.0), "{:?}", summary);
+        assert!(within(summary.p95, 95.0), "{:?}", summary);
+    }
+
+    #[test]
+    fn test_extreme_latencies() {
+        let mut sketch = LatencySketch::default();
+        sketch.record(Duration::from_secs(0));
+        sketch.record(Duration::from_secs(3600));
+        assert_eq!(sketch.quantile(0.0), sketch.quantile(0.5));
+        assert!(sketch.quantile(1.0).unwrap() > Duration::from_secs(100));
+    }
+}
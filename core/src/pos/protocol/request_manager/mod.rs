@@ -3,28 +3,144 @@
 // See https://www.apache.org/licenses/LICENSE-2.0
 
 use crate::{
-    pos::protocol::sync_protocol::RpcResponse,
+    pos::{
+        consensus::counters,
+        protocol::{
+            clock::{Clock, SystemClock},
+            log_context::SendLogContext,
+            sync_protocol::RpcResponse,
+        },
+    },
     sync::{Error, ErrorKind, ProtocolConfiguration},
 };
 use cfx_parameters::sync::REQUEST_START_WAITING_TIME;
+use circuit_breaker::BreakerState;
 use diem_logger::prelude::diem_debug;
 use futures::{channel::oneshot, future::Future};
+use latency_sketch::LatencySummary;
 use network::{node_table::NodeId, NetworkContext};
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
+use peer_score::{choose_peer, choose_peer_by_voting_power, PeerScore};
+use rand::thread_rng;
 pub use request_handler::{
     AsAny, Request, RequestHandler, RequestMessage, SynchronizationPeerRequest,
 };
 use std::{
     cmp::Ordering,
-    collections::binary_heap::BinaryHeap,
-    sync::Arc,
+    collections::{binary_heap::BinaryHeap, HashMap, HashSet},
+    mem,
+    sync::{
+        atomic::{AtomicBool, Ordering as AtomicOrdering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
+pub mod circuit_breaker;
+pub mod latency_sketch;
+pub mod peer_score;
 pub mod request_handler;
+pub mod retry_budget;
+pub mod rpc_timeouts;
 
+// (request, delay, retry_count, deadline)
 #[derive(Debug)]
-struct WaitingRequest(Box<dyn Request>, Duration); // (request, delay)
+struct WaitingRequest(Box<dyn Request>, Duration, usize, Option<Instant>);
+
+/// Whether `deadline` has passed at `now`, after which a request is not
+/// sent.
+fn is_past_deadline(deadline: Option<Instant>, now: Instant) -> bool {
+    deadline.map_or(false, |deadline| deadline <= now)
+}
+
+/// The retry policy of the requests that time out.
+#[derive(Clone, Debug)]
+pub struct RequestManagerConfig {
+    /// The number of times a timed out request is resent before the timeout
+    /// error is returned to the sender. 0 disables the retry.
+    pub max_retries: usize,
+    /// The delay before the first resend.
+    pub base_delay: Duration,
+    /// The upper bound of the delay between two resends.
+    pub max_delay: Duration,
+    /// The delay is multiplied by this factor after each resend.
+    pub backoff_multiplier: f64,
+    /// The maximum number of the RPCs waiting for responses at the same
+    /// time, 0 means no limit.
+    pub max_concurrent_rpcs: usize,
+    /// The maximum number of the requests tracked, in flight, queued behind
+    /// them or waiting to be resent. New requests beyond it fail with
+    /// `ErrorKind::TooManyPendingRequests`, 0 means no limit.
+    pub max_pending_requests: usize,
+    /// Whether `select_peer` prefers the peers of the validators with more
+    /// voting power, see `RequestManager::set_voting_powers`.
+    pub weight_by_voting_power: bool,
+}
+
+impl Default for RequestManagerConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 0,
+            base_delay: *REQUEST_START_WAITING_TIME,
+            max_delay: *REQUEST_START_WAITING_TIME * 10,
+            backoff_multiplier: 2.0,
+            max_concurrent_rpcs: 0,
+            max_pending_requests: 0,
+            weight_by_voting_power: false,
+        }
+    }
+}
+
+impl From<&ProtocolConfiguration> for RequestManagerConfig {
+    fn from(conf: &ProtocolConfiguration) -> Self {
+        Self {
+            max_retries: conf.pos_request_max_retries,
+            base_delay: conf.pos_request_retry_base_delay,
+            max_delay: conf.pos_request_retry_max_delay,
+            backoff_multiplier: conf.pos_request_retry_backoff_multiplier,
+            max_concurrent_rpcs: conf.pos_max_concurrent_rpcs,
+            max_pending_requests: conf.pos_max_pending_requests,
+            weight_by_voting_power: conf.pos_request_weight_by_voting_power,
+        }
+    }
+}
+
+impl RequestManagerConfig {
+    /// Return the delay before the next resend, given the delay before the
+    /// last one (`None` for the first resend).
+    pub fn next_delay(&self, delay: Option<Duration>) -> Duration {
+        match delay {
+            None => self.base_delay.min(self.max_delay),
+            Some(d) => {
+                let max_secs = self.max_delay.as_secs_f64();
+                let secs = d.as_secs_f64() * self.backoff_multiplier;
+                // Guard against invalid multipliers from the configuration.
+                if secs.is_finite() && secs >= 0.0 {
+                    Duration::from_secs_f64(secs.min(max_secs))
+                } else {
+                    self.max_delay
+                }
+            }
+        }
+    }
+
+    /// Return the longest time a request may wait for its response over all
+    /// the retries, if each attempt waits for `request_timeout` and every
+    /// timeout is detected within `check_period`.
+    pub fn max_wait(
+        &self, request_timeout: Duration, check_period: Duration,
+    ) -> Duration {
+        let mut total = request_timeout + check_period;
+        let mut delay = None;
+        for _ in 0..self.max_retries {
+            let d = self.next_delay(delay);
+            total += d + check_period + request_timeout + check_period;
+            delay = Some(d);
+        }
+        total
+    }
+}
 
 pub struct RequestManager {
     /// Each element is (timeout_time, request, chosen_peer)
@@ -32,16 +148,70 @@ pub struct RequestManager {
 
     /// This is used to handle request_id matching
     request_handler: Arc<RequestHandler>,
+
+    config: RequestManagerConfig,
+
+    /// The permits of the outstanding RPCs, `None` if they are not limited.
+    rpc_permits: Option<Arc<Semaphore>>,
+
+    /// The voting power of the validator behind each peer in the current
+    /// epoch. The peers not in it have none.
+    voting_powers: RwLock<HashMap<NodeId, u64>>,
+
+    /// Set by `shutdown`, after which every request fails at once.
+    shut_down: AtomicBool,
+
+    /// The time of the timeouts and the backoff delays.
+    clock: Arc<dyn Clock>,
 }
 
 impl RequestManager {
     pub fn new(protocol_config: &ProtocolConfiguration) -> Self {
+        Self::with_clock(protocol_config, Arc::new(SystemClock))
+    }
+
+    /// Read the time of the timeouts and the backoff delays from `clock`.
+    pub fn with_clock(
+        protocol_config: &ProtocolConfiguration, clock: Arc<dyn Clock>,
+    ) -> Self {
+        let config: RequestManagerConfig = protocol_config.into();
+        let rpc_permits = match config.max_concurrent_rpcs {
+            0 => None,
+            n => Some(Arc::new(Semaphore::new(n))),
+        };
         Self {
             waiting_requests: Default::default(),
-            request_handler: Arc::new(RequestHandler::new(protocol_config)),
+            request_handler: Arc::new(RequestHandler::with_clock(
+                protocol_config,
+                clock.clone(),
+            )),
+            config,
+            rpc_permits,
+            voting_powers: Default::default(),
+            shut_down: AtomicBool::new(false),
+            clock,
         }
     }
 
+    pub fn config(&self) -> &RequestManagerConfig { &self.config }
+
+    /// Wait until fewer than `max_concurrent_rpcs` RPCs are outstanding, and
+    /// return the permit to hold until the RPC finishes. The waiters are
+    /// served in order.
+    pub async fn acquire_rpc_permit(&self) -> RpcPermit {
+        let permit = match &self.rpc_permits {
+            Some(permits) => Some(
+                permits
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("the semaphore is never closed"),
+            ),
+            None => None,
+        };
+        RpcPermit::new(permit)
+    }
+
     /// Send a unary rpc request to remote peer `recipient`.
     pub async fn unary_rpc<'a>(
         &'a self, io: &'a dyn NetworkContext, recipient: Option<NodeId>,
@@ -53,30 +223,95 @@ impl RequestManager {
             let (res_tx, res_rx) = oneshot::channel();
             request.set_response_notification(res_tx);
 
-            self.request_with_delay(io, request, recipient, None);
+            self.request_with_delay(io, request, recipient, None, None);
 
             // wait for response
             let response = res_rx.await??;
-            Ok(response)
+            Ok(response.response)
         }
     }
 
     /// Send request to remote peer with delay mechanism. If failed,
     /// add the request to waiting queue to resend later.
+    ///
+    /// A request to be sent immediately is not sent if an identical request
+    /// to the same peer is inflight or pending. The existing request answers
+    /// both instead.
+    ///
+    /// The request is neither sent nor resent once `deadline` has passed,
+    /// and its sender gets `ErrorKind::DeadlineExceeded` instead, e.g. for
+    /// the requests sharing the deadline of a sync.
+    ///
+    /// A new request fails with `ErrorKind::TooManyPendingRequests` if
+    /// `max_pending_requests` requests are tracked already, until some of
+    /// them finish. The resends of the tracked requests are not limited.
+    ///
+    /// Return the request id if the request is sent out immediately.
     pub fn request_with_delay(
         &self, io: &dyn NetworkContext, mut request: Box<dyn Request>,
         peer: Option<NodeId>, delay: Option<Duration>,
-    )
+        deadline: Option<Instant>,
+    ) -> Option<u64>
+    {
+        if self.shut_down.load(AtomicOrdering::SeqCst) {
+            request.notify_error(ErrorKind::Shutdown.into());
+            return None;
+        }
+        if let (None, Some(peer), Some(key)) =
+            (delay, peer, request.coalescing_key())
+        {
+            if self.request_handler.coalesce(
+                &peer,
+                &key,
+                &mut *request,
+                deadline,
+            ) {
+                counters::RPC_COALESCED.inc();
+                return None;
+            }
+        }
+        let max_pending_requests = self.config.max_pending_requests;
+        if max_pending_requests > 0
+            && self.update_pending_requests() >= max_pending_requests
+        {
+            counters::RPC_REJECTED_TOO_MANY_PENDING.inc();
+            request.notify_error(ErrorKind::TooManyPendingRequests.into());
+            return None;
+        }
+        let request_id = self
+            .request_with_retry_count(io, request, peer, delay, 0, deadline);
+        self.update_pending_requests();
+        request_id
+    }
+
+    /// The number of the requests tracked, which is also exported to
+    /// `RPC_PENDING_REQUESTS`.
+    fn update_pending_requests(&self) -> usize {
+        let num_requests = self.request_handler.num_requests()
+            + self.waiting_requests.lock().len();
+        counters::RPC_PENDING_REQUESTS.set(num_requests as i64);
+        num_requests
+    }
+
+    fn request_with_retry_count(
+        &self, io: &dyn NetworkContext, mut request: Box<dyn Request>,
+        peer: Option<NodeId>, delay: Option<Duration>, retry_count: usize,
+        deadline: Option<Instant>,
+    ) -> Option<u64>
     {
+        if is_past_deadline(deadline, self.clock.now()) {
+            request.notify_error(ErrorKind::DeadlineExceeded.into());
+            return None;
+        }
+
         // increase delay for resent request.
-        let (cur_delay, next_delay) = match delay {
-            Some(d) => (d, d + *REQUEST_START_WAITING_TIME),
-            None => (*REQUEST_START_WAITING_TIME, *REQUEST_START_WAITING_TIME),
-        };
+        let cur_delay = delay.unwrap_or(self.config.base_delay);
+        let next_delay = self.config.next_delay(delay);
 
+        let peer = peer.or_else(|| self.select_peer(&HashSet::new()));
         if peer.is_none() {
             request.notify_error(ErrorKind::RpcCancelledByDisconnection.into());
-            return;
+            return None;
         }
 
         // delay if no peer available or delay required
@@ -84,27 +319,116 @@ impl RequestManager {
             // todo remove the request if waiting time is too long?
             // E.g. attacker may broadcast many many invalid block hashes,
             // and no peer could return the corresponding block header.
-            diem_debug!("request_with_delay: add request to waiting_requests, peer={:?}, request={:?}, delay={:?}", peer, request, cur_delay);
+            diem_debug!("request_with_delay: add request to waiting_requests, {}, request={:?}, delay={:?}", SendLogContext::of(&*request).to_maybe_peer(peer.as_ref()), request, cur_delay);
             self.waiting_requests.lock().push(TimedWaitingRequest::new(
-                Instant::now() + cur_delay,
-                WaitingRequest(request, next_delay),
+                self.clock.now() + cur_delay,
+                WaitingRequest(request, next_delay, retry_count, deadline),
                 peer.unwrap(),
             ));
 
-            return;
+            return None;
         }
 
-        if let Err(mut req) = self.request_handler.send_request(
+        match self.request_handler.send_request(
             io,
             peer,
             request,
             Some(next_delay),
+            retry_count,
+            deadline,
         ) {
-            debug!("request_with_delay: send_request fails, peer={:?}, request={:?}", peer, req);
-            req.notify_error(ErrorKind::RpcCancelledByDisconnection.into());
+            Ok(request_id) => request_id,
+            Err(mut req) => {
+                debug!(
+                    "request_with_delay: send_request fails, {}, request={:?}",
+                    SendLogContext::of(&*req).to_maybe_peer(peer.as_ref()),
+                    req
+                );
+                req.notify_error(ErrorKind::RpcCancelledByDisconnection.into());
+                None
+            }
+        }
+    }
+
+    /// Remove an inflight request without notifying its sender, e.g. after
+    /// the caller has stopped waiting for the response.
+    pub fn remove_request(
+        &self, io: &dyn NetworkContext, peer_id: &NodeId, request_id: u64,
+    ) -> Option<RequestMessage> {
+        self.request_handler
+            .cancel_request(io, peer_id, request_id)
+            .ok()
+    }
+
+    /// Cancel an inflight request whose response is no longer needed.
+    ///
+    /// The request and its response notification are dropped, so a late
+    /// response is ignored as an unknown request. Returns whether the
+    /// request is still inflight and removed.
+    pub fn cancel(
+        &self, io: &dyn NetworkContext, peer_id: &NodeId, request_id: u64,
+    ) -> bool {
+        self.request_handler
+            .discard_request(io, peer_id, request_id)
+            .is_ok()
+    }
+
+    /// Remove the inflight requests nobody waits for anymore, e.g. the
+    /// resends of a request whose `RpcHandle` is cancelled, which the handle
+    /// does not know the ids of. Return the number of the requests removed.
+    pub fn discard_canceled_requests(&self, io: &dyn NetworkContext) -> usize {
+        let discarded = self.request_handler.discard_canceled_requests(io);
+        self.update_pending_requests();
+        discarded
+    }
+
+    /// Choose a connected peer that is not in `exclude` and whose circuit
+    /// breaker is not open, biased toward the
+    /// peers that answer requests successfully and quickly, and with
+    /// `weight_by_voting_power` also toward the peers of the validators with
+    /// more voting power.
+    pub fn select_peer(&self, exclude: &HashSet<NodeId>) -> Option<NodeId> {
+        let candidates: Vec<_> = self
+            .request_handler
+            .available_peer_scores()
+            .into_iter()
+            .filter(|(peer, _)| !exclude.contains(peer))
+            .collect();
+        if self.config.weight_by_voting_power {
+            choose_peer_by_voting_power(
+                &candidates,
+                &self.voting_powers.read(),
+                &mut thread_rng(),
+            )
+        } else {
+            choose_peer(&candidates, &mut thread_rng())
         }
     }
 
+    /// Set the voting power of the validator behind each connected peer,
+    /// replacing the previous ones, e.g. when a new epoch starts or a peer
+    /// of a validator connects.
+    pub fn set_voting_powers(&self, voting_powers: HashMap<NodeId, u64>) {
+        *self.voting_powers.write() = voting_powers;
+    }
+
+    /// Return the scores used to select peers, for debugging.
+    pub fn peer_scores(&self) -> Vec<(NodeId, PeerScore)> {
+        self.request_handler.peer_scores()
+    }
+
+    /// Return the states of the circuit breakers of the peers, for
+    /// debugging.
+    pub fn breaker_states(&self) -> Vec<(NodeId, BreakerState)> {
+        self.request_handler.breaker_states()
+    }
+
+    /// Return the response latencies of the peers, for finding the slow
+    /// ones.
+    pub fn peer_latencies(&self) -> Vec<(NodeId, LatencySummary)> {
+        self.request_handler.peer_latencies()
+    }
+
     // Match request with given response.
     // No need to let caller handle request resending.
     pub fn match_request(
@@ -118,15 +442,32 @@ impl RequestManager {
         let timeout_requests = self.request_handler.get_timeout_requests(io);
         for mut req in timeout_requests {
             debug!("Timeout requests: {:?}", req);
+            if req.request.is_canceled() {
+                continue;
+            }
+            if req.retry_count < self.config.max_retries {
+                // Resend to any available peer after the backoff delay.
+                let delay = req.delay.unwrap_or(self.config.base_delay);
+                self.request_with_retry_count(
+                    io,
+                    req.request,
+                    None,
+                    Some(delay),
+                    req.retry_count + 1,
+                    req.deadline,
+                );
+                continue;
+            }
             req.request.notify_error(ErrorKind::RpcTimeout.into());
         }
+        self.update_pending_requests();
     }
 
     /// Send waiting requests that their backoff delay have passes
     pub fn resend_waiting_requests(&self, io: &dyn NetworkContext) {
         debug!("resend_waiting_requests: start");
         let mut waiting_requests = self.waiting_requests.lock();
-        let now = Instant::now();
+        let now = self.clock.now();
 
         while let Some(req) = waiting_requests.pop() {
             if req.time_to_send >= now {
@@ -137,14 +478,24 @@ impl RequestManager {
             let chosen_peer = req.peer;
             debug!("Send waiting req {:?} to peer={}", req, chosen_peer);
 
-            let WaitingRequest(request, delay) = req.request;
-            let next_delay = delay + *REQUEST_START_WAITING_TIME;
+            let WaitingRequest(mut request, delay, retry_count, deadline) =
+                req.request;
+            if request.is_canceled() {
+                continue;
+            }
+            if is_past_deadline(deadline, now) {
+                request.notify_error(ErrorKind::DeadlineExceeded.into());
+                continue;
+            }
+            let next_delay = self.config.next_delay(Some(delay));
 
             if let Err(mut req) = self.request_handler.send_request(
                 io,
                 Some(chosen_peer),
                 request,
                 Some(next_delay),
+                retry_count,
+                deadline,
             ) {
                 req.notify_error(ErrorKind::RpcCancelledByDisconnection.into());
             }
@@ -158,20 +509,87 @@ impl RequestManager {
     pub fn on_peer_disconnected(
         &self, _io: &dyn NetworkContext, peer: &NodeId,
     ) {
-        if let Some(unfinished_requests) =
-            self.request_handler.remove_peer(peer)
-        {
-            for mut msg in unfinished_requests {
-                msg.request.notify_error(
-                    ErrorKind::RpcCancelledByDisconnection.into(),
-                );
+        self.cancel_peer_requests(peer);
+    }
+
+    /// Fail all the requests to `peer` with `RpcCancelledByDisconnection`,
+    /// so their senders stop waiting at once instead of at the timeouts.
+    ///
+    /// Besides the inflight and pending requests, this covers the requests
+    /// waiting for their resend delay to be sent to `peer`. Return the
+    /// number of the requests cancelled.
+    pub fn cancel_peer_requests(&self, peer: &NodeId) -> usize {
+        self.cancel_requests(Some(peer), || {
+            ErrorKind::RpcCancelledByDisconnection.into()
+        })
+    }
+
+    /// Fail all the requests with `ErrorKind::Shutdown`, and the requests
+    /// made from now on as well. Return the number of the requests
+    /// cancelled, which is 0 if it is already shut down.
+    pub fn shutdown(&self) -> usize {
+        if self.shut_down.swap(true, AtomicOrdering::SeqCst) {
+            return 0;
+        }
+        self.cancel_requests(None, || ErrorKind::Shutdown.into())
+    }
+
+    /// Fail the requests to `peer`, or to all the peers if it is `None`,
+    /// with the errors made by `error`.
+    fn cancel_requests(
+        &self, peer: Option<&NodeId>, error: impl Fn() -> Error,
+    ) -> usize {
+        let peers = match peer {
+            Some(peer) => vec![*peer],
+            None => self.request_handler.peer_ids(),
+        };
+        let mut cancelled: Vec<Box<dyn Request>> = Vec::new();
+        for peer in &peers {
+            if let Some(unfinished_requests) =
+                self.request_handler.remove_peer(peer)
+            {
+                cancelled
+                    .extend(unfinished_requests.into_iter().map(|m| m.request));
+            } else {
+                debug!("Peer already removed form request manager when disconnected peer={}", peer);
             }
-        } else {
-            debug!("Peer already removed form request manager when disconnected peer={}", peer);
         }
+        {
+            let mut waiting_requests = self.waiting_requests.lock();
+            let (to_peer, others): (Vec<_>, Vec<_>) =
+                mem::take(&mut *waiting_requests)
+                    .into_vec()
+                    .into_iter()
+                    .partition(|req| peer.map_or(true, |p| req.peer == *p));
+            *waiting_requests = others.into();
+            cancelled.extend(to_peer.into_iter().map(|req| req.request.0));
+        }
+        self.update_pending_requests();
+        // The senders are notified without holding the locks.
+        let count = cancelled.len();
+        for mut request in cancelled {
+            request.notify_error(error());
+        }
+        count
+    }
+}
+
+/// A permit of an outstanding RPC, released on drop.
+pub struct RpcPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl RpcPermit {
+    fn new(permit: Option<OwnedSemaphorePermit>) -> Self {
+        counters::RPC_PERMITS_IN_USE.inc();
+        Self { _permit: permit }
     }
 }
 
+impl Drop for RpcPermit {
+    fn drop(&mut self) { counters::RPC_PERMITS_IN_USE.dec(); }
+}
+
 #[derive(Debug)]
 struct TimedWaitingRequest {
     time_to_send: Instant,
@@ -207,3 +625,382 @@ impl PartialEq for TimedWaitingRequest {
         self.time_to_send == other.time_to_send
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Request, RequestManager, RequestManagerConfig};
+    use crate::{
+        pos::{
+            consensus::counters,
+            protocol::{
+                message::block_retrieval::BlockRetrievalRpcRequest,
+                sync_protocol::RpcResponseWithPeer,
+                test_utils::{MockClock, MockNetworkContext},
+            },
+        },
+        sync::{Error, ErrorKind, ProtocolConfiguration},
+    };
+    use consensus_types::block_retrieval::BlockRetrievalRequest;
+    use diem_crypto::HashValue;
+    use futures::{channel::oneshot, FutureExt};
+    use network::node_table::NodeId;
+    use std::{
+        mem::discriminant,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    type ResponseRx = oneshot::Receiver<Result<RpcResponseWithPeer, Error>>;
+
+    /// A request of `num_blocks` blocks from `block_id`, with the receiver
+    /// of its result.
+    fn block_request(
+        block_id: HashValue, num_blocks: u64,
+    ) -> (Box<BlockRetrievalRpcRequest>, ResponseRx) {
+        let mut request = Box::new(BlockRetrievalRpcRequest {
+            request_id: 0,
+            request: BlockRetrievalRequest::new(block_id, num_blocks),
+            is_empty: false,
+            response_tx: None,
+            coalesced_tx: Vec::new(),
+            timeout: Duration::from_secs(60),
+        });
+        let (res_tx, res_rx) = oneshot::channel();
+        request.set_response_notification(res_tx);
+        (request, res_rx)
+    }
+
+    /// Check that the request of `res_rx` has failed with `expected`.
+    fn expect_error_kind(res_rx: ResponseRx, expected: ErrorKind) {
+        let result = res_rx
+            .now_or_never()
+            .expect("resolved at once")
+            .expect("sender notified");
+        match result {
+            Err(e) => assert_eq!(
+                discriminant(e.kind()),
+                discriminant(&expected),
+                "{}",
+                e
+            ),
+            Ok(_) => panic!("unexpected response"),
+        }
+    }
+
+    fn config(max_retries: usize) -> RequestManagerConfig {
+        RequestManagerConfig {
+            max_retries,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5),
+            backoff_multiplier: 2.0,
+            max_concurrent_rpcs: 0,
+            max_pending_requests: 0,
+            weight_by_voting_power: false,
+        }
+    }
+
+    #[test]
+    fn test_next_delay_backoff() {
+        let config = config(3);
+        let mut delay = None;
+        let mut delays = Vec::new();
+        for _ in 0..5 {
+            let d = config.next_delay(delay);
+            delays.push(d.as_secs());
+            delay = Some(d);
+        }
+        assert_eq!(delays, vec![1, 2, 4, 5, 5]);
+    }
+
+    #[test]
+    fn test_max_wait() {
+        let timeout = Duration::from_secs(10);
+        let check = Duration::from_secs(1);
+        // Without retry, one attempt and one check period.
+        assert_eq!(config(0).max_wait(timeout, check), timeout + check);
+        // Each retry adds its delay and another attempt.
+        assert_eq!(
+            config(2).max_wait(timeout, check),
+            Duration::from_secs(11 + (1 + 12) + (2 + 12))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rpc_permits_limited() {
+        let limit = 3;
+        let request_manager = RequestManager::new(&ProtocolConfiguration {
+            pos_max_concurrent_rpcs: limit,
+            ..Default::default()
+        });
+        let in_use = counters::RPC_PERMITS_IN_USE.get();
+        let mut permits = Vec::new();
+        for _ in 0..limit {
+            permits.push(
+                request_manager
+                    .acquire_rpc_permit()
+                    .now_or_never()
+                    .expect("below the limit"),
+            );
+        }
+        // The RPCs beyond the limit wait for a permit.
+        let mut waiting = Box::pin(request_manager.acquire_rpc_permit());
+        assert!((&mut waiting).now_or_never().is_none());
+
+        // Finishing an RPC lets the next one be sent.
+        permits.pop();
+        permits.push(waiting.now_or_never().expect("permit released"));
+        // Other tests may hold permits of their own at the same time.
+        assert!(counters::RPC_PERMITS_IN_USE.get() >= in_use + limit as i64);
+    }
+
+    #[test]
+    fn test_rpc_permits_unlimited() {
+        let request_manager =
+            RequestManager::new(&ProtocolConfiguration::default());
+        let permits: Vec<_> = (0..1000)
+            .map(|_| {
+                request_manager
+                    .acquire_rpc_permit()
+                    .now_or_never()
+                    .expect("no limit")
+            })
+            .collect();
+        assert_eq!(permits.len(), 1000);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_peer_requests() {
+        let request_manager = RequestManager::new(&ProtocolConfiguration {
+            max_inflight_request_count: 1,
+            ..Default::default()
+        });
+        let io = MockNetworkContext::default();
+        let peer = NodeId::from_low_u64_be(1);
+        let other = NodeId::from_low_u64_be(2);
+        request_manager.on_peer_connected(&peer);
+        request_manager.on_peer_connected(&other);
+
+        let send = |peer, delay| {
+            // Different blocks, so the requests are not coalesced.
+            let (request, res_rx) = block_request(HashValue::random(), 1);
+            request_manager.request_with_delay(
+                &io,
+                request,
+                Some(peer),
+                delay,
+                None,
+            );
+            res_rx
+        };
+        // One inflight, one pending behind it and one waiting to be resent.
+        let to_peer = vec![
+            send(peer, None),
+            send(peer, None),
+            send(peer, Some(Duration::from_secs(60))),
+        ];
+        let mut to_other = send(other, Some(Duration::from_secs(60)));
+
+        request_manager.on_peer_disconnected(&io, &peer);
+        for res_rx in to_peer {
+            expect_error_kind(res_rx, ErrorKind::RpcCancelledByDisconnection);
+        }
+        // The requests to the other peers are kept.
+        assert!(to_other.try_recv().unwrap().is_none());
+        assert_eq!(request_manager.cancel_peer_requests(&peer), 0);
+        assert_eq!(request_manager.cancel_peer_requests(&other), 1);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let request_manager =
+            RequestManager::new(&ProtocolConfiguration::default());
+        let io = MockNetworkContext::default();
+        let peers = [NodeId::from_low_u64_be(1), NodeId::from_low_u64_be(2)];
+        for peer in &peers {
+            request_manager.on_peer_connected(peer);
+        }
+        let send = |peer, delay| {
+            let (request, res_rx) = block_request(HashValue::random(), 1);
+            request_manager.request_with_delay(
+                &io,
+                request,
+                Some(peer),
+                delay,
+                None,
+            );
+            res_rx
+        };
+        let mut waiting: Vec<_> = peers
+            .iter()
+            .flat_map(|peer| {
+                vec![
+                    send(*peer, None),
+                    send(*peer, Some(Duration::from_secs(60))),
+                ]
+            })
+            .collect();
+        assert_eq!(request_manager.shutdown(), 4);
+        // The requests made after the shutdown fail at once as well.
+        waiting.push(send(peers[0], None));
+        for res_rx in waiting {
+            expect_error_kind(res_rx, ErrorKind::Shutdown);
+        }
+        assert_eq!(request_manager.shutdown(), 0);
+    }
+
+    #[test]
+    fn test_identical_requests_coalesced() {
+        let request_manager =
+            RequestManager::new(&ProtocolConfiguration::default());
+        let io = MockNetworkContext::default();
+        let peer = NodeId::from_low_u64_be(1);
+        request_manager.on_peer_connected(&peer);
+
+        let block_id = HashValue::random();
+        let send = |peer| {
+            let (request, res_rx) = block_request(block_id, 2);
+            let request_id = request_manager
+                .request_with_delay(&io, request, peer, None, None);
+            (request_id, res_rx)
+        };
+        let (request_id, first) = send(Some(peer));
+        let (coalesced_id, second) = send(Some(peer));
+        assert!(request_id.is_some());
+        assert!(coalesced_id.is_none());
+        assert_eq!(io.sent.lock().len(), 1);
+
+        // Both callers are notified with the result of the one request.
+        request_manager.on_peer_disconnected(&io, &peer);
+        for res_rx in vec![first, second] {
+            expect_error_kind(res_rx, ErrorKind::RpcCancelledByDisconnection);
+        }
+    }
+
+    #[test]
+    fn test_past_deadline_not_dispatched() {
+        let request_manager =
+            RequestManager::new(&ProtocolConfiguration::default());
+        let io = MockNetworkContext::default();
+        let peer = NodeId::from_low_u64_be(1);
+        request_manager.on_peer_connected(&peer);
+
+        let send = |deadline| {
+            let (request, res_rx) = block_request(HashValue::random(), 1);
+            let request_id = request_manager.request_with_delay(
+                &io,
+                request,
+                Some(peer),
+                None,
+                Some(deadline),
+            );
+            (request_id, res_rx)
+        };
+        let (request_id, res_rx) =
+            send(Instant::now() - Duration::from_secs(1));
+        assert!(request_id.is_none());
+        expect_error_kind(res_rx, ErrorKind::DeadlineExceeded);
+        assert!(io.sent.lock().is_empty());
+
+        // A request before its deadline is sent as usual.
+        let (request_id, mut res_rx) =
+            send(Instant::now() + Duration::from_secs(60));
+        assert!(request_id.is_some());
+        assert!(res_rx.try_recv().unwrap().is_none());
+        assert_eq!(io.sent.lock().len(), 1);
+    }
+
+    #[test]
+    fn test_too_many_pending_requests() {
+        let request_manager = RequestManager::new(&ProtocolConfiguration {
+            max_inflight_request_count: 1,
+            pos_max_pending_requests: 2,
+            ..Default::default()
+        });
+        let io = MockNetworkContext::default();
+        let peer = NodeId::from_low_u64_be(1);
+        request_manager.on_peer_connected(&peer);
+
+        let send = || {
+            let (request, res_rx) = block_request(HashValue::random(), 1);
+            let request_id = request_manager.request_with_delay(
+                &io,
+                request,
+                Some(peer),
+                None,
+                None,
+            );
+            (request_id, res_rx)
+        };
+        // One inflight and one pending behind it.
+        let (request_id, _inflight) = send();
+        let (_, _pending) = send();
+        assert_eq!(request_id, Some(0));
+
+        let rejected = counters::RPC_REJECTED_TOO_MANY_PENDING.get();
+        let (request_id, res_rx) = send();
+        assert!(request_id.is_none());
+        expect_error_kind(res_rx, ErrorKind::TooManyPendingRequests);
+        // Other tests may reject requests at the same time.
+        assert!(counters::RPC_REJECTED_TOO_MANY_PENDING.get() > rejected);
+
+        // The response of the inflight request sends the pending one, and
+        // makes room for another request.
+        assert!(request_manager.match_request(&io, &peer, 0).is_ok());
+        assert_eq!(io.sent.lock().len(), 2);
+        let (_, mut res_rx) = send();
+        assert!(res_rx.try_recv().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_timeout_and_backoff_with_mock_clock() {
+        let clock = Arc::new(MockClock::new());
+        let request_manager = RequestManager::with_clock(
+            &ProtocolConfiguration {
+                pos_request_max_retries: 1,
+                pos_request_retry_base_delay: Duration::from_secs(1),
+                pos_request_retry_max_delay: Duration::from_secs(1),
+                max_allowed_timeout_in_observing_period: 10,
+                ..Default::default()
+            },
+            clock.clone(),
+        );
+        let io = MockNetworkContext::default();
+        let peer = NodeId::from_low_u64_be(1);
+        request_manager.on_peer_connected(&peer);
+        let timeout = Duration::from_secs(10);
+        let (mut request, mut res_rx) = block_request(HashValue::random(), 1);
+        request.timeout = timeout;
+        request_manager.request_with_delay(
+            &io,
+            request,
+            Some(peer),
+            None,
+            None,
+        );
+        assert_eq!(io.sent.lock().len(), 1);
+
+        // Not timed out until the timeout has fully elapsed.
+        let tick = Duration::from_millis(1);
+        clock.advance(timeout);
+        request_manager.process_timeout_requests(&io);
+        request_manager.resend_waiting_requests(&io);
+        assert_eq!(io.sent.lock().len(), 1);
+
+        // Timed out, and resent after the backoff delay.
+        clock.advance(tick);
+        request_manager.process_timeout_requests(&io);
+        clock.advance(Duration::from_secs(1));
+        request_manager.resend_waiting_requests(&io);
+        assert_eq!(io.sent.lock().len(), 1);
+        clock.advance(tick);
+        request_manager.resend_waiting_requests(&io);
+        assert_eq!(io.sent.lock().len(), 2);
+        assert!(res_rx.try_recv().unwrap().is_none());
+
+        // The retry times out too, and the error is returned.
+        clock.advance(timeout + tick);
+        request_manager.process_timeout_requests(&io);
+        expect_error_kind(res_rx, ErrorKind::RpcTimeout);
+        assert_eq!(io.sent.lock().len(), 2);
+    }
+}
@@ -0,0 +1,238 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+use network::node_table::NodeId;
+use rand::{seq::SliceRandom, Rng};
+use std::{collections::HashMap, time::Duration};
+
+/// The weight of the newest sample in the rolling averages.
+const SMOOTHING_FACTOR: f64 = 0.2;
+
+/// The probability to choose a peer regardless of its score, so peers with
+/// low scores are still probed and can recover.
+pub const PROBE_PROBABILITY: f64 = 0.1;
+
+/// The lowest weight a peer can get in the selection.
+const MIN_WEIGHT: f64 = 0.01;
+
+/// The lowest share of the highest voting power a peer is weighted by in
+/// `choose_peer_by_voting_power`, so the peers of the validators with little
+/// or no voting power still get some requests.
+pub const MIN_VOTING_POWER_SHARE: f64 = 0.1;
+
+/// Rolling statistics of how well a peer answers requests.
+#[derive(Clone, Copy, Debug)]
+pub struct PeerScore {
+    /// Exponential moving average of the request success rate.
+    success_rate: f64,
+    /// Exponential moving average of the response latency, `None` before
+    /// the first response.
+    latency: Option<Duration>,
+}
+
+impl Default for PeerScore {
+    // New peers start with a full success rate so they are tried early.
+    fn default() -> Self {
+        Self {
+            success_rate: 1.0,
+            latency: None,
+        }
+    }
+}
+
+impl PeerScore {
+    pub fn success_rate(&self) -> f64 { self.success_rate }
+
+    pub fn latency(&self) -> Option<Duration> { self.latency }
+
+    /// Record a response received after `latency`.
+    pub fn on_success(&mut self, latency: Duration) {
+        self.success_rate = Self::smooth(self.success_rate, 1.0);
+        self.latency = Some(match self.latency {
+            Some(avg) => Duration::from_secs_f64(Self::smooth(
+                avg.as_secs_f64(),
+                latency.as_secs_f64(),
+            )),
+            None => latency,
+        });
+    }
+
+    /// Record a request that times out or is cancelled without response.
+    pub fn on_failure(&mut self) {
+        self.success_rate = Self::smooth(self.success_rate, 0.0);
+    }
+
+    /// The selection weight of the peer. Higher is better.
+    pub fn weight(&self) -> f64 {
+        let latency = self.latency.map_or(0.0, |l| l.as_secs_f64());
+        (self.success_rate / (1.0 + latency)).max(MIN_WEIGHT)
+    }
+
+    fn smooth(avg: f64, sample: f64) -> f64 {
+        avg * (1.0 - SMOOTHING_FACTOR) + sample * SMOOTHING_FACTOR
+    }
+}
+
+/// Choose a peer among `candidates`, biased toward higher scores. With a
+/// probability of `PROBE_PROBABILITY` the peer is chosen uniformly.
+pub fn choose_peer<R: Rng>(
+    candidates: &[(NodeId, PeerScore)], rng: &mut R,
+) -> Option<NodeId> {
+    choose_peer_with(candidates, rng, |_| 1.0)
+}
+
+/// Choose a peer like `choose_peer`, with the score of each peer also
+/// weighted by its share of the highest voting power among `candidates`, at
+/// least `MIN_VOTING_POWER_SHARE`. The peers not in `voting_powers` have no
+/// voting power.
+pub fn choose_peer_by_voting_power<R: Rng>(
+    candidates: &[(NodeId, PeerScore)], voting_powers: &HashMap<NodeId, u64>,
+    rng: &mut R,
+) -> Option<NodeId>
+{
+    let max_power = candidates
+        .iter()
+        .filter_map(|(peer, _)| voting_powers.get(peer))
+        .max()
+        .cloned()
+        .unwrap_or(0);
+    if max_power == 0 {
+        return choose_peer(candidates, rng);
+    }
+    choose_peer_with(candidates, rng, |peer| {
+        let power = voting_powers.get(peer).cloned().unwrap_or(0);
+        (power as f64 / max_power as f64).max(MIN_VOTING_POWER_SHARE)
+    })
+}
+
+/// Choose a peer like `choose_peer`, with the score of each peer multiplied
+/// by `factor` of the peer.
+fn choose_peer_with<R: Rng>(
+    candidates: &[(NodeId, PeerScore)], rng: &mut R,
+    factor: impl Fn(&NodeId) -> f64,
+) -> Option<NodeId>
+{
+    if rng.gen_bool(PROBE_PROBABILITY) {
+        return candidates.choose(rng).map(|(peer, _)| *peer);
+    }
+    candidates
+        .choose_weighted(rng, |(peer, score)| score.weight() * factor(peer))
+        .ok()
+        .map(|(peer, _)| *peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        choose_peer, choose_peer_by_voting_power, PeerScore,
+        MIN_VOTING_POWER_SHARE, PROBE_PROBABILITY,
+    };
+    use network::node_table::NodeId;
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::{collections::HashMap, time::Duration};
+
+    #[test]
+    fn test_peer_score() {
+        let mut score = PeerScore::default();
+        assert_eq!(score.success_rate(), 1.0);
+        assert_eq!(score.latency(), None);
+
+        score.on_success(Duration::from_secs(1));
+        assert_eq!(score.latency(), Some(Duration::from_secs(1)));
+        let weight = score.weight();
+
+        score.on_failure();
+        assert!(score.success_rate() < 1.0);
+        assert!(score.weight() < weight);
+
+        // The score recovers after successes.
+        for _ in 0..20 {
+            score.on_success(Duration::from_secs(1));
+        }
+        assert!(score.success_rate() > 0.95);
+    }
+
+    #[test]
+    fn test_choose_peer() {
+        let good = NodeId::from_low_u64_be(1);
+        let bad = NodeId::from_low_u64_be(2);
+        let mut good_score = PeerScore::default();
+        let mut bad_score = PeerScore::default();
+        for _ in 0..10 {
+            good_score.on_success(Duration::from_millis(10));
+            bad_score.on_failure();
+        }
+        let candidates = vec![(good, good_score), (bad, bad_score)];
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut bad_count = 0;
+        for _ in 0..1000 {
+            if choose_peer(&candidates, &mut rng) == Some(bad) {
+                bad_count += 1;
+            }
+        }
+        // The bad peer is mostly avoided but still probed.
+        assert!(bad_count > 0);
+        assert!(bad_count < 200);
+
+        assert_eq!(choose_peer(&[], &mut rng), None);
+    }
+
+    #[test]
+    fn test_choose_peer_by_voting_power() {
+        // The last peer is not a validator.
+        let powers = [1u64, 2, 7, 0];
+        let candidates: Vec<_> = (0..powers.len() as u64)
+            .map(|i| (NodeId::from_low_u64_be(i), PeerScore::default()))
+            .collect();
+        let voting_powers: HashMap<_, _> = candidates
+            .iter()
+            .zip(powers.iter())
+            .filter(|(_, power)| **power > 0)
+            .map(|((peer, _), power)| (*peer, *power))
+            .collect();
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut counts = vec![0; powers.len()];
+        let draws = 20000;
+        for _ in 0..draws {
+            let peer = choose_peer_by_voting_power(
+                &candidates,
+                &voting_powers,
+                &mut rng,
+            )
+            .unwrap();
+            counts[peer.to_low_u64_be() as usize] += 1;
+        }
+
+        // The peers are probed uniformly, and otherwise chosen by their
+        // shares of the highest voting power.
+        let shares: Vec<_> = powers
+            .iter()
+            .map(|power| (*power as f64 / 7.0).max(MIN_VOTING_POWER_SHARE))
+            .collect();
+        let total: f64 = shares.iter().sum();
+        for (count, share) in counts.iter().zip(shares.iter()) {
+            let expected = PROBE_PROBABILITY / powers.len() as f64
+                + (1.0 - PROBE_PROBABILITY) * share / total;
+            let frequency = *count as f64 / draws as f64;
+            assert!(
+                (frequency - expected).abs() < 0.02,
+                "{:?} {:?}",
+                counts,
+                shares
+            );
+        }
+        // The peer without voting power still gets some requests.
+        assert!(counts[3] > 0);
+
+        // Without any voting power the peers are chosen by their scores.
+        assert!(choose_peer_by_voting_power(
+            &candidates,
+            &HashMap::new(),
+            &mut rng
+        )
+        .is_some());
+    }
+}
@@ -4,11 +4,23 @@
 
 use crate::{
     message::{Message, SetRequestId},
-    pos::protocol::{
-        request_manager::RequestManager, sync_protocol::RpcResponse,
+    pos::{
+        consensus::counters,
+        protocol::{
+            clock::{Clock, SystemClock},
+            request_manager::{
+                circuit_breaker::{BreakerState, CircuitBreaker},
+                is_past_deadline,
+                latency_sketch::{LatencySketch, LatencySummary},
+                peer_score::PeerScore,
+                RequestManager,
+            },
+            sync_protocol::RpcResponseWithPeer,
+        },
     },
     sync::{Error, ErrorKind, ProtocolConfiguration},
 };
+use diem_crypto::HashValue;
 use futures::channel::oneshot;
 use network::{
     node_table::NodeId, ErrorKind as NetworkErrorKind, NetworkContext,
@@ -25,21 +37,38 @@ use std::{
         atomic::{AtomicBool, Ordering as AtomicOrdering},
         Arc,
     },
-    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant},
 };
 
+/// Why an inflight request is taken out of its peer, which decides how the
+/// peer score is updated.
+enum RequestOutcome {
+    Responded,
+    Failed,
+    Discarded,
+}
+
 pub struct RequestHandler {
     protocol_config: ProtocolConfiguration,
     peers: Mutex<HashMap<NodeId, RequestContainer>>,
     requests_queue: Mutex<BinaryHeap<Arc<TimedSyncRequests>>>,
+    clock: Arc<dyn Clock>,
 }
 
 impl RequestHandler {
     pub fn new(protocol_config: &ProtocolConfiguration) -> Self {
+        Self::with_clock(protocol_config, Arc::new(SystemClock))
+    }
+
+    /// Read the time of the timeouts from `clock`.
+    pub fn with_clock(
+        protocol_config: &ProtocolConfiguration, clock: Arc<dyn Clock>,
+    ) -> Self {
         Self {
             protocol_config: protocol_config.clone(),
             peers: Mutex::new(HashMap::new()),
             requests_queue: Default::default(),
+            clock,
         }
     }
 
@@ -58,6 +87,95 @@ impl RequestHandler {
         );
     }
 
+    /// Return the ids of all the peers that requests can be sent to.
+    pub fn peer_ids(&self) -> Vec<NodeId> {
+        self.peers.lock().keys().cloned().collect()
+    }
+
+    /// Return the ids and scores of all the peers that requests can be
+    /// sent to.
+    pub fn peer_scores(&self) -> Vec<(NodeId, PeerScore)> {
+        self.peers
+            .lock()
+            .iter()
+            .map(|(peer_id, container)| (*peer_id, container.score))
+            .collect()
+    }
+
+    /// Return the ids and scores of the peers that requests can be sent to
+    /// and whose circuit breakers are not open.
+    pub fn available_peer_scores(&self) -> Vec<(NodeId, PeerScore)> {
+        self.available_peer_scores_at(self.clock.now())
+    }
+
+    fn available_peer_scores_at(
+        &self, now: Instant,
+    ) -> Vec<(NodeId, PeerScore)> {
+        self.peers
+            .lock()
+            .iter_mut()
+            .filter(|(_, container)| container.breaker.allows(now))
+            .map(|(peer_id, container)| (*peer_id, container.score))
+            .collect()
+    }
+
+    /// Return the states of the circuit breakers of the peers.
+    pub fn breaker_states(&self) -> Vec<(NodeId, BreakerState)> {
+        let now = self.clock.now();
+        self.peers
+            .lock()
+            .iter()
+            .map(|(peer_id, container)| {
+                (*peer_id, container.breaker.state(now))
+            })
+            .collect()
+    }
+
+    /// Return the response latency summaries of the peers that have
+    /// answered any request.
+    pub fn peer_latencies(&self) -> Vec<(NodeId, LatencySummary)> {
+        self.peers
+            .lock()
+            .iter()
+            .filter_map(|(peer_id, container)| {
+                Some((*peer_id, container.latency.summary()?))
+            })
+            .collect()
+    }
+
+    /// Let the inflight or pending request to `peer` with the coalescing
+    /// key `key` also answer `request`, which is identical to it. The
+    /// request must not give up before `deadline`, the one of `request`.
+    ///
+    /// Return whether `request` is taken by such a request.
+    pub fn coalesce(
+        &self, peer: &NodeId, key: &HashValue, request: &mut dyn Request,
+        deadline: Option<Instant>,
+    ) -> bool
+    {
+        let mut peers = self.peers.lock();
+        let container = match peers.get_mut(peer) {
+            Some(container) => container,
+            None => return false,
+        };
+        let inflight = container
+            .inflight_requests
+            .values_mut()
+            .map(|req| &mut req.message);
+        let existing = inflight
+            .chain(container.pending_requests.iter_mut())
+            .find(|msg| {
+                msg.request.coalescing_key().as_ref() == Some(key)
+                    && msg.deadline.map_or(true, |existing| {
+                        deadline.map_or(false, |deadline| existing >= deadline)
+                    })
+            });
+        match existing {
+            Some(msg) => msg.request.absorb(request),
+            None => false,
+        }
+    }
+
     // Match request for given response.
     // Could return the following error:
     // 1. Error return from peer.match_request():
@@ -67,15 +185,82 @@ impl RequestHandler {
     pub fn match_request(
         &self, io: &dyn NetworkContext, peer_id: &NodeId, request_id: u64,
     ) -> Result<RequestMessage, Error> {
+        self.take_request(io, peer_id, request_id, RequestOutcome::Responded)
+    }
+
+    /// Remove an inflight request that is not answered in time, which
+    /// lowers the score of the peer.
+    pub fn cancel_request(
+        &self, io: &dyn NetworkContext, peer_id: &NodeId, request_id: u64,
+    ) -> Result<RequestMessage, Error> {
+        self.take_request(io, peer_id, request_id, RequestOutcome::Failed)
+    }
+
+    /// Remove an inflight request that is no longer needed by its sender.
+    /// The score of the peer is not affected.
+    pub fn discard_request(
+        &self, io: &dyn NetworkContext, peer_id: &NodeId, request_id: u64,
+    ) -> Result<RequestMessage, Error> {
+        self.take_request(io, peer_id, request_id, RequestOutcome::Discarded)
+    }
+
+    /// Remove the inflight requests nobody waits for anymore, see
+    /// `Request::is_canceled`, like `discard_request`. Return the number
+    /// of the requests removed.
+    pub fn discard_canceled_requests(&self, io: &dyn NetworkContext) -> usize {
+        let canceled: Vec<(NodeId, u64)> = self
+            .peers
+            .lock()
+            .iter()
+            .flat_map(|(peer_id, peer)| {
+                peer.inflight_requests
+                    .iter()
+                    .filter(|(_, req)| req.message.request.is_canceled())
+                    .map(move |(request_id, _)| (*peer_id, *request_id))
+            })
+            .collect();
+        canceled
+            .iter()
+            .filter(|(peer_id, request_id)| {
+                self.discard_request(io, peer_id, *request_id).is_ok()
+            })
+            .count()
+    }
+
+    fn take_request(
+        &self, io: &dyn NetworkContext, peer_id: &NodeId, request_id: u64,
+        outcome: RequestOutcome,
+    ) -> Result<RequestMessage, Error>
+    {
+        let now = self.clock.now();
         let mut peers = self.peers.lock();
         let mut requests_queue = self.requests_queue.lock();
         if let Some(peer) = peers.get_mut(peer_id) {
-            peer.match_request(
+            let req = peer.match_request(
                 io,
                 request_id,
                 &mut *requests_queue,
                 &self.protocol_config,
-            )
+                now,
+            )?;
+            match outcome {
+                RequestOutcome::Responded => {
+                    let latency =
+                        now.saturating_duration_since(req.timed_req.sent_time);
+                    peer.score.on_success(latency);
+                    peer.breaker.on_success();
+                    peer.record_latency(latency);
+                }
+                RequestOutcome::Failed => {
+                    peer.score.on_failure();
+                    peer.breaker.on_failure(
+                        &self.protocol_config.pos_rpc_circuit_breaker,
+                        now,
+                    );
+                }
+                RequestOutcome::Discarded => {}
+            }
+            Ok(req.message)
         } else {
             bail!(ErrorKind::UnknownPeer);
         }
@@ -83,10 +268,17 @@ impl RequestHandler {
 
     /// Send request to the specified peer. If peer is `None` or send request
     /// failed, return the request back to caller to handle in advance.
+    ///
+    /// Return the assigned request id if the request is in flight, or `None`
+    /// if it is queued as pending because the peer has too many inflight
+    /// requests. `retry_count` is the number of times the request has been
+    /// resent after timeouts. A pending request is dropped with
+    /// `ErrorKind::DeadlineExceeded` if `deadline` passes before it is sent.
     pub fn send_request(
         &self, io: &dyn NetworkContext, peer: Option<NodeId>,
         mut request: Box<dyn Request>, delay: Option<Duration>,
-    ) -> Result<(), Box<dyn Request>>
+        retry_count: usize, deadline: Option<Instant>,
+    ) -> Result<Option<u64>, Box<dyn Request>>
     {
         let peer = match peer {
             Some(peer) => peer,
@@ -104,10 +296,12 @@ impl RequestHandler {
         let request_id = match peer_info.get_next_request_id() {
             Some(id) => id,
             None => {
-                peer_info.append_pending_request(RequestMessage::new(
-                    request, delay,
-                ));
-                return Ok(());
+                peer_info.append_pending_request(
+                    RequestMessage::new(request, delay)
+                        .with_retry_count(retry_count)
+                        .with_deadline(deadline),
+                );
+                return Ok(None);
             }
         };
 
@@ -125,7 +319,9 @@ impl RequestHandler {
             false
         };
 
-        let msg = RequestMessage::new(request, delay);
+        let msg = RequestMessage::new(request, delay)
+            .with_retry_count(retry_count)
+            .with_deadline(deadline);
 
         let timed_req = Arc::new(TimedSyncRequests::from_request(
             peer,
@@ -133,17 +329,18 @@ impl RequestHandler {
             &msg,
             &self.protocol_config,
             is_send_error,
+            self.clock.now(),
         ));
         peer_info.append_inflight_request(request_id, msg, timed_req.clone());
         requests_queue.push(timed_req);
 
-        Ok(())
+        Ok(Some(request_id))
     }
 
     fn get_timeout_sync_requests(&self) -> Vec<Arc<TimedSyncRequests>> {
         let mut requests = self.requests_queue.lock();
         let mut timeout_requests = Vec::new();
-        let now = Instant::now();
+        let now = self.clock.now();
         loop {
             if requests.is_empty() {
                 break;
@@ -171,14 +368,16 @@ impl RequestHandler {
         let mut peers_to_disconnect = HashSet::new();
         for sync_req in self.get_timeout_sync_requests() {
             if let Ok(req) =
-                self.match_request(io, &sync_req.peer_id, sync_req.request_id)
+                self.cancel_request(io, &sync_req.peer_id, sync_req.request_id)
             {
                 let peer_id = &sync_req.peer_id;
                 if let Some(request_container) =
                     self.peers.lock().get_mut(peer_id)
                 {
-                    if request_container
-                        .on_timeout_should_disconnect(&self.protocol_config)
+                    if request_container.on_timeout_should_disconnect(
+                        &self.protocol_config,
+                        self.clock.now(),
+                    )
                     {
                         peers_to_disconnect.insert(*peer_id);
                     }
@@ -206,12 +405,28 @@ impl RequestHandler {
         timeout_requests
     }
 
-    /// Return unfinished_requests
-    pub fn remove_peer(&self, peer_id: &NodeId) -> Option<Vec<RequestMessage>> {
+    /// The number of the requests in flight or queued behind them, over
+    /// all the peers.
+    pub fn num_requests(&self) -> usize {
         self.peers
             .lock()
-            .remove(peer_id)
-            .map(|mut p| p.get_unfinished_requests())
+            .values()
+            .map(|container| {
+                container.inflight_requests.len()
+                    + container.pending_requests.len()
+            })
+            .sum()
+    }
+
+    /// Return unfinished_requests
+    ///
+    /// The latency of the peer is dropped with it, so the summaries and
+    /// metrics only cover the connected peers.
+    pub fn remove_peer(&self, peer_id: &NodeId) -> Option<Vec<RequestMessage>> {
+        self.peers.lock().remove(peer_id).map(|mut p| {
+            p.remove_latency_metrics();
+            p.get_unfinished_requests()
+        })
     }
 }
 
@@ -222,17 +437,47 @@ struct RequestContainer {
     pub next_request_id: u64,
     pub max_inflight_request_count: u64,
     pub pending_requests: VecDeque<RequestMessage>,
-    pub timeout_statistics: VecDeque<u64>,
+    pub timeout_statistics: VecDeque<Instant>,
+    pub score: PeerScore,
+    pub latency: LatencySketch,
+    pub breaker: CircuitBreaker,
 }
 
+/// The quantiles exported to `RPC_PEER_LATENCY_MS`.
+const LATENCY_QUANTILE_LABELS: [&str; 2] = ["p50", "p95"];
+
 impl RequestContainer {
+    fn record_latency(&mut self, latency: Duration) {
+        self.latency.record(latency);
+        if let Some(summary) = self.latency.summary() {
+            let peer = self.peer_id.to_string();
+            for (label, value) in LATENCY_QUANTILE_LABELS
+                .iter()
+                .zip(&[summary.p50, summary.p95])
+            {
+                counters::RPC_PEER_LATENCY_MS
+                    .with_label_values(&[&peer, label])
+                    .set(value.as_millis() as i64);
+            }
+        }
+    }
+
+    fn remove_latency_metrics(&self) {
+        if self.latency.count() == 0 {
+            return;
+        }
+        let peer = self.peer_id.to_string();
+        for label in &LATENCY_QUANTILE_LABELS {
+            let _ = counters::RPC_PEER_LATENCY_MS
+                .remove_label_values(&[&peer, label]);
+        }
+    }
+
     pub fn on_timeout_should_disconnect(
-        &mut self, config: &ProtocolConfiguration,
+        &mut self, config: &ProtocolConfiguration, now: Instant,
     ) -> bool {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        let observing_period =
+            Duration::from_secs(config.timeout_observing_period_s);
         if self.timeout_statistics.is_empty() {
             self.timeout_statistics.push_back(now);
             return false;
@@ -241,7 +486,7 @@ impl RequestContainer {
         self.timeout_statistics.push_back(now);
         loop {
             let old_time = *self.timeout_statistics.front().unwrap();
-            if now - old_time <= config.timeout_observing_period_s {
+            if now.saturating_duration_since(old_time) <= observing_period {
                 break;
             }
             self.timeout_statistics.pop_front();
@@ -294,6 +539,19 @@ impl RequestContainer {
         self.pending_requests.pop_front()
     }
 
+    /// Fail the pending requests whose deadlines have passed with
+    /// `ErrorKind::DeadlineExceeded`, instead of sending them.
+    fn drop_expired_pending_requests(&mut self, now: Instant) {
+        let (expired, pending): (VecDeque<_>, VecDeque<_>) =
+            mem::take(&mut self.pending_requests)
+                .into_iter()
+                .partition(|msg| is_past_deadline(msg.deadline, now));
+        self.pending_requests = pending;
+        for mut msg in expired {
+            msg.request.notify_error(ErrorKind::DeadlineExceeded.into());
+        }
+    }
+
     pub fn remove_inflight_request(
         &mut self, request_id: u64,
     ) -> Option<SynchronizationPeerRequest> {
@@ -319,8 +577,8 @@ impl RequestContainer {
     pub fn match_request(
         &mut self, io: &dyn NetworkContext, request_id: u64,
         requests_queue: &mut BinaryHeap<Arc<TimedSyncRequests>>,
-        protocol_config: &ProtocolConfiguration,
-    ) -> Result<RequestMessage, Error>
+        protocol_config: &ProtocolConfiguration, now: Instant,
+    ) -> Result<SynchronizationPeerRequest, Error>
     {
         let removed_req = self.remove_inflight_request(request_id);
         if let Some(removed_req) = removed_req {
@@ -328,9 +586,13 @@ impl RequestContainer {
                 .timed_req
                 .removed
                 .store(true, AtomicOrdering::Relaxed);
+            self.drop_expired_pending_requests(now);
             while self.has_pending_requests() {
                 if let Some(new_request_id) = self.get_next_request_id() {
                     let mut pending_msg = self.pop_pending_request().unwrap();
+                    if pending_msg.request.is_canceled() {
+                        continue;
+                    }
                     pending_msg.set_request_id(new_request_id);
                     let send_res = pending_msg.request.send(io, &self.peer_id);
                     let is_send_error = if let Err(e) = send_res {
@@ -351,6 +613,7 @@ impl RequestContainer {
                         &pending_msg,
                         protocol_config,
                         is_send_error,
+                        now,
                     ));
                     self.append_inflight_request(
                         new_request_id,
@@ -362,7 +625,7 @@ impl RequestContainer {
                     break;
                 }
             }
-            Ok(removed_req.message)
+            Ok(removed_req)
         } else {
             bail!(ErrorKind::RequestNotFound)
         }
@@ -398,27 +661,69 @@ pub trait AsAny {
 
 /// Trait of request message
 pub trait Request: Send + Debug + AsAny + Message + SetRequestId {
-    /// Request timeout for resend purpose.
-    fn timeout(&self, conf: &ProtocolConfiguration) -> Duration;
+    /// Request timeout for resend purpose, by default the one of the
+    /// message type in `pos_rpc_timeouts`.
+    fn timeout(&self, conf: &ProtocolConfiguration) -> Duration {
+        conf.pos_rpc_timeouts.get(self.msg_name())
+    }
 
     /// Notify the handler when error happens for the request.
     fn notify_error(&mut self, error: Error);
 
     /// This is for RPC request. Set the notification handle for the request.
     fn set_response_notification(
-        &mut self, res_tx: oneshot::Sender<Result<Box<dyn RpcResponse>, Error>>,
+        &mut self, res_tx: oneshot::Sender<Result<RpcResponseWithPeer, Error>>,
     );
+
+    /// Return a copy of the request (without the response notification) to
+    /// be sent again after the original one failed.
+    ///
+    /// If resend is not supported, return `None`.
+    fn resend(&self) -> Option<Box<dyn Request>> { None }
+
+    /// The hash of the content of the request, if an identical request to
+    /// the same peer can be answered by this one instead of being sent.
+    fn coalescing_key(&self) -> Option<HashValue> { None }
+
+    /// Take the response notification of `other`, an identical request, so
+    /// it is notified with the result of this request.
+    ///
+    /// Return `false` if `other` is not taken.
+    fn absorb(&mut self, _other: &mut dyn Request) -> bool { false }
+
+    /// Whether nobody waits for the result of the request anymore, e.g.
+    /// after its `RpcHandle` is cancelled, so it is neither sent nor resent.
+    fn is_canceled(&self) -> bool { false }
 }
 
 #[derive(Debug)]
 pub struct RequestMessage {
     pub request: Box<dyn Request>,
     pub delay: Option<Duration>,
+    /// The number of times the request has been resent after timeouts.
+    pub retry_count: usize,
+    /// The request is not sent or resent after this time.
+    pub deadline: Option<Instant>,
 }
 
 impl RequestMessage {
     pub fn new(request: Box<dyn Request>, delay: Option<Duration>) -> Self {
-        RequestMessage { request, delay }
+        RequestMessage {
+            request,
+            delay,
+            retry_count: 0,
+            deadline: None,
+        }
+    }
+
+    pub fn with_retry_count(mut self, retry_count: usize) -> Self {
+        self.retry_count = retry_count;
+        self
+    }
+
+    pub fn with_deadline(mut self, deadline: Option<Instant>) -> Self {
+        self.deadline = deadline;
+        self
     }
 
     pub fn set_request_id(&mut self, request_id: u64) {
@@ -444,23 +749,26 @@ pub struct TimedSyncRequests {
     pub timeout_time: Instant,
     pub request_id: u64,
     pub removed: AtomicBool,
+    /// The time the request is sent, for measuring the response latency.
+    pub sent_time: Instant,
 }
 
 impl TimedSyncRequests {
     pub fn new(
-        peer_id: NodeId, timeout: Duration, request_id: u64,
+        peer_id: NodeId, timeout: Duration, request_id: u64, now: Instant,
     ) -> TimedSyncRequests {
         TimedSyncRequests {
             peer_id,
-            timeout_time: Instant::now() + timeout,
+            timeout_time: now + timeout,
             request_id,
             removed: AtomicBool::new(false),
+            sent_time: now,
         }
     }
 
     pub fn from_request(
         peer_id: NodeId, request_id: u64, msg: &RequestMessage,
-        conf: &ProtocolConfiguration, is_send_error: bool,
+        conf: &ProtocolConfiguration, is_send_error: bool, now: Instant,
     ) -> TimedSyncRequests
     {
         let timeout = if is_send_error {
@@ -468,7 +776,7 @@ impl TimedSyncRequests {
         } else {
             msg.request.timeout(conf)
         };
-        TimedSyncRequests::new(peer_id, timeout, request_id)
+        TimedSyncRequests::new(peer_id, timeout, request_id, now)
     }
 }
 
@@ -491,3 +799,111 @@ impl PartialEq for TimedSyncRequests {
         self.timeout_time == other.timeout_time
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::RequestHandler;
+    use crate::{
+        pos::{
+            consensus::counters,
+            protocol::request_manager::circuit_breaker::{
+                BreakerState, CircuitBreakerConfig,
+            },
+        },
+        sync::ProtocolConfiguration,
+    };
+    use network::node_table::NodeId;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_peer_latencies() {
+        let handler = RequestHandler::new(&ProtocolConfiguration::default());
+        let slow = NodeId::from_low_u64_be(1);
+        let fast = NodeId::from_low_u64_be(2);
+        handler.add_peer(slow);
+        handler.add_peer(fast);
+        assert!(handler.peer_latencies().is_empty());
+
+        for ms in 1..=20 {
+            let mut peers = handler.peers.lock();
+            peers
+                .get_mut(&slow)
+                .unwrap()
+                .record_latency(Duration::from_millis(ms * 100));
+            peers
+                .get_mut(&fast)
+                .unwrap()
+                .record_latency(Duration::from_millis(ms));
+        }
+        let latencies = handler.peer_latencies();
+        assert_eq!(latencies.len(), 2);
+        let summary = |peer| {
+            latencies
+                .iter()
+                .find(|(peer_id, _)| *peer_id == peer)
+                .unwrap()
+                .1
+        };
+        assert_eq!(summary(slow).count, 20);
+        assert!(summary(slow).p50 > summary(fast).p95);
+        assert!(summary(slow).p95 >= summary(slow).p50);
+        let p95 = counters::RPC_PEER_LATENCY_MS
+            .with_label_values(&[&slow.to_string(), "p95"])
+            .get();
+        assert_eq!(p95, summary(slow).p95.as_millis() as i64);
+
+        // The disconnected peer is pruned.
+        handler.remove_peer(&slow);
+        let latencies = handler.peer_latencies();
+        assert_eq!(latencies.len(), 1);
+        assert_eq!(latencies[0].0, fast);
+    }
+
+    #[test]
+    fn test_peer_skipped_while_breaker_open() {
+        let cooldown = Duration::from_secs(30);
+        let config = CircuitBreakerConfig {
+            max_failures: 2,
+            cooldown,
+        };
+        let handler = RequestHandler::new(&ProtocolConfiguration {
+            pos_rpc_circuit_breaker: config,
+            ..Default::default()
+        });
+        let failing = NodeId::from_low_u64_be(1);
+        let healthy = NodeId::from_low_u64_be(2);
+        handler.add_peer(failing);
+        handler.add_peer(healthy);
+        let now = Instant::now();
+        let available = |now| {
+            let mut peers: Vec<_> = handler
+                .available_peer_scores_at(now)
+                .into_iter()
+                .map(|(peer, _)| peer)
+                .collect();
+            peers.sort();
+            peers
+        };
+
+        for _ in 0..2 {
+            handler
+                .peers
+                .lock()
+                .get_mut(&failing)
+                .unwrap()
+                .breaker
+                .on_failure(&config, now);
+        }
+        assert_eq!(available(now), vec![healthy]);
+        assert!(handler
+            .breaker_states()
+            .contains(&(failing, BreakerState::Open)));
+        assert_eq!(available(now + cooldown / 2), vec![healthy]);
+
+        // Probed again once the breaker is half open.
+        assert_eq!(available(now + cooldown), vec![failing, healthy]);
+        assert!(handler
+            .breaker_states()
+            .contains(&(failing, BreakerState::HalfOpen)));
+    }
+}
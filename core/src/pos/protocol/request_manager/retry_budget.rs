@@ -0,0 +1,121 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+//! The retries shared by the RPC requests of a sync session.
+//!
+//! Each block retrieval tries the peers one by one until one of them
+//! answers, so when the peers fail at once, e.g. during a bad sync, the
+//! retries of all the requests add up to a storm. The requests of a session,
+//! e.g. a `BlockRetriever`, share a token bucket instead: each retry takes a
+//! token, `capacity` tokens at most are kept, and one is added back every
+//! `refill_interval`. Once the bucket is empty, a failed request fails at
+//! once rather than trying another peer.
+
+use std::time::{Duration, Instant};
+
+use crate::pos::consensus::counters;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RetryBudgetConfig {
+    /// The most retries taken at once. 0 disables the budget, and each
+    /// request retries on its own.
+    pub capacity: u32,
+    /// How long it takes to add a retry back to the budget. 0 means none is
+    /// added back.
+    pub refill_interval: Duration,
+}
+
+impl RetryBudgetConfig {
+    pub fn is_enabled(&self) -> bool { self.capacity > 0 }
+}
+
+/// The token bucket of a session, see the module doc.
+#[derive(Debug)]
+pub struct RetryBudget {
+    config: RetryBudgetConfig,
+    tokens: u32,
+    /// The time from which the next token is refilled.
+    refilled_at: Instant,
+}
+
+impl RetryBudget {
+    pub fn new(config: RetryBudgetConfig) -> Self {
+        Self {
+            config,
+            tokens: config.capacity,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// The retries left at `now`.
+    pub fn remaining(&mut self, now: Instant) -> u32 {
+        self.refill(now);
+        self.tokens
+    }
+
+    /// Take a retry at `now`, and return whether the request may retry.
+    pub fn try_acquire(&mut self, now: Instant) -> bool {
+        if !self.config.is_enabled() {
+            return true;
+        }
+        self.refill(now);
+        let granted = self.tokens > 0;
+        if granted {
+            self.tokens -= 1;
+        }
+        counters::RETRY_BUDGET_RETRIES
+            .with_label_values(&[if granted { "granted" } else { "exhausted" }])
+            .inc();
+        counters::RETRY_BUDGET_REMAINING.set(self.tokens as i64);
+        granted
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let interval = self.config.refill_interval;
+        if self.tokens >= self.config.capacity
+            || interval == Duration::from_secs(0)
+        {
+            self.refilled_at = now;
+            return;
+        }
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        let refills = (elapsed.as_nanos() / interval.as_nanos()) as u64;
+        let missing = (self.config.capacity - self.tokens) as u64;
+        if refills >= missing {
+            self.tokens = self.config.capacity;
+            self.refilled_at = now;
+        } else {
+            self.tokens += refills as u32;
+            self.refilled_at += interval * refills as u32;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RetryBudget, RetryBudgetConfig};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_retries_bounded_and_refilled() {
+        let refill_interval = Duration::from_secs(1);
+        let mut budget = RetryBudget::new(RetryBudgetConfig {
+            capacity: 3,
+            refill_interval,
+        });
+        let now = Instant::now();
+        assert!((0..3).all(|_| budget.try_acquire(now)));
+        assert!(!budget.try_acquire(now));
+        assert_eq!(budget.remaining(now), 0);
+
+        // One retry is added back each interval, up to the capacity.
+        assert!(budget.try_acquire(now + refill_interval));
+        assert!(!budget.try_acquire(now + refill_interval));
+        assert_eq!(budget.remaining(now + refill_interval * 10), 3);
+
+        // Unbounded if disabled.
+        let mut budget = RetryBudget::new(RetryBudgetConfig::default());
+        assert!((0..100).all(|_| budget.try_acquire(now)));
+    }
+}
@@ -0,0 +1,128 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+//! The timeouts of the PoS RPC requests of each message type.
+//!
+//! A block retrieval of a hundred blocks takes much longer to answer than an
+//! epoch retrieval, so a single timeout is either too aggressive for the
+//! former or too lax for the latter. The timeout of a request is looked up
+//! by its `Message::msg_name`, from the defaults of `DEFAULT_TIMEOUTS_MS` and
+//! the overrides of the operator, and the other types fall back to
+//! `default`.
+
+use std::{collections::HashMap, time::Duration};
+
+/// The default timeouts of the message types, in milliseconds.
+const DEFAULT_TIMEOUTS_MS: &[(&str, u64)] = &[
+    ("BlockRetrievalMessage", 20_000),
+    ("EpochRetrievalMessage", 5_000),
+];
+
+#[derive(Clone, Debug)]
+pub struct RpcTimeouts {
+    /// The timeout of the message types without their own.
+    default: Duration,
+    by_msg_name: HashMap<String, Duration>,
+}
+
+impl RpcTimeouts {
+    /// The default timeouts of the known types, and `default` for the
+    /// others.
+    pub fn new(default: Duration) -> Self {
+        Self {
+            default,
+            by_msg_name: DEFAULT_TIMEOUTS_MS
+                .iter()
+                .map(|(name, ms)| {
+                    (name.to_string(), Duration::from_millis(*ms))
+                })
+                .collect(),
+        }
+    }
+
+    /// Override the timeouts with `overrides`, a comma separated list of
+    /// `<msg_name>:<milliseconds>`, e.g. `BlockRetrievalMessage:60000`.
+    pub fn with_overrides(mut self, overrides: &str) -> Result<Self, String> {
+        for entry in overrides.split(',').map(str::trim) {
+            if entry.is_empty() {
+                continue;
+            }
+            let (name, ms) = match entry.split_once(':') {
+                Some((name, ms)) => (name.trim(), ms.trim()),
+                None => return Err(format!("Invalid rpc timeout {:?}", entry)),
+            };
+            let ms = ms.parse::<u64>().map_err(|e| {
+                format!("Invalid rpc timeout {:?}: {}", entry, e)
+            })?;
+            self.by_msg_name
+                .insert(name.to_string(), Duration::from_millis(ms));
+        }
+        Ok(self)
+    }
+
+    /// The timeout of the requests of `msg_name`.
+    pub fn get(&self, msg_name: &str) -> Duration {
+        self.by_msg_name
+            .get(msg_name)
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+impl Default for RpcTimeouts {
+    fn default() -> Self { Self::new(Duration::from_secs(10)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RpcTimeouts;
+    use crate::{
+        message::Message,
+        pos::protocol::{
+            message::block_retrieval::BlockRetrievalRpcRequest,
+            request_manager::Request,
+        },
+        sync::ProtocolConfiguration,
+    };
+    use consensus_types::{
+        block_retrieval::BlockRetrievalRequest,
+        epoch_retrieval::EpochRetrievalRequest,
+    };
+    use diem_crypto::HashValue;
+    use std::time::Duration;
+
+    #[test]
+    fn test_timeout_by_request_type() {
+        let block_retrieval = BlockRetrievalRpcRequest {
+            request_id: 0,
+            request: BlockRetrievalRequest::new(HashValue::zero(), 100),
+            is_empty: false,
+            response_tx: None,
+            coalesced_tx: Vec::new(),
+            timeout: Duration::from_secs(0),
+        };
+        let epoch_retrieval = EpochRetrievalRequest {
+            start_epoch: 1,
+            end_epoch: 2,
+        };
+        let conf = ProtocolConfiguration::default();
+        assert!(
+            block_retrieval.timeout(&conf)
+                > conf.pos_rpc_timeouts.get(epoch_retrieval.msg_name())
+        );
+        let timeouts = RpcTimeouts::default();
+        assert_eq!(timeouts.get("Unknown"), Duration::from_secs(10));
+
+        let timeouts = RpcTimeouts::default()
+            .with_overrides("EpochRetrievalMessage: 1000, Unknown:2000")
+            .unwrap();
+        assert_eq!(
+            timeouts.get(epoch_retrieval.msg_name()),
+            Duration::from_secs(1)
+        );
+        assert_eq!(timeouts.get("Unknown"), Duration::from_secs(2));
+        assert!(RpcTimeouts::default().with_overrides("Unknown").is_err());
+        assert!(RpcTimeouts::default().with_overrides("Unknown:x").is_err());
+    }
+}
@@ -0,0 +1,75 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+//! The dedup of the proposals and votes gossiped by several peers.
+//!
+//! The same proposal or vote is usually received once from each peer
+//! relaying it. The content hashes of the last `pos_seen_msgs_cache_size`
+//! proposals and votes delivered to consensus are kept regardless of the
+//! peers they are from, and a copy received again is not delivered. The
+//! equivocating proposals are detected before, see `ProposalTracker`, and a
+//! conflicting proposal has another hash, so it is never taken as a copy.
+
+use diem_crypto::HashValue;
+use lru_time_cache::LruCache;
+use parking_lot::Mutex;
+
+use crate::pos::consensus::network::ConsensusMsg;
+
+/// The recently seen proposals and votes, see the module doc.
+pub struct SeenMsgs {
+    /// None if the dedup is disabled.
+    hashes: Option<Mutex<LruCache<HashValue, ()>>>,
+}
+
+impl SeenMsgs {
+    /// Keep the hashes of the last `capacity` messages, 0 disables the
+    /// dedup.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            hashes: match capacity {
+                0 => None,
+                n => Some(Mutex::new(LruCache::with_capacity(n))),
+            },
+        }
+    }
+
+    /// Record `msg`, and return whether it is not seen before. The messages
+    /// other than the proposals and votes are always taken as new.
+    pub fn check(&self, msg: &ConsensusMsg) -> bool {
+        let hashes = match &self.hashes {
+            Some(hashes) => hashes,
+            None => return true,
+        };
+        if !matches!(
+            msg,
+            ConsensusMsg::ProposalMsg(_) | ConsensusMsg::VoteMsg(_)
+        ) {
+            return true;
+        }
+        let encoded = bcs::to_bytes(msg).expect("Failed to serialize.");
+        let hash = HashValue::sha3_256_of(&encoded);
+        hashes.lock().insert(hash, ()).is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SeenMsgs;
+    use crate::pos::consensus::network::ConsensusMsg;
+    use consensus_types::epoch_retrieval::EpochRetrievalRequest;
+
+    #[test]
+    fn test_only_proposals_and_votes_deduped() {
+        let seen = SeenMsgs::new(2);
+        let request = ConsensusMsg::EpochRetrievalRequest(Box::new(
+            EpochRetrievalRequest {
+                start_epoch: 1,
+                end_epoch: 2,
+            },
+        ));
+        assert!(seen.check(&request));
+        assert!(seen.check(&request));
+    }
+}
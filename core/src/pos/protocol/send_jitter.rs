@@ -0,0 +1,130 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+//! Random delays of the consensus messages sent to many peers at once.
+//!
+//! The validators vote at about the same time, so a receiver gets the votes
+//! of all of them in a spike. Delaying the send to each recipient by a
+//! random jitter spreads them over a few milliseconds. The proposals and
+//! votes are on the critical path of a round, so they have their own bound,
+//! which is usually zero or much smaller than the one of the other messages.
+
+use std::time::Duration;
+
+use network::node_table::NodeId;
+use rand::Rng;
+
+use crate::pos::consensus::network::ConsensusMsg;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SendJitterConfig {
+    /// The longest delay of the proposals and votes.
+    pub max_critical_jitter: Duration,
+    /// The longest delay of the other messages, e.g. the sync infos.
+    pub max_jitter: Duration,
+}
+
+impl SendJitterConfig {
+    /// The longest delay of the sends of `msg`.
+    pub fn max_jitter_for(&self, msg: &ConsensusMsg) -> Duration {
+        match msg {
+            ConsensusMsg::ProposalMsg(_)
+            | ConsensusMsg::VoteMsg(_)
+            | ConsensusMsg::CommitVote(_) => self.max_critical_jitter,
+            _ => self.max_jitter,
+        }
+    }
+
+    /// Whether the sends of `msg` are delayed at all.
+    pub fn is_enabled_for(&self, msg: &ConsensusMsg) -> bool {
+        self.max_jitter_for(msg) > Duration::from_secs(0)
+    }
+
+    /// The delay of the send of `msg` to each of `peer_ids`, uniformly
+    /// distributed in the window of `max_jitter_for(msg)`.
+    pub fn schedule<R: Rng>(
+        &self, peer_ids: &[NodeId], msg: &ConsensusMsg, rng: &mut R,
+    ) -> Vec<(NodeId, Duration)> {
+        let max_micros = self.max_jitter_for(msg).as_micros() as u64;
+        peer_ids
+            .iter()
+            .map(|peer_id| {
+                let jitter = rng.gen_range(0, max_micros + 1);
+                (*peer_id, Duration::from_micros(jitter))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SendJitterConfig;
+    use crate::pos::consensus::network::ConsensusMsg;
+    use consensus_types::{
+        block_retrieval::BlockRetrievalRequest, quorum_cert::QuorumCert,
+        sync_info::SyncInfo, vote::Vote, vote_data::VoteData,
+        vote_msg::VoteMsg,
+    };
+    use diem_crypto::HashValue;
+    use diem_types::{
+        block_info::BlockInfo, ledger_info::LedgerInfo,
+        validator_signer::ValidatorSigner,
+    };
+    use network::node_table::NodeId;
+    use rand::thread_rng;
+    use std::time::Duration;
+
+    #[test]
+    fn test_sends_scheduled_in_window() {
+        let config = SendJitterConfig {
+            max_critical_jitter: Duration::from_millis(1),
+            max_jitter: Duration::from_millis(20),
+        };
+        let signer = ValidatorSigner::from_int(1);
+        let ledger_info =
+            LedgerInfo::new(BlockInfo::empty(), HashValue::zero());
+        let qc = QuorumCert::certificate_for_genesis_from_ledger_info(
+            &ledger_info,
+            HashValue::zero(),
+        );
+        let sync_info = SyncInfo::new(qc.clone(), qc, None);
+        let vote = ConsensusMsg::VoteMsg(Box::new(VoteMsg::new(
+            Vote::new(
+                VoteData::new(BlockInfo::empty(), BlockInfo::empty()),
+                signer.author(),
+                ledger_info,
+                &signer,
+            ),
+            sync_info.clone(),
+        )));
+        let sync_info = ConsensusMsg::SyncInfo(Box::new(sync_info));
+        let peer_ids: Vec<_> = (0..1000).map(NodeId::from_low_u64_be).collect();
+
+        for (msg, window) in &[
+            (&vote, config.max_critical_jitter),
+            (&sync_info, config.max_jitter),
+        ] {
+            let schedule = config.schedule(&peer_ids, msg, &mut thread_rng());
+            assert_eq!(schedule.len(), peer_ids.len());
+            assert!(schedule.iter().all(|(_, delay)| delay <= window));
+            // The sends are spread over the window instead of all at once.
+            let latest = schedule.iter().map(|(_, delay)| *delay).max();
+            assert!(latest.unwrap() > *window / 2);
+        }
+    }
+
+    #[test]
+    fn test_disabled_by_default() {
+        let config = SendJitterConfig::default();
+        let msg = ConsensusMsg::BlockRetrievalRequest(Box::new(
+            BlockRetrievalRequest::new(HashValue::zero(), 1),
+        ));
+        assert!(!config.is_enabled_for(&msg));
+        let peer_ids = vec![NodeId::from_low_u64_be(1)];
+        assert_eq!(
+            config.schedule(&peer_ids, &msg, &mut thread_rng()),
+            vec![(peer_ids[0], Duration::from_secs(0))]
+        );
+    }
+}
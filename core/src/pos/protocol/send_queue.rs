@@ -0,0 +1,292 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+//! The ordered queues of the messages sent to each PoS peer.
+//!
+//! The messages to a peer are handed to the network strictly in the order
+//! they are queued, even if several tasks send to the peer at once, so e.g.
+//! a `SyncInfo` is not overtaken by the proposal depending on it. The first
+//! sender to find the queue idle sends the queued messages in order until
+//! the queue is empty, and the others only queue their messages.
+//!
+//! The network keeps the order of the messages of one priority, while the
+//! high priority messages are still sent before the others. A full queue
+//! sheds the messages that are not high priority, and does not block the
+//! high priority ones.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
+
+use diem_logger::prelude::diem_debug;
+use network::{
+    node_table::NodeId, service::ProtocolVersion, Error, NetworkContext,
+    SendCompletion,
+};
+use parking_lot::Mutex;
+use priority_send_queue::SendQueuePriority;
+
+use crate::pos::{consensus::counters, protocol::log_context::SendLogContext};
+
+/// An encoded message waiting in the queue of a peer.
+pub struct QueuedSend {
+    pub log_context: SendLogContext,
+    pub payload: Vec<u8>,
+    pub min_protocol_version: ProtocolVersion,
+    pub version_valid_till: ProtocolVersion,
+    pub priority: SendQueuePriority,
+    /// Notified when the message is written to the socket of the peer.
+    pub completion: Option<SendCompletion>,
+}
+
+impl QueuedSend {
+    fn send(self, io: &dyn NetworkContext, peer: &NodeId) -> Result<(), Error> {
+        match self.completion {
+            Some(completion) => io.send_with_completion(
+                peer,
+                self.payload,
+                self.min_protocol_version,
+                self.version_valid_till,
+                self.priority,
+                completion,
+            ),
+            None => io.send(
+                peer,
+                self.payload,
+                self.min_protocol_version,
+                self.version_valid_till,
+                self.priority,
+            ),
+        }
+    }
+}
+
+/// What happens to a message given to `PeerSendQueues::send`.
+#[derive(Debug)]
+pub enum SendOutcome {
+    /// The message is sent with the result, after the messages queued
+    /// before it.
+    Sent(Result<(), Error>),
+    /// Another sender is sending the queue, and sends the message in its
+    /// turn. A failure is only logged.
+    Queued,
+    /// The queue is full.
+    Shed,
+}
+
+#[derive(Default)]
+struct PeerQueue {
+    /// The messages with their sequence numbers.
+    queue: VecDeque<(u64, QueuedSend)>,
+    next_seq: u64,
+    /// Whether a sender is sending the queue.
+    sending: bool,
+}
+
+pub struct PeerSendQueues {
+    capacity: usize,
+    peers: Mutex<HashMap<NodeId, Arc<Mutex<PeerQueue>>>>,
+}
+
+impl PeerSendQueues {
+    /// The queues holding at most `capacity` messages for each peer, 0
+    /// means no limit.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: if capacity == 0 { usize::MAX } else { capacity },
+            peers: Default::default(),
+        }
+    }
+
+    /// Queue `msg` for `peer`, and send the queue within `io` if no other
+    /// sender is sending it.
+    pub fn send(
+        &self, io: &dyn NetworkContext, peer: &NodeId, msg: QueuedSend,
+    ) -> SendOutcome {
+        self.send_with(peer, msg, |queued| queued.send(io, peer))
+    }
+
+    fn send_with(
+        &self, peer: &NodeId, msg: QueuedSend,
+        mut send: impl FnMut(QueuedSend) -> Result<(), Error>,
+    ) -> SendOutcome
+    {
+        let peer_queue = self.peers.lock().entry(*peer).or_default().clone();
+        let mut state = peer_queue.lock();
+        if state.queue.len() >= self.capacity {
+            // Make room for a high priority message with the oldest message
+            // that is not.
+            let oldest_sheddable = match msg.priority {
+                SendQueuePriority::High => {
+                    state.queue.iter().position(|(_, queued)| {
+                        queued.priority != SendQueuePriority::High
+                    })
+                }
+                _ => None,
+            };
+            let shed_name = match oldest_sheddable {
+                Some(i) => {
+                    let shed = state.queue.remove(i).expect("in the queue").1;
+                    shed.log_context.variant
+                }
+                None => msg.log_context.variant,
+            };
+            counters::NETWORK_MSGS_SHED
+                .with_label_values(&[shed_name])
+                .inc();
+            if oldest_sheddable.is_none() {
+                return SendOutcome::Shed;
+            }
+        }
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.queue.push_back((seq, msg));
+        if state.sending {
+            return SendOutcome::Queued;
+        }
+        state.sending = true;
+
+        let mut result = None;
+        while let Some((queued_seq, queued)) = state.queue.pop_front() {
+            // The queue is not locked while sending, so the other senders
+            // can queue their messages.
+            drop(state);
+            let log_context = queued.log_context;
+            let res = send(queued);
+            if queued_seq == seq {
+                result = Some(res);
+            } else if let Err(e) = res {
+                diem_debug!(
+                    "Error sending queued message, {}: {:?}",
+                    log_context.to_peer(peer),
+                    e
+                );
+            }
+            state = peer_queue.lock();
+        }
+        state.sending = false;
+        SendOutcome::Sent(result.expect("the message is sent in its turn"))
+    }
+
+    /// The number of the messages queued for `peer`.
+    pub fn queued(&self, peer: &NodeId) -> usize {
+        self.peers
+            .lock()
+            .get(peer)
+            .map_or(0, |peer_queue| peer_queue.lock().queue.len())
+    }
+
+    /// Forget a disconnected peer. The messages still queued are sent by
+    /// the sender sending the queue.
+    pub fn remove_peer(&self, peer: &NodeId) { self.peers.lock().remove(peer); }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PeerSendQueues, QueuedSend, SendOutcome};
+    use crate::pos::protocol::{
+        log_context::SendLogContext, test_utils::MockNetworkContext,
+    };
+    use network::{node_table::NodeId, service::ProtocolVersion};
+    use priority_send_queue::SendQueuePriority;
+    use std::{sync::Arc, thread};
+
+    fn queued(payload: Vec<u8>, priority: SendQueuePriority) -> QueuedSend {
+        QueuedSend {
+            log_context: SendLogContext {
+                variant: "Test",
+                epoch: None,
+                round: None,
+            },
+            payload,
+            min_protocol_version: ProtocolVersion(1),
+            version_valid_till: ProtocolVersion(1),
+            priority,
+            completion: None,
+        }
+    }
+
+    #[test]
+    fn test_order_kept_for_concurrent_senders() {
+        // Room for all the messages sent, so none is shed however the
+        // senders interleave.
+        let queues = Arc::new(PeerSendQueues::new(200));
+        let io = Arc::new(MockNetworkContext::default());
+        let peer = NodeId::from_low_u64_be(1);
+        let senders: Vec<_> = (0..2u8)
+            .map(|sender| {
+                let queues = queues.clone();
+                let io = io.clone();
+                thread::spawn(move || {
+                    for i in 0..100u8 {
+                        let msg =
+                            queued(vec![sender, i], SendQueuePriority::High);
+                        assert!(!matches!(
+                            queues.send(&*io, &peer, msg),
+                            SendOutcome::Shed
+                        ));
+                    }
+                })
+            })
+            .collect();
+        for sender in senders {
+            sender.join().unwrap();
+        }
+
+        let payloads = io.payloads.lock();
+        assert_eq!(payloads.len(), 200);
+        for sender in 0..2u8 {
+            let sent: Vec<u8> = payloads
+                .iter()
+                .filter(|payload| payload[0] == sender)
+                .map(|payload| payload[1])
+                .collect();
+            assert_eq!(sent, (0..100u8).collect::<Vec<_>>());
+        }
+        assert_eq!(queues.queued(&peer), 0);
+    }
+
+    #[test]
+    fn test_queued_while_sending() {
+        let queues = PeerSendQueues::new(2);
+        let peer = NodeId::from_low_u64_be(1);
+        let mut sent = vec![];
+        let outcome = queues.send_with(
+            &peer,
+            queued(vec![0], SendQueuePriority::High),
+            |msg| {
+                if msg.payload == vec![0] {
+                    // Other senders queue while the first message is sent.
+                    for (i, priority) in [
+                        SendQueuePriority::Normal,
+                        SendQueuePriority::High,
+                        SendQueuePriority::Normal,
+                        SendQueuePriority::High,
+                    ]
+                    .iter()
+                    .enumerate()
+                    {
+                        let outcome = queues.send_with(
+                            &peer,
+                            queued(vec![i as u8 + 1], *priority),
+                            |_| panic!("the queue is being sent"),
+                        );
+                        // The queue is full at the third message, which is
+                        // shed, while the fourth one sheds the first.
+                        if i == 2 {
+                            assert!(matches!(outcome, SendOutcome::Shed));
+                        } else {
+                            assert!(matches!(outcome, SendOutcome::Queued));
+                        }
+                    }
+                }
+                sent.push(msg.payload[0]);
+                Ok(())
+            },
+        );
+        assert!(matches!(outcome, SendOutcome::Sent(Ok(()))));
+        assert_eq!(sent, vec![0, 2, 4]);
+    }
+}
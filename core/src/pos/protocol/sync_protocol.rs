@@ -2,21 +2,34 @@
 // TreeGraph is free software and distributed under Apache License 2.0.
 // See https://www.apache.org/licenses/LICENSE-2.0
 
-use std::{collections::HashMap, fmt::Debug, mem::discriminant, sync::Arc};
+use std::{
+    any::{type_name, Any},
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    mem::discriminant,
+    sync::{
+        atomic::{AtomicBool, Ordering as AtomicOrdering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
+use futures::Stream;
 use keccak_hash::keccak;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use serde::Deserialize;
+use tokio::sync::broadcast;
 
 use cfx_types::H256;
 use consensus_types::{
-    epoch_retrieval::EpochRetrievalRequest, proposal_msg::ProposalMsg,
-    sync_info::SyncInfo, vote_msg::VoteMsg,
+    commit_vote_msg::CommitVoteMsg, epoch_retrieval::EpochRetrievalRequest,
+    proposal_msg::ProposalMsg, sync_info::SyncInfo, vote_msg::VoteMsg,
 };
 use diem_types::{
     account_address::{from_consensus_public_key, AccountAddress},
     epoch_change::EpochChangeProof,
     validator_config::{ConsensusPublicKey, ConsensusVRFPublicKey},
+    validator_verifier::ValidatorVerifier,
 };
 use io::TimerToken;
 use network::{
@@ -27,25 +40,74 @@ use network::{
 use crate::{
     message::{Message, MsgId},
     pos::{
-        consensus::network::{
-            ConsensusMsg, NetworkTask as ConsensusNetworkTask,
+        consensus::{
+            counters,
+            network::{
+                peer_msg_key, ConsensusMsg, NetworkTask as ConsensusNetworkTask,
+            },
         },
         mempool::network::{MempoolSyncMsg, NetworkTask as MempoolNetworkTask},
         protocol::{
+            blacklist::PeerBlacklist,
+            block_stream::{BlockStreamSource, BlockStreams},
+            compression::decompress,
+            epoch_change_reassembly::EpochChangeReassembly,
+            error::NetworkError,
+            handshake::{HandshakeFailure, PendingHandshakes},
+            incoming_msgs::IncomingMsgPublisher,
+            liveness::PeerLiveness,
             message::{
-                block_retrieval::BlockRetrievalRpcRequest,
-                block_retrieval_response::BlockRetrievalRpcResponse, msgid,
+                block_retrieval::{
+                    BlockRetrievalRpcRequest, UnlimitedBlockRetrievalRpcRequest,
+                },
+                block_retrieval_response::{
+                    BlockRetrievalRpcResponse,
+                    UncursoredBlockRetrievalRpcResponse,
+                },
+                block_stream::{
+                    BlockStreamChunk, BlockStreamCredit, BlockStreamOpen,
+                },
+                chain_id_handshake::ChainIdHandshake,
+                codec::CodecKind,
+                codec_negotiation::CodecNegotiation,
+                epoch_change_chunk::EpochChangeChunk,
+                epoch_retrieval_busy::EpochRetrievalBusy,
+                latency_probe::{
+                    LatencyProbeRpcRequest, LatencyProbeRpcResponse,
+                },
+                msgid,
+                ping::{Ping, Pong},
+                with_sync_info::WithSyncInfo,
             },
             network_event::NetworkEvent,
+            peer_activity::PeerActivity,
+            peer_event::{
+                ConsensusPeerEvent, DisconnectReason, PeerEventPublisher,
+                ProtocolViolationKind,
+            },
+            pending_proposals::PendingProposals,
+            proposal_tracker::ProposalTracker,
+            rate_limit::PeerRateLimiter,
+            replay_guard::{unstamp, ReplayGuard, SequenceNumbers},
             request_manager::{
                 request_handler::AsAny, RequestManager, RequestMessage,
             },
+            seen_msgs::SeenMsgs,
+            send_queue::PeerSendQueues,
+            sync_status::SyncStatusTracker,
+            vote_dedup::VoteDedup,
         },
     },
     sync::{Error, ErrorKind, ProtocolConfiguration, CHECK_RPC_REQUEST_TIMER},
 };
 
-use super::{HSB_PROTOCOL_ID, HSB_PROTOCOL_VERSION};
+use super::{
+    HSB_PROTOCOL_ID, HSB_PROTOCOL_V1, HSB_PROTOCOL_V14, HSB_PROTOCOL_V3,
+    HSB_PROTOCOL_V5, HSB_PROTOCOL_VERSION,
+};
+
+/// Fires every liveness ping interval, see `PeerLiveness`.
+const CHECK_PEER_LIVENESS_TIMER: TimerToken = 12;
 
 #[derive(Default)]
 pub struct PeerState {
@@ -53,6 +115,13 @@ pub struct PeerState {
     peer_hash: H256,
     // TODO(lpl): Only keep AccountAddress?
     pos_public_key: Option<(ConsensusPublicKey, ConsensusVRFPublicKey)>,
+    /// The protocol version negotiated with the peer, which decides the
+    /// messages it can decode.
+    protocol_version: ProtocolVersion,
+    /// The codec of the `ConsensusMsg`s negotiated with the peer.
+    codec: CodecKind,
+    /// The chain id of the peer, set once its handshake matches ours.
+    chain_id: Option<u64>,
 }
 
 impl PeerState {
@@ -65,6 +134,9 @@ impl PeerState {
             id,
             peer_hash,
             pos_public_key,
+            protocol_version: HSB_PROTOCOL_V1,
+            codec: CodecKind::Bcs,
+            chain_id: None,
         }
     }
 
@@ -77,6 +149,23 @@ impl PeerState {
     }
 
     pub fn get_id(&self) -> NodeId { self.id }
+
+    pub fn protocol_version(&self) -> ProtocolVersion { self.protocol_version }
+
+    pub fn codec(&self) -> CodecKind { self.codec }
+
+    pub fn set_codec(&mut self, codec: CodecKind) { self.codec = codec }
+
+    pub fn set_chain_id(&mut self, chain_id: u64) {
+        self.chain_id = Some(chain_id)
+    }
+
+    /// Whether the messages of the peer can be handled. The peers before
+    /// `HSB_PROTOCOL_V3` cannot send the chain id handshake, and are only
+    /// checked by the network id of the session.
+    pub fn is_chain_id_verified(&self) -> bool {
+        self.protocol_version < HSB_PROTOCOL_V3 || self.chain_id.is_some()
+    }
 }
 
 #[derive(Default)]
@@ -107,6 +196,19 @@ impl Peers {
         self.0.read().contains_key(peer)
     }
 
+    /// The protocol version negotiated with the connected peer `node_id`.
+    pub fn protocol_version(
+        &self, node_id: &NodeId,
+    ) -> Option<ProtocolVersion> {
+        Some(self.get(&keccak(node_id))?.read().protocol_version())
+    }
+
+    /// The codec of the `ConsensusMsg`s negotiated with the connected peer
+    /// `node_id`.
+    pub fn codec(&self, node_id: &NodeId) -> Option<CodecKind> {
+        Some(self.get(&keccak(node_id))?.read().codec())
+    }
+
     pub fn remove(&self, peer: &H256) -> Option<Arc<RwLock<PeerState>>> {
         self.0.write().remove(peer)
     }
@@ -178,9 +280,61 @@ pub struct HotStuffSynchronizationProtocol {
     pub own_node_hash: H256,
     pub peers: Arc<Peers>,
     pub request_manager: Arc<RequestManager>,
+    /// Drops the copies of the votes delivered to consensus before.
+    pub vote_dedup: VoteDedup,
     pub consensus_network_task: ConsensusNetworkTask,
     pub mempool_network_task: MempoolNetworkTask,
     pub pos_peer_mapping: RwLock<HashMap<AccountAddress, H256>>,
+    /// The `NodeId`s of the connected PoS nodes, kept together with
+    /// `pos_peer_mapping` so sending to a PoS node needs only one lookup.
+    pub pos_node_id_cache: RwLock<HashMap<AccountAddress, NodeId>>,
+    /// Limits the rate of the messages sent to each peer.
+    pub send_rate_limiter: PeerRateLimiter,
+    /// Keeps the order of the messages sent to each peer.
+    pub send_queues: PeerSendQueues,
+    /// Publishes the connections, disconnections and protocol violations of
+    /// the peers.
+    pub peer_events: PeerEventPublisher,
+    /// Publishes the `ConsensusMsg`s received from the peers.
+    pub incoming_msgs: IncomingMsgPublisher,
+    /// Detects the equivocating proposals received from peers.
+    pub proposal_tracker: ProposalTracker,
+    /// Holds back the proposals received before their parents.
+    pub pending_proposals: PendingProposals,
+    /// Reassembles the `EpochChangeProof`s received in chunks.
+    pub epoch_change_chunks: EpochChangeReassembly,
+    /// Numbers the consensus messages sent to each peer.
+    pub sequence_numbers: SequenceNumbers,
+    /// Drops the consensus messages replayed by the peers.
+    pub replay_guard: ReplayGuard,
+    /// Drops the proposals and votes received from another peer before.
+    pub seen_msgs: SeenMsgs,
+    /// Whether consensus is caught up or retrieving the blocks it is behind.
+    pub sync_status: SyncStatusTracker,
+    /// Counts the messages each peer has sent recently.
+    pub peer_activity: PeerActivity,
+    /// Pings the peers and tracks when each peer is last seen.
+    pub peer_liveness: PeerLiveness,
+    /// The peers shunned for their protocol violations.
+    pub peer_blacklist: PeerBlacklist,
+    /// The connections waiting for the chain id handshakes of the peers.
+    pub pending_handshakes: PendingHandshakes,
+    /// The block streams opened with or by the peers.
+    pub block_streams: BlockStreams,
+    /// The blocks the streams opened by the peers are pushed from, set once
+    /// consensus is started.
+    block_stream_source: RwLock<Option<Arc<dyn BlockStreamSource>>>,
+    /// The voting powers of the validators of the current epoch, by which
+    /// the peers of the RPC requests are chosen with
+    /// `pos_request_weight_by_voting_power`.
+    validator_voting_powers: RwLock<HashMap<AccountAddress, u64>>,
+    /// The sessions of the validators of the current epoch, see
+    /// `set_validator_peers`. None until it is set.
+    validator_peers: RwLock<Option<HashSet<NodeId>>>,
+    /// Why we disconnect the peers, reported once they are disconnected.
+    disconnect_reasons: Mutex<HashMap<NodeId, DisconnectReason>>,
+    /// Set by `shutdown`, after which nothing is sent or received.
+    shut_down: AtomicBool,
 }
 
 impl HotStuffSynchronizationProtocol {
@@ -191,14 +345,48 @@ impl HotStuffSynchronizationProtocol {
     ) -> Self
     {
         let request_manager = Arc::new(RequestManager::new(&protocol_config));
+        let send_rate_limiter =
+            PeerRateLimiter::new(protocol_config.pos_send_rate_limit);
+        let send_queues =
+            PeerSendQueues::new(protocol_config.pos_peer_send_queue_size);
+        let peer_liveness =
+            PeerLiveness::new(&protocol_config.pos_peer_liveness);
+        let peer_blacklist =
+            PeerBlacklist::new(&protocol_config.pos_peer_blacklist);
+        let seen_msgs = SeenMsgs::new(protocol_config.pos_seen_msgs_cache_size);
+        let pending_handshakes =
+            PendingHandshakes::new(protocol_config.pos_handshake_timeout);
         HotStuffSynchronizationProtocol {
             protocol_config,
             own_node_hash,
             peers: Arc::new(Peers::new()),
             request_manager,
+            vote_dedup: VoteDedup::new(),
             consensus_network_task,
             mempool_network_task,
             pos_peer_mapping: RwLock::new(Default::default()),
+            pos_node_id_cache: RwLock::new(Default::default()),
+            send_rate_limiter,
+            send_queues,
+            peer_events: PeerEventPublisher::default(),
+            incoming_msgs: IncomingMsgPublisher::default(),
+            proposal_tracker: ProposalTracker::new(),
+            pending_proposals: PendingProposals::new(),
+            epoch_change_chunks: EpochChangeReassembly::new(),
+            sequence_numbers: SequenceNumbers::default(),
+            replay_guard: ReplayGuard::default(),
+            seen_msgs,
+            sync_status: SyncStatusTracker::new(),
+            peer_activity: PeerActivity::default(),
+            peer_liveness,
+            peer_blacklist,
+            pending_handshakes,
+            block_streams: BlockStreams::default(),
+            block_stream_source: Default::default(),
+            validator_voting_powers: Default::default(),
+            validator_peers: Default::default(),
+            disconnect_reasons: Default::default(),
+            shut_down: AtomicBool::new(false),
         }
     }
 
@@ -209,15 +397,76 @@ impl HotStuffSynchronizationProtocol {
     ) -> Self
     {
         let request_manager = Arc::new(RequestManager::new(&protocol_config));
+        let send_rate_limiter =
+            PeerRateLimiter::new(protocol_config.pos_send_rate_limit);
+        let send_queues =
+            PeerSendQueues::new(protocol_config.pos_peer_send_queue_size);
+        let peer_liveness =
+            PeerLiveness::new(&protocol_config.pos_peer_liveness);
+        let peer_blacklist =
+            PeerBlacklist::new(&protocol_config.pos_peer_blacklist);
+        let seen_msgs = SeenMsgs::new(protocol_config.pos_seen_msgs_cache_size);
+        let pending_handshakes =
+            PendingHandshakes::new(protocol_config.pos_handshake_timeout);
         HotStuffSynchronizationProtocol {
             protocol_config,
             own_node_hash,
             peers,
             request_manager,
+            vote_dedup: VoteDedup::new(),
             consensus_network_task,
             mempool_network_task,
             pos_peer_mapping: RwLock::new(Default::default()),
+            pos_node_id_cache: RwLock::new(Default::default()),
+            send_rate_limiter,
+            send_queues,
+            peer_events: PeerEventPublisher::default(),
+            incoming_msgs: IncomingMsgPublisher::default(),
+            proposal_tracker: ProposalTracker::new(),
+            pending_proposals: PendingProposals::new(),
+            epoch_change_chunks: EpochChangeReassembly::new(),
+            sequence_numbers: SequenceNumbers::default(),
+            replay_guard: ReplayGuard::default(),
+            seen_msgs,
+            sync_status: SyncStatusTracker::new(),
+            peer_activity: PeerActivity::default(),
+            peer_liveness,
+            peer_blacklist,
+            pending_handshakes,
+            block_streams: BlockStreams::default(),
+            block_stream_source: Default::default(),
+            validator_voting_powers: Default::default(),
+            validator_peers: Default::default(),
+            disconnect_reasons: Default::default(),
+            shut_down: AtomicBool::new(false),
+        }
+    }
+
+    /// Stop sending and receiving the PoS messages, and fail all the
+    /// requests waiting for their responses with `ErrorKind::Shutdown`, so
+    /// their senders do not hang.
+    ///
+    /// The messages already queued in the sessions are still sent by the
+    /// network until it stops. Calling this again does nothing.
+    pub fn shutdown(&self) {
+        if self.shut_down.swap(true, AtomicOrdering::SeqCst) {
+            return;
         }
+        let cancelled = self.request_manager.shutdown();
+        info!("hsb shut down, {} requests cancelled", cancelled);
+    }
+
+    /// Push the block streams opened by the peers from `source`.
+    pub fn set_block_stream_source(&self, source: Arc<dyn BlockStreamSource>) {
+        *self.block_stream_source.write() = Some(source);
+    }
+
+    pub fn block_stream_source(&self) -> Option<Arc<dyn BlockStreamSource>> {
+        self.block_stream_source.read().clone()
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down.load(AtomicOrdering::SeqCst)
     }
 
     pub fn register(
@@ -233,11 +482,268 @@ impl HotStuffSynchronizationProtocol {
             })
     }
 
+    /// Receive the peer events published from now on.
+    pub fn subscribe_peer_events(
+        &self,
+    ) -> broadcast::Receiver<ConsensusPeerEvent> {
+        self.peer_events.subscribe()
+    }
+
+    /// Receive a copy of each `ConsensusMsg` received from now on, with the
+    /// peer that sends it. This only observes the messages, which are still
+    /// delivered to consensus, and a subscriber that falls behind misses the
+    /// oldest messages.
+    pub fn subscribe_incoming(
+        &self,
+    ) -> impl Stream<Item = (NodeId, ConsensusMsg)> {
+        self.incoming_msgs.subscribe()
+    }
+
+    /// Deliver `msg` received from `peer` to consensus, queued by `author`,
+    /// and publish it to the subscribers of `subscribe_incoming`.
+    pub fn deliver_consensus_msg(
+        &self, peer: &NodeId, peer_address: AccountAddress,
+        author: AccountAddress, msg: ConsensusMsg,
+    ) -> Result<(), Error>
+    {
+        self.incoming_msgs.publish(peer, &msg);
+        if !self.seen_msgs.check(&msg) {
+            counters::NETWORK_MSGS_SEEN
+                .with_label_values(&[msg.name()])
+                .inc();
+            return Ok(());
+        }
+        self.consensus_network_task
+            .consensus_messages_tx
+            .push(peer_msg_key(author, &msg), (peer_address, msg))?;
+        Ok(())
+    }
+
+    /// Ask the peer to use the preferred codec of this node for the
+    /// `ConsensusMsg`s. Nothing is sent if BCS is preferred, which is used
+    /// with all the peers by default. The peers before `HSB_PROTOCOL_V14`
+    /// do not know the negotiation, so BCS is kept with them.
+    fn negotiate_codec(
+        &self, io: &dyn NetworkContext, node_id: &NodeId,
+        protocol_version: ProtocolVersion,
+    )
+    {
+        let preferred = self.protocol_config.pos_consensus_msg_codec;
+        if preferred == CodecKind::Bcs || protocol_version < HSB_PROTOCOL_V14 {
+            return;
+        }
+        if let Err(e) = CodecNegotiation::new(preferred).send(io, node_id) {
+            warn!("failed to send codec negotiation to {}: {:?}", node_id, e);
+        }
+    }
+
+    /// Tell the peer our chain id. The peers before `HSB_PROTOCOL_V3` do
+    /// not know the handshake.
+    fn send_chain_id_handshake(
+        &self, io: &dyn NetworkContext, node_id: &NodeId,
+        protocol_version: ProtocolVersion,
+    )
+    {
+        if protocol_version < HSB_PROTOCOL_V3 {
+            return;
+        }
+        self.pending_handshakes.start(node_id);
+        let handshake = ChainIdHandshake {
+            chain_id: self.protocol_config.pos_chain_id,
+        };
+        if let Err(e) = handshake.send(io, node_id) {
+            warn!("failed to send chain id handshake to {}: {:?}", node_id, e);
+        }
+    }
+
+    /// Remove the peer from the peer table and the PoS peer mapping.
+    /// Returns whether the peer is in the peer table.
+    ///
+    /// A PoS node reconnecting with another `NodeId` may have its new
+    /// session mapped before the old one is removed, so the mapping is
+    /// refreshed rather than removed.
+    fn remove_peer(&self, peer_hash: &H256) -> bool {
+        let peer_state = match self.peers.remove(peer_hash) {
+            Some(peer_state) => peer_state,
+            None => return false,
+        };
+        let pos_public_key = peer_state.read().pos_public_key.clone();
+        if let Some((public_key, vrf_public_key)) = pos_public_key {
+            self.refresh_peer(&from_consensus_public_key(
+                &public_key,
+                &vrf_public_key,
+            ));
+        }
+        true
+    }
+
+    /// Re-read the current session of the PoS node `peer` from the peer
+    /// table into `pos_peer_mapping` and `pos_node_id_cache`, which may
+    /// still hold a dead session after it reconnects with another `NodeId`.
+    /// Returns the `NodeId` of the current session, or None if the node is
+    /// not connected, in which case it is removed from both.
+    pub fn refresh_peer(&self, peer: &AccountAddress) -> Option<NodeId> {
+        let mapped = self.pos_peer_mapping.read().get(peer).copied();
+        let node_id = mapped
+            .and_then(|peer_hash| self.peers.get(&peer_hash))
+            .map(|state| state.read().get_id())
+            .or_else(|| {
+                self.peers.fold(None, |found, state| {
+                    found.or_else(|| {
+                        let state = state.read();
+                        let (public_key, vrf_public_key) =
+                            state.pos_public_key.as_ref()?;
+                        let account_address = from_consensus_public_key(
+                            public_key,
+                            vrf_public_key,
+                        );
+                        if account_address == *peer {
+                            Some(state.id)
+                        } else {
+                            None
+                        }
+                    })
+                })
+            });
+        match node_id {
+            Some(node_id) => {
+                self.pos_peer_mapping
+                    .write()
+                    .insert(*peer, keccak(&node_id));
+                self.pos_node_id_cache.write().insert(*peer, node_id);
+            }
+            None => {
+                self.pos_peer_mapping.write().remove(peer);
+                self.pos_node_id_cache.write().remove(peer);
+            }
+        }
+        counters::PEER_SESSION_REFRESHES
+            .with_label_values(&[if node_id.is_some() {
+                "found"
+            } else {
+                "removed"
+            }])
+            .inc();
+        self.refresh_peer_voting_powers();
+        node_id
+    }
+
+    /// Set the validators of the current epoch, whose voting powers the
+    /// peers of the RPC requests are chosen by with
+    /// `pos_request_weight_by_voting_power`.
+    pub fn set_validators(&self, validators: &ValidatorVerifier) {
+        *self.validator_voting_powers.write() = validators
+            .get_ordered_account_addresses_iter()
+            .filter_map(|account| {
+                Some((account, validators.get_voting_power(&account)?))
+            })
+            .collect();
+        self.refresh_peer_voting_powers();
+    }
+
+    /// Whether the PoS node `account` is a validator of the current epoch, or
+    /// None if `set_validators` is not called yet.
+    pub fn is_validator(&self, account: &AccountAddress) -> Option<bool> {
+        let voting_powers = self.validator_voting_powers.read();
+        if voting_powers.is_empty() {
+            return None;
+        }
+        Some(voting_powers.contains_key(account))
+    }
+
+    /// Set the connected sessions of the validators of the current epoch,
+    /// on each epoch change. With `pos_broadcast_to_validators_only` the
+    /// consensus messages are only broadcast to them, while the other PoS
+    /// peers stay connected for the sync. A validator connecting later is
+    /// added once its PoS public key is known.
+    pub fn set_validator_peers(&self, peers: HashSet<NodeId>) {
+        *self.validator_peers.write() = Some(peers);
+    }
+
+    /// Whether `node_id` is the session of a validator of the current
+    /// epoch, or None if `set_validator_peers` is not called yet.
+    pub fn is_validator_peer(&self, node_id: &NodeId) -> Option<bool> {
+        Some(self.validator_peers.read().as_ref()?.contains(node_id))
+    }
+
+    /// Pass the voting powers of the connected validators to the request
+    /// manager, after the validators or the connected PoS nodes change.
+    fn refresh_peer_voting_powers(&self) {
+        if !self.protocol_config.pos_request_weight_by_voting_power {
+            return;
+        }
+        let voting_powers = self.validator_voting_powers.read();
+        let peer_voting_powers = self
+            .pos_node_id_cache
+            .read()
+            .iter()
+            .filter_map(|(account, node_id)| {
+                Some((*node_id, *voting_powers.get(account)?))
+            })
+            .collect();
+        self.request_manager.set_voting_powers(peer_voting_powers);
+    }
+
+    /// Remove the peer whose session is found closed when sending to it,
+    /// before the network reports the disconnection, so the later sends do
+    /// not keep targeting the dead session. Returns whether the peer is in
+    /// the peer table.
+    pub fn evict_stale_peer(&self, node_id: &NodeId) -> bool {
+        self.remove_peer(&keccak(node_id))
+    }
+
+    /// Whether the messages to the peer `node_id` in the peer table reach
+    /// the network, i.e. its session in `io` is live and it is not
+    /// blacklisted.
+    pub fn is_peer_reachable(
+        &self, io: &dyn NetworkContext, node_id: &NodeId,
+    ) -> bool {
+        (io.is_peer_self(node_id)
+            || io.get_peer_connection_origin(node_id).is_some())
+            && !self.peer_blacklist.is_blacklisted(node_id)
+    }
+
     pub fn remove_expired_flying_request(&self, io: &dyn NetworkContext) {
         self.request_manager.process_timeout_requests(io);
         self.request_manager.resend_waiting_requests(io);
     }
 
+    /// Disconnect the peers that do not send their chain id handshakes in
+    /// time.
+    pub fn disconnect_handshake_timeouts(&self, io: &dyn NetworkContext) {
+        if self.is_shut_down() {
+            return;
+        }
+        for peer in self.pending_handshakes.take_expired() {
+            warn!("peer {} does not send its chain id handshake", peer);
+            self.set_disconnect_reason(
+                &peer,
+                DisconnectReason::HandshakeTimeout,
+            );
+            io.disconnect_peer(&peer, None, "chain id handshake timeout");
+        }
+    }
+
+    /// Ping the peers, and disconnect the ones that miss too many pongs, so
+    /// the network dials them again.
+    pub fn check_peer_liveness(&self, io: &dyn NetworkContext) {
+        if self.is_shut_down() {
+            return;
+        }
+        let check = self.peer_liveness.check();
+        for peer in &check.unhealthy {
+            warn!("peer {} misses too many liveness pongs", peer);
+            counters::NETWORK_PEERS_UNRESPONSIVE.inc();
+            self.set_disconnect_reason(peer, DisconnectReason::Unresponsive);
+            io.disconnect_peer(peer, None, "missed liveness pongs");
+        }
+        for (peer, nonce) in check.pings {
+            if let Err(e) = (Ping { nonce }).send(io, &peer) {
+                debug!("failed to send ping to {}: {:?}", peer, e);
+            }
+        }
+    }
+
     /// In the event two peers simultaneously dial each other we need to be able
     /// to do tie-breaking to determine which connection to keep and which
     /// to drop in a deterministic way. One simple way is to compare our
@@ -271,6 +777,16 @@ impl HotStuffSynchronizationProtocol {
     fn handle_error(
         &self, io: &dyn NetworkContext, peer: &NodeId, msg_id: MsgId, e: Error,
     ) {
+        self.handle_error_as(io, peer, msg_id, e, None)
+    }
+
+    /// Handle `e` from `peer`, which is a protocol violation of `violation`
+    /// if it is given, or decided by the error kind otherwise.
+    fn handle_error_as(
+        &self, io: &dyn NetworkContext, peer: &NodeId, msg_id: MsgId, e: Error,
+        mut violation: Option<ProtocolViolationKind>,
+    )
+    {
         let mut disconnect = true;
         let mut warn = false;
         let reason = format!("{}", e.0);
@@ -280,14 +796,21 @@ impl HotStuffSynchronizationProtocol {
         // NOTE, DO NOT USE WILDCARD IN THE FOLLOWING MATCH STATEMENT!
         // COMPILER WILL HELP TO FIND UNHANDLED ERROR CASES.
         match e.0 {
-            ErrorKind::InvalidBlock => op = Some(UpdateNodeOperation::Demotion),
+            ErrorKind::InvalidBlock => {
+                violation.get_or_insert(ProtocolViolationKind::InvalidMessage);
+                op = Some(UpdateNodeOperation::Demotion)
+            }
             ErrorKind::InvalidGetBlockTxn(_) => {
+                violation.get_or_insert(ProtocolViolationKind::InvalidMessage);
                 op = Some(UpdateNodeOperation::Demotion)
             }
             ErrorKind::InvalidStatus(_) => {
+                violation.get_or_insert(ProtocolViolationKind::InvalidMessage);
                 op = Some(UpdateNodeOperation::Failure)
             }
             ErrorKind::InvalidMessageFormat => {
+                violation
+                    .get_or_insert(ProtocolViolationKind::MalformedMessage);
                 op = Some(UpdateNodeOperation::Remove)
             }
             ErrorKind::UnknownPeer => {
@@ -296,7 +819,11 @@ impl HotStuffSynchronizationProtocol {
             }
             // TODO handle the unexpected response case (timeout or real invalid
             // message type)
-            ErrorKind::UnexpectedResponse => disconnect = true,
+            ErrorKind::UnexpectedResponse => {
+                violation
+                    .get_or_insert(ProtocolViolationKind::UnexpectedResponse);
+                disconnect = true
+            }
             ErrorKind::RequestNotFound => {
                 warn = false;
                 disconnect = false;
@@ -307,15 +834,19 @@ impl HotStuffSynchronizationProtocol {
             }
             ErrorKind::TooManyTrans => {}
             ErrorKind::InvalidTimestamp => {
+                violation.get_or_insert(ProtocolViolationKind::InvalidMessage);
                 op = Some(UpdateNodeOperation::Demotion)
             }
             ErrorKind::InvalidSnapshotManifest(_) => {
+                violation.get_or_insert(ProtocolViolationKind::InvalidMessage);
                 op = Some(UpdateNodeOperation::Demotion)
             }
             ErrorKind::InvalidSnapshotChunk(_) => {
+                violation.get_or_insert(ProtocolViolationKind::InvalidMessage);
                 op = Some(UpdateNodeOperation::Demotion)
             }
             ErrorKind::AlreadyThrottled(_) => {
+                violation.get_or_insert(ProtocolViolationKind::Throttled);
                 op = Some(UpdateNodeOperation::Remove)
             }
             ErrorKind::EmptySnapshotChunk => disconnect = false,
@@ -327,17 +858,25 @@ impl HotStuffSynchronizationProtocol {
                     disconnect = true;
                 }
             }
-            ErrorKind::Decoder(_) => op = Some(UpdateNodeOperation::Remove),
+            ErrorKind::Decoder(_) => {
+                violation
+                    .get_or_insert(ProtocolViolationKind::MalformedMessage);
+                op = Some(UpdateNodeOperation::Remove)
+            }
             ErrorKind::Io(_) => disconnect = false,
             ErrorKind::Network(kind) => match kind {
                 network::ErrorKind::AddressParse => disconnect = false,
                 network::ErrorKind::AddressResolve(_) => disconnect = false,
                 network::ErrorKind::Auth => disconnect = false,
                 network::ErrorKind::BadProtocol => {
+                    violation
+                        .get_or_insert(ProtocolViolationKind::MalformedMessage);
                     op = Some(UpdateNodeOperation::Remove)
                 }
                 network::ErrorKind::BadAddr => disconnect = false,
                 network::ErrorKind::Decoder(_) => {
+                    violation
+                        .get_or_insert(ProtocolViolationKind::MalformedMessage);
                     op = Some(UpdateNodeOperation::Remove)
                 }
                 network::ErrorKind::Expired => disconnect = false,
@@ -370,7 +909,12 @@ impl HotStuffSynchronizationProtocol {
             ErrorKind::InternalError(_) => {}
             ErrorKind::RpcTimeout => {}
             ErrorKind::RpcCancelledByDisconnection => {}
+            ErrorKind::Shutdown => {}
+            ErrorKind::DeadlineExceeded => {}
+            ErrorKind::TooManyPendingRequests => {}
             ErrorKind::UnexpectedMessage(_) => {
+                violation
+                    .get_or_insert(ProtocolViolationKind::UnexpectedResponse);
                 op = Some(UpdateNodeOperation::Remove)
             }
             ErrorKind::NotSupported(_) => disconnect = false,
@@ -388,24 +932,105 @@ impl HotStuffSynchronizationProtocol {
             );
         }
 
+        if let Some(kind) = violation {
+            self.on_protocol_violation(peer, kind);
+        }
+
         if disconnect {
+            let disconnect_reason = match violation {
+                Some(kind) => DisconnectReason::ProtocolViolation(kind),
+                None => DisconnectReason::Error,
+            };
+            self.set_disconnect_reason(peer, disconnect_reason);
             io.disconnect_peer(peer, op, reason.as_str());
         }
     }
 
+    /// Report the protocol violation `kind` of `peer`, and blacklist the
+    /// peer if it violates the protocol too often. The caller decides
+    /// whether to disconnect the peer.
+    pub fn on_protocol_violation(
+        &self, peer: &NodeId, kind: ProtocolViolationKind,
+    ) {
+        self.peer_events
+            .publish(ConsensusPeerEvent::ProtocolViolation {
+                peer: *peer,
+                kind,
+            });
+        if self.peer_blacklist.on_violation(peer) {
+            warn!(
+                "blacklist peer {:?} for {:?} after repeated protocol violations",
+                peer, kind
+            );
+            counters::NETWORK_PEERS_BLACKLISTED.inc();
+        }
+    }
+
+    /// The blacklisted peers with how long they are still blacklisted, for
+    /// debugging.
+    pub fn blacklisted_peers(&self) -> Vec<(NodeId, Duration)> {
+        self.peer_blacklist.entries()
+    }
+
+    /// Disconnect `peer` for the protocol violation `kind`.
+    pub fn disconnect_for_violation(
+        &self, io: &dyn NetworkContext, peer: &NodeId,
+        kind: ProtocolViolationKind, reason: &str,
+    )
+    {
+        self.on_protocol_violation(peer, kind);
+        self.set_disconnect_reason(
+            peer,
+            DisconnectReason::ProtocolViolation(kind),
+        );
+        io.disconnect_peer(peer, Some(UpdateNodeOperation::Remove), reason);
+    }
+
+    /// Remember why we disconnect `peer`, so it is reported when the peer is
+    /// disconnected.
+    fn set_disconnect_reason(&self, peer: &NodeId, reason: DisconnectReason) {
+        self.disconnect_reasons.lock().insert(*peer, reason);
+    }
+
     fn dispatch_message(
         &self, io: &dyn NetworkContext, peer: &NodeId, msg_id: MsgId,
         msg: &[u8],
     ) -> Result<(), Error>
     {
         trace!("Dispatching message: peer={:?}, msg_id={:?}", peer, msg_id);
+        if self.is_shut_down() {
+            return Ok(());
+        }
         let peer_hash = if !io.is_peer_self(peer) {
             if *peer == NodeId::default() {
                 return Err(ErrorKind::UnknownPeer.into());
             }
+            if self.peer_blacklist.is_blacklisted(peer) {
+                debug!(
+                    "drop message from blacklisted peer: peer={:?}, msgid={:?}",
+                    peer, msg_id
+                );
+                counters::NETWORK_MSGS_BLACKLISTED
+                    .with_label_values(&["received"])
+                    .inc();
+                return Ok(());
+            }
             let peer_hash = keccak(peer);
-            if !self.peers.contains(&peer_hash) {
-                return Err(ErrorKind::UnknownPeer.into());
+            let verified = match self.peers.get(&peer_hash) {
+                Some(state) => state.read().is_chain_id_verified(),
+                None => return Err(ErrorKind::UnknownPeer.into()),
+            };
+            // Nothing of a peer is handled before its chain id is known to
+            // match ours.
+            if !verified && msg_id != msgid::CHAIN_ID_HANDSHAKE {
+                debug!(
+                    "drop message before chain id handshake: peer={:?}, msgid={:?}",
+                    peer, msg_id
+                );
+                counters::NETWORK_MSGS_BEFORE_HANDSHAKE
+                    .with_label_values(&[&msg_id.to_string()])
+                    .inc();
+                return Ok(());
             }
             peer_hash
         } else {
@@ -423,9 +1048,10 @@ impl HotStuffSynchronizationProtocol {
             warn!("Unknown message: peer={:?} msgid={:?}", peer, msg_id);
             let reason =
                 format!("unknown sync protocol message id {:?}", msg_id);
-            io.disconnect_peer(
+            self.disconnect_for_violation(
+                io,
                 peer,
-                Some(UpdateNodeOperation::Remove),
+                ProtocolViolationKind::MalformedMessage,
                 reason.as_str(),
             );
         }
@@ -438,33 +1064,183 @@ pub fn handle_serialized_message(
     id: MsgId, ctx: &Context, msg: &[u8],
 ) -> Result<bool, Error> {
     match id {
-        msgid::PROPOSAL => handle_message::<ProposalMsg>(ctx, msg)?,
-        msgid::VOTE => handle_message::<VoteMsg>(ctx, msg)?,
-        msgid::SYNC_INFO => handle_message::<SyncInfo>(ctx, msg)?,
-        msgid::BLOCK_RETRIEVAL => {
-            handle_message::<BlockRetrievalRpcRequest>(ctx, msg)?
+        msgid::PROPOSAL => handle_message::<ProposalMsg>(ctx, id, msg)?,
+        msgid::VOTE => handle_message::<VoteMsg>(ctx, id, msg)?,
+        msgid::COMMIT_VOTE => handle_message::<CommitVoteMsg>(ctx, id, msg)?,
+        msgid::SYNC_INFO => handle_message::<SyncInfo>(ctx, id, msg)?,
+        msgid::BLOCK_RETRIEVAL => handle_message_as::<
+            UnlimitedBlockRetrievalRpcRequest,
+            BlockRetrievalRpcRequest,
+        >(ctx, id, msg)?,
+        msgid::BLOCK_RETRIEVAL_PAGE => {
+            handle_message::<BlockRetrievalRpcRequest>(ctx, id, msg)?
         }
-        msgid::BLOCK_RETRIEVAL_RESPONSE => {
-            handle_message::<BlockRetrievalRpcResponse>(ctx, msg)?
+        msgid::BLOCK_RETRIEVAL_RESPONSE => handle_message_as::<
+            UncursoredBlockRetrievalRpcResponse,
+            BlockRetrievalRpcResponse,
+        >(ctx, id, msg)?,
+        msgid::BLOCK_RETRIEVAL_PAGE_RESPONSE => {
+            handle_message::<BlockRetrievalRpcResponse>(ctx, id, msg)?
         }
         msgid::EPOCH_RETRIEVAL => {
-            handle_message::<EpochRetrievalRequest>(ctx, msg)?
+            handle_message::<EpochRetrievalRequest>(ctx, id, msg)?
+        }
+        msgid::EPOCH_RETRIEVAL_BUSY => {
+            handle_message::<EpochRetrievalBusy>(ctx, id, msg)?
+        }
+        msgid::EPOCH_CHANGE => {
+            handle_message::<EpochChangeProof>(ctx, id, msg)?
+        }
+        msgid::EPOCH_CHANGE_CHUNK => {
+            handle_message::<EpochChangeChunk>(ctx, id, msg)?
+        }
+        msgid::CONSENSUS_MSG => handle_message::<ConsensusMsg>(ctx, id, msg)?,
+        msgid::CONSENSUS_MSG_JSON => {
+            handle_consensus_msg(ctx, id, msg, CodecKind::Json)?
+        }
+        msgid::CODEC_NEGOTIATION => {
+            handle_message::<CodecNegotiation>(ctx, id, msg)?
+        }
+        msgid::CHAIN_ID_HANDSHAKE => {
+            handle_message::<ChainIdHandshake>(ctx, id, msg)?
+        }
+        msgid::WITH_SYNC_INFO => handle_message::<WithSyncInfo>(ctx, id, msg)?,
+        msgid::PING => handle_message::<Ping>(ctx, id, msg)?,
+        msgid::PONG => handle_message::<Pong>(ctx, id, msg)?,
+        msgid::LATENCY_PROBE => {
+            handle_message::<LatencyProbeRpcRequest>(ctx, id, msg)?
+        }
+        msgid::LATENCY_PROBE_RESPONSE => {
+            handle_message::<LatencyProbeRpcResponse>(ctx, id, msg)?
+        }
+        msgid::BLOCK_STREAM_OPEN => {
+            handle_message::<BlockStreamOpen>(ctx, id, msg)?
+        }
+        msgid::BLOCK_STREAM_CHUNK => {
+            handle_message::<BlockStreamChunk>(ctx, id, msg)?
+        }
+        msgid::BLOCK_STREAM_CREDIT => {
+            handle_message::<BlockStreamCredit>(ctx, id, msg)?
+        }
+        msgid::MEMPOOL_SYNC_MSG => {
+            handle_message::<MempoolSyncMsg>(ctx, id, msg)?
         }
-        msgid::EPOCH_CHANGE => handle_message::<EpochChangeProof>(ctx, msg)?,
-        msgid::CONSENSUS_MSG => handle_message::<ConsensusMsg>(ctx, msg)?,
-        msgid::MEMPOOL_SYNC_MSG => handle_message::<MempoolSyncMsg>(ctx, msg)?,
         _ => return Ok(false),
     }
     Ok(true)
 }
 
-fn handle_message<'a, M>(ctx: &Context, msg: &'a [u8]) -> Result<(), Error>
+fn handle_message<'a, M>(
+    ctx: &Context, id: MsgId, msg: &'a [u8],
+) -> Result<(), Error>
 where M: Deserialize<'a> + Handleable + Message {
-    let msg: M = bcs::from_bytes(msg)?;
+    let started = Instant::now();
+    match bcs::from_bytes::<M>(msg) {
+        Ok(decoded) => {
+            observe_decode_time(&decoded, started);
+            handle_decoded_message(ctx, msg.len(), decoded)
+        }
+        Err(e) => {
+            drop_malformed_message(ctx, id, msg.len(), &e);
+            Ok(())
+        }
+    }
+}
+
+/// Decode a message sent in the layout `W` the older peers decode, and handle
+/// it as `M`.
+fn handle_message_as<'a, W, M>(
+    ctx: &Context, id: MsgId, msg: &'a [u8],
+) -> Result<(), Error>
+where
+    W: Deserialize<'a> + Into<M>,
+    M: Handleable + Message,
+{
+    let started = Instant::now();
+    match bcs::from_bytes::<W>(msg) {
+        Ok(decoded) => {
+            let decoded: M = decoded.into();
+            observe_decode_time(&decoded, started);
+            handle_decoded_message(ctx, msg.len(), decoded)
+        }
+        Err(e) => {
+            drop_malformed_message(ctx, id, msg.len(), &e);
+            Ok(())
+        }
+    }
+}
+
+/// Decode a `ConsensusMsg` encoded with `codec`, which must be the codec
+/// negotiated with the peer.
+fn handle_consensus_msg(
+    ctx: &Context, id: MsgId, msg: &[u8], codec: CodecKind,
+) -> Result<(), Error> {
+    let started = Instant::now();
+    let decoded = match codec.codec() {
+        Some(msg_codec)
+            if ctx.manager.peers.codec(&ctx.peer) == Some(codec) =>
+        {
+            msg_codec.decode(msg)
+        }
+        _ => Err(anyhow::format_err!("codec {:?} is not negotiated", codec)),
+    };
+    match decoded {
+        Ok(decoded) => {
+            observe_decode_time(&decoded, started);
+            handle_decoded_message(ctx, msg.len(), decoded)
+        }
+        Err(e) => {
+            drop_malformed_message(ctx, id, msg.len(), &e);
+            Ok(())
+        }
+    }
+}
+
+/// Record the time since `started` to decode `msg`. The messages that fail
+/// to decode are only counted in `NETWORK_MSGS_MALFORMED`.
+fn observe_decode_time(msg: &dyn Message, started: Instant) {
+    counters::NETWORK_MSG_DECODE_S
+        .with_label_values(&[msg.msg_name()])
+        .observe(started.elapsed().as_secs_f64());
+}
+
+/// Only this message is dropped, and the following ones from the peer are
+/// still handled.
+pub fn drop_malformed_message(
+    ctx: &Context, id: MsgId, size: usize, e: &dyn std::fmt::Debug,
+) {
+    warn!(
+        "drop malformed sync protocol message, peer = {}, id = {}, size = {}, error = {:?}",
+        ctx.peer, id, size, e,
+    );
+    counters::NETWORK_MSGS_MALFORMED
+        .with_label_values(&[&id.to_string()])
+        .inc();
+    ctx.manager.on_protocol_violation(
+        &ctx.peer,
+        ProtocolViolationKind::MalformedMessage,
+    );
+}
+
+fn handle_decoded_message<M>(
+    ctx: &Context, size: usize, msg: M,
+) -> Result<(), Error>
+where M: Handleable + Message {
     let msg_id = msg.msg_id();
     let msg_name = msg.msg_name();
     let req_id = msg.get_request_id();
 
+    if !ctx.io.is_peer_self(&ctx.peer) {
+        counters::NETWORK_MSGS_RECEIVED
+            .with_label_values(&[msg_name])
+            .inc();
+        counters::NETWORK_BYTES_RECEIVED
+            .with_label_values(&[msg_name])
+            .inc_by(size as u64);
+        ctx.manager.peer_activity.record(&ctx.peer, msg_name);
+        ctx.manager.peer_liveness.on_message(&ctx.peer);
+    }
+
     trace!(
         "handle sync protocol message, peer = {:?}, id = {}, name = {}, request_id = {:?}",
         ctx.peer_hash, msg_id, msg_name, req_id,
@@ -495,6 +1271,14 @@ impl NetworkProtocolHandler for HotStuffSynchronizationProtocol {
             self.protocol_config.check_request_period,
         )
         .expect("Error registering check rpc request timer");
+        let liveness = &self.protocol_config.pos_peer_liveness;
+        if liveness.is_enabled() {
+            io.register_timer(
+                CHECK_PEER_LIVENESS_TIMER,
+                liveness.ping_interval,
+            )
+            .expect("Error registering check peer liveness timer");
+        }
     }
 
     fn on_message(&self, io: &dyn NetworkContext, peer: &NodeId, raw: &[u8]) {
@@ -509,27 +1293,131 @@ impl NetworkProtocolHandler for HotStuffSynchronizationProtocol {
             );
         }
 
-        let msg_id = raw[len - 1];
+        let raw = if raw[len - 1] as MsgId == msgid::SEQUENCED {
+            match unstamp(raw) {
+                Some((msg, epoch, seq)) => {
+                    if !self.replay_guard.check(peer, epoch, seq) {
+                        debug!(
+                            "drop replayed message: peer={:?}, epoch={}, seq={}",
+                            peer, epoch, seq
+                        );
+                        counters::NETWORK_MSGS_REPLAYED
+                            .with_label_values(&[&peer.to_string()])
+                            .inc();
+                        return;
+                    }
+                    msg
+                }
+                None => {
+                    return self.handle_error(
+                        io,
+                        peer,
+                        msgid::SEQUENCED,
+                        ErrorKind::InvalidMessageFormat.into(),
+                    );
+                }
+            }
+        } else {
+            raw
+        };
+        let len = raw.len();
+
+        let decompressed;
+        let raw = if raw[len - 1] as MsgId == msgid::COMPRESSED {
+            let max_size =
+                self.protocol_config.pos_message_size_limits.max_limit();
+            match decompress(&raw[..len - 1], max_size) {
+                Ok(d) => {
+                    decompressed = d;
+                    &decompressed[..]
+                }
+                Err(e) => {
+                    debug!("failed to decompress message: {:?}", e);
+                    return self.handle_error(
+                        io,
+                        peer,
+                        msgid::COMPRESSED,
+                        ErrorKind::InvalidMessageFormat.into(),
+                    );
+                }
+            }
+        } else {
+            raw
+        };
+        let len = raw.len();
+
+        let msg_id = raw[len - 1] as MsgId;
         debug!("on_message: peer={:?}, msgid={:?}", peer, msg_id);
 
         let msg = &raw[0..raw.len() - 1];
-        self.dispatch_message(io, peer, msg_id.into(), msg)
-            .unwrap_or_else(|e| self.handle_error(io, peer, msg_id.into(), e));
+        // Reject oversized messages before decoding them.
+        if !self
+            .protocol_config
+            .pos_message_size_limits
+            .is_allowed(msg_id, msg.len())
+        {
+            debug!(
+                "reject oversized message: peer={:?}, msgid={:?}, size={}",
+                peer,
+                msg_id,
+                msg.len()
+            );
+            counters::NETWORK_MSGS_OVERSIZED
+                .with_label_values(&[&msg_id.to_string()])
+                .inc();
+            return self.handle_error_as(
+                io,
+                peer,
+                msg_id,
+                ErrorKind::InvalidMessageFormat.into(),
+                Some(ProtocolViolationKind::OversizedMessage),
+            );
+        }
+        self.dispatch_message(io, peer, msg_id, msg)
+            .unwrap_or_else(|e| self.handle_error(io, peer, msg_id, e));
     }
 
     fn on_peer_connected(
         &self, io: &dyn NetworkContext, node_id: &NodeId,
-        _peer_protocol_version: ProtocolVersion,
+        peer_protocol_version: ProtocolVersion,
         pos_public_key: Option<(ConsensusPublicKey, ConsensusVRFPublicKey)>,
     )
     {
-        // TODO(linxi): maintain peer protocol version
         let new_originated = io.get_peer_connection_origin(node_id);
         if new_originated.is_none() {
             debug!("Peer does not exist when just connected");
             return;
         }
         let new_originated = new_originated.unwrap();
+        if self.peer_blacklist.is_blacklisted(node_id) {
+            debug!("Disconnect blacklisted peer {:?}", node_id);
+            io.disconnect_peer(
+                node_id,
+                Some(UpdateNodeOperation::Failure),
+                "blacklisted peer",
+            );
+            return;
+        }
+        if peer_protocol_version
+            < self.protocol_config.pos_min_peer_protocol_version
+        {
+            debug!(
+                "Disconnect peer {:?} of protocol version {}",
+                node_id, peer_protocol_version.0
+            );
+            self.pending_handshakes
+                .fail(node_id, HandshakeFailure::VersionMismatch);
+            self.set_disconnect_reason(
+                node_id,
+                DisconnectReason::IncompatibleVersion,
+            );
+            io.disconnect_peer(
+                node_id,
+                Some(UpdateNodeOperation::Failure),
+                "incompatible protocol version",
+            );
+            return;
+        }
         let peer_hash = keccak(node_id);
 
         let add_new_peer = if let Some(old_peer) = self.peers.remove(&peer_hash)
@@ -549,6 +1437,10 @@ impl NetworkProtocolHandler for HotStuffSynchronizationProtocol {
                 ) {
                     // Drop the existing connection and replace it with the new
                     // connection.
+                    self.set_disconnect_reason(
+                        old_peer_id,
+                        DisconnectReason::Replaced,
+                    );
                     io.disconnect_peer(
                         old_peer_id,
                         Some(UpdateNodeOperation::Failure),
@@ -570,7 +1462,26 @@ impl NetworkProtocolHandler for HotStuffSynchronizationProtocol {
                 let mut state = state.write();
                 state.id = *node_id;
                 state.peer_hash = peer_hash;
+                // `ProtocolVersion` is only `PartialOrd`.
+                state.protocol_version =
+                    if peer_protocol_version < HSB_PROTOCOL_VERSION {
+                        peer_protocol_version
+                    } else {
+                        HSB_PROTOCOL_VERSION
+                    };
+                counters::NETWORK_NEGOTIATED_PROTOCOL_VERSIONS
+                    .with_label_values(&[&state.protocol_version.0.to_string()])
+                    .inc();
+                let protocol_version = state.protocol_version;
+                drop(state);
                 self.request_manager.on_peer_connected(node_id);
+                self.peer_liveness
+                    .add_peer(node_id, protocol_version >= HSB_PROTOCOL_V5);
+                self.peer_events
+                    .publish(ConsensusPeerEvent::Connected { peer: *node_id });
+                // The handshake is sent before any other message.
+                self.send_chain_id_handshake(io, node_id, protocol_version);
+                self.negotiate_codec(io, node_id, protocol_version);
             } else {
                 warn!(
                     "PeerState is missing for peer: peer_hash={:?}",
@@ -578,6 +1489,7 @@ impl NetworkProtocolHandler for HotStuffSynchronizationProtocol {
                 );
             }
         } else {
+            self.set_disconnect_reason(node_id, DisconnectReason::Replaced);
             io.disconnect_peer(
                 node_id,
                 Some(UpdateNodeOperation::Failure),
@@ -586,11 +1498,25 @@ impl NetworkProtocolHandler for HotStuffSynchronizationProtocol {
         }
 
         if let Some(public_key) = pos_public_key {
-            self.pos_peer_mapping.write().insert(
-                from_consensus_public_key(&public_key.0, &public_key.1),
-                peer_hash,
-            );
+            let account_address =
+                from_consensus_public_key(&public_key.0, &public_key.1);
+            self.pos_peer_mapping
+                .write()
+                .insert(account_address, peer_hash);
             if add_new_peer {
+                self.pos_node_id_cache
+                    .write()
+                    .insert(account_address, *node_id);
+                if self
+                    .validator_voting_powers
+                    .read()
+                    .contains_key(&account_address)
+                {
+                    if let Some(peers) = self.validator_peers.write().as_mut() {
+                        peers.insert(*node_id);
+                    }
+                }
+                self.refresh_peer_voting_powers();
                 let event = NetworkEvent::PeerConnected;
                 if let Err(e) = self
                     .mempool_network_task
@@ -625,15 +1551,18 @@ impl NetworkProtocolHandler for HotStuffSynchronizationProtocol {
 
     fn on_peer_disconnected(&self, io: &dyn NetworkContext, peer: &NodeId) {
         let peer_hash = keccak(*peer);
-        if let Some(peer_state) = self.peers.remove(&peer_hash) {
-            if let Some(pos_public_key) = &peer_state.read().pos_public_key {
-                self.pos_peer_mapping.write().remove(
-                    &from_consensus_public_key(
-                        &pos_public_key.0,
-                        &pos_public_key.1,
-                    ),
-                );
-            }
+        // The peer is closed by itself or the network unless we have
+        // disconnected it.
+        let reason = self
+            .disconnect_reasons
+            .lock()
+            .remove(peer)
+            .unwrap_or(DisconnectReason::Closed);
+        if self.remove_peer(&peer_hash) {
+            self.peer_events.publish(ConsensusPeerEvent::Disconnected {
+                peer: *peer,
+                reason,
+            });
         }
         // notify pos mempool
         let event = NetworkEvent::PeerDisconnected;
@@ -646,6 +1575,15 @@ impl NetworkProtocolHandler for HotStuffSynchronizationProtocol {
         }
 
         self.request_manager.on_peer_disconnected(io, peer);
+        self.send_rate_limiter.remove_peer(peer);
+        self.send_queues.remove_peer(peer);
+        self.epoch_change_chunks.remove_peer(peer);
+        self.peer_activity.remove_peer(peer);
+        self.peer_liveness.remove_peer(peer);
+        self.block_streams.remove_peer(peer);
+        self.pending_handshakes.remove_peer(peer);
+        self.sequence_numbers.remove_peer(peer);
+        self.replay_guard.remove_peer(peer);
         debug!(
             "hsb on_peer_disconnected: peer={}, peer count {}",
             peer,
@@ -658,7 +1596,9 @@ impl NetworkProtocolHandler for HotStuffSynchronizationProtocol {
         match timer {
             CHECK_RPC_REQUEST_TIMER => {
                 self.remove_expired_flying_request(io);
+                self.disconnect_handshake_timeouts(io);
             }
+            CHECK_PEER_LIVENESS_TIMER => self.check_peer_liveness(io),
             _ => warn!("hsb protocol: unknown timer {} triggered.", timer),
         }
     }
@@ -676,7 +1616,37 @@ pub trait Handleable {
     fn handle(self, ctx: &Context) -> Result<(), Error>;
 }
 
-pub trait RpcResponse: Send + Sync + Debug + AsAny {}
+pub trait RpcResponse: Send + Sync + Debug + AsAny {
+    /// Convert the boxed response to `Box<dyn Any>` so it can be downcast
+    /// by value.
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+
+    /// The name of the concrete response type, for error messages.
+    fn type_name(&self) -> &'static str { type_name::<Self>() }
+}
+
+/// The response of an RPC together with the peer that sent it, which may
+/// not be the peer the RPC is first sent to if it is resent after a timeout.
+#[derive(Debug)]
+pub struct RpcResponseWithPeer {
+    pub peer: NodeId,
+    pub response: Box<dyn RpcResponse>,
+}
+
+impl dyn RpcResponse {
+    /// Downcast the response returned by `send_rpc` to its concrete type.
+    pub fn into_typed<T: RpcResponse + 'static>(
+        self: Box<Self>,
+    ) -> Result<Box<T>, NetworkError> {
+        let actual = self.type_name();
+        self.into_any().downcast::<T>().map_err(|_| {
+            NetworkError::UnexpectedRpcResponseType {
+                expected: type_name::<T>(),
+                actual,
+            }
+        })
+    }
+}
 
 impl From<bcs::Error> for Error {
     fn from(_: bcs::Error) -> Self { ErrorKind::InvalidMessageFormat.into() }
@@ -687,3 +1657,886 @@ impl From<anyhow::Error> for Error {
         ErrorKind::InternalError(format!("{}", error)).into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        HotStuffSynchronizationProtocol, RpcResponse, CHECK_PEER_LIVENESS_TIMER,
+    };
+    use crate::{
+        message::{Message, MsgId},
+        pos::{
+            consensus::{
+                counters,
+                network::{
+                    peer_msg_key, BackpressureConfig, ConsensusMsg,
+                    ConsensusQueueConfig, NetworkTask as ConsensusNetworkTask,
+                },
+            },
+            mempool::network::NetworkTask as MempoolNetworkTask,
+            protocol::{
+                blacklist::PeerBlacklistConfig,
+                error::NetworkError,
+                liveness::PeerLivenessConfig,
+                message::{
+                    block_retrieval::BlockRetrievalRpcRequest,
+                    block_retrieval_response::BlockRetrievalRpcResponse,
+                    chain_id_handshake::ChainIdHandshake, codec::CodecKind,
+                    msgid, with_sync_info::WithSyncInfo,
+                },
+                peer_event::{ConsensusPeerEvent, DisconnectReason},
+                replay_guard::stamp,
+                request_manager::AsAny,
+                test_utils::MockNetworkContext,
+                HSB_PROTOCOL_V10, HSB_PROTOCOL_V11, HSB_PROTOCOL_V13,
+                HSB_PROTOCOL_V14, HSB_PROTOCOL_V3, HSB_PROTOCOL_V4,
+                HSB_PROTOCOL_V5,
+            },
+        },
+        sync::ProtocolConfiguration,
+    };
+    use cfx_types::H256;
+    use channel::message_queues::QueueStyle;
+    use consensus_types::{
+        block::Block,
+        block_retrieval::{
+            BlockRetrievalRequest, BlockRetrievalResponse, BlockRetrievalStatus,
+        },
+        proposal_msg::ProposalMsg,
+        quorum_cert::QuorumCert,
+        sync_info::SyncInfo,
+        vote::Vote,
+        vote_data::VoteData,
+        vote_msg::VoteMsg,
+    };
+    use diem_crypto::HashValue;
+    use diem_types::{
+        block_info::BlockInfo,
+        ledger_info::{LedgerInfo, LedgerInfoWithSignatures},
+        validator_signer::ValidatorSigner,
+    };
+    use futures::{FutureExt, StreamExt};
+    use keccak_hash::keccak;
+    use network::{node_table::NodeId, NetworkProtocolHandler};
+    use std::{any::Any, collections::BTreeMap, time::Duration};
+
+    #[derive(Debug)]
+    struct OtherRpcResponse;
+
+    impl AsAny for OtherRpcResponse {
+        fn as_any(&self) -> &dyn Any { self }
+
+        fn as_any_mut(&mut self) -> &mut dyn Any { self }
+    }
+
+    impl RpcResponse for OtherRpcResponse {
+        fn into_any(self: Box<Self>) -> Box<dyn Any> { self }
+    }
+
+    fn block_retrieval_response() -> BlockRetrievalRpcResponse {
+        BlockRetrievalRpcResponse {
+            request_id: 1,
+            response: BlockRetrievalResponse::new(
+                BlockRetrievalStatus::Succeeded,
+                vec![Block::make_genesis_block()],
+            ),
+        }
+    }
+
+    #[test]
+    fn test_into_typed() {
+        let response: Box<dyn RpcResponse> =
+            Box::new(block_retrieval_response());
+        let typed = response.into_typed::<BlockRetrievalRpcResponse>().unwrap();
+        assert_eq!(*typed, block_retrieval_response());
+    }
+
+    #[test]
+    fn test_into_typed_mismatch() {
+        let response: Box<dyn RpcResponse> =
+            Box::new(block_retrieval_response());
+        match response.into_typed::<OtherRpcResponse>() {
+            Err(NetworkError::UnexpectedRpcResponseType {
+                expected,
+                actual,
+            }) => {
+                assert!(expected.ends_with("OtherRpcResponse"));
+                assert!(actual.ends_with("BlockRetrievalRpcResponse"));
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_malformed_message_dropped() {
+        let (consensus_network_task, mut receivers) =
+            ConsensusNetworkTask::new();
+        let handler = HotStuffSynchronizationProtocol::new(
+            H256::zero(),
+            consensus_network_task,
+            MempoolNetworkTask::new().0,
+            ProtocolConfiguration::default(),
+        );
+        let io = MockNetworkContext::default();
+        let peer = NodeId::from_low_u64_be(1);
+        let peer_signer = ValidatorSigner::from_int(1);
+        handler.peers.insert(
+            keccak(&peer),
+            peer,
+            Some((
+                peer_signer.public_key(),
+                peer_signer.vrf_public_key().unwrap(),
+            )),
+        );
+
+        let ledger_info =
+            LedgerInfo::new(BlockInfo::empty(), HashValue::zero());
+        let qc = QuorumCert::certificate_for_genesis_from_ledger_info(
+            &ledger_info,
+            HashValue::zero(),
+        );
+        let vote_msg = |signer: &ValidatorSigner| {
+            VoteMsg::new(
+                Vote::new(
+                    VoteData::new(BlockInfo::empty(), BlockInfo::empty()),
+                    signer.author(),
+                    ledger_info.clone(),
+                    signer,
+                ),
+                SyncInfo::new(qc.clone(), qc.clone(), None),
+            )
+        };
+        // The votes of different authors are queued separately.
+        let signers =
+            vec![ValidatorSigner::from_int(2), ValidatorSigner::from_int(3)];
+        handler.on_message(&io, &peer, &vote_msg(&signers[0]).encode());
+        handler.on_message(&io, &peer, &[0xff, 0xff, msgid::VOTE as u8]);
+        handler.on_message(&io, &peer, &vote_msg(&signers[1]).encode());
+
+        let mut authors = vec![];
+        while let Some(Some((_, msg))) =
+            receivers.consensus_messages.next().now_or_never()
+        {
+            match msg {
+                ConsensusMsg::VoteMsg(vote_msg) => {
+                    authors.push(vote_msg.vote().author())
+                }
+                _ => panic!("unexpected message"),
+            }
+        }
+        authors.sort();
+        assert_eq!(authors, vec![signers[0].author(), signers[1].author()]);
+        assert!(io.disconnected.lock().is_empty());
+    }
+
+    #[test]
+    fn test_cross_chain_peer_rejected() {
+        let (consensus_network_task, mut receivers) =
+            ConsensusNetworkTask::new();
+        let handler = HotStuffSynchronizationProtocol::new(
+            H256::zero(),
+            consensus_network_task,
+            MempoolNetworkTask::new().0,
+            ProtocolConfiguration {
+                pos_chain_id: 1,
+                ..Default::default()
+            },
+        );
+        let io = MockNetworkContext::default();
+        let same_chain = NodeId::from_low_u64_be(1);
+        let other_chain = NodeId::from_low_u64_be(2);
+        for (i, peer) in [same_chain, other_chain].iter().enumerate() {
+            let peer_signer = ValidatorSigner::from_int(10 + i as u8);
+            handler.on_peer_connected(
+                &io,
+                peer,
+                HSB_PROTOCOL_V3,
+                Some((
+                    peer_signer.public_key(),
+                    peer_signer.vrf_public_key().unwrap(),
+                )),
+            );
+        }
+        // Our handshake is sent to both.
+        assert_eq!(*io.sent.lock(), vec![same_chain, other_chain]);
+
+        let signer = ValidatorSigner::from_int(1);
+        let ledger_info =
+            LedgerInfo::new(BlockInfo::empty(), HashValue::zero());
+        let qc = QuorumCert::certificate_for_genesis_from_ledger_info(
+            &ledger_info,
+            HashValue::zero(),
+        );
+        let vote_msg = VoteMsg::new(
+            Vote::new(
+                VoteData::new(BlockInfo::empty(), BlockInfo::empty()),
+                signer.author(),
+                ledger_info,
+                &signer,
+            ),
+            SyncInfo::new(qc.clone(), qc, None),
+        )
+        .encode();
+        let rejected = counters::NETWORK_CROSS_CHAIN_PEERS_REJECTED.get();
+
+        // A message before the handshake is dropped.
+        handler.on_message(&io, &same_chain, &vote_msg);
+        assert!(receivers.consensus_messages.next().now_or_never().is_none());
+
+        handler.on_message(
+            &io,
+            &other_chain,
+            &ChainIdHandshake { chain_id: 2 }.encode(),
+        );
+        handler.on_message(&io, &other_chain, &vote_msg);
+        assert_eq!(*io.disconnected.lock(), vec![other_chain]);
+        // Other tests may reject peers at the same time.
+        assert!(counters::NETWORK_CROSS_CHAIN_PEERS_REJECTED.get() > rejected);
+
+        handler.on_message(
+            &io,
+            &same_chain,
+            &ChainIdHandshake { chain_id: 1 }.encode(),
+        );
+        handler.on_message(&io, &same_chain, &vote_msg);
+        let (_, msg) = receivers
+            .consensus_messages
+            .next()
+            .now_or_never()
+            .expect("vote of the same chain received")
+            .unwrap();
+        assert!(matches!(msg, ConsensusMsg::VoteMsg(_)));
+        assert!(receivers.consensus_messages.next().now_or_never().is_none());
+        assert_eq!(*io.disconnected.lock(), vec![other_chain]);
+    }
+
+    #[test]
+    fn test_version_mismatch_handshake_failure() {
+        let handler = HotStuffSynchronizationProtocol::new(
+            H256::zero(),
+            ConsensusNetworkTask::new().0,
+            MempoolNetworkTask::new().0,
+            ProtocolConfiguration {
+                pos_min_peer_protocol_version: HSB_PROTOCOL_V5,
+                ..Default::default()
+            },
+        );
+        let io = MockNetworkContext::default();
+        let failures = || {
+            counters::NETWORK_HANDSHAKE_FAILURES
+                .with_label_values(&["version_mismatch"])
+                .get()
+        };
+        let (failed, handshakes) = (
+            failures(),
+            counters::NETWORK_HANDSHAKE_DURATION_S.get_sample_count(),
+        );
+
+        let old_peer = NodeId::from_low_u64_be(1);
+        handler.on_peer_connected(&io, &old_peer, HSB_PROTOCOL_V4, None);
+        assert_eq!(*io.disconnected.lock(), vec![old_peer]);
+        assert!(handler.peers.get(&keccak(&old_peer)).is_none());
+        // Our handshake is not sent to it.
+        assert!(io.sent.lock().is_empty());
+        // Other tests may fail handshakes at the same time.
+        assert!(failures() > failed);
+
+        let new_peer = NodeId::from_low_u64_be(2);
+        handler.on_peer_connected(&io, &new_peer, HSB_PROTOCOL_V5, None);
+        handler.on_message(
+            &io,
+            &new_peer,
+            &ChainIdHandshake { chain_id: 0 }.encode(),
+        );
+        assert_eq!(*io.disconnected.lock(), vec![old_peer]);
+        assert!(
+            counters::NETWORK_HANDSHAKE_DURATION_S.get_sample_count()
+                > handshakes
+        );
+    }
+
+    #[test]
+    fn test_backpressured_retrieval_answered_busy() {
+        let (consensus_network_task, _receivers) =
+            ConsensusNetworkTask::new_with_queue_config(ConsensusQueueConfig {
+                queue_style: QueueStyle::FIFO,
+                max_queue_size_per_key: 16,
+                backpressure: Some(BackpressureConfig {
+                    high_water_mark: 1,
+                    low_water_mark: 0,
+                }),
+            });
+        let handler = HotStuffSynchronizationProtocol::new(
+            H256::zero(),
+            consensus_network_task,
+            MempoolNetworkTask::new().0,
+            ProtocolConfiguration::default(),
+        );
+        let io = MockNetworkContext::default();
+        let (new_peer, old_peer) =
+            (NodeId::from_low_u64_be(1), NodeId::from_low_u64_be(2));
+        for (i, (peer, version)) in
+            [(new_peer, HSB_PROTOCOL_V11), (old_peer, HSB_PROTOCOL_V10)]
+                .iter()
+                .enumerate()
+        {
+            let peer_signer = ValidatorSigner::from_int(i as u8 + 1);
+            handler.on_peer_connected(
+                &io,
+                peer,
+                *version,
+                Some((
+                    peer_signer.public_key(),
+                    peer_signer.vrf_public_key().unwrap(),
+                )),
+            );
+            handler.on_message(
+                &io,
+                peer,
+                &ChainIdHandshake { chain_id: 0 }.encode(),
+            );
+        }
+
+        // Consensus is behind.
+        let qc = QuorumCert::certificate_for_genesis_from_ledger_info(
+            &LedgerInfo::new(BlockInfo::empty(), HashValue::zero()),
+            HashValue::zero(),
+        );
+        let msg = ConsensusMsg::SyncInfo(Box::new(SyncInfo::new(
+            qc.clone(),
+            qc,
+            None,
+        )));
+        let author = ValidatorSigner::from_int(3).author();
+        let consensus_messages_tx =
+            &handler.consensus_network_task.consensus_messages_tx;
+        consensus_messages_tx
+            .push(peer_msg_key(author, &msg), (author, msg))
+            .unwrap();
+        assert!(consensus_messages_tx.is_backpressured());
+
+        let request = BlockRetrievalRpcRequest {
+            request_id: 7,
+            request: BlockRetrievalRequest::new(HashValue::random(), 1),
+            is_empty: false,
+            response_tx: None,
+            coalesced_tx: Vec::new(),
+            timeout: Duration::from_secs(0),
+        }
+        .encode();
+        let answer = |status| {
+            BlockRetrievalRpcResponse {
+                request_id: 7,
+                response: BlockRetrievalResponse::new(status, vec![]),
+            }
+            .encode()
+        };
+        for (peer, status) in &[
+            (new_peer, BlockRetrievalStatus::Busy),
+            // The old peer cannot decode the busy status.
+            (old_peer, BlockRetrievalStatus::IdNotFound),
+        ] {
+            io.sent.lock().clear();
+            io.payloads.lock().clear();
+            handler.on_message(&io, peer, &request);
+            assert_eq!(*io.sent.lock(), vec![*peer]);
+            assert_eq!(*io.payloads.lock(), vec![answer(status.clone())]);
+        }
+    }
+
+    #[test]
+    fn test_with_sync_info_split() {
+        let (consensus_network_task, mut receivers) =
+            ConsensusNetworkTask::new();
+        let handler = HotStuffSynchronizationProtocol::new(
+            H256::zero(),
+            consensus_network_task,
+            MempoolNetworkTask::new().0,
+            ProtocolConfiguration::default(),
+        );
+        let io = MockNetworkContext::default();
+        let peer = NodeId::from_low_u64_be(1);
+        let peer_signer = ValidatorSigner::from_int(1);
+        handler.on_peer_connected(
+            &io,
+            &peer,
+            HSB_PROTOCOL_V4,
+            Some((
+                peer_signer.public_key(),
+                peer_signer.vrf_public_key().unwrap(),
+            )),
+        );
+        handler.on_message(
+            &io,
+            &peer,
+            &ChainIdHandshake { chain_id: 0 }.encode(),
+        );
+
+        let signer = ValidatorSigner::from_int(2);
+        let ledger_info =
+            LedgerInfo::new(BlockInfo::empty(), HashValue::zero());
+        let qc = QuorumCert::certificate_for_genesis_from_ledger_info(
+            &ledger_info,
+            HashValue::zero(),
+        );
+        let sync_info = SyncInfo::new(qc.clone(), qc, None);
+        let vote_msg = VoteMsg::new(
+            Vote::new(
+                VoteData::new(BlockInfo::empty(), BlockInfo::empty()),
+                signer.author(),
+                ledger_info,
+                &signer,
+            ),
+            sync_info.clone(),
+        );
+        let combined = WithSyncInfo {
+            sync_info,
+            msg: ConsensusMsg::VoteMsg(Box::new(vote_msg)),
+        };
+        assert_eq!(combined.msg_id(), msgid::WITH_SYNC_INFO);
+        handler.on_message(&io, &peer, &combined.encode());
+
+        // Both parts are handled as if they are sent separately.
+        let mut names = vec![];
+        while let Some(Some((_, msg))) =
+            receivers.consensus_messages.next().now_or_never()
+        {
+            names.push(msg.name());
+        }
+        names.sort();
+        assert_eq!(names, vec!["SyncInfo", "VoteMsg"]);
+        assert!(io.disconnected.lock().is_empty());
+    }
+
+    #[test]
+    fn test_decode_time_recorded() {
+        let handler = HotStuffSynchronizationProtocol::new(
+            H256::zero(),
+            ConsensusNetworkTask::new().0,
+            MempoolNetworkTask::new().0,
+            ProtocolConfiguration::default(),
+        );
+        let io = MockNetworkContext::default();
+        let peer = NodeId::from_low_u64_be(1);
+        handler.peers.insert(keccak(&peer), peer, None);
+
+        let response = BlockRetrievalRpcResponse {
+            request_id: 1,
+            response: BlockRetrievalResponse::new(
+                BlockRetrievalStatus::Succeeded,
+                vec![Block::make_genesis_block(); 1000],
+            ),
+        };
+        let decode_time = counters::NETWORK_MSG_DECODE_S
+            .with_label_values(&[response.msg_name()]);
+        let (count, sum) =
+            (decode_time.get_sample_count(), decode_time.get_sample_sum());
+        // The response answers no request, which only matters after it is
+        // decoded.
+        handler.on_message(&io, &peer, &response.encode());
+        assert_eq!(decode_time.get_sample_count(), count + 1);
+        assert!(decode_time.get_sample_sum() > sum);
+    }
+
+    #[test]
+    fn test_unresponsive_peer_disconnected() {
+        let handler = HotStuffSynchronizationProtocol::new(
+            H256::zero(),
+            ConsensusNetworkTask::new().0,
+            MempoolNetworkTask::new().0,
+            ProtocolConfiguration {
+                pos_peer_liveness: PeerLivenessConfig {
+                    ping_interval: Duration::from_secs(10),
+                    max_missed_pongs: 2,
+                },
+                ..Default::default()
+            },
+        );
+        let io = MockNetworkContext::default();
+        let silent = NodeId::from_low_u64_be(1);
+        let old = NodeId::from_low_u64_be(2);
+        handler.on_peer_connected(&io, &silent, HSB_PROTOCOL_V5, None);
+        handler.on_peer_connected(&io, &old, HSB_PROTOCOL_V4, None);
+        let mut events = handler.subscribe_peer_events();
+        io.sent.lock().clear();
+
+        // The peer before `HSB_PROTOCOL_V5` is never pinged.
+        handler.on_timeout(&io, CHECK_PEER_LIVENESS_TIMER);
+        assert_eq!(*io.sent.lock(), vec![silent]);
+        handler.on_timeout(&io, CHECK_PEER_LIVENESS_TIMER);
+        assert_eq!(*io.sent.lock(), vec![silent, silent]);
+        assert!(io.disconnected.lock().is_empty());
+        assert!(handler.peer_liveness.status(&silent).unwrap().healthy);
+
+        // The second missed pong makes the peer unhealthy.
+        handler.on_timeout(&io, CHECK_PEER_LIVENESS_TIMER);
+        assert_eq!(io.sent.lock().len(), 2);
+        assert_eq!(*io.disconnected.lock(), vec![silent]);
+        assert!(!handler.peer_liveness.status(&silent).unwrap().healthy);
+        assert!(handler.peer_liveness.status(&old).unwrap().healthy);
+
+        handler.on_peer_disconnected(&io, &silent);
+        assert_eq!(
+            events.try_recv().unwrap(),
+            ConsensusPeerEvent::Disconnected {
+                peer: silent,
+                reason: DisconnectReason::Unresponsive,
+            }
+        );
+        assert!(handler.peer_liveness.status(&silent).is_none());
+    }
+
+    #[test]
+    fn test_proposal_before_parent() {
+        let (consensus_network_task, mut receivers) =
+            ConsensusNetworkTask::new();
+        let handler = HotStuffSynchronizationProtocol::new(
+            H256::zero(),
+            consensus_network_task,
+            MempoolNetworkTask::new().0,
+            ProtocolConfiguration::default(),
+        );
+        let io = MockNetworkContext::default();
+        let peer = NodeId::from_low_u64_be(1);
+        let peer_signer = ValidatorSigner::from_int(1);
+        handler.on_peer_connected(
+            &io,
+            &peer,
+            HSB_PROTOCOL_V5,
+            Some((
+                peer_signer.public_key(),
+                peer_signer.vrf_public_key().unwrap(),
+            )),
+        );
+        handler.on_message(
+            &io,
+            &peer,
+            &ChainIdHandshake { chain_id: 0 }.encode(),
+        );
+
+        let signer = ValidatorSigner::from_int(2);
+        let child_of = |parent: &Block, round| {
+            let qc = QuorumCert::new(
+                VoteData::new(
+                    parent.gen_block_info(HashValue::zero(), 0, None, None),
+                    parent.quorum_cert().certified_block().clone(),
+                ),
+                LedgerInfoWithSignatures::new(
+                    LedgerInfo::new(BlockInfo::empty(), HashValue::zero()),
+                    BTreeMap::new(),
+                ),
+            );
+            ProposalMsg::new(
+                Block::new_proposal(vec![], round, round, qc.clone(), &signer),
+                SyncInfo::new(qc.clone(), qc, None),
+            )
+        };
+        let first = child_of(&Block::make_genesis_block(), 1);
+        let parent = child_of(first.proposal(), 2);
+        let proposal = child_of(parent.proposal(), 3);
+        let mut delivered = || {
+            let mut ids = vec![];
+            while let Some(Some((_, msg))) =
+                receivers.consensus_messages.next().now_or_never()
+            {
+                match msg {
+                    ConsensusMsg::ProposalMsg(p) => ids.push(p.proposal().id()),
+                    _ => panic!("unexpected message"),
+                }
+            }
+            ids
+        };
+
+        handler.on_message(&io, &peer, &first.encode());
+        assert_eq!(delivered(), vec![first.proposal().id()]);
+        io.sent.lock().clear();
+
+        // The proposal is held back, and its parent is retrieved from the
+        // peer.
+        handler.on_message(&io, &peer, &proposal.encode());
+        assert!(delivered().is_empty());
+        assert_eq!(*io.sent.lock(), vec![peer]);
+        assert_eq!(handler.pending_proposals.num_pending(), 1);
+
+        let response = BlockRetrievalRpcResponse {
+            request_id: 0,
+            response: BlockRetrievalResponse::new(
+                BlockRetrievalStatus::Succeeded,
+                vec![parent.proposal().clone()],
+            ),
+        };
+        handler.on_message(&io, &peer, &response.encode());
+        assert_eq!(delivered(), vec![proposal.proposal().id()]);
+        assert_eq!(handler.pending_proposals.num_pending(), 0);
+        assert!(io.disconnected.lock().is_empty());
+    }
+
+    #[test]
+    fn test_subscribe_incoming() {
+        let (consensus_network_task, _receivers) = ConsensusNetworkTask::new();
+        let handler = HotStuffSynchronizationProtocol::new(
+            H256::zero(),
+            consensus_network_task,
+            MempoolNetworkTask::new().0,
+            ProtocolConfiguration::default(),
+        );
+        let io = MockNetworkContext::default();
+        let peer = NodeId::from_low_u64_be(1);
+        let peer_signer = ValidatorSigner::from_int(1);
+        handler.on_peer_connected(
+            &io,
+            &peer,
+            HSB_PROTOCOL_V5,
+            Some((
+                peer_signer.public_key(),
+                peer_signer.vrf_public_key().unwrap(),
+            )),
+        );
+        handler.on_message(
+            &io,
+            &peer,
+            &ChainIdHandshake { chain_id: 0 }.encode(),
+        );
+        let mut incoming = Box::pin(handler.subscribe_incoming());
+
+        let signer = ValidatorSigner::from_int(2);
+        let ledger_info =
+            LedgerInfo::new(BlockInfo::empty(), HashValue::zero());
+        let qc = QuorumCert::certificate_for_genesis_from_ledger_info(
+            &ledger_info,
+            HashValue::zero(),
+        );
+        let sync_info = SyncInfo::new(qc.clone(), qc, None);
+        let vote_msg = VoteMsg::new(
+            Vote::new(
+                VoteData::new(BlockInfo::empty(), BlockInfo::empty()),
+                signer.author(),
+                ledger_info,
+                &signer,
+            ),
+            sync_info.clone(),
+        );
+        handler.on_message(&io, &peer, &vote_msg.encode());
+        handler.on_message(&io, &peer, &sync_info.encode());
+
+        let mut names = vec![];
+        while let Some(Some((sender, msg))) = incoming.next().now_or_never() {
+            assert_eq!(sender, peer);
+            names.push(msg.name());
+        }
+        assert_eq!(names, vec!["VoteMsg", "SyncInfo"]);
+    }
+
+    #[test]
+    fn test_blacklisted_peer_dropped() {
+        let (consensus_network_task, mut receivers) =
+            ConsensusNetworkTask::new();
+        let mut config = ProtocolConfiguration::default();
+        config.pos_peer_blacklist = PeerBlacklistConfig {
+            ttl: Duration::from_secs(600),
+            max_violations: 1,
+        };
+        let handler = HotStuffSynchronizationProtocol::new(
+            H256::zero(),
+            consensus_network_task,
+            MempoolNetworkTask::new().0,
+            config,
+        );
+        let io = MockNetworkContext::default();
+        let peer = NodeId::from_low_u64_be(1);
+        let peer_signer = ValidatorSigner::from_int(1);
+        let pos_public_key = Some((
+            peer_signer.public_key(),
+            peer_signer.vrf_public_key().unwrap(),
+        ));
+        handler.on_peer_connected(
+            &io,
+            &peer,
+            HSB_PROTOCOL_V5,
+            pos_public_key.clone(),
+        );
+        handler.on_message(
+            &io,
+            &peer,
+            &ChainIdHandshake { chain_id: 0 }.encode(),
+        );
+
+        let signer = ValidatorSigner::from_int(2);
+        let ledger_info =
+            LedgerInfo::new(BlockInfo::empty(), HashValue::zero());
+        let qc = QuorumCert::certificate_for_genesis_from_ledger_info(
+            &ledger_info,
+            HashValue::zero(),
+        );
+        let vote_msg = VoteMsg::new(
+            Vote::new(
+                VoteData::new(BlockInfo::empty(), BlockInfo::empty()),
+                signer.author(),
+                ledger_info,
+                &signer,
+            ),
+            SyncInfo::new(qc.clone(), qc, None),
+        );
+        handler.on_message(&io, &peer, &[0xff, 0xff, msgid::VOTE as u8]);
+        let blacklisted = handler.blacklisted_peers();
+        assert_eq!(blacklisted.len(), 1);
+        assert_eq!(blacklisted[0].0, peer);
+
+        // Nothing of the peer is handled until its entry expires.
+        handler.on_message(&io, &peer, &vote_msg.encode());
+        assert!(receivers.consensus_messages.next().now_or_never().is_none());
+
+        // It is disconnected at once when it connects again.
+        io.disconnected.lock().clear();
+        handler.on_peer_connected(&io, &peer, HSB_PROTOCOL_V5, pos_public_key);
+        assert_eq!(*io.disconnected.lock(), vec![peer]);
+    }
+
+    #[test]
+    fn test_replayed_message_dropped() {
+        let (consensus_network_task, mut receivers) =
+            ConsensusNetworkTask::new();
+        let handler = HotStuffSynchronizationProtocol::new(
+            H256::zero(),
+            consensus_network_task,
+            MempoolNetworkTask::new().0,
+            ProtocolConfiguration::default(),
+        );
+        let io = MockNetworkContext::default();
+        let peer = NodeId::from_low_u64_be(1);
+        let peer_signer = ValidatorSigner::from_int(1);
+        handler.peers.insert(
+            keccak(&peer),
+            peer,
+            Some((
+                peer_signer.public_key(),
+                peer_signer.vrf_public_key().unwrap(),
+            )),
+        );
+
+        let signer = ValidatorSigner::from_int(2);
+        let ledger_info =
+            LedgerInfo::new(BlockInfo::empty(), HashValue::zero());
+        let qc = QuorumCert::certificate_for_genesis_from_ledger_info(
+            &ledger_info,
+            HashValue::zero(),
+        );
+        let vote_msg = ConsensusMsg::VoteMsg(Box::new(VoteMsg::new(
+            Vote::new(
+                VoteData::new(BlockInfo::empty(), BlockInfo::empty()),
+                signer.author(),
+                ledger_info,
+                &signer,
+            ),
+            SyncInfo::new(qc.clone(), qc, None),
+        )));
+        let mut stamped = vote_msg.encode();
+        stamp(&mut stamped, 0, 1);
+        let replayed = counters::NETWORK_MSGS_REPLAYED
+            .with_label_values(&[&peer.to_string()]);
+        let replayed_before = replayed.get();
+
+        handler.on_message(&io, &peer, &stamped);
+        let (_, msg) = receivers
+            .consensus_messages
+            .next()
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert!(matches!(msg, ConsensusMsg::VoteMsg(_)));
+
+        // The copy sent again is dropped.
+        handler.on_message(&io, &peer, &stamped);
+        assert!(receivers.consensus_messages.next().now_or_never().is_none());
+        assert!(replayed.get() > replayed_before);
+        assert!(io.disconnected.lock().is_empty());
+    }
+
+    #[test]
+    fn test_proposal_from_peers_delivered_once() {
+        let (consensus_network_task, mut receivers) =
+            ConsensusNetworkTask::new();
+        let handler = HotStuffSynchronizationProtocol::new(
+            H256::zero(),
+            consensus_network_task,
+            MempoolNetworkTask::new().0,
+            ProtocolConfiguration {
+                pos_seen_msgs_cache_size: 16,
+                ..Default::default()
+            },
+        );
+        let io = MockNetworkContext::default();
+        let peers: Vec<_> = (1..=2).map(NodeId::from_low_u64_be).collect();
+        for (i, peer) in peers.iter().enumerate() {
+            let peer_signer = ValidatorSigner::from_int(i as u8 + 1);
+            handler.peers.insert(
+                keccak(peer),
+                *peer,
+                Some((
+                    peer_signer.public_key(),
+                    peer_signer.vrf_public_key().unwrap(),
+                )),
+            );
+        }
+
+        let signer = ValidatorSigner::from_int(3);
+        let qc = QuorumCert::certificate_for_genesis_from_ledger_info(
+            &LedgerInfo::new(BlockInfo::empty(), HashValue::zero()),
+            HashValue::zero(),
+        );
+        let proposal = ProposalMsg::new(
+            Block::new_proposal(vec![], 1, 1, qc.clone(), &signer),
+            SyncInfo::new(qc.clone(), qc, None),
+        );
+        let seen =
+            counters::NETWORK_MSGS_SEEN.with_label_values(&["ProposalMsg"]);
+        let seen_before = seen.get();
+
+        for peer in &peers {
+            handler.on_message(&io, peer, &proposal.encode());
+        }
+        let (_, msg) = receivers
+            .consensus_messages
+            .next()
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert!(matches!(msg, ConsensusMsg::ProposalMsg(_)));
+        assert!(receivers.consensus_messages.next().now_or_never().is_none());
+        assert!(seen.get() > seen_before);
+        assert!(io.disconnected.lock().is_empty());
+    }
+
+    #[test]
+    fn test_codec_not_negotiated_with_older_peers() {
+        let handler = HotStuffSynchronizationProtocol::new(
+            H256::zero(),
+            ConsensusNetworkTask::new().0,
+            MempoolNetworkTask::new().0,
+            ProtocolConfiguration {
+                pos_consensus_msg_codec: CodecKind::Json,
+                ..Default::default()
+            },
+        );
+        let io = MockNetworkContext::default();
+        let (old_peer, new_peer) =
+            (NodeId::from_low_u64_be(1), NodeId::from_low_u64_be(2));
+        handler.on_peer_connected(&io, &old_peer, HSB_PROTOCOL_V13, None);
+        handler.on_peer_connected(&io, &new_peer, HSB_PROTOCOL_V14, None);
+
+        // Only the new peer is asked, the old one would drop the unknown
+        // msg id.
+        let negotiations: Vec<_> = io
+            .sent
+            .lock()
+            .iter()
+            .zip(io.payloads.lock().iter())
+            .filter(|(_, payload)| {
+                *payload.last().unwrap() as MsgId == msgid::CODEC_NEGOTIATION
+            })
+            .map(|(peer, _)| *peer)
+            .collect();
+        assert_eq!(negotiations, vec![new_peer]);
+        assert_eq!(handler.peers.codec(&old_peer), Some(CodecKind::Bcs));
+    }
+}
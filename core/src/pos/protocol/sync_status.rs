@@ -0,0 +1,111 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+//! Whether the node is caught up or retrieving the blocks it is behind.
+//!
+//! The status is `Syncing` from the time a block retrieval is issued for a
+//! quorum certificate ahead of our blocks, and `Synced` again once the
+//! certified block is inserted, i.e. we have caught the tip shown by the
+//! peers. A failed retrieval keeps the node `Syncing` until a later one
+//! succeeds. The status is published through a watch channel and the gauge
+//! `SYNC_BEHIND_BY_ROUNDS`.
+
+use diem_logger::prelude::diem_debug;
+use tokio::sync::watch;
+
+use crate::pos::consensus::counters;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncStatus {
+    Synced,
+    /// Retrieving the blocks of the `behind_by` rounds above our root.
+    Syncing {
+        behind_by: u64,
+    },
+}
+
+/// Publishes the `SyncStatus`, see the module doc.
+pub struct SyncStatusTracker {
+    status_tx: watch::Sender<SyncStatus>,
+}
+
+impl SyncStatusTracker {
+    pub fn new() -> Self {
+        Self {
+            status_tx: watch::channel(SyncStatus::Synced).0,
+        }
+    }
+
+    pub fn status(&self) -> SyncStatus { *self.status_tx.borrow() }
+
+    /// Watch the status, which is notified whenever it changes.
+    pub fn subscribe(&self) -> watch::Receiver<SyncStatus> {
+        self.status_tx.subscribe()
+    }
+
+    /// Observe a block retrieval issued for the blocks of `behind_by`
+    /// rounds.
+    pub fn on_retrieval_started(&self, behind_by: u64) {
+        self.update(SyncStatus::Syncing { behind_by });
+    }
+
+    /// Observe the retrieved blocks inserted up to the tip.
+    pub fn on_caught_up(&self) { self.update(SyncStatus::Synced); }
+
+    fn update(&self, status: SyncStatus) {
+        let behind_by = match status {
+            SyncStatus::Synced => 0,
+            SyncStatus::Syncing { behind_by } => behind_by,
+        };
+        counters::SYNC_BEHIND_BY_ROUNDS.set(behind_by as i64);
+        self.status_tx.send_if_modified(|current| {
+            if *current == status {
+                return false;
+            }
+            diem_debug!("sync status: {:?} -> {:?}", current, status);
+            *current = status;
+            true
+        });
+    }
+}
+
+impl Default for SyncStatusTracker {
+    fn default() -> Self { Self::new() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SyncStatus, SyncStatusTracker};
+    use crate::pos::consensus::counters;
+
+    #[test]
+    fn test_status_follows_retrievals() {
+        let tracker = SyncStatusTracker::new();
+        let mut status_rx = tracker.subscribe();
+        tracker.on_caught_up();
+        assert!(!status_rx.has_changed().unwrap());
+
+        // The retrievals of a long chain, each closer to the tip.
+        tracker.on_retrieval_started(100);
+        assert!(status_rx.has_changed().unwrap());
+        assert_eq!(
+            *status_rx.borrow_and_update(),
+            SyncStatus::Syncing { behind_by: 100 }
+        );
+        tracker.on_retrieval_started(40);
+        tracker.on_retrieval_started(40);
+        assert_eq!(
+            *status_rx.borrow_and_update(),
+            SyncStatus::Syncing { behind_by: 40 }
+        );
+        assert_eq!(counters::SYNC_BEHIND_BY_ROUNDS.get(), 40);
+        assert!(!status_rx.has_changed().unwrap());
+
+        tracker.on_caught_up();
+        assert!(status_rx.has_changed().unwrap());
+        assert_eq!(*status_rx.borrow_and_update(), SyncStatus::Synced);
+        assert_eq!(tracker.status(), SyncStatus::Synced);
+        assert_eq!(counters::SYNC_BEHIND_BY_ROUNDS.get(), 0);
+    }
+}
@@ -0,0 +1,180 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use cfx_types::H256;
+use io::TimerToken;
+use network::{
+    node_table::NodeId, service::ProtocolVersion, DiscoveryConfiguration,
+    Error, ErrorKind, HandlerWorkType, NetworkConfiguration, NetworkContext,
+    NetworkService, ProtocolId, SendCompletion, UpdateNodeOperation,
+};
+use parking_lot::Mutex;
+use priority_send_queue::SendQueuePriority;
+
+use super::{
+    clock::Clock, network_sender::NetworkSender,
+    sync_protocol::HotStuffSynchronizationProtocol, HSB_PROTOCOL_ID,
+};
+use crate::{
+    pos::{
+        consensus::network::NetworkTask as ConsensusNetworkTask,
+        mempool::network::NetworkTask as MempoolNetworkTask,
+    },
+    sync::ProtocolConfiguration,
+};
+
+/// A sender over a network service that is not started, so every message
+/// sent to a peer fails.
+pub fn unstarted_sender() -> NetworkSender {
+    unstarted_sender_with_config(ProtocolConfiguration::default())
+}
+
+pub fn unstarted_sender_with_config(
+    protocol_config: ProtocolConfiguration,
+) -> NetworkSender {
+    let network = Arc::new(NetworkService::new(NetworkConfiguration::new(
+        1,
+        DiscoveryConfiguration::default(),
+    )));
+    let protocol_handler = Arc::new(HotStuffSynchronizationProtocol::new(
+        H256::zero(),
+        ConsensusNetworkTask::new().0,
+        MempoolNetworkTask::new().0,
+        protocol_config,
+    ));
+    NetworkSender {
+        network,
+        protocol_handler,
+    }
+}
+
+/// A network context that records the messages sent and the peers
+/// disconnected, without a network behind it.
+#[derive(Default)]
+pub struct MockNetworkContext {
+    /// The peers each message is sent to, in order.
+    pub sent: Mutex<Vec<NodeId>>,
+    /// The messages sent, in order.
+    pub payloads: Mutex<Vec<Vec<u8>>>,
+    pub disconnected: Mutex<Vec<NodeId>>,
+    /// The peers whose sessions are closed. All the other peers have a live
+    /// session.
+    pub dead_sessions: Mutex<HashSet<NodeId>>,
+    /// The peers the transport fails to send to.
+    pub failed_sends: Mutex<HashSet<NodeId>>,
+}
+
+impl NetworkContext for MockNetworkContext {
+    fn get_protocol(&self) -> ProtocolId { HSB_PROTOCOL_ID }
+
+    fn get_peer_connection_origin(&self, node_id: &NodeId) -> Option<bool> {
+        if self.dead_sessions.lock().contains(node_id) {
+            None
+        } else {
+            Some(true)
+        }
+    }
+
+    fn send(
+        &self, node_id: &NodeId, msg: Vec<u8>,
+        _min_protocol_version: ProtocolVersion,
+        _version_valid_till: ProtocolVersion, _priority: SendQueuePriority,
+    ) -> Result<(), Error>
+    {
+        if self.failed_sends.lock().contains(node_id) {
+            return Err(ErrorKind::OversizedPacket.into());
+        }
+        self.sent.lock().push(*node_id);
+        self.payloads.lock().push(msg);
+        Ok(())
+    }
+
+    fn send_with_completion(
+        &self, node_id: &NodeId, msg: Vec<u8>,
+        min_protocol_version: ProtocolVersion,
+        version_valid_till: ProtocolVersion, priority: SendQueuePriority,
+        completion: SendCompletion,
+    ) -> Result<(), Error>
+    {
+        self.send(
+            node_id,
+            msg,
+            min_protocol_version,
+            version_valid_till,
+            priority,
+        )?;
+        completion.complete(true);
+        Ok(())
+    }
+
+    fn disconnect_peer(
+        &self, node_id: &NodeId, _op: Option<UpdateNodeOperation>,
+        _reason: &str,
+    )
+    {
+        self.disconnected.lock().push(*node_id);
+    }
+
+    fn register_timer(
+        &self, _token: TimerToken, _delay: Duration,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn dispatch_work(&self, _work_type: HandlerWorkType) {}
+
+    fn insert_peer_node_tag(&self, _peer: NodeId, _key: &str, _value: &str) {}
+
+    fn is_peer_self(&self, _node_id: &NodeId) -> bool { false }
+
+    fn self_node_id(&self) -> NodeId { NodeId::default() }
+}
+
+thread_local! {
+    static MOCK_CONTEXT: RefCell<Option<Arc<MockNetworkContext>>> =
+        RefCell::new(None);
+}
+
+/// Run `f` with the messages of the `NetworkSender`s sent within `io` on
+/// this thread, instead of the network service, so the full sending paths
+/// can be tested without a started network.
+pub fn with_mock_context<R>(
+    io: Arc<MockNetworkContext>, f: impl FnOnce() -> R,
+) -> R {
+    MOCK_CONTEXT.with(|context| *context.borrow_mut() = Some(io));
+    let result = f();
+    MOCK_CONTEXT.with(|context| *context.borrow_mut() = None);
+    result
+}
+
+/// The context set by `with_mock_context`, if any.
+pub fn mock_context() -> Option<Arc<MockNetworkContext>> {
+    MOCK_CONTEXT.with(|context| context.borrow().clone())
+}
+
+/// A clock that only moves when the test advances it.
+pub struct MockClock {
+    now: Mutex<Instant>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            now: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) { *self.now.lock() += duration; }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant { *self.now.lock() }
+}
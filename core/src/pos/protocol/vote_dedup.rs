@@ -0,0 +1,164 @@
+// Copyright 2019-2020 Conflux Foundation. All rights reserved.
+// TreeGraph is free software and distributed under Apache License 2.0.
+// See https://www.apache.org/licenses/LICENSE-2.0
+
+//! The dedup of the votes received before they reach consensus.
+//!
+//! A peer whose round times out sends its vote again, and consensus would
+//! verify the signature of each copy. The votes are keyed by the hash of
+//! their whole encoding, signatures included, and a copy of a vote already
+//! delivered is dropped. The votes are not verified yet, so a vote copied
+//! with another signature is not taken as a copy and cannot suppress the
+//! genuine one. Two votes of the same author and round signing different
+//! `LedgerInfo`s are equivocating, and both are delivered so consensus sees
+//! them. A vote sent again with a timeout signature is delivered once more,
+//! since the timeout certificates are aggregated from it.
+//!
+//! Only the votes of the highest round received from each author are kept:
+//! the votes of an author are pruned when a vote of a higher round arrives
+//! from it.
+
+use std::collections::{HashMap, HashSet};
+
+use consensus_types::{common::Round, vote::Vote};
+use diem_crypto::HashValue;
+use diem_types::account_address::AccountAddress;
+use parking_lot::Mutex;
+
+use crate::pos::consensus::counters;
+
+/// The bound of the authors whose votes are kept. The authors are not
+/// verified yet, so all the votes are forgotten when more authors are seen,
+/// which only makes consensus verify the next copies.
+const MAX_AUTHORS: usize = 1024;
+/// The bound of the votes kept for the round of an author.
+const MAX_VOTES_PER_AUTHOR: usize = 8;
+
+#[derive(Default)]
+struct SeenVotes {
+    round: Round,
+    /// The hashes of the votes of `round`.
+    votes: HashSet<HashValue>,
+}
+
+/// The votes delivered to consensus recently, see the module doc.
+#[derive(Default)]
+pub struct VoteDedup {
+    seen: Mutex<HashMap<AccountAddress, SeenVotes>>,
+}
+
+impl VoteDedup {
+    pub fn new() -> Self { Self::default() }
+
+    /// Record `vote`, and return whether it is to be delivered, i.e. it is
+    /// not a copy of a vote delivered before.
+    pub fn check(&self, vote: &Vote) -> bool {
+        let round = vote.vote_data().proposed().round();
+        let encoded = bcs::to_bytes(vote).expect("Failed to serialize.");
+        let hash = HashValue::sha3_256_of(&encoded);
+        let mut seen = self.seen.lock();
+        if seen.len() >= MAX_AUTHORS && !seen.contains_key(&vote.author()) {
+            seen.clear();
+        }
+        let seen = seen.entry(vote.author()).or_default();
+        if round < seen.round {
+            // Consensus drops the votes of the rounds passed anyway.
+            return true;
+        }
+        if round > seen.round {
+            seen.round = round;
+            seen.votes.clear();
+        }
+        if seen.votes.contains(&hash) {
+            counters::VOTES_DEDUPED.inc();
+            return false;
+        }
+        if seen.votes.len() < MAX_VOTES_PER_AUTHOR {
+            seen.votes.insert(hash);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VoteDedup;
+    use consensus_types::{vote::Vote, vote_data::VoteData};
+    use diem_crypto::HashValue;
+    use diem_types::{
+        block_info::BlockInfo, ledger_info::LedgerInfo,
+        validator_signer::ValidatorSigner,
+    };
+
+    fn vote(signer: &ValidatorSigner, round: u64, parent: BlockInfo) -> Vote {
+        Vote::new(
+            VoteData::new(BlockInfo::random(round), parent),
+            signer.author(),
+            LedgerInfo::new(BlockInfo::empty(), HashValue::zero()),
+            signer,
+        )
+    }
+
+    #[test]
+    fn test_duplicate_votes_dropped() {
+        let dedup = VoteDedup::new();
+        let (signer, other_signer) =
+            (ValidatorSigner::from_int(1), ValidatorSigner::from_int(2));
+        let first = vote(&signer, 1, BlockInfo::empty());
+        assert!(dedup.check(&first));
+        assert!(!dedup.check(&first));
+        let other = vote(&other_signer, 1, BlockInfo::empty());
+        assert!(dedup.check(&other));
+
+        // An equivocating vote of the same author and round gets through.
+        let equivocating = vote(&signer, 1, BlockInfo::random(0));
+        assert!(dedup.check(&equivocating));
+        assert!(!dedup.check(&equivocating));
+
+        // So does the vote sent again on a timeout.
+        let mut timeout = first.clone();
+        timeout.add_timeout_signature(first.timeout().sign(&signer));
+        assert!(dedup.check(&timeout));
+        assert!(!dedup.check(&timeout));
+
+        // The votes of the rounds passed are not kept once the round
+        // advances.
+        assert!(dedup.check(&vote(&signer, 2, BlockInfo::empty())));
+        assert!(dedup.check(&first));
+        assert!(dedup.check(&first));
+        // Only for the author of the higher round.
+        assert!(!dedup.check(&other));
+    }
+
+    #[test]
+    fn test_forged_copy_does_not_suppress_vote() {
+        let dedup = VoteDedup::new();
+        let (signer, forger) =
+            (ValidatorSigner::from_int(1), ValidatorSigner::from_int(2));
+        let genuine = vote(&signer, 1, BlockInfo::empty());
+        // The ledger info of the vote with a signature of another node gets
+        // there first.
+        let forged = Vote::new_with_signature(
+            genuine.vote_data().clone(),
+            genuine.author(),
+            genuine.ledger_info().clone(),
+            forger.sign(genuine.ledger_info()),
+        );
+        assert!(dedup.check(&forged));
+        assert!(dedup.check(&genuine));
+        assert!(!dedup.check(&genuine));
+
+        // A forged vote of a high round does not stop the dedup of the
+        // votes of the other authors.
+        let other_signer = ValidatorSigner::from_int(3);
+        let other = vote(&other_signer, 1, BlockInfo::empty());
+        assert!(dedup.check(&other));
+        assert!(dedup.check(&Vote::new_with_signature(
+            VoteData::new(BlockInfo::random(100), BlockInfo::empty()),
+            forger.author(),
+            genuine.ledger_info().clone(),
+            forger.sign(genuine.ledger_info()),
+        )));
+        assert!(!dedup.check(&other));
+    }
+}
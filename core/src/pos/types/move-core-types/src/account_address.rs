@@ -343,6 +343,32 @@ mod tests {
         AccountAddress::from_bytes(bytes).unwrap_err();
     }
 
+    #[test]
+    fn test_peer_id_of_other_lengths() {
+        for len in [16, 20] {
+            let bytes = vec![1u8; len];
+            AccountAddress::from_bytes(&bytes).unwrap_err();
+            AccountAddress::try_from(bytes.as_slice()).unwrap_err();
+            AccountAddress::try_from(bytes.clone()).unwrap_err();
+
+            // The hex literal of a shorter id is left-padded, so it
+            // round-trips through the 32-byte address.
+            let literal = format!("0x{}", hex::encode(&bytes));
+            let address = AccountAddress::from_hex_literal(&literal).unwrap();
+            assert_eq!(
+                &address.as_ref()[AccountAddress::LENGTH - len..],
+                &bytes[..]
+            );
+            assert_eq!(format!("0x{}", address.short_str_lossless()), literal);
+        }
+
+        let address = AccountAddress::random();
+        assert_eq!(
+            AccountAddress::from_bytes(address.to_vec()).unwrap(),
+            address
+        );
+    }
+
     #[test]
     fn test_deserialize_from_json_value() {
         let address = AccountAddress::random();
@@ -7,12 +7,76 @@
 
 #![forbid(unsafe_code)]
 
-use crate::ledger_info::{LedgerInfo, LedgerInfoWithSignatures};
-use anyhow::{ensure, format_err, Result};
+use crate::{
+    ledger_info::{LedgerInfo, LedgerInfoWithSignatures},
+    validator_verifier::VerifyError,
+};
+use anyhow::Result;
 #[cfg(any(test, feature = "fuzzing"))]
 use proptest::{collection::vec, prelude::*};
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
+use thiserror::Error;
+
+/// Why an `EpochChangeProof` fails the verification. The errors returned by
+/// `EpochChangeProof::verify` can be downcast to this.
+#[derive(Debug, Error, PartialEq)]
+pub enum EpochChangeProofError {
+    #[error("The EpochChangeProof is empty")]
+    Empty,
+    #[error(
+        "The EpochChangeProof is stale as our verifier is already ahead of \
+         the entire EpochChangeProof"
+    )]
+    Stale,
+    /// A LedgerInfo does not follow the epoch trusted before it.
+    #[error("LedgerInfo has unexpected epoch {actual}, expected {expected}")]
+    NonContiguousEpochs { expected: u64, actual: u64 },
+    #[error("LedgerInfo of epoch {epoch} has bad signatures: {error}")]
+    BadSignature {
+        epoch: u64,
+        #[source]
+        error: VerifyError,
+    },
+    #[error("LedgerInfo of epoch {epoch} doesn't carry a ValidatorSet")]
+    MissingNextEpochState { epoch: u64 },
+    /// The proof is for another epoch than the one it is expected for.
+    #[error("EpochChangeProof starts at epoch {actual}, expected {expected}")]
+    WrongEpoch { expected: u64, actual: u64 },
+    /// The verifier rejects the LedgerInfo for another reason, e.g. it does
+    /// not match the waypoint.
+    #[error("LedgerInfo of epoch {epoch} is rejected: {reason}")]
+    Rejected { epoch: u64, reason: String },
+}
+
+impl EpochChangeProofError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EpochChangeProofError::Empty => "empty",
+            EpochChangeProofError::Stale => "stale",
+            EpochChangeProofError::NonContiguousEpochs { .. } => {
+                "non_contiguous_epochs"
+            }
+            EpochChangeProofError::BadSignature { .. } => "bad_signature",
+            EpochChangeProofError::MissingNextEpochState { .. } => {
+                "missing_next_epoch_state"
+            }
+            EpochChangeProofError::WrongEpoch { .. } => "wrong_epoch",
+            EpochChangeProofError::Rejected { .. } => "rejected",
+        }
+    }
+}
+
+/// The first LedgerInfo of an `EpochChangeProof` failing the verification,
+/// at `index` of `ledger_info_with_sigs`. An empty proof fails at index 0,
+/// and a stale one at its last LedgerInfo.
+#[derive(Debug, Error, PartialEq)]
+#[error("LedgerInfo {index} of the EpochChangeProof fails: {error}")]
+pub struct EpochChangeProofFailure {
+    pub index: usize,
+    #[source]
+    pub error: EpochChangeProofError,
+}
 
 /// The verification of the epoch change proof starts with verifier that is
 /// trusted by the client: could be either a waypoint (upon startup) or a known
@@ -61,7 +125,7 @@ impl EpochChangeProof {
         self.ledger_info_with_sigs
             .first()
             .map(|li| li.ledger_info().epoch())
-            .ok_or_else(|| format_err!("Empty EpochChangeProof"))
+            .ok_or_else(|| EpochChangeProofError::Empty.into())
     }
 
     /// Verify the proof is correctly chained with known epoch and validator
@@ -73,25 +137,44 @@ impl EpochChangeProof {
     /// pass a waypoint in case it's not needed).
     ///
     /// We will also skip any stale ledger info's in the [`EpochChangeProof`].
+    ///
+    /// The verification failures are returned as [`EpochChangeProofError`].
     pub fn verify(
         &self, verifier: &dyn Verifier,
     ) -> Result<&LedgerInfoWithSignatures> {
-        ensure!(
-            !self.ledger_info_with_sigs.is_empty(),
-            "The EpochChangeProof is empty"
-        );
-        ensure!(
-            !verifier.is_ledger_info_stale(
-                self.ledger_info_with_sigs.last().unwrap().ledger_info()
-            ),
-            "The EpochChangeProof is stale as our verifier is already ahead \
-             of the entire EpochChangeProof"
-        );
+        self.verify_incrementally(verifier)
+            .map_err(|failure| failure.error.into())
+    }
+
+    /// Verify the proof like `verify`, one LedgerInfo at a time in epoch
+    /// order, and stop at the first one failing, so the later epochs of a
+    /// bad proof are not verified. Returns the index of the failing
+    /// LedgerInfo with the reason.
+    pub fn verify_incrementally(
+        &self, verifier: &dyn Verifier,
+    ) -> std::result::Result<&LedgerInfoWithSignatures, EpochChangeProofFailure>
+    {
+        let last = match self.ledger_info_with_sigs.last() {
+            Some(last) => last,
+            None => {
+                return Err(EpochChangeProofFailure {
+                    index: 0,
+                    error: EpochChangeProofError::Empty,
+                })
+            }
+        };
+        if verifier.is_ledger_info_stale(last.ledger_info()) {
+            return Err(EpochChangeProofFailure {
+                index: self.ledger_info_with_sigs.len() - 1,
+                error: EpochChangeProofError::Stale,
+            });
+        }
         let mut verifier_ref = verifier;
 
-        for ledger_info_with_sigs in self
+        for (index, ledger_info_with_sigs) in self
             .ledger_info_with_sigs
             .iter()
+            .enumerate()
             // Skip any stale ledger infos in the proof prefix. Note that with
             // the assertion above, we are guaranteed there is at least one
             // non-stale ledger info in the proof.
@@ -112,22 +195,35 @@ impl EpochChangeProof {
             //
             // Of course, if B's response returns first, we will reject A's
             // response as it's completely stale.
-            .skip_while(|&ledger_info_with_sigs| {
+            .skip_while(|(_, ledger_info_with_sigs)| {
                 verifier
                     .is_ledger_info_stale(ledger_info_with_sigs.ledger_info())
             })
         {
+            let epoch = ledger_info_with_sigs.ledger_info().epoch();
             // Try to verify each (epoch -> epoch + 1) jump in the
             // EpochChangeProof.
-            verifier_ref.verify(ledger_info_with_sigs)?;
+            verifier_ref.verify(ledger_info_with_sigs).map_err(|e| {
+                let error = match e.downcast::<EpochChangeProofError>() {
+                    Ok(e) => e,
+                    Err(e) => EpochChangeProofError::Rejected {
+                        epoch,
+                        reason: format!("{:#}", e),
+                    },
+                };
+                EpochChangeProofFailure { index, error }
+            })?;
             // While the original verification could've been via waypoints,
             // all the next epoch changes are verified using the (already
             // trusted) validator sets.
             verifier_ref = ledger_info_with_sigs
                 .ledger_info()
                 .next_epoch_state()
-                .ok_or_else(|| {
-                    format_err!("LedgerInfo doesn't carry a ValidatorSet")
+                .ok_or(EpochChangeProofFailure {
+                    index,
+                    error: EpochChangeProofError::MissingNextEpochState {
+                        epoch,
+                    },
                 })?;
         }
 
@@ -263,6 +359,21 @@ mod tests {
                 vec![]
             ))
             .is_err());
+        // The gap is after epoch 5.
+        let err = proof_4
+            .verify(&EpochState::new(
+                all_epoch[3],
+                validator_verifier[3].clone(),
+                vec![],
+            ))
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<EpochChangeProofError>(),
+            Some(&EpochChangeProofError::NonContiguousEpochs {
+                expected: 6,
+                actual: 9,
+            })
+        );
 
         // Test non increasing proof will fail
         let mut list = valid_ledger_info.clone();
@@ -291,6 +402,17 @@ mod tests {
                 vec![]
             ))
             .is_err());
+        let err = proof_6
+            .verify(&EpochState::new(
+                all_epoch[0],
+                validator_verifier[0].clone(),
+                vec![],
+            ))
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<EpochChangeProofError>(),
+            Some(EpochChangeProofError::BadSignature { epoch: 1, .. })
+        ));
 
         // Test proof with waypoint corresponding to the first epoch change
         // succeeds.
@@ -322,4 +444,80 @@ mod tests {
         );
         assert!(proof_8.verify(&waypoint_for_3_to_4).is_err());
     }
+
+    #[test]
+    fn verify_epoch_change_proof_incrementally() {
+        use crate::validator_verifier::random_validator_verifier;
+        use diem_crypto::hash::HashValue;
+
+        // The validators are the same in all the epochs.
+        let (signers, validator_verifier) =
+            random_validator_verifier(1, None, true);
+        let mut ledger_infos = vec![];
+        for epoch in 1..=5 {
+            let ledger_info = LedgerInfo::new(
+                BlockInfo::new(
+                    epoch,
+                    0,
+                    HashValue::zero(),
+                    HashValue::zero(),
+                    epoch,
+                    0,
+                    Some(EpochState::new(
+                        epoch + 1,
+                        validator_verifier.clone(),
+                        vec![],
+                    )),
+                    None,
+                ),
+                HashValue::zero(),
+            );
+            // The third epoch is signed over another LedgerInfo.
+            let signed = if epoch == 3 {
+                LedgerInfo::new(BlockInfo::empty(), HashValue::zero())
+            } else {
+                ledger_info.clone()
+            };
+            let signatures = signers
+                .iter()
+                .map(|s| (s.author(), s.sign(&signed)))
+                .collect();
+            ledger_infos
+                .push(LedgerInfoWithSignatures::new(ledger_info, signatures));
+        }
+        let verifier = EpochState::new(1, validator_verifier, vec![]);
+
+        let proof = EpochChangeProof::new(ledger_infos.clone(), false);
+        let failure = proof.verify_incrementally(&verifier).unwrap_err();
+        assert_eq!(failure.index, 2);
+        assert!(matches!(
+            failure.error,
+            EpochChangeProofError::BadSignature { epoch: 3, .. }
+        ));
+        assert_eq!(
+            proof
+                .verify(&verifier)
+                .unwrap_err()
+                .downcast::<EpochChangeProofError>()
+                .unwrap(),
+            failure.error
+        );
+
+        // The prefix before the bad epoch is fine.
+        let proof = EpochChangeProof::new(ledger_infos[..2].to_vec(), false);
+        assert_eq!(
+            proof.verify_incrementally(&verifier).unwrap(),
+            &ledger_infos[1]
+        );
+        let failure = EpochChangeProof::new(vec![], false)
+            .verify_incrementally(&verifier)
+            .unwrap_err();
+        assert_eq!(
+            failure,
+            EpochChangeProofFailure {
+                index: 0,
+                error: EpochChangeProofError::Empty,
+            }
+        );
+    }
 }
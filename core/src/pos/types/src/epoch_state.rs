@@ -6,12 +6,11 @@
 // See http://www.gnu.org/licenses/
 
 use crate::{
-    epoch_change::Verifier,
+    epoch_change::{EpochChangeProofError, Verifier},
     ledger_info::{LedgerInfo, LedgerInfoWithSignatures},
     on_chain_config::OnChainConfig,
     validator_verifier::ValidatorVerifier,
 };
-use anyhow::ensure;
 use once_cell::sync::OnceCell;
 #[cfg(any(test, feature = "fuzzing"))]
 use proptest_derive::Arbitrary;
@@ -73,13 +72,20 @@ impl Verifier for EpochState {
     fn verify(
         &self, ledger_info: &LedgerInfoWithSignatures,
     ) -> anyhow::Result<()> {
-        ensure!(
-            self.epoch == ledger_info.ledger_info().epoch(),
-            "LedgerInfo has unexpected epoch {}, expected {}",
-            ledger_info.ledger_info().epoch(),
-            self.epoch
-        );
-        ledger_info.verify_signatures(&self.verifier())?;
+        let epoch = ledger_info.ledger_info().epoch();
+        if self.epoch != epoch {
+            return Err(EpochChangeProofError::NonContiguousEpochs {
+                expected: self.epoch,
+                actual: epoch,
+            }
+            .into());
+        }
+        ledger_info
+            .verify_signatures(&self.verifier())
+            .map_err(|error| EpochChangeProofError::BadSignature {
+                epoch,
+                error,
+            })?;
         Ok(())
     }
 
@@ -71,6 +71,21 @@ error_chain! {
             display("Rpc gets cancelled by disconnection"),
         }
 
+        Shutdown {
+            description("Rpc gets cancelled by shutdown"),
+            display("Rpc gets cancelled by shutdown"),
+        }
+
+        DeadlineExceeded {
+            description("Rpc deadline exceeded"),
+            display("Rpc deadline exceeded before the request is sent"),
+        }
+
+        TooManyPendingRequests {
+            description("Too many pending rpc requests"),
+            display("Too many rpc requests waiting for their responses"),
+        }
+
         InvalidTimestamp {
             description("Peer timestamp drifts too much"),
             display("Drift too much"),
@@ -10,6 +10,24 @@ use crate::{
     block_data_manager::BlockStatus,
     light_protocol::Provider as LightProvider,
     message::{decode_msg, Message, MsgId},
+    pos::{
+        consensus::{
+            ConsensusQueueConfig, EpochRetrievalFailoverConfig,
+            VoteRebroadcastConfig,
+        },
+        protocol::{
+            blacklist::PeerBlacklistConfig,
+            liveness::PeerLivenessConfig,
+            message::codec::CodecKind,
+            message_size::MessageSizeLimits,
+            rate_limit::SendRateLimit,
+            request_manager::{
+                circuit_breaker::CircuitBreakerConfig,
+                retry_budget::RetryBudgetConfig, rpc_timeouts::RpcTimeouts,
+            },
+            send_jitter::SendJitterConfig,
+        },
+    },
     sync::{
         message::{
             handle_rlp_message, msgid, Context, DynamicCapability,
@@ -427,6 +445,109 @@ pub struct ProtocolConfiguration {
     pub check_status_genesis: bool,
 
     pub pos_started_as_voter: bool,
+    /// PoS messages larger than this size in bytes are compressed before
+    /// sending. 0 disables the compression.
+    pub pos_message_compression_threshold: usize,
+    /// The retry policy of the PoS RPC requests that time out.
+    pub pos_request_max_retries: usize,
+    pub pos_request_retry_base_delay: Duration,
+    pub pos_request_retry_max_delay: Duration,
+    pub pos_request_retry_backoff_multiplier: f64,
+    /// The maximum number of the PoS RPC requests waiting for responses at
+    /// the same time, 0 means no limit.
+    pub pos_max_concurrent_rpcs: usize,
+    /// The maximum number of the PoS RPC requests tracked by the request
+    /// manager, beyond which new requests are rejected, 0 means no limit.
+    pub pos_max_pending_requests: usize,
+    /// Whether the peers for the PoS RPC requests without a recipient are
+    /// chosen by the voting power of their validators.
+    pub pos_request_weight_by_voting_power: bool,
+    /// The circuit breakers taking the PoS peers failing the RPC requests
+    /// out of the peer selection for a while.
+    #[ignore_malloc_size_of = "plain configuration"]
+    pub pos_rpc_circuit_breaker: CircuitBreakerConfig,
+    /// The timeouts of the PoS RPC requests of each message type.
+    #[ignore_malloc_size_of = "plain configuration"]
+    pub pos_rpc_timeouts: RpcTimeouts,
+    /// The retries shared by the block retrievals of a PoS sync session.
+    #[ignore_malloc_size_of = "plain configuration"]
+    pub pos_sync_retry_budget: RetryBudgetConfig,
+    /// The maximum number of the epoch change proofs assembled for the PoS
+    /// peers at the same time, beyond which the epoch retrievals are
+    /// answered busy, 0 means no limit.
+    pub pos_max_concurrent_epoch_retrievals: usize,
+    /// The failover of the epoch retrievals timing out to the other PoS
+    /// peers ahead of us.
+    #[ignore_malloc_size_of = "plain configuration"]
+    pub pos_epoch_retrieval_failover: EpochRetrievalFailoverConfig,
+    /// How long the PoS messages of the same later epoch do not trigger
+    /// another epoch retrieval after one is triggered.
+    pub pos_epoch_sync_debounce: Duration,
+    /// Whether the PoS consensus messages sent to the peers are stamped
+    /// with the sequence numbers by which the peers drop the replays.
+    pub pos_consensus_msg_sequencing: bool,
+    /// The number of the recent PoS proposals and votes whose copies from
+    /// the other peers are dropped, 0 disables the dedup.
+    pub pos_seen_msgs_cache_size: usize,
+    #[ignore_malloc_size_of = "plain configuration"]
+    pub pos_consensus_queue_config: ConsensusQueueConfig,
+    /// The size limits of the PoS messages received from peers. Peers
+    /// sending larger messages are disconnected.
+    #[ignore_malloc_size_of = "plain configuration"]
+    pub pos_message_size_limits: MessageSizeLimits,
+    /// The limit of the total size of the blocks in one block retrieval
+    /// response, 0 means no limit.
+    pub pos_block_retrieval_max_response_bytes: u64,
+    /// The most blocks of a block stream pushed to a PoS peer before it
+    /// acknowledges them, whatever window it grants, 0 means no limit.
+    pub pos_block_stream_max_in_flight: u32,
+    /// The bounds of the number of blocks asked in one block retrieval
+    /// request, which is adjusted toward the target transfer time of a
+    /// response. A max of 0 disables the adjustment.
+    pub pos_block_retrieval_min_chunk_size: u64,
+    pub pos_block_retrieval_max_chunk_size: u64,
+    pub pos_block_retrieval_target_latency: Duration,
+    /// The rate limit of the PoS messages sent to each peer, `None` means
+    /// no limit.
+    #[ignore_malloc_size_of = "plain configuration"]
+    pub pos_send_rate_limit: Option<SendRateLimit>,
+    /// The most messages queued for each PoS peer to keep their order, 0
+    /// means no limit.
+    pub pos_peer_send_queue_size: usize,
+    /// The `EpochChangeProof`s larger than this size in bytes once encoded
+    /// are sent in chunks of this size, 0 disables the chunks.
+    pub pos_epoch_change_chunk_size: usize,
+    /// Whether each chunk of an `EpochChangeProof` is compressed.
+    pub pos_epoch_change_chunk_compression: bool,
+    /// The random delays of the consensus messages sent to many peers, so
+    /// the peers do not receive them all at once.
+    #[ignore_malloc_size_of = "plain configuration"]
+    pub pos_send_jitter: SendJitterConfig,
+    /// Only broadcast the consensus messages to the validators of the
+    /// current epoch, instead of all the connected PoS peers.
+    pub pos_broadcast_to_validators_only: bool,
+    /// The liveness pings of the PoS peers.
+    #[ignore_malloc_size_of = "plain configuration"]
+    pub pos_peer_liveness: PeerLivenessConfig,
+    /// The blacklisting of the PoS peers violating the protocol too often.
+    #[ignore_malloc_size_of = "plain configuration"]
+    pub pos_peer_blacklist: PeerBlacklistConfig,
+    /// The re-sends of the votes until their rounds are superseded.
+    #[ignore_malloc_size_of = "plain configuration"]
+    pub pos_vote_rebroadcast: VoteRebroadcastConfig,
+    /// The codec of the `ConsensusMsg`s this node prefers. Another codec
+    /// than BCS is only used with the peers preferring the same.
+    #[ignore_malloc_size_of = "plain configuration"]
+    pub pos_consensus_msg_codec: CodecKind,
+    /// The chain id exchanged in the PoS chain id handshake. The peers with
+    /// another chain id are disconnected.
+    pub pos_chain_id: u64,
+    /// The PoS peers of a protocol version below it are disconnected once
+    /// connected.
+    pub pos_min_peer_protocol_version: ProtocolVersion,
+    /// How long a PoS peer is waited for to send its chain id handshake once
+    /// connected, 0 means no limit.
+    pub pos_handshake_timeout: Duration,
 }
 
 impl SynchronizationProtocolHandler {
@@ -711,6 +832,9 @@ impl SynchronizationProtocolHandler {
             }
             ErrorKind::InternalError(_) => {}
             ErrorKind::RpcCancelledByDisconnection => {}
+            ErrorKind::Shutdown => {}
+            ErrorKind::DeadlineExceeded => {}
+            ErrorKind::TooManyPendingRequests => {}
             ErrorKind::RpcTimeout => {}
             ErrorKind::UnexpectedMessage(_) => {
                 op = Some(UpdateNodeOperation::Remove)
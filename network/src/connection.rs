@@ -5,7 +5,7 @@
 use crate::{
     io::{IoContext, StreamToken},
     throttling::THROTTLING_SERVICE,
-    Error, ErrorKind,
+    Error, ErrorKind, SendCompletion,
 };
 use bytes::{Bytes, BytesMut};
 use lazy_static::lazy_static;
@@ -113,10 +113,16 @@ struct Packet {
     original_is_high_priority: bool,
     throttling_size: usize,
     creation_time: Instant,
+    // notified when the packet is written or dropped.
+    completion: Option<SendCompletion>,
 }
 
 impl Packet {
-    fn new(data: Vec<u8>, priority: SendQueuePriority) -> Result<Self, Error> {
+    fn new(
+        data: Vec<u8>, priority: SendQueuePriority,
+        completion: Option<SendCompletion>,
+    ) -> Result<Self, Error>
+    {
         // update throttling
         let throttling_size = data.len();
         THROTTLING_SERVICE
@@ -131,6 +137,7 @@ impl Packet {
             original_is_high_priority: is_high_priority,
             throttling_size,
             creation_time: Instant::now(),
+            completion,
         })
     }
 
@@ -149,6 +156,10 @@ impl Packet {
 
 impl Drop for Packet {
     fn drop(&mut self) {
+        if let Some(completion) = self.completion.take() {
+            completion.complete(self.is_send_completed());
+        }
+
         THROTTLING_SERVICE
             .write()
             .on_dequeue(self.throttling_size, self.original_is_high_priority);
@@ -334,6 +345,16 @@ impl<Socket: GenericSocket> GenericConnection<Socket> {
         &mut self, io: &IoContext<Message>, data: Vec<u8>,
         priority: SendQueuePriority,
     ) -> Result<SendQueueStatus, Error>
+    {
+        self.send_with_completion(io, data, priority, None)
+    }
+
+    /// Add a packet to send queue, and notify `completion` when the packet
+    /// is written to the socket or dropped.
+    pub fn send_with_completion<Message: Sync + Send + Clone + 'static>(
+        &mut self, io: &IoContext<Message>, data: Vec<u8>,
+        priority: SendQueuePriority, completion: Option<SendCompletion>,
+    ) -> Result<SendQueueStatus, Error>
     {
         if !data.is_empty() {
             let size = data.len();
@@ -343,7 +364,7 @@ impl<Socket: GenericSocket> GenericConnection<Socket> {
 
             trace!("Sending packet, token = {}, size = {}", self.token, size);
 
-            let packet = Packet::new(data, priority)?;
+            let packet = Packet::new(data, priority, completion)?;
             self.send_queue.push_back(packet, priority);
 
             SEND_METER.mark(size);
@@ -695,7 +716,8 @@ mod tests {
     fn connection_write_is_buffered() {
         let mut connection = TestConnection::new();
         connection.socket = TestSocket::with_buf(10);
-        let packet = Packet::new(vec![0; 60], SendQueuePriority::High).unwrap();
+        let packet =
+            Packet::new(vec![0; 60], SendQueuePriority::High, None).unwrap();
         connection
             .send_queue
             .push_back(packet, SendQueuePriority::High);
@@ -710,6 +732,47 @@ mod tests {
         assert_eq!(sending_packet.sending_pos, 10);
     }
 
+    #[test]
+    fn connection_write_completion() {
+        let written = Arc::new(parking_lot::Mutex::new(vec![]));
+        let completion = || {
+            let written = written.clone();
+            Some(SendCompletion::new(move |w| written.lock().push(w)))
+        };
+        let mut connection = TestConnection::new();
+        connection.socket = TestSocket::with_buf(10);
+        connection
+            .send_with_completion(
+                &test_io(),
+                vec![0; 15],
+                SendQueuePriority::High,
+                completion(),
+            )
+            .unwrap();
+        connection
+            .send_with_completion(
+                &test_io(),
+                vec![0; 15],
+                SendQueuePriority::High,
+                completion(),
+            )
+            .unwrap();
+
+        // The first packet is only notified after all its bytes are written.
+        connection.writable(&test_io()).unwrap();
+        assert!(written.lock().is_empty());
+        assert_eq!(
+            connection.writable(&test_io()).unwrap(),
+            WriteStatus::Complete
+        );
+        assert_eq!(*written.lock(), vec![true]);
+
+        // The second packet is dropped with the connection before written.
+        connection.writable(&test_io()).unwrap();
+        drop(connection);
+        assert_eq!(*written.lock(), vec![true, false]);
+    }
+
     #[test]
     fn connection_read() {
         let mut connection = TestConnection::new();
@@ -297,6 +297,37 @@ pub enum UpdateNodeOperation {
     Remove,
 }
 
+/// Tells the sender of a packet whether the packet is written to the socket.
+///
+/// The callback is called exactly once: with `true` when all the bytes of
+/// the packet are written, or with `false` if the packet is dropped before,
+/// e.g. because the session is closed or the packet cannot be queued.
+pub struct SendCompletion {
+    callback: Option<Box<dyn FnOnce(bool) + Send + Sync>>,
+}
+
+impl SendCompletion {
+    pub fn new(callback: impl FnOnce(bool) + Send + Sync + 'static) -> Self {
+        SendCompletion {
+            callback: Some(Box::new(callback)),
+        }
+    }
+
+    pub fn complete(mut self, written: bool) {
+        if let Some(callback) = self.callback.take() {
+            callback(written);
+        }
+    }
+}
+
+impl Drop for SendCompletion {
+    fn drop(&mut self) {
+        if let Some(callback) = self.callback.take() {
+            callback(false);
+        }
+    }
+}
+
 pub trait NetworkContext {
     fn get_protocol(&self) -> ProtocolId;
 
@@ -308,6 +339,16 @@ pub trait NetworkContext {
         version_valid_till: ProtocolVersion, priority: SendQueuePriority,
     ) -> Result<(), Error>;
 
+    /// Same as `send`, and `completion` is notified when the message is
+    /// written to the socket of the peer. A message to the node itself is
+    /// delivered locally and reported as written at once.
+    fn send_with_completion(
+        &self, node_id: &NodeId, msg: Vec<u8>,
+        min_protocol_version: ProtocolVersion,
+        version_valid_till: ProtocolVersion, priority: SendQueuePriority,
+        completion: SendCompletion,
+    ) -> Result<(), Error>;
+
     fn disconnect_peer(
         &self, node_id: &NodeId, op: Option<UpdateNodeOperation>, reason: &str,
     );
@@ -47,7 +47,7 @@ use crate::{
     session_manager::SessionManager,
     Error, ErrorKind, HandlerWorkType, IpFilter, NatType, NetworkConfiguration,
     NetworkContext as NetworkContextTrait, NetworkIoMessage,
-    NetworkProtocolHandler, PeerInfo, ProtocolId, ProtocolInfo,
+    NetworkProtocolHandler, PeerInfo, ProtocolId, ProtocolInfo, SendCompletion,
     UpdateNodeOperation, NODE_TAG_ARCHIVE, NODE_TAG_NODE_TYPE,
 };
 
@@ -303,12 +303,12 @@ impl NetworkService {
     where
         F: FnOnce(&NetworkContext) -> R,
     {
-        let io = IoContext::new(self.io_service.as_ref().unwrap().channel(), 0);
-        match self.inner {
-            Some(ref inner) => {
+        match (&self.io_service, &self.inner) {
+            (Some(io_service), Some(inner)) => {
+                let io = IoContext::new(io_service.channel(), 0);
                 Ok(inner.with_context(handler, protocol, &io, action))
             }
-            None => Err("Network service not started yet!".to_owned().into()),
+            _ => Err("Network service not started yet!".to_owned().into()),
         }
     }
 
@@ -479,13 +479,14 @@ impl DelayedQueue {
 
     fn send_delayed_messages(&self, network_service: &NetworkServiceInner) {
         let context = self.queue.lock().pop().unwrap();
-        let r = context.session.write().send_packet(
+        let r = context.session.write().send_packet_with_completion(
             &context.io,
             Some(context.protocol),
             context.min_protocol_version,
             session::PACKET_USER,
             context.msg,
             context.priority,
+            context.completion,
         );
         match r {
             Ok(_) => {}
@@ -1885,6 +1886,7 @@ struct DelayMessageContext {
     /// The minimum peer protocol version since which the message is supported.
     min_protocol_version: ProtocolVersion,
     priority: SendQueuePriority,
+    completion: Option<SendCompletion>,
 }
 
 impl DelayMessageContext {
@@ -1892,6 +1894,7 @@ impl DelayMessageContext {
         ts: Instant, io: IoContext<NetworkIoMessage>, protocol: ProtocolId,
         session: SharedSession, peer: NodeId, msg: Vec<u8>,
         min_protocol_version: ProtocolVersion, priority: SendQueuePriority,
+        completion: Option<SendCompletion>,
     ) -> Self
     {
         DelayMessageContext {
@@ -1903,6 +1906,7 @@ impl DelayMessageContext {
             msg,
             min_protocol_version,
             priority,
+            completion,
         }
     }
 }
@@ -1952,26 +1956,15 @@ impl<'a> NetworkContext<'a> {
     pub fn protocol_handler(&self) -> &dyn NetworkProtocolHandler {
         &*self.handler
     }
-}
-
-impl<'a> NetworkContextTrait for NetworkContext<'a> {
-    fn get_protocol(&self) -> ProtocolId { self.protocol }
-
-    fn get_peer_connection_origin(&self, node_id: &NodeId) -> Option<bool> {
-        self.network_service.get_peer_connection_origin(node_id)
-    }
-
-    fn is_peer_self(&self, node_id: &NodeId) -> bool {
-        *node_id == *self.network_service.metadata.id()
-    }
-
-    fn self_node_id(&self) -> NodeId { *self.network_service.metadata.id() }
 
-    /// Message is sent through this method.
-    fn send(
+    /// Send a user packet of the protocol, see `NetworkContextTrait::send`.
+    /// `completion` is dropped, i.e. notified as not written, if the peer is
+    /// not connected.
+    fn send_user_packet(
         &self, node_id: &NodeId, msg: Vec<u8>,
         min_protocol_version: ProtocolVersion,
         version_valid_till: ProtocolVersion, priority: SendQueuePriority,
+        completion: Option<SendCompletion>,
     ) -> Result<(), Error>
     {
         if version_valid_till < self.min_supported_version {
@@ -1985,6 +1978,9 @@ impl<'a> NetworkContextTrait for NetworkContext<'a> {
 
         if *node_id == *self.network_service.metadata.id() {
             self.handler.send_local_message(self, msg);
+            if let Some(completion) = completion {
+                completion.complete(true);
+            }
             return Ok(());
         }
 
@@ -2014,6 +2010,7 @@ impl<'a> NetworkContextTrait for NetworkContext<'a> {
                         msg,
                         min_protocol_version,
                         priority,
+                        completion,
                     ));
                     self.io.register_timer_once_nocancel(
                         SEND_DELAYED_MESSAGES,
@@ -2022,13 +2019,14 @@ impl<'a> NetworkContextTrait for NetworkContext<'a> {
                     trace!("register delayed timer delay:{:?} ts_to_send:{:?} length:{}", latency, ts_to_send, queue.len());
                 }
                 None => {
-                    session.write().send_packet(
+                    session.write().send_packet_with_completion(
                         self.io,
                         Some(self.protocol),
                         min_protocol_version,
                         session::PACKET_USER,
                         msg,
                         priority,
+                        completion,
                     )?;
                 }
             }
@@ -2036,6 +2034,54 @@ impl<'a> NetworkContextTrait for NetworkContext<'a> {
         }
         Ok(())
     }
+}
+
+impl<'a> NetworkContextTrait for NetworkContext<'a> {
+    fn get_protocol(&self) -> ProtocolId { self.protocol }
+
+    fn get_peer_connection_origin(&self, node_id: &NodeId) -> Option<bool> {
+        self.network_service.get_peer_connection_origin(node_id)
+    }
+
+    fn is_peer_self(&self, node_id: &NodeId) -> bool {
+        *node_id == *self.network_service.metadata.id()
+    }
+
+    fn self_node_id(&self) -> NodeId { *self.network_service.metadata.id() }
+
+    /// Message is sent through this method.
+    fn send(
+        &self, node_id: &NodeId, msg: Vec<u8>,
+        min_protocol_version: ProtocolVersion,
+        version_valid_till: ProtocolVersion, priority: SendQueuePriority,
+    ) -> Result<(), Error>
+    {
+        self.send_user_packet(
+            node_id,
+            msg,
+            min_protocol_version,
+            version_valid_till,
+            priority,
+            None,
+        )
+    }
+
+    fn send_with_completion(
+        &self, node_id: &NodeId, msg: Vec<u8>,
+        min_protocol_version: ProtocolVersion,
+        version_valid_till: ProtocolVersion, priority: SendQueuePriority,
+        completion: SendCompletion,
+    ) -> Result<(), Error>
+    {
+        self.send_user_packet(
+            node_id,
+            msg,
+            min_protocol_version,
+            version_valid_till,
+            priority,
+            Some(completion),
+        )
+    }
 
     fn disconnect_peer(
         &self, node_id: &NodeId, op: Option<UpdateNodeOperation>, reason: &str,
@@ -9,7 +9,7 @@ use crate::{
     parse_msg_id_leb128_2_bytes_at_most,
     service::{NetworkServiceInner, ProtocolVersion},
     DisconnectReason, Error, ErrorKind, ProtocolId, ProtocolInfo,
-    SessionMetadata, UpdateNodeOperation, PROTOCOL_ID_SIZE,
+    SendCompletion, SessionMetadata, UpdateNodeOperation, PROTOCOL_ID_SIZE,
 };
 use bytes::Bytes;
 use diem_crypto::{bls::BLS_PUBLIC_KEY_LENGTH, ValidCryptoMaterial};
@@ -559,6 +559,25 @@ impl Session {
         min_proto_version: ProtocolVersion, packet_id: u8, data: Vec<u8>,
         priority: SendQueuePriority,
     ) -> Result<SendQueueStatus, Error>
+    {
+        self.send_packet_with_completion(
+            io,
+            protocol,
+            min_proto_version,
+            packet_id,
+            data,
+            priority,
+            None,
+        )
+    }
+
+    /// Send a packet to remote peer asynchronously, and notify `completion`
+    /// when it is written to the socket or dropped.
+    pub fn send_packet_with_completion<Message: Send + Sync + Clone>(
+        &mut self, io: &IoContext<Message>, protocol: Option<ProtocolId>,
+        min_proto_version: ProtocolVersion, packet_id: u8, data: Vec<u8>,
+        priority: SendQueuePriority, completion: Option<SendCompletion>,
+    ) -> Result<SendQueueStatus, Error>
     {
         self.check_message_protocol_version(
             protocol.clone(),
@@ -566,7 +585,8 @@ impl Session {
             &data,
         )?;
         let packet = self.prepare_packet(protocol, packet_id, data)?;
-        self.connection_mut().send(io, packet, priority)
+        self.connection_mut()
+            .send_with_completion(io, packet, priority, completion)
     }
 
     /// Send a packet to remote peer immediately.